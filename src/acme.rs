@@ -0,0 +1,533 @@
+use actix_web::{HttpRequest, HttpResponse};
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, RrSet, TxtRecord};
+use serde_json::json;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::instrument;
+
+use crate::auth::auth_ok;
+use crate::db::{get_or_mget_records, DbLock};
+use crate::delete::delete_rr_sets;
+use crate::errors_and_responses::{auth_err, err, internal_err, success_with_toplevel_data};
+use crate::journal::ChangeAuthor;
+use crate::set::sign_and_set_records;
+use crate::storage::{Connection, Storage};
+use crate::types::{AppState, RecordIdentifier, RequestBody};
+use crate::validation::{check_soa, validate_records};
+
+/// The label that is prepended to a domain to get the name of its DNS-01 challenge records.
+pub const ACME_CHALLENGE_LABEL: &str = "_acme-challenge";
+/// How long we wait at most for a change to become visible if the client requested to wait.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn default_acme_ttl() -> u32 {
+    60
+}
+
+/// Returns the name of the DNS-01 challenge TXT records for the domain (RFC 8555, section 8.4).
+///
+/// For wildcard domains, the challenge is placed at the base domain, i.e. the challenge for
+/// `*.example.com.` is at `_acme-challenge.example.com.`. Some clients pass the challenge name
+/// instead of the domain, so a name that already starts with `_acme-challenge` is returned as is.
+pub fn challenge_name(domain: &Name) -> Option<Name> {
+    let is_challenge_name = domain
+        .iter()
+        .next()
+        .is_some_and(|label| label.eq_ignore_ascii_case(ACME_CHALLENGE_LABEL.as_bytes()));
+    if is_challenge_name {
+        return Some(domain.clone());
+    }
+    let domain = if domain.is_wildcard() {
+        domain.base_name()
+    } else {
+        domain.clone()
+    };
+    Name::from_ascii(ACME_CHALLENGE_LABEL)
+        .and_then(|label| label.append_domain(&domain))
+        .ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeUpdate {
+    Present,
+    Cleanup,
+}
+
+/// A request of the `acme-present` or `acme-cleanup` method.
+#[derive(Debug, Clone)]
+pub struct ChallengeRequest<'a> {
+    pub client_username: &'a str,
+    pub confidant_password: &'a str,
+    pub domain: &'a Name,
+    pub value: &'a str,
+    /// The TTL to set the challenge RR set to; only used for `Present`.
+    pub ttl: Option<u32>,
+    pub update: ChallengeUpdate,
+    /// Wait until the change is visible in the db before answering.
+    pub wait: bool,
+}
+
+/// Handles an `acme-present` or `acme-cleanup` request: authorizes it for the challenge name of
+/// the domain, updates the challenge RR set, and optionally waits until the change is visible.
+pub async fn handle_challenge_request(
+    req: &HttpRequest,
+    challenge: ChallengeRequest<'_>,
+    state: &AppState,
+) -> HttpResponse {
+    let name = match challenge_name(challenge.domain) {
+        Some(n) if challenge.domain.is_fqdn() => n,
+        _ => {
+            return err(
+                "The domain is not absolute or too long for a challenge name.",
+                Vec::<Option<String>>::new(),
+            )
+        }
+    };
+
+    // policies see the challenge name, so they can restrict clients to _acme-challenge names
+    let request_body = match challenge.update {
+        ChallengeUpdate::Present => RequestBody::AcmePresent {
+            name: name.clone(),
+            value: challenge.value.to_string(),
+        },
+        ChallengeUpdate::Cleanup => RequestBody::AcmeCleanup {
+            name: name.clone(),
+            value: challenge.value.to_string(),
+        },
+    };
+    let mut auth = auth_ok(
+        req,
+        request_body,
+        state,
+        challenge.client_username,
+        challenge.confidant_password,
+    )
+    .await;
+    if !auth.success {
+        auth.message.push('\n');
+        return auth_err(auth.message);
+    }
+
+    let mut con = match state.db_pool.get().await {
+        Ok(c) => c,
+        Err(_) => return internal_err("No db connection."),
+    };
+    let mut dnssec_con = match state.db_pool_dnssec.get().await {
+        Ok(c) => c,
+        Err(_) => return internal_err("No db connection."),
+    };
+
+    let changed = match update_challenge(
+        &name,
+        challenge.value,
+        challenge.ttl,
+        challenge.update,
+        &ChangeAuthor::from_request(req, challenge.client_username),
+        state,
+        &mut con,
+        &mut dnssec_con,
+    )
+    .await
+    {
+        Ok(c) => c,
+        Err(res) => return res,
+    };
+    if challenge.wait {
+        if let Err(res) = wait_for_challenge(
+            &name,
+            challenge.value,
+            challenge.update,
+            &mut con,
+            &mut dnssec_con,
+        )
+        .await
+        {
+            return res;
+        }
+    }
+
+    match (challenge.update, changed) {
+        (ChallengeUpdate::Present, true) => success_with_toplevel_data(
+            "added challenge value",
+            json!({ "name": name, "ttl": challenge.ttl }),
+        ),
+        (ChallengeUpdate::Present, false) => success_with_toplevel_data(
+            "challenge value was already present",
+            json!({ "name": name, "ttl": challenge.ttl }),
+        ),
+        (ChallengeUpdate::Cleanup, true) => {
+            success_with_toplevel_data("removed challenge value", json!({ "name": name }))
+        }
+        (ChallengeUpdate::Cleanup, false) => {
+            success_with_toplevel_data("challenge value was not present", json!({ "name": name }))
+        }
+    }
+}
+
+/// Adds the value to (`Present`) or removes it from (`Cleanup`) the challenge TXT RR set at
+/// `name` and signs the result. If `ttl` is given, the RR set's TTL is set to it.
+///
+/// The TXT RR set is locked for the duration of the update so that concurrent challenges for the
+/// same name don't overwrite each other's values.
+///
+/// Returns whether the RR set was changed. If something goes wrong, the response that should be
+/// returned to the client is returned as the error.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(state, con, dnssec_con))]
+pub async fn update_challenge(
+    name: &Name,
+    value: &str,
+    ttl: Option<u32>,
+    update: ChallengeUpdate,
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<bool, HttpResponse> {
+    let ident = RecordIdentifier {
+        name: name.clone(),
+        rr_type: RecordType::TXT,
+    };
+    let lock = match DbLock::acquire(&ident, con).await {
+        Ok(l) => l,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    let res =
+        update_challenge_locked(&ident, value, ttl, update, author, state, con, dnssec_con).await;
+    if let Err(e) = lock.release(con).await {
+        return Err(internal_err(e.to_string()));
+    }
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn update_challenge_locked(
+    ident: &RecordIdentifier,
+    value: &str,
+    ttl: Option<u32>,
+    update: ChallengeUpdate,
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<bool, HttpResponse> {
+    let existing = match get_or_mget_records(&[ident.db_key()], con).await {
+        Ok(mut e) => e.pop().flatten(),
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    let mut values = match existing.as_ref().map(|e| &e.rr_set) {
+        Some(RrSet::TXT { rr_set }) => rr_set.clone(),
+        _ => vec![],
+    };
+    let contained = values.iter().any(|txt| txt.value == value);
+
+    match update {
+        ChallengeUpdate::Present => {
+            if contained && (ttl.is_none() || existing.as_ref().map(|e| e.ttl) == ttl) {
+                return Ok(false);
+            }
+            if !contained {
+                values.push(TxtRecord {
+                    value: value.to_string(),
+                });
+            }
+        }
+        ChallengeUpdate::Cleanup => {
+            if !contained {
+                return Ok(false);
+            }
+            values.retain(|txt| txt.value != value);
+            if values.is_empty() {
+                return delete_rr_sets(
+                    std::slice::from_ref(ident),
+                    &[],
+                    author,
+                    state,
+                    con,
+                    dnssec_con,
+                )
+                .await
+                .map(|_| true);
+            }
+        }
+    }
+
+    let records = vec![DbEntry {
+        name: ident.name.clone(),
+        ttl: ttl
+            .or_else(|| existing.as_ref().map(|e| e.ttl))
+            .unwrap_or_else(default_acme_ttl),
+        meta: existing.map(|e| e.meta).unwrap_or_default(),
+        rr_set: RrSet::TXT { rr_set: values },
+    }];
+    let valid = validate_records(&records);
+    if let Some(Err(e)) = valid.first() {
+        return Err(err(
+            "The challenge record is invalid.",
+            vec![Some(e.to_string())],
+        ));
+    }
+    let (soa_check, used_zones, new_authoritative_zones) = match check_soa(&records, con).await {
+        Ok(s) => s,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    if let Some(Err(e)) = soa_check.first() {
+        return Err(err(
+            "The challenge record is not part of a zone managed by pektin.",
+            vec![Some(e.to_string())],
+        ));
+    }
+
+    sign_and_set_records(
+        &records,
+        &used_zones,
+        &new_authoritative_zones,
+        author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await
+    .map(|_| true)
+}
+
+/// Waits until the challenge value is present in (`Present`) or absent from (`Cleanup`) the db.
+/// When waiting for a value to be present, this also waits for the RRSIG covering it.
+#[instrument(skip(con, dnssec_con))]
+pub async fn wait_for_challenge(
+    name: &Name,
+    value: &str,
+    update: ChallengeUpdate,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    let ident = RecordIdentifier {
+        name: name.clone(),
+        rr_type: RecordType::TXT,
+    };
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    loop {
+        let existing = match get_or_mget_records(&[ident.db_key()], con).await {
+            Ok(mut e) => e.pop().flatten(),
+            Err(e) => return Err(internal_err(e.to_string())),
+        };
+        let contained = matches!(
+            existing.map(|e| e.rr_set),
+            Some(RrSet::TXT { rr_set }) if rr_set.iter().any(|txt| txt.value == value)
+        );
+        let visible = match update {
+            ChallengeUpdate::Present => {
+                contained
+                    && match dnssec_con.count_existing(&[ident.rrsig_db_key()]).await {
+                        Ok(n) => n > 0,
+                        Err(e) => return Err(internal_err(e.to_string())),
+                    }
+            }
+            ChallengeUpdate::Cleanup => !contained,
+        };
+        if visible {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(internal_err(format!(
+                "The change was not visible in the db after {} seconds.",
+                WAIT_TIMEOUT.as_secs()
+            )));
+        }
+        sleep(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::*;
+    use crate::acme_cleanup::acme_cleanup;
+    use crate::acme_present::acme_present;
+    use crate::test_utils::{
+        author, call_endpoint, get_with_rrsig, name, set_records, soa_entry, test_state,
+    };
+
+    async fn update(
+        value: &str,
+        ttl: Option<u32>,
+        update: ChallengeUpdate,
+        state: &AppState,
+    ) -> Result<bool, HttpResponse> {
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        update_challenge(
+            &name("_acme-challenge.example.com."),
+            value,
+            ttl,
+            update,
+            &author(),
+            state,
+            &mut con,
+            &mut dnssec_con,
+        )
+        .await
+    }
+
+    /// The TTL and values of the challenge RR set, if it exists.
+    async fn challenge(state: &AppState) -> Option<(u32, Vec<String>)> {
+        let (entry, rrsig) =
+            get_with_rrsig("_acme-challenge.example.com.", RecordType::TXT, state).await;
+        assert_eq!(entry.is_some(), rrsig.is_some());
+        entry.map(|entry| match entry.rr_set {
+            RrSet::TXT { rr_set } => (entry.ttl, rr_set.into_iter().map(|r| r.value).collect()),
+            _ => panic!("not a TXT RR set"),
+        })
+    }
+
+    #[test]
+    fn challenge_names() {
+        for (domain, expected) in [
+            ("example.com.", "_acme-challenge.example.com."),
+            ("www.example.com.", "_acme-challenge.www.example.com."),
+            ("*.example.com.", "_acme-challenge.example.com."),
+            (
+                "_acme-challenge.example.com.",
+                "_acme-challenge.example.com.",
+            ),
+            (
+                "_ACME-Challenge.example.com.",
+                "_ACME-Challenge.example.com.",
+            ),
+            (
+                "_acme-challenge-x.example.com.",
+                "_acme-challenge._acme-challenge-x.example.com.",
+            ),
+        ] {
+            assert_eq!(
+                challenge_name(&name(domain)),
+                Some(name(expected)),
+                "{domain}"
+            );
+        }
+
+        // the challenge label doesn't fit
+        let long_label = "a".repeat(63);
+        let long_name = format!("{long_label}.{long_label}.{long_label}.{}.", "b".repeat(61));
+        assert_eq!(challenge_name(&name(&long_name)), None);
+    }
+
+    #[actix_web::test]
+    async fn values_are_merged_and_removed() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+
+        assert_eq!(
+            update("a", Some(60), ChallengeUpdate::Present, &state)
+                .await
+                .ok(),
+            Some(true)
+        );
+        assert_eq!(challenge(&state).await, Some((60, vec!["a".into()])));
+        // a second value is added, e.g. for a wildcard and the base domain
+        assert_eq!(
+            update("b", None, ChallengeUpdate::Present, &state)
+                .await
+                .ok(),
+            Some(true)
+        );
+        assert_eq!(
+            challenge(&state).await,
+            Some((60, vec!["a".into(), "b".into()]))
+        );
+        // an existing value is only changed if the TTL differs
+        assert_eq!(
+            update("a", Some(60), ChallengeUpdate::Present, &state)
+                .await
+                .ok(),
+            Some(false)
+        );
+        assert_eq!(
+            update("a", Some(120), ChallengeUpdate::Present, &state)
+                .await
+                .ok(),
+            Some(true)
+        );
+        assert_eq!(
+            challenge(&state).await,
+            Some((120, vec!["a".into(), "b".into()]))
+        );
+
+        assert_eq!(
+            update("c", None, ChallengeUpdate::Cleanup, &state)
+                .await
+                .ok(),
+            Some(false)
+        );
+        assert_eq!(
+            update("a", None, ChallengeUpdate::Cleanup, &state)
+                .await
+                .ok(),
+            Some(true)
+        );
+        assert_eq!(challenge(&state).await, Some((120, vec!["b".into()])));
+        // removing the last value deletes the RR set
+        assert_eq!(
+            update("b", None, ChallengeUpdate::Cleanup, &state)
+                .await
+                .ok(),
+            Some(true)
+        );
+        assert_eq!(challenge(&state).await, None);
+        assert_eq!(
+            update("b", None, ChallengeUpdate::Cleanup, &state)
+                .await
+                .ok(),
+            Some(false)
+        );
+    }
+
+    #[actix_web::test]
+    async fn challenges_outside_of_zones_are_rejected() {
+        let state = test_state();
+        let res = update("a", Some(60), ChallengeUpdate::Present, &state).await;
+        assert_eq!(res.unwrap_err().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(challenge(&state).await, None);
+    }
+
+    #[actix_web::test]
+    async fn endpoints_wait_for_the_change() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let request = |wait: bool| {
+            serde_json::json!({
+                "client_username": "test",
+                "confidant_password": "",
+                "domain": "*.example.com.",
+                "value": "a",
+                "wait": wait,
+            })
+        };
+
+        let (status, body) =
+            call_endpoint(acme_present, &state, "/acme-present", request(true)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "added challenge value");
+        assert_eq!(
+            body["data"],
+            json!({ "name": "_acme-challenge.example.com.", "ttl": 60 })
+        );
+        assert_eq!(challenge(&state).await, Some((60, vec!["a".into()])));
+        let (status, body) =
+            call_endpoint(acme_present, &state, "/acme-present", request(false)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "challenge value was already present");
+
+        let (status, body) =
+            call_endpoint(acme_cleanup, &state, "/acme-cleanup", request(true)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["message"], "removed challenge value");
+        assert_eq!(
+            body["data"],
+            json!({ "name": "_acme-challenge.example.com." })
+        );
+        assert_eq!(challenge(&state).await, None);
+    }
+}
@@ -1,6 +1,6 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use actix_web::HttpRequest;
+use actix_web::{http::header::USER_AGENT, HttpRequest};
 use tracing::{debug, instrument};
 
 use crate::{
@@ -60,9 +60,28 @@ pub async fn auth(
         format!("Could not get Vault token for confidant: {}", err)
     );
 
+    authorize(
+        vault_endpoint,
+        &api_token,
+        ribston_endpoint,
+        client_username,
+        ribston_request_data,
+    )
+    .await
+}
+
+/// Evaluates the policy of the client for the request. The client must already be authenticated.
+#[instrument(skip(vault_endpoint, api_token, ribston_endpoint, ribston_request_data))]
+pub async fn authorize(
+    vault_endpoint: &str,
+    api_token: &str,
+    ribston_endpoint: &str,
+    client_username: &str,
+    ribston_request_data: RibstonRequestData,
+) -> AuthAnswer {
     // cache until restart
     let client_policy = return_if_err!(
-        vault::get_policy(vault_endpoint, &api_token, client_username).await,
+        vault::get_policy(vault_endpoint, api_token, client_username).await,
         err,
         format!("Could not get client policy: {}", err)
     );
@@ -115,22 +134,11 @@ pub async fn auth_ok(
         .expect("Time went backwards")
         .as_millis();
 
-    let api_method = match request_body {
-        RequestBody::Get { .. } => "get",
-        RequestBody::GetZoneRecords { .. } => "get-zone-records",
-        RequestBody::Set { .. } => "set",
-        RequestBody::Delete { .. } => "delete",
-        RequestBody::Search { .. } => "search",
-        RequestBody::Health => "health",
-    }
-    .into();
+    let api_method = api_method(&request_body).into();
 
     // somewhere in here a RefCell is involved. if we don't store it in this variable before the
     // auth call, clippy warns that the RefCell is held across an await point
-    let ip = req
-        .connection_info()
-        .realip_remote_addr()
-        .map(|s| s.to_string());
+    let ip = request_ip(req);
     let res = auth(
         &state.vault_uri,
         &state.vault_password,
@@ -141,8 +149,7 @@ pub async fn auth_ok(
         RibstonRequestData {
             api_method,
             ip,
-            // TODO user agent
-            user_agent: "TODO user agent".into(),
+            user_agent: request_user_agent(req),
             utc_millis,
             request_body,
         },
@@ -152,3 +159,103 @@ pub async fn auth_ok(
     debug!("Authentication result: {:?}", res);
     res
 }
+
+/// The IP address of the client that sent the request, as reported by a trusted proxy if any.
+pub fn request_ip(req: &HttpRequest) -> Option<String> {
+    req.connection_info()
+        .realip_remote_addr()
+        .map(|s| s.to_string())
+}
+
+/// The value of the request's `User-Agent` header, or an empty string if it has none.
+pub fn request_user_agent(req: &HttpRequest) -> String {
+    req.headers()
+        .get(USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Authorizes a request of a client that was already authenticated by other means than its
+/// confidant password, e.g. a DNS UPDATE signed with a TSIG key that is mapped to the client.
+#[instrument(skip(request_body, state))]
+pub async fn auth_ok_authenticated(
+    request_body: RequestBody,
+    state: &AppState,
+    client_username: &str,
+    ip: Option<String>,
+    user_agent: &str,
+) -> AuthAnswer {
+    if "yes, I really want to disable authentication" == state.skip_auth {
+        debug!("Skipping authentication");
+        return AuthAnswer {
+            success: true,
+            message: "Skipped authentication because SKIP_AUTH is set".into(),
+        };
+    }
+
+    let utc_millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis();
+
+    let api_token = return_if_err!(
+        vault::ApiTokenCache::get(
+            &state.vault_uri,
+            &state.vault_user_name,
+            &state.vault_password
+        )
+        .await,
+        err,
+        format!("Could not get Vault token for pektin-api: {}", err)
+    );
+
+    let res = authorize(
+        &state.vault_uri,
+        &api_token,
+        &state.ribston_uri,
+        client_username,
+        RibstonRequestData {
+            api_method: api_method(&request_body).into(),
+            ip,
+            user_agent: user_agent.into(),
+            utc_millis,
+            request_body,
+        },
+    )
+    .await;
+
+    debug!("Authorization result: {:?}", res);
+    res
+}
+
+/// The name of the API method that the request body belongs to, as passed to Ribston.
+fn api_method(request_body: &RequestBody) -> &'static str {
+    match request_body {
+        RequestBody::Get { .. } => "get",
+        RequestBody::GetZoneRecords { .. } => "get-zone-records",
+        RequestBody::Set { .. } => "set",
+        RequestBody::Delete { .. } => "delete",
+        RequestBody::Search { .. } => "search",
+        RequestBody::Health => "health",
+        RequestBody::ImportZone { .. } => "import-zone",
+        RequestBody::ExportZone { .. } => "export-zone",
+        RequestBody::ImportZoneTransfer { .. } => "import-zone-transfer",
+        RequestBody::GetNotifyTargets { .. } => "get-notify-targets",
+        RequestBody::SetNotifyTargets { .. } => "set-notify-targets",
+        RequestBody::AcmePresent { .. } => "acme-present",
+        RequestBody::AcmeCleanup { .. } => "acme-cleanup",
+        RequestBody::CreateZone { .. } => "create-zone",
+        RequestBody::DeleteZone { .. } => "delete-zone",
+        RequestBody::Update { .. } => "update",
+        RequestBody::ListZones { .. } => "list-zones",
+        RequestBody::DnsUpdate { .. } => "dns-update",
+        RequestBody::Transaction { .. } => "transaction",
+        RequestBody::Diff { .. } => "diff",
+        RequestBody::Sync { .. } => "sync",
+        RequestBody::History { .. } => "history",
+        RequestBody::Rollback { .. } => "rollback",
+        RequestBody::Snapshot { .. } => "snapshot",
+        RequestBody::RestoreSnapshot { .. } => "restore-snapshot",
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_web::HttpResponse;
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, RrSet};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::db::{
+    acquire_locks, get_records_atomically, get_zone_keys, release_locks, DbWriteBatch,
+};
+use crate::diff::diff_zone;
+use crate::dnssec::{get_dnskey_for_zone, verify_rrsig};
+use crate::errors_and_responses::{internal_err, PektinApiError, PektinApiResult};
+use crate::journal::{commit_zone_changes, prepare_zone_changes, soa_serial, ChangeAuthor};
+use crate::macros::unwrap_or_return_if_err;
+use crate::notify::{get_notify_targets, set_notify_targets, NotifyTarget};
+use crate::set::{sign_and_set_records, sign_and_write_changes};
+use crate::storage::Connection;
+use crate::types::{AppState, RecordIdentifier};
+use crate::validation::{check_soa, validate_records};
+use crate::zone_file::{parse_zone_file, write_zone_file};
+
+/// The version of the snapshot format. Snapshots with a greater version can't be restored.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// How often we try to read a zone before giving up because it is changed all the time.
+const SNAPSHOT_ATTEMPTS: usize = 5;
+
+/// A backup of one or more zones.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Snapshot {
+    pub version: u32,
+    /// When the snapshot was taken, in milliseconds since the Unix epoch.
+    pub utc_millis: u64,
+    pub zones: Vec<ZoneSnapshot>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ZoneSnapshot {
+    pub zone: Name,
+    pub serial: u32,
+    #[serde(flatten)]
+    pub records: SnapshotRecords,
+    #[serde(default)]
+    pub notify_targets: Vec<NotifyTarget>,
+}
+
+/// All RR sets of a zone, including DNSKEY, NSEC3, and RRSIG records.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotRecords {
+    Records(Vec<DbEntry>),
+    /// The zone in master file format. The DNSSEC records are ignored when the zone is restored,
+    /// so it is always signed again.
+    ZoneFile(String),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotFormat {
+    #[default]
+    Json,
+    ZoneFile,
+}
+
+/// A zone snapshot that was checked with `check_zone_snapshot()` and can be restored.
+#[derive(Debug, Clone)]
+pub struct CheckedZoneSnapshot {
+    pub zone: Name,
+    entries: Vec<DbEntry>,
+    resign: bool,
+    notify_targets: Vec<NotifyTarget>,
+}
+
+/// Takes a snapshot of the zone, or returns `None` if it doesn't exist. The zone's records are
+/// separated from those of its child zones like in `get_zone_keys()`.
+///
+/// The records and RRSIGs are read in a single db transaction. If an RR set is created or deleted
+/// after we listed the zone's keys, the zone is read again.
+#[instrument(skip(con))]
+pub async fn snapshot_zone(
+    zone: &Name,
+    format: SnapshotFormat,
+    con: &mut Connection,
+) -> PektinApiResult<Option<ZoneSnapshot>> {
+    for _ in 0..SNAPSHOT_ATTEMPTS {
+        let keys = match get_zone_keys(&[zone], con).await?.pop().flatten() {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+        let rrsig_keys: Vec<_> = keys
+            .iter()
+            .map(|key| RecordIdentifier::from_db_key(key).map(|ident| ident.rrsig_db_key()))
+            .collect::<Result<_, _>>()?;
+        let entries = get_records_atomically(&keys, &rrsig_keys, con).await?;
+        let notify_targets = get_notify_targets(zone, con).await?;
+
+        let keys_before: HashSet<_> = keys.iter().collect();
+        match get_zone_keys(&[zone], con).await?.pop().flatten() {
+            Some(keys_after) if keys_after.iter().collect::<HashSet<_>>() == keys_before => {}
+            _ => continue,
+        }
+
+        let entries: Vec<_> = entries.into_iter().flatten().collect();
+        let serial = entries
+            .iter()
+            .find(|e| &e.name == zone && e.rr_type() == RecordType::SOA)
+            .and_then(soa_serial);
+        let serial = match serial {
+            Some(s) => s,
+            // the zone was deleted in the meantime
+            None => continue,
+        };
+        let records = match format {
+            SnapshotFormat::Json => SnapshotRecords::Records(entries),
+            SnapshotFormat::ZoneFile => SnapshotRecords::ZoneFile(write_zone_file(zone, &entries)),
+        };
+        return Ok(Some(ZoneSnapshot {
+            zone: zone.clone(),
+            serial,
+            records,
+            notify_targets,
+        }));
+    }
+    Err(PektinApiError::SnapshotInconsistent(zone.to_string()))
+}
+
+/// Checks that the zone snapshot can be restored. Returns a message describing the problem if it
+/// can't.
+///
+/// If `resign` is false, the DNSSEC records of the snapshot are restored as they are. This
+/// requires the DNSKEY in the snapshot to be the zone's current key in Vault and every RR set to
+/// have a valid, unexpired RRSIG by it. Otherwise, only the other records are restored and signed
+/// again.
+#[instrument(skip(zone_snapshot, state, vault_api_token), fields(zone = %zone_snapshot.zone))]
+pub async fn check_zone_snapshot(
+    zone_snapshot: &ZoneSnapshot,
+    resign: bool,
+    state: &AppState,
+    vault_api_token: &str,
+) -> PektinApiResult<Result<CheckedZoneSnapshot, String>> {
+    let zone = &zone_snapshot.zone;
+    if !zone.is_fqdn() {
+        return Ok(Err(format!("The zone name {zone} is not absolute.")));
+    }
+    let mut entries = match &zone_snapshot.records {
+        SnapshotRecords::Records(entries) => entries.clone(),
+        SnapshotRecords::ZoneFile(_) if !resign => {
+            return Ok(Err(format!(
+                "The snapshot of {zone} is a zone file without signatures, it can only be restored with resign."
+            )))
+        }
+        SnapshotRecords::ZoneFile(text) => match parse_zone_file(text, zone) {
+            Ok(parsed) => parsed.entries,
+            Err(errors) => {
+                return Ok(Err(format!(
+                    "The zone file of {zone} is invalid: {}",
+                    errors.first().map(ToString::to_string).unwrap_or_default()
+                )))
+            }
+        },
+    };
+
+    if let Some(outside) = entries.iter().find(|e| !zone.zone_of(&e.name)) {
+        return Ok(Err(format!(
+            "{} is not part of the zone {zone}.",
+            outside.name
+        )));
+    }
+    if !entries
+        .iter()
+        .any(|e| &e.name == zone && e.rr_type() == RecordType::SOA)
+    {
+        return Ok(Err(format!(
+            "The snapshot of {zone} contains no SOA record."
+        )));
+    }
+    // DNSSEC records can't be set by clients, so only the other records are validated
+    let plain_entries: Vec<_> = entries
+        .iter()
+        .filter(|e| !e.rr_type().is_dnssec())
+        .cloned()
+        .collect();
+    let invalid = plain_entries
+        .iter()
+        .zip(validate_records(&plain_entries))
+        .find_map(|(e, valid)| valid.err().map(|err| (e.name.clone(), err)));
+    if let Some((name, e)) = invalid {
+        return Ok(Err(format!("The RR set {name} is invalid: {e}")));
+    }
+
+    if resign {
+        entries = plain_entries;
+    } else if let Some(problem) = check_signatures(zone, &entries, state, vault_api_token).await? {
+        return Ok(Err(format!(
+            "{problem}; restore the snapshot of {zone} with resign to sign it again."
+        )));
+    }
+
+    Ok(Ok(CheckedZoneSnapshot {
+        zone: zone.clone(),
+        entries,
+        resign,
+        notify_targets: zone_snapshot.notify_targets.clone(),
+    }))
+}
+
+/// Returns why the signatures of the entries can't be restored, if they can't.
+async fn check_signatures(
+    zone: &Name,
+    entries: &[DbEntry],
+    state: &AppState,
+    vault_api_token: &str,
+) -> PektinApiResult<Option<String>> {
+    let dnskey = entries
+        .iter()
+        .find(|e| &e.name == zone && e.rr_type() == RecordType::DNSKEY)
+        .and_then(|e| match &e.rr_set {
+            RrSet::DNSKEY { rr_set } => rr_set.first(),
+            _ => None,
+        });
+    let dnskey = match dnskey {
+        Some(d) => d,
+        None => return Ok(Some("The snapshot contains no DNSKEY record".into())),
+    };
+    let current_dnskey = get_dnskey_for_zone(zone, &state.vault_uri, vault_api_token).await?;
+    if serde_json::to_value(dnskey)? != serde_json::to_value(&current_dnskey)? {
+        return Ok(Some(
+            "The DNSKEY in the snapshot is not the zone's current key".into(),
+        ));
+    }
+
+    let rrsigs: HashMap<_, _> = entries
+        .iter()
+        .filter_map(|e| match &e.rr_set {
+            RrSet::RRSIG { rr_set } => Some((e.db_key(), rr_set)),
+            _ => None,
+        })
+        .collect();
+    let unsigned: Vec<_> = entries
+        .iter()
+        .filter(|e| e.rr_type() != RecordType::RRSIG)
+        .filter(|e| {
+            let ident = RecordIdentifier {
+                name: e.name.clone(),
+                rr_type: e.rr_type(),
+            };
+            !rrsigs
+                .get(&ident.rrsig_db_key())
+                .is_some_and(|rr_set| rr_set.iter().any(|r| verify_rrsig(e, r, dnskey)))
+        })
+        .collect();
+    Ok(unsigned.first().map(|e| {
+        format!(
+            "{} RR sets (e.g. {} {}) have no valid RRSIG",
+            unsigned.len(),
+            e.name,
+            e.rr_type()
+        )
+    }))
+}
+
+/// Replaces the zone with the checked snapshot and restores its notify targets. RR sets of the
+/// zone that are not part of the snapshot are deleted.
+///
+/// If the zone already exists, the change is recorded in its journal as made by `author` and its
+/// SOA serial is incremented beyond the current one, so that secondaries pick up the restored zone.
+///
+/// If something goes wrong, the response that should be returned to the client is returned as
+/// the error.
+#[instrument(skip(checked, state, vault_api_token, con, dnssec_con), fields(zone = %checked.zone))]
+pub async fn restore_zone(
+    checked: &CheckedZoneSnapshot,
+    author: &ChangeAuthor,
+    state: &AppState,
+    vault_api_token: &str,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    let zone = &checked.zone;
+    let current_keys = match get_zone_keys(&[zone], con).await {
+        Ok(mut k) => k.pop().flatten().unwrap_or_default(),
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    let current_idents = match current_keys
+        .iter()
+        .map(RecordIdentifier::from_db_key)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(i) => i,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    let restored_idents = checked
+        .entries
+        .iter()
+        .filter(|e| e.rr_type() != RecordType::RRSIG)
+        .map(|e| RecordIdentifier {
+            name: e.name.clone(),
+            rr_type: e.rr_type(),
+        });
+    let idents: Vec<_> = current_idents
+        .iter()
+        .cloned()
+        .chain(restored_idents)
+        .collect();
+
+    let locks = match acquire_locks(&idents, con).await {
+        Ok(l) => l,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    let res = if checked.resign {
+        resign_zone(checked, author, state, con, dnssec_con).await
+    } else {
+        write_zone(
+            checked,
+            &current_idents,
+            author,
+            state,
+            vault_api_token,
+            con,
+            dnssec_con,
+        )
+        .await
+    };
+    if let Err(e) = release_locks(locks, con).await {
+        return Err(internal_err(e.to_string()));
+    }
+    res?;
+
+    if let Err(e) = set_notify_targets(zone, &checked.notify_targets, con).await {
+        return Err(internal_err(e.to_string()));
+    }
+    Ok(())
+}
+
+/// Restores the records of the snapshot without their DNSSEC records and signs them again.
+async fn resign_zone(
+    checked: &CheckedZoneSnapshot,
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    let zone_diff = match diff_zone(&checked.zone, &checked.entries, con, dnssec_con).await {
+        Ok(d) => d,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    // the zone doesn't exist, so it is created like with import-zone
+    let (to_set, to_delete) = match zone_diff {
+        Some(zone_diff) => (
+            zone_diff
+                .added
+                .into_iter()
+                .chain(zone_diff.modified.into_iter().map(|change| change.after))
+                .collect(),
+            zone_diff
+                .removed
+                .iter()
+                .map(|r| RecordIdentifier {
+                    name: r.name.clone(),
+                    rr_type: r.rr_type(),
+                })
+                .collect(),
+        ),
+        None => (checked.entries.clone(), vec![]),
+    };
+    if to_set.is_empty() && to_delete.is_empty() {
+        return Ok(());
+    }
+
+    let (_soa_check, used_zones, new_authoritative_zones) = match check_soa(&to_set, con).await {
+        Ok(s) => s,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    unwrap_or_return_if_err!(
+        _soa_check,
+        "Tried to restore one or more records for a zone that does not have a SOA record.",
+        Err
+    );
+
+    if to_delete.is_empty() {
+        sign_and_set_records(
+            &to_set,
+            &used_zones,
+            &new_authoritative_zones,
+            author,
+            state,
+            con,
+            dnssec_con,
+        )
+        .await
+    } else {
+        sign_and_write_changes(
+            &to_set,
+            &to_delete,
+            &used_zones,
+            &new_authoritative_zones,
+            author,
+            state,
+            con,
+            dnssec_con,
+        )
+        .await
+    }
+}
+
+/// Restores the records of the snapshot together with their DNSSEC records as they are.
+async fn write_zone(
+    checked: &CheckedZoneSnapshot,
+    current_idents: &[RecordIdentifier],
+    author: &ChangeAuthor,
+    state: &AppState,
+    vault_api_token: &str,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    let (rrsigs, entries): (Vec<_>, Vec<_>) = checked
+        .entries
+        .iter()
+        .cloned()
+        .partition(|e| e.rr_type() == RecordType::RRSIG);
+    let restored_keys: HashSet<_> = entries.iter().map(DbEntry::db_key).collect();
+    let stale: Vec<_> = current_idents
+        .iter()
+        .filter(|ident| !restored_keys.contains(&ident.db_key()))
+        .collect();
+
+    let changed: Vec<_> = current_idents
+        .iter()
+        .cloned()
+        .chain(entries.iter().map(|e| RecordIdentifier {
+            name: e.name.clone(),
+            rr_type: e.rr_type(),
+        }))
+        .collect();
+    let zone_changes = match prepare_zone_changes(&changed, con, dnssec_con).await {
+        Ok(c) => c,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+
+    let batch = DbWriteBatch {
+        del: stale.iter().map(|ident| ident.db_key()).collect(),
+        dnssec_del: stale.iter().map(|ident| ident.rrsig_db_key()).collect(),
+        set: entries,
+        dnssec_set: rrsigs,
+        ..Default::default()
+    };
+    if let Err(e) = commit_zone_changes(
+        zone_changes,
+        batch,
+        state,
+        vault_api_token,
+        Some(author),
+        con,
+    )
+    .await
+    {
+        return Err(internal_err(e.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::restore_snapshot::restore_snapshot;
+    use crate::test_utils::{
+        a_entry, call_endpoint, get_with_rrsig, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    async fn take_snapshot(format: SnapshotFormat, state: &AppState) -> Snapshot {
+        let request = json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "zones": ["example.com."],
+            "format": format,
+        });
+        let (status, body) =
+            call_endpoint(crate::snapshot::snapshot, state, "/snapshot", request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        serde_json::from_value(body["data"].clone()).unwrap()
+    }
+
+    async fn restore(snapshot: &Snapshot, resign: bool, state: &AppState) -> (StatusCode, Value) {
+        let request = json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "snapshot": snapshot,
+            "resign": resign,
+        });
+        call_endpoint(restore_snapshot, state, "/restore-snapshot", request).await
+    }
+
+    #[actix_web::test]
+    async fn json_snapshots_are_restored_with_their_signatures() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&www), &state).await;
+        let (_, www_rrsig) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        let snapshot = take_snapshot(SnapshotFormat::Json, &state).await;
+        assert_eq!(snapshot.zones[0].serial, 2);
+
+        set_records(
+            &[
+                a_entry("www.example.com.", [192, 0, 2, 10]),
+                a_entry("mail.example.com.", [192, 0, 2, 2]),
+            ],
+            &state,
+        )
+        .await;
+
+        let (status, body) = restore(&snapshot, false, &state).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (entry, rrsig) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(www.clone()));
+        assert_eq!(rrsig, www_rrsig);
+        assert_eq!(
+            get_with_rrsig("mail.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+        // the serial is incremented so that secondaries pick up the restored zone
+        assert_eq!(zone_serial("example.com.", &state).await, Some(4));
+
+        // records whose RRSIG doesn't match can only be restored by signing them again
+        let mut tampered = snapshot.clone();
+        match &mut tampered.zones[0].records {
+            SnapshotRecords::Records(entries) => {
+                let entry = entries.iter_mut().find(|e| e.db_key() == www.db_key());
+                *entry.unwrap() = a_entry("www.example.com.", [192, 0, 2, 66]);
+            }
+            SnapshotRecords::ZoneFile(_) => unreachable!(),
+        }
+        let (status, body) = restore(&tampered, false, &state).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(
+            body["data"][0]["message"],
+            "1 RR sets (e.g. www.example.com. A) have no valid RRSIG; restore the snapshot of example.com. with resign to sign it again."
+        );
+        let (entry, _) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(www));
+    }
+
+    #[actix_web::test]
+    async fn zone_file_snapshots_are_signed_again() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&www), &state).await;
+        let snapshot = take_snapshot(SnapshotFormat::ZoneFile, &state).await;
+
+        let restored_state = test_state();
+        let (status, body) = restore(&snapshot, false, &restored_state).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(
+            body["data"][0]["message"],
+            "The snapshot of example.com. is a zone file without signatures, it can only be restored with resign."
+        );
+        assert_eq!(zone_serial("example.com.", &restored_state).await, None);
+
+        let (status, body) = restore(&snapshot, true, &restored_state).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (entry, rrsig) =
+            get_with_rrsig("www.example.com.", RecordType::A, &restored_state).await;
+        assert_eq!(entry, Some(www));
+        assert!(rrsig.is_some());
+        assert_eq!(
+            get_with_rrsig("example.com.", RecordType::SOA, &restored_state)
+                .await
+                .0,
+            get_with_rrsig("example.com.", RecordType::SOA, &state)
+                .await
+                .0
+        );
+    }
+}
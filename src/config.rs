@@ -1,4 +1,6 @@
-use pektin_common::load_env;
+use std::str::FromStr;
+
+use pektin_common::{load_env, PektinCommonError};
 
 use crate::errors_and_responses::PektinApiResult;
 
@@ -6,16 +8,41 @@ use crate::errors_and_responses::PektinApiResult;
 pub struct Config {
     pub bind_address: String,
     pub bind_port: u16,
+    pub db_backend: String,
     pub db_hostname: String,
     pub db_username: String,
     pub db_password: String,
     pub db_port: u16,
+    pub db_tls: bool,
+    /// Comma-separated `host:port` list of Redis Sentinels. If set, the db host is discovered via
+    /// the sentinels instead of using `db_hostname` and `db_port`.
+    pub db_sentinels: String,
+    pub db_sentinel_master: String,
+    pub db_sentinel_password: String,
+    pub db_pool_max_size: Option<usize>,
+    pub db_pool_wait_timeout_millis: Option<u64>,
+    pub db_pool_create_timeout_millis: Option<u64>,
+    pub db_pool_recycle_timeout_millis: Option<u64>,
+    /// If set, the DNSSEC data is stored in db 0 with this key prefix instead of in a separate db,
+    /// for managed redis offerings that only provide db 0. Only a single redis node (optionally
+    /// behind Sentinels) is supported; Redis Cluster isn't, as the transactions span keys in
+    /// different hash slots.
+    pub db_dnssec_key_prefix: String,
+    /// Whether to repair inconsistencies between the records and their DNSSEC data on startup (see
+    /// `repair::repair_consistency()`), e.g. after upgrading from a version that didn't write them
+    /// atomically.
+    pub repair_db_on_start: bool,
     pub vault_uri: String,
     pub ribston_uri: String,
     pub vault_password: String,
     pub vault_user_name: String,
     pub skip_auth: String,
     pub use_policies: String,
+    pub zone_transfer_bind_port: Option<u16>,
+    pub tsig_keys: String,
+    pub dns_update_bind_port: Option<u16>,
+    pub tsig_key_clients: String,
+    pub tsig_key_zones: String,
 }
 
 impl Config {
@@ -25,18 +52,52 @@ impl Config {
             bind_port: load_env("80", "BIND_PORT", false)?
                 .parse()
                 .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("BIND_PORT".into()))?,
+            db_backend: load_env("redis", "DB_BACKEND", false)?,
             db_hostname: load_env("pektin-db", "DB_HOSTNAME", false)?,
             db_port: load_env("6379", "DB_PORT", false)?
                 .parse()
                 .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("DB_PORT".into()))?,
             db_username: load_env("db-pektin-api", "DB_USERNAME", false)?,
             db_password: load_env("", "DB_PASSWORD", true)?,
+            db_tls: load_env("false", "DB_TLS", false)?
+                .parse()
+                .map_err(|_| pektin_common::PektinCommonError::InvalidEnvVar("DB_TLS".into()))?,
+            db_sentinels: load_env("", "DB_SENTINELS", false)?,
+            db_sentinel_master: load_env("mymaster", "DB_SENTINEL_MASTER", false)?,
+            db_sentinel_password: load_env("", "DB_SENTINEL_PASSWORD", true)?,
+            db_pool_max_size: load_optional_env("DB_POOL_MAX_SIZE")?,
+            db_pool_wait_timeout_millis: load_optional_env("DB_POOL_WAIT_TIMEOUT_MILLIS")?,
+            db_pool_create_timeout_millis: load_optional_env("DB_POOL_CREATE_TIMEOUT_MILLIS")?,
+            db_pool_recycle_timeout_millis: load_optional_env("DB_POOL_RECYCLE_TIMEOUT_MILLIS")?,
+            db_dnssec_key_prefix: load_env("", "DB_DNSSEC_KEY_PREFIX", false)?,
+            repair_db_on_start: load_env("false", "REPAIR_DB_ON_START", false)?
+                .parse()
+                .map_err(|_| {
+                    pektin_common::PektinCommonError::InvalidEnvVar("REPAIR_DB_ON_START".into())
+                })?,
             vault_uri: load_env("http://pektin-vault:80", "VAULT_URI", false)?,
             ribston_uri: load_env("http://pektin-ribston:80", "RIBSTON_URI", false)?,
             vault_password: load_env("", "V_PEKTIN_API_PASSWORD", true)?,
             vault_user_name: load_env("", "V_PEKTIN_API_USER_NAME", false)?,
             use_policies: load_env("ribston", "USE_POLICIES", false)?,
             skip_auth: load_env("false", "SKIP_AUTH", false)?,
+            zone_transfer_bind_port: load_optional_env("ZONE_TRANSFER_BIND_PORT")?,
+            tsig_keys: load_env("", "TSIG_KEYS", true)?,
+            dns_update_bind_port: load_optional_env("DNS_UPDATE_BIND_PORT")?,
+            tsig_key_clients: load_env("", "TSIG_KEY_CLIENTS", false)?,
+            tsig_key_zones: load_env("", "TSIG_KEY_ZONES", false)?,
         })
     }
 }
+
+/// Loads and parses an environment variable that is unset (`None`) by default.
+fn load_optional_env<T: FromStr>(name: &str) -> PektinApiResult<Option<T>> {
+    match load_env("", name, false)?.as_str() {
+        "" => Ok(None),
+        value => {
+            Ok(Some(value.parse().map_err(|_| {
+                PektinCommonError::InvalidEnvVar(name.into())
+            })?))
+        }
+    }
+}
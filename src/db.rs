@@ -1,91 +1,71 @@
-use pektin_common::deadpool_redis::redis::{AsyncCommands, FromRedisValue, Value};
-use pektin_common::proto::rr::Name;
-use pektin_common::{deadpool_redis, DnskeyRecord, PektinCommonError, RrSet};
-use pektin_common::{deadpool_redis::Connection, DbEntry};
-use tracing::{debug, instrument};
+use std::collections::BTreeMap;
+
+use data_encoding::HEXLOWER;
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, DnskeyRecord, RrSet};
+use sha2::{Digest, Sha256};
+use tokio::time::{sleep, Duration, Instant};
+use tracing::instrument;
 
 use crate::errors_and_responses::{PektinApiError, PektinApiResult};
-use crate::types::RecordIdentifier;
+use crate::journal::JournalEntry;
+use crate::storage::{Connection, Storage};
+use crate::types::{RecordIdentifier, VersionedDbEntry};
+use crate::utils::random_string;
 
 #[instrument(skip(con))]
 pub async fn get_or_mget_records(
     keys: &[String],
     con: &mut Connection,
-) -> Result<Vec<Option<DbEntry>>, PektinCommonError> {
-    // if only one key comes back in the response, db returns an error because it cannot parse the reponse as a vector,
-    // and there were also issues with a "too many arguments for a GET command" error. we therefore roll our own implementation
-    // using only low-level commands.
-    if keys.len() == 1 {
-        debug!("using GET command");
-        match deadpool_redis::redis::cmd("GET")
-            .arg(&keys[0])
-            .query_async::<_, String>(con)
-            .await
-        {
-            Ok(s) => match DbEntry::deserialize_from_db(&keys[0], &s) {
-                Ok(data) => Ok(vec![Some(data)]),
-                Err(e) => Err(e),
-            },
-            Err(_) => Ok(vec![None]),
-        }
-    } else {
-        debug!("using MGET command");
-        match deadpool_redis::redis::cmd("MGET")
-            .arg(&keys)
-            .query_async::<_, Vec<Value>>(con)
-            .await
-        {
-            Ok(v) => {
-                let parsed_opt: Result<Vec<_>, _> = keys
-                    .iter()
-                    .zip(v.into_iter())
-                    .map(|(key, val)| {
-                        if val == Value::Nil {
-                            Ok(None)
-                        } else {
-                            DbEntry::deserialize_from_db(
-                                key,
-                                &String::from_redis_value(&val)
-                                    .expect("db response could not be deserialized"),
-                            )
-                            .map(Some)
-                        }
-                    })
-                    .collect();
-                Ok(parsed_opt?)
-            }
-            Err(e) => Err(e.into()),
-        }
-    }
+) -> PektinApiResult<Vec<Option<DbEntry>>> {
+    con.get_records(keys).await
 }
 
 /// Takes a list of zone names and returns the list of all DNSKEY records for those zones, as a
-/// tuple together with the zone name.
+/// tuple together with the zone name. Returns an error if one of the zones has no DNSKEY record.
 #[instrument(skip(con))]
 pub async fn get_zone_dnskey_records(
     zones: &[Name],
     con: &mut Connection,
-) -> Result<Vec<(Name, DnskeyRecord)>, PektinCommonError> {
+) -> PektinApiResult<Vec<(Name, DnskeyRecord)>> {
     if zones.is_empty() {
         return Ok(vec![]);
     }
 
     let dnskey_db_keys: Vec<_> = zones.iter().map(|z| format!("{z}:DNSKEY")).collect();
-    get_or_mget_records(&dnskey_db_keys, con).await.map(|keys| {
-        std::iter::zip(dnskey_db_keys, keys)
-            .map(|(db_key, dnskey)| {
-                let dnskey_entry =
-                    dnskey.unwrap_or_else(|| panic!("No DNSKEY entry for zone {} in db", db_key));
-                let dnskey = match dnskey_entry.rr_set {
-                    RrSet::DNSKEY { mut rr_set } => {
-                        rr_set.pop().expect("DNSKEY record set is empty")
-                    }
-                    _ => panic!("DNSKEY db entry did not contain a DNSKEY record"),
-                };
-                (dnskey_entry.name.clone(), dnskey)
-            })
-            .collect()
-    })
+    let dnskeys = get_or_mget_records(&dnskey_db_keys, con).await?;
+    std::iter::zip(zones, dnskeys)
+        .map(|(zone, dnskey)| match dnskey {
+            Some(DbEntry {
+                name,
+                rr_set: RrSet::DNSKEY { mut rr_set },
+                ..
+            }) => rr_set
+                .pop()
+                .map(|dnskey| (name, dnskey))
+                .ok_or_else(|| PektinApiError::NoDnskeyRecord(zone.to_string())),
+            _ => Err(PektinApiError::NoDnskeyRecord(zone.to_string())),
+        })
+        .collect()
+}
+
+/// Returns the DNSKEY record of the zone, or an error if the zone has none.
+#[instrument(skip(con))]
+pub async fn get_zone_dnskey(zone: &Name, con: &mut Connection) -> PektinApiResult<DnskeyRecord> {
+    let dnskey_db_key = format!("{zone}:DNSKEY");
+    match get_or_mget_records(&[dnskey_db_key], con)
+        .await?
+        .pop()
+        .flatten()
+    {
+        Some(DbEntry {
+            rr_set: RrSet::DNSKEY { mut rr_set },
+            ..
+        }) => rr_set
+            .pop()
+            .ok_or_else(|| PektinApiError::NoDnskeyRecord(zone.to_string())),
+        _ => Err(PektinApiError::NoDnskeyRecord(zone.to_string())),
+    }
 }
 
 /// Takes a list of zone names and gets all records of all zones, respectively, if a zone with the
@@ -98,18 +78,13 @@ pub async fn get_zone_keys(
     zones: &[&Name],
     con: &mut Connection,
 ) -> PektinApiResult<Vec<Option<Vec<String>>>> {
-    let available_zones = pektin_common::get_authoritative_zones(con).await?;
+    let available_zones = con.get_zones().await?;
 
     // we ignore non-existing names for now and store None for them
     let mut zones_record_keys = Vec::with_capacity(zones.len());
     for name in zones {
         if available_zones.contains(&name.to_string()) {
-            let glob = format!("*{}:*", name);
-            let record_keys = con
-                .keys::<_, Vec<String>>(glob)
-                .await
-                .map_err(PektinCommonError::from)?;
-            zones_record_keys.push(Some(record_keys));
+            zones_record_keys.push(Some(con.get_zone_index(name).await?));
         } else {
             zones_record_keys.push(None);
         }
@@ -161,6 +136,11 @@ impl RecordIdentifier {
         format!("{}:{:?}", self.name.to_lowercase(), self.rr_type)
     }
 
+    /// The key in the DNSSEC db under which the RRSIG covering this entry is stored.
+    pub fn rrsig_db_key(&self) -> String {
+        format!("{}:RRSIG:{:?}", self.name.to_lowercase(), self.rr_type)
+    }
+
     /// Creates a `RecordIdentifier` from a db key.
     pub fn from_db_key(db_key: impl AsRef<str>) -> PektinApiResult<Self> {
         let (name, rr_type) = db_key
@@ -174,3 +154,350 @@ impl RecordIdentifier {
         Ok(Self { name, rr_type })
     }
 }
+
+/// Takes a list of zone names and gets all RR sets of all zones, respectively (see
+/// `get_zone_keys()`). DNSKEY and NSEC3 records as well as the RRSIGs from the DNSSEC db are only
+/// included if `include_dnssec` is true.
+///
+/// The RR sets in the return value are in the same order as the zones in `names`.
+#[instrument(skip(con, dnssec_con))]
+pub async fn get_zone_rr_sets(
+    zones: &[&Name],
+    include_dnssec: bool,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<Vec<Option<Vec<DbEntry>>>> {
+    let zones_record_keys = get_zone_keys(zones, con).await?;
+
+    let mut zones_rr_sets = Vec::with_capacity(zones_record_keys.len());
+    for keys_opt in zones_record_keys {
+        let keys = match keys_opt {
+            Some(keys) => keys,
+            None => {
+                zones_rr_sets.push(None);
+                continue;
+            }
+        };
+
+        let idents: Vec<_> = keys
+            .iter()
+            .map(RecordIdentifier::from_db_key)
+            .collect::<Result<_, _>>()?;
+        // DNSKEY and NSEC3 records are stored together with the other records
+        let (keys, idents): (Vec<_>, Vec<_>) = keys
+            .into_iter()
+            .zip(idents)
+            .filter(|(_, ident)| include_dnssec || !ident.rr_type.is_dnssec())
+            .unzip();
+        if keys.is_empty() {
+            zones_rr_sets.push(Some(vec![]));
+            continue;
+        }
+
+        let mut entries = get_or_mget_records(&keys, con).await?;
+        if include_dnssec {
+            let rrsig_keys: Vec<_> = idents.iter().map(RecordIdentifier::rrsig_db_key).collect();
+            entries.extend(get_or_mget_records(&rrsig_keys, dnssec_con).await?);
+        }
+        zones_rr_sets.push(Some(entries.into_iter().flatten().collect()));
+    }
+
+    Ok(zones_rr_sets)
+}
+
+/// The version of an RR set that doesn't exist. Expecting it makes sure that an RR set is only
+/// created, not overwritten.
+pub const ABSENT_VERSION: &str = "absent";
+
+/// How long a lock is held at most, in case its holder crashes before releasing it.
+const LOCK_EXPIRY_MILLIS: u64 = 30_000;
+/// How long we wait for a lock before giving up.
+const LOCK_WAIT: Duration = Duration::from_secs(10);
+/// How long we wait before trying to acquire a lock again.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A lock in db 0 that serializes read-modify-write operations on an RR set or a whole zone across
+/// all API instances. It must be released with `release()`; otherwise it expires after 30 seconds.
+#[derive(Debug)]
+pub struct DbLock {
+    key: String,
+    token: String,
+}
+
+impl DbLock {
+    /// The key of the lock for the given RR set. Like the journal key, it contains no colon so
+    /// that it never matches the globs used for records.
+    fn db_key(ident: &RecordIdentifier) -> String {
+        format!("lock/{}/{:?}", ident.name.to_lowercase(), ident.rr_type)
+    }
+
+    /// The key of the lock for the given zone. It differs from the keys of the RR set locks
+    /// because it has no type.
+    fn zone_db_key(zone: &Name) -> String {
+        format!("lock/{}", zone.to_lowercase())
+    }
+
+    /// Waits until the lock for the given RR set is acquired.
+    #[instrument(skip(con))]
+    pub async fn acquire(ident: &RecordIdentifier, con: &mut Connection) -> PektinApiResult<Self> {
+        Self::acquire_key(Self::db_key(ident), ident.db_key(), con).await
+    }
+
+    /// Waits until the lock for the given zone is acquired. The zone lock is held while the SOA
+    /// serial, the NSEC3 chain, and the journal of the zone are updated, see
+    /// `journal::prepare_zone_changes()`.
+    #[instrument(skip(con))]
+    pub async fn acquire_zone(zone: &Name, con: &mut Connection) -> PektinApiResult<Self> {
+        Self::acquire_key(Self::zone_db_key(zone), zone.to_string(), con).await
+    }
+
+    async fn acquire_key(
+        key: String,
+        locked: String,
+        con: &mut Connection,
+    ) -> PektinApiResult<Self> {
+        let lock = Self {
+            key,
+            token: random_string(),
+        };
+        let deadline = Instant::now() + LOCK_WAIT;
+        loop {
+            if con
+                .try_lock(&lock.key, &lock.token, LOCK_EXPIRY_MILLIS)
+                .await?
+            {
+                return Ok(lock);
+            }
+            if Instant::now() >= deadline {
+                return Err(PektinApiError::LockTimeout(locked));
+            }
+            sleep(LOCK_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Releases the lock if we still hold it, i.e. if it didn't expire in the meantime.
+    #[instrument(skip(con))]
+    pub async fn release(self, con: &mut Connection) -> PektinApiResult<()> {
+        con.unlock(&self.key, &self.token).await
+    }
+}
+
+/// Acquires the locks of all given RR sets. The locks are acquired in the order of their keys so
+/// that concurrent requests for overlapping RR sets can't deadlock.
+///
+/// RR set locks must always be acquired before zone locks (see `acquire_zone_locks()`).
+pub async fn acquire_locks(
+    idents: &[RecordIdentifier],
+    con: &mut Connection,
+) -> PektinApiResult<Vec<DbLock>> {
+    let idents: BTreeMap<_, _> = idents.iter().map(|ident| (ident.db_key(), ident)).collect();
+    let mut locks = Vec::with_capacity(idents.len());
+    for ident in idents.values() {
+        match DbLock::acquire(ident, con).await {
+            Ok(lock) => locks.push(lock),
+            Err(e) => {
+                release_locks(locks, con).await?;
+                return Err(e);
+            }
+        }
+    }
+    Ok(locks)
+}
+
+/// Acquires the locks of all given zones, in the order of their names like `acquire_locks()`.
+pub async fn acquire_zone_locks(
+    zones: &[Name],
+    con: &mut Connection,
+) -> PektinApiResult<Vec<DbLock>> {
+    let zones: BTreeMap<_, _> = zones
+        .iter()
+        .map(|zone| (zone.to_lowercase(), zone))
+        .collect();
+    let mut locks = Vec::with_capacity(zones.len());
+    for zone in zones.values() {
+        match DbLock::acquire_zone(zone, con).await {
+            Ok(lock) => locks.push(lock),
+            Err(e) => {
+                release_locks(locks, con).await?;
+                return Err(e);
+            }
+        }
+    }
+    Ok(locks)
+}
+
+pub async fn release_locks(locks: Vec<DbLock>, con: &mut Connection) -> PektinApiResult<()> {
+    for lock in locks {
+        lock.release(con).await?;
+    }
+    Ok(())
+}
+
+/// The version of an RR set, which changes whenever anything about the RR set changes. This is a
+/// hash of its db representation.
+pub fn rr_set_version(entry: &DbEntry) -> PektinApiResult<String> {
+    let hash = Sha256::digest(entry.serialize_for_db()?.as_bytes());
+    Ok(HEXLOWER.encode(&hash[..16]))
+}
+
+impl VersionedDbEntry {
+    /// Wraps the entry together with its current version.
+    pub fn new(entry: DbEntry) -> PektinApiResult<Self> {
+        Ok(Self {
+            version: Some(rr_set_version(&entry)?),
+            entry,
+        })
+    }
+}
+
+/// Compares the versions of the RR sets in the db with the expected versions. Returns a conflict
+/// message for each RR set whose version doesn't match, or `None` if it matches or no version is
+/// expected.
+///
+/// To make sure the RR sets don't change after this check, their locks must be held.
+#[instrument(skip(con))]
+pub async fn check_versions(
+    idents: &[RecordIdentifier],
+    expected_versions: &[Option<String>],
+    con: &mut Connection,
+) -> PektinApiResult<Vec<Option<String>>> {
+    let keys: Vec<_> = idents.iter().map(RecordIdentifier::db_key).collect();
+    let current = get_or_mget_records(&keys, con).await?;
+    current
+        .iter()
+        .zip(expected_versions)
+        .map(|(entry, expected)| match expected {
+            Some(expected) => version_conflict(entry.as_ref(), expected),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// Returns a conflict message if the RR set (`None` if it doesn't exist) doesn't have the expected
+/// version.
+pub fn version_conflict(
+    entry: Option<&DbEntry>,
+    expected: &str,
+) -> PektinApiResult<Option<String>> {
+    let version = match entry {
+        Some(entry) => rr_set_version(entry)?,
+        None => ABSENT_VERSION.to_string(),
+    };
+    Ok((version != expected).then(|| {
+        format!("conflict: expected version {expected}, but the current version is {version}")
+    }))
+}
+
+/// The index of the db that contains the RRSIG records. All other records are in db 0.
+pub const DNSSEC_DB: u8 = 1;
+
+/// Writes to db 0 and the DNSSEC db that are applied atomically with `execute()`.
+#[derive(Debug, Default)]
+pub struct DbWriteBatch {
+    /// Records to set in db 0.
+    pub set: Vec<DbEntry>,
+    /// Keys to delete from db 0.
+    pub del: Vec<String>,
+    /// RRSIG records to set in the DNSSEC db.
+    pub dnssec_set: Vec<DbEntry>,
+    /// Keys to delete from the DNSSEC db.
+    pub dnssec_del: Vec<String>,
+    /// Entries to append to the zone journals (see the `journal` module).
+    pub journal: Vec<(Name, JournalEntry)>,
+}
+
+impl DbWriteBatch {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+            && self.del.is_empty()
+            && self.dnssec_set.is_empty()
+            && self.dnssec_del.is_empty()
+            && self.journal.is_empty()
+    }
+
+    /// The NSEC3 RR sets that are set or deleted by the batch.
+    pub fn nsec3_idents(&self) -> PektinApiResult<Vec<RecordIdentifier>> {
+        let deleted = self
+            .del
+            .iter()
+            .map(RecordIdentifier::from_db_key)
+            .collect::<PektinApiResult<Vec<_>>>()?;
+        Ok(self
+            .set
+            .iter()
+            .map(|entry| RecordIdentifier {
+                name: entry.name.clone(),
+                rr_type: entry.rr_type(),
+            })
+            .chain(deleted)
+            .filter(|ident| ident.rr_type == RecordType::NSEC3)
+            .collect())
+    }
+
+    /// Applies all writes atomically, so that either all of them or none are visible to other
+    /// clients. `con` must be a connection to db 0.
+    ///
+    /// Keys that are both set and deleted are deleted first and then set.
+    ///
+    /// The zone indexes and the RRSIG expiry set (see the `index` module) are updated and the
+    /// journal entries are appended within the same transaction.
+    #[instrument(skip(con))]
+    pub async fn execute(&self, con: &mut Connection) -> PektinApiResult<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        con.write(self).await
+    }
+}
+
+/// Gets the records with the given keys from db 0 and the RRSIGs with the given keys from the
+/// DNSSEC db atomically, so that the result is a consistent state of both dbs (see
+/// `DbWriteBatch::execute()`). `con` must be a connection to db 0.
+///
+/// The entries are returned in the order of `keys` followed by `dnssec_keys`.
+#[instrument(skip(con))]
+pub async fn get_records_atomically(
+    keys: &[String],
+    dnssec_keys: &[String],
+    con: &mut Connection,
+) -> PektinApiResult<Vec<Option<DbEntry>>> {
+    con.get_records_atomically(keys, dnssec_keys).await
+}
+
+#[cfg(test)]
+mod tests {
+    use pektin_common::DnskeyRecord;
+
+    use super::*;
+    use crate::memory_storage::MemoryStorage;
+    use crate::test_utils::{name, soa_entry};
+
+    #[tokio::test]
+    async fn missing_or_empty_dnskeys_are_errors() {
+        let mut con = Connection::Memory(MemoryStorage::new().connection());
+        let empty_dnskey = DbEntry {
+            name: name("empty.example."),
+            ttl: 3600,
+            meta: "".into(),
+            rr_set: RrSet::DNSKEY {
+                rr_set: Vec::<DnskeyRecord>::new(),
+            },
+        };
+        DbWriteBatch {
+            set: vec![
+                soa_entry("missing.example.", 1),
+                soa_entry("empty.example.", 1),
+                empty_dnskey,
+            ],
+            ..Default::default()
+        }
+        .execute(&mut con)
+        .await
+        .unwrap();
+
+        for zone in ["missing.example.", "empty.example."] {
+            let res = get_zone_dnskey_records(&[name(zone)], &mut con).await;
+            assert!(matches!(res, Err(PektinApiError::NoDnskeyRecord(z)) if z == zone));
+        }
+    }
+}
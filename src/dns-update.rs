@@ -0,0 +1,898 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use pektin_common::proto::op::{Message, MessageType, OpCode, ResponseCode};
+use pektin_common::proto::rr::{DNSClass, Name, Record, RecordType};
+use pektin_common::{DbEntry, RrSet};
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::auth::auth_ok_authenticated;
+use crate::db::{acquire_locks, get_or_mget_records, release_locks};
+use crate::errors_and_responses::PektinApiResult;
+use crate::journal::ChangeAuthor;
+use crate::set::sign_and_write_changes;
+use crate::storage::{Connection, Storage};
+use crate::tsig::{key_allowed_for_zone, sign_message, verify_message, TsigKey};
+use crate::types::{AppState, RecordIdentifier, RequestBody};
+use crate::utils::escape_glob;
+use crate::validation::{check_soa, validate_records};
+use crate::zone_file::{merge_rr_sets, rr_set_from_rdata};
+
+/// Serves RFC 2136 DNS UPDATE requests via UDP and TCP on the given address.
+///
+/// All requests must be signed with one of the TSIG keys in `state.tsig_keys` that is allowed for
+/// the zone (see `state.tsig_key_zones`). The key determines the pektin client the update is made
+/// by (see `state.tsig_key_clients`), whose policy is evaluated for the resulting changes like for
+/// any other API request.
+pub async fn dns_update_server(state: AppState, bind_addr: String) -> PektinApiResult<()> {
+    let udp_socket = Arc::new(UdpSocket::bind(&bind_addr).await?);
+    let tcp_listener = TcpListener::bind(&bind_addr).await?;
+    info!("Serving DNS UPDATE on {}", bind_addr);
+
+    let udp_state = state.clone();
+    let udp_server = async move {
+        let mut buf = vec![0; 65535];
+        loop {
+            let (len, peer) = udp_socket.recv_from(&mut buf).await?;
+            let request = buf[..len].to_vec();
+            let (state, socket) = (udp_state.clone(), udp_socket.clone());
+            tokio::spawn(async move {
+                if let Some(response) = handle_update(&state, &request, peer).await {
+                    if let Err(e) = socket.send_to(&response, peer).await {
+                        debug!("Could not send DNS UPDATE response to {}: {}", peer, e);
+                    }
+                }
+            });
+        }
+    };
+    let tcp_server = async move {
+        loop {
+            let (stream, peer) = match tcp_listener.accept().await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Could not accept DNS UPDATE connection: {}", e);
+                    continue;
+                }
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_tcp_connection(&state, stream, peer).await {
+                    debug!("DNS UPDATE connection to {} closed: {}", peer, e);
+                }
+            });
+        }
+    };
+
+    tokio::select! {
+        res = udp_server => res,
+        res = tcp_server => res,
+    }
+}
+
+async fn handle_tcp_connection(
+    state: &AppState,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+) -> PektinApiResult<()> {
+    // DNS over TCP prefixes every message with its length as a two byte integer
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut request = vec![0; len as usize];
+        stream.read_exact(&mut request).await?;
+
+        if let Some(response) = handle_update(state, &request, peer).await {
+            stream.write_u16(response.len() as u16).await?;
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+        }
+    }
+}
+
+/// Handles a single request and returns the response in wire format, if there is one.
+#[instrument(skip(state, request))]
+async fn handle_update(state: &AppState, request: &[u8], peer: SocketAddr) -> Option<Vec<u8>> {
+    let request_message = match Message::from_vec(request) {
+        Ok(m) => m,
+        Err(e) => {
+            debug!("Received invalid DNS message: {}", e);
+            return None;
+        }
+    };
+    if request_message.message_type() != MessageType::Query {
+        return None;
+    }
+
+    let mut response = Message::error_msg(
+        request_message.id(),
+        request_message.op_code(),
+        ResponseCode::NoError,
+    );
+    let response_code = match verify_message(request, &state.tsig_keys, None, true) {
+        Ok((key, request_mac)) => {
+            let response_code = match process_update(state, &request_message, key, peer).await {
+                Ok(code) => code,
+                Err(e) => {
+                    error!("Could not process DNS UPDATE: {}", e);
+                    ResponseCode::ServFail
+                }
+            };
+            response.set_response_code(response_code);
+            response.add_queries(request_message.queries().iter().cloned());
+            if let Err(e) = sign_message(&mut response, key, Some(&request_mac), true) {
+                error!("Could not sign DNS UPDATE response: {}", e);
+                return None;
+            }
+            response_code
+        }
+        Err(e) => {
+            // responses to requests that fail TSIG verification are not signed
+            debug!("Rejecting DNS UPDATE request: {}", e);
+            response.set_response_code(ResponseCode::NotAuth);
+            ResponseCode::NotAuth
+        }
+    };
+    debug!("Answering DNS UPDATE with {}", response_code);
+
+    response.to_vec().ok()
+}
+
+/// The state of an RR set that is touched by an update.
+struct RrSetState {
+    ident: RecordIdentifier,
+    original: Option<DbEntry>,
+    current: Option<DbEntry>,
+}
+
+/// Handles an update with the RR sets it refers to locked. Returns the response code.
+async fn process_update(
+    state: &AppState,
+    request: &Message,
+    key: &TsigKey,
+    peer: SocketAddr,
+) -> PektinApiResult<ResponseCode> {
+    if request.op_code() != OpCode::Update {
+        return Ok(ResponseCode::NotImp);
+    }
+    let client_username = match state.tsig_key_clients.get(&key.name.to_lowercase()) {
+        Some(c) => c,
+        None => {
+            warn!(
+                "TSIG key {} is not mapped to a pektin client, refusing DNS UPDATE",
+                key.name
+            );
+            return Ok(ResponseCode::Refused);
+        }
+    };
+
+    // the zone section must contain exactly one SOA "query" for the zone
+    let zone = match request.queries() {
+        [zone] if zone.query_type() == RecordType::SOA && zone.query_class() == DNSClass::IN => {
+            zone.name().to_lowercase()
+        }
+        _ => return Ok(ResponseCode::FormErr),
+    };
+    if !key_allowed_for_zone(&state.tsig_key_zones, key, &zone) {
+        warn!(
+            "TSIG key {} is not allowed for zone {}, refusing DNS UPDATE",
+            key.name, zone
+        );
+        return Ok(ResponseCode::Refused);
+    }
+
+    let mut con = state.db_pool.get().await?;
+    let mut dnssec_con = state.db_pool_dnssec.get().await?;
+    if !con.get_zones().await?.contains(&zone.to_string()) {
+        return Ok(ResponseCode::NotAuth);
+    }
+
+    // the RR sets that are checked or changed must not change until the update is written
+    let idents = touched_rr_sets(&zone, request, &mut con).await?;
+    let locks = acquire_locks(&idents, &mut con).await?;
+    let res = apply_updates(
+        state,
+        request,
+        &zone,
+        client_username,
+        peer,
+        &mut con,
+        &mut dnssec_con,
+    )
+    .await;
+    release_locks(locks, &mut con).await?;
+    res
+}
+
+/// Returns the RR sets that the prerequisites and updates of the request refer to. For type ANY,
+/// these are all RR sets that currently exist at the name.
+async fn touched_rr_sets(
+    zone: &Name,
+    request: &Message,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<RecordIdentifier>> {
+    let mut idents = Vec::new();
+    for record in request.answers().iter().chain(request.name_servers()) {
+        if !zone.zone_of(record.name()) {
+            continue;
+        }
+        let name = record.name().to_lowercase();
+        let rr_types = if record.record_type() == RecordType::ANY {
+            get_rr_types_at_name(&name, zone, con).await?
+        } else {
+            vec![record.record_type()]
+        };
+        idents.extend(rr_types.into_iter().map(|rr_type| RecordIdentifier {
+            name: name.clone(),
+            rr_type,
+        }));
+    }
+    Ok(idents)
+}
+
+/// Checks the prerequisites, computes the resulting RR sets, and, if the client's policy allows
+/// it, writes them using the same pipeline as `set` and `delete`. Returns the response code.
+///
+/// The locks of the RR sets returned by `touched_rr_sets()` must be held.
+#[allow(clippy::too_many_arguments)]
+async fn apply_updates(
+    state: &AppState,
+    request: &Message,
+    zone: &Name,
+    client_username: &str,
+    peer: SocketAddr,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<ResponseCode> {
+    // see RFC 2136, section 3.2 (the prerequisite section is parsed as the answer section)
+    if let Some(code) = check_prerequisites(zone, request.answers(), con).await? {
+        return Ok(code);
+    }
+
+    // see RFC 2136, section 3.4 (the update section is parsed as the authority section)
+    let updates = request.name_servers();
+    for update in updates {
+        if !zone.zone_of(update.name()) {
+            return Ok(ResponseCode::NotZone);
+        }
+        let valid = match update.dns_class() {
+            DNSClass::IN => update.data().is_some() && !is_meta_type(update.record_type()),
+            DNSClass::ANY => update.ttl() == 0 && update.data().is_none(),
+            DNSClass::NONE => update.ttl() == 0 && !is_meta_type(update.record_type()),
+            _ => false,
+        };
+        if !valid {
+            return Ok(ResponseCode::FormErr);
+        }
+        // pektin signs zones itself
+        if update.record_type().is_dnssec() {
+            return Ok(ResponseCode::Refused);
+        }
+    }
+
+    let mut rr_sets: HashMap<String, RrSetState> = HashMap::new();
+    for update in updates {
+        let name = update.name().to_lowercase();
+        let is_apex = &name == zone;
+        let rr_types = if update.record_type() == RecordType::ANY {
+            get_rr_types_at_name(&name, zone, con).await?
+        } else {
+            vec![update.record_type()]
+        };
+        for rr_type in rr_types {
+            let ident = RecordIdentifier {
+                name: name.clone(),
+                rr_type,
+            };
+            let db_key = ident.db_key();
+            if !rr_sets.contains_key(&db_key) {
+                let original = get_or_mget_records(std::slice::from_ref(&db_key), con)
+                    .await?
+                    .pop()
+                    .flatten();
+                rr_sets.insert(
+                    db_key.clone(),
+                    RrSetState {
+                        ident,
+                        original: original.clone(),
+                        current: original,
+                    },
+                );
+            }
+            let rr_set = rr_sets.get_mut(&db_key).expect("RR set was just inserted");
+            if let Err(code) = apply_update(update, rr_set, is_apex) {
+                return Ok(code);
+            }
+        }
+    }
+
+    // only RR sets that actually changed are written
+    let mut to_set = Vec::new();
+    let mut to_delete = Vec::new();
+    for rr_set in rr_sets.into_values() {
+        match (&rr_set.original, rr_set.current) {
+            (Some(_), None) => to_delete.push(rr_set.ident),
+            (original, Some(current)) => {
+                let changed = match original {
+                    Some(original) => original.serialize_for_db()? != current.serialize_for_db()?,
+                    None => true,
+                };
+                if changed {
+                    to_set.push(current);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    if to_set.is_empty() && to_delete.is_empty() {
+        return Ok(ResponseCode::NoError);
+    }
+
+    let auth = auth_ok_authenticated(
+        RequestBody::DnsUpdate {
+            zone: zone.clone(),
+            set: to_set.clone(),
+            delete: to_delete.clone(),
+        },
+        state,
+        client_username,
+        Some(peer.ip().to_string()),
+        "dns-update",
+    )
+    .await;
+    if !auth.success {
+        debug!("DNS UPDATE not authorized: {}", auth.message);
+        return Ok(ResponseCode::Refused);
+    }
+
+    if let Some(Err(e)) = validate_records(&to_set).into_iter().find(Result::is_err) {
+        debug!("Refusing DNS UPDATE with invalid records: {}", e);
+        return Ok(ResponseCode::Refused);
+    }
+
+    let author = ChangeAuthor {
+        client_username: client_username.to_string(),
+        ip: Some(peer.ip().to_string()),
+        user_agent: "dns-update".into(),
+    };
+    // the RR sets are set and deleted in a single transaction, so that the zone gets a single new
+    // serial
+    let (soa_check, used_zones, new_authoritative_zones) = check_soa(&to_set, con).await?;
+    if soa_check.iter().any(Result::is_err) {
+        return Ok(ResponseCode::NotZone);
+    }
+    // new zones can't be created via DNS UPDATE
+    if !new_authoritative_zones.is_empty() {
+        return Ok(ResponseCode::Refused);
+    }
+    if let Err(res) = sign_and_write_changes(
+        &to_set,
+        &to_delete,
+        &used_zones,
+        &new_authoritative_zones,
+        &author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await
+    {
+        let status = res.status();
+        let body = res
+            .into_body()
+            .try_into_bytes()
+            .map(|body| String::from_utf8_lossy(&body).into_owned())
+            .unwrap_or_default();
+        // client errors mean that the records were rejected, e.g. because they can't be signed
+        return Ok(if status.is_client_error() {
+            warn!("DNS UPDATE for {} was rejected: {}", zone, body);
+            ResponseCode::Refused
+        } else {
+            error!("Could not write DNS UPDATE for {}: {}", zone, body);
+            ResponseCode::ServFail
+        });
+    }
+
+    Ok(ResponseCode::NoError)
+}
+
+/// Checks the prerequisites of an update. Returns the response code if a prerequisite isn't met.
+async fn check_prerequisites(
+    zone: &Name,
+    prerequisites: &[Record],
+    con: &mut Connection,
+) -> PektinApiResult<Option<ResponseCode>> {
+    // prerequisites with the zone's class are collected per RR set and compared as a whole
+    let mut value_dependent: HashMap<String, (RecordIdentifier, Vec<Value>)> = HashMap::new();
+
+    for prerequisite in prerequisites {
+        if prerequisite.ttl() != 0 {
+            return Ok(Some(ResponseCode::FormErr));
+        }
+        if !zone.zone_of(prerequisite.name()) {
+            return Ok(Some(ResponseCode::NotZone));
+        }
+        let name = prerequisite.name().to_lowercase();
+        let rr_type = prerequisite.record_type();
+        let rr_types = get_rr_types_at_name(&name, zone, con).await?;
+
+        let failed = match (prerequisite.dns_class(), prerequisite.data()) {
+            (DNSClass::ANY, None) if rr_type == RecordType::ANY => {
+                (rr_types.is_empty()).then_some(ResponseCode::NXDomain)
+            }
+            (DNSClass::ANY, None) => {
+                (!rr_types.contains(&rr_type)).then_some(ResponseCode::NXRRSet)
+            }
+            (DNSClass::NONE, None) if rr_type == RecordType::ANY => {
+                (!rr_types.is_empty()).then_some(ResponseCode::YXDomain)
+            }
+            (DNSClass::NONE, None) => rr_types.contains(&rr_type).then_some(ResponseCode::YXRRSet),
+            (DNSClass::IN, Some(rdata)) => {
+                let rr_set = match rr_set_from_rdata(rdata) {
+                    Ok(r) => r,
+                    Err(_) => return Ok(Some(ResponseCode::FormErr)),
+                };
+                let ident = RecordIdentifier { name, rr_type };
+                value_dependent
+                    .entry(ident.db_key())
+                    .or_insert_with(|| (ident, vec![]))
+                    .1
+                    .extend(rr_set_values(&rr_set));
+                None
+            }
+            _ => Some(ResponseCode::FormErr),
+        };
+        if failed.is_some() {
+            return Ok(failed);
+        }
+    }
+
+    for (ident, expected) in value_dependent.into_values() {
+        let existing = get_or_mget_records(&[ident.db_key()], con)
+            .await?
+            .pop()
+            .flatten();
+        let existing: HashSet<_> = existing
+            .map(|e| rr_set_values(&e.rr_set))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|v| v.to_string())
+            .collect();
+        let expected: HashSet<_> = expected.into_iter().map(|v| v.to_string()).collect();
+        if existing != expected {
+            return Ok(Some(ResponseCode::NXRRSet));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Applies a single update RR to the RR set (see RFC 2136, section 3.4.2). The SOA and NS records
+/// at the zone apex can't be deleted via DNS UPDATE.
+fn apply_update(
+    update: &Record,
+    rr_set: &mut RrSetState,
+    is_apex: bool,
+) -> Result<(), ResponseCode> {
+    let rr_type = rr_set.ident.rr_type;
+    let protected = is_apex && matches!(rr_type, RecordType::SOA | RecordType::NS);
+
+    match update.dns_class() {
+        DNSClass::IN => {
+            let new = update
+                .data()
+                .map(rr_set_from_rdata)
+                .ok_or(ResponseCode::FormErr)?
+                .map_err(|_| ResponseCode::Refused)?;
+            // new zones can't be created via DNS UPDATE
+            if rr_type == RecordType::SOA && !is_apex {
+                return Err(ResponseCode::Refused);
+            }
+            match &mut rr_set.current {
+                // there is only one SOA record, so it is replaced
+                Some(current) if rr_type != RecordType::SOA => {
+                    current.ttl = update.ttl();
+                    merge_rr_sets(&mut current.rr_set, new).map_err(|_| ResponseCode::ServFail)?;
+                }
+                _ => {
+                    rr_set.current = Some(DbEntry {
+                        name: rr_set.ident.name.clone(),
+                        ttl: update.ttl(),
+                        meta: "".to_string(),
+                        rr_set: new,
+                    })
+                }
+            }
+        }
+        DNSClass::ANY if !protected => rr_set.current = None,
+        DNSClass::NONE if rr_type != RecordType::SOA => {
+            let to_remove = update
+                .data()
+                .map(rr_set_from_rdata)
+                .ok_or(ResponseCode::FormErr)?
+                .map_err(|_| ResponseCode::FormErr)?;
+            let to_remove = rr_set_values(&to_remove);
+            if let Some(current) = &mut rr_set.current {
+                let remaining: Vec<_> = rr_set_values(&current.rr_set)
+                    .into_iter()
+                    .filter(|v| !to_remove.contains(v))
+                    .collect();
+                if remaining.is_empty() {
+                    // the last NS record of the zone is kept
+                    if !protected {
+                        rr_set.current = None;
+                    }
+                } else {
+                    current.rr_set = rr_set_from_values(rr_type, remaining)?;
+                }
+            }
+        }
+        // deleting the SOA record or the apex NS RR set is silently ignored
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Types that can't be stored as records.
+fn is_meta_type(rr_type: RecordType) -> bool {
+    matches!(
+        rr_type,
+        RecordType::ANY | RecordType::AXFR | RecordType::IXFR | RecordType::OPT
+    )
+}
+
+/// The records of an RR set as JSON values, which allows comparing records of all types.
+fn rr_set_values(rr_set: &RrSet) -> Vec<Value> {
+    match serde_json::to_value(rr_set) {
+        Ok(Value::Object(mut map)) => match map.remove("rr_set") {
+            Some(Value::Array(values)) => values,
+            _ => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+fn rr_set_from_values(rr_type: RecordType, values: Vec<Value>) -> Result<RrSet, ResponseCode> {
+    serde_json::from_value(json!({
+        "rr_type": rr_type,
+        "rr_set": values,
+    }))
+    .map_err(|_| ResponseCode::ServFail)
+}
+
+/// Returns the types of all RR sets that exist at the name, which must be in the zone. The DNSSEC
+/// records that pektin maintains itself are left out, so they can't be deleted or checked via DNS
+/// UPDATE.
+async fn get_rr_types_at_name(
+    name: &Name,
+    zone: &Name,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<RecordType>> {
+    // names may e.g. contain '*' for wildcard records
+    let glob = format!("{}:*", escape_glob(&name.to_string()));
+
+    let keys = con.scan_keys(Some(zone), &glob).await?;
+    Ok(keys
+        .iter()
+        .filter_map(|key| RecordIdentifier::from_db_key(key).ok())
+        .map(|ident| ident.rr_type)
+        .filter(|rr_type| !rr_type.is_dnssec())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use pektin_common::proto::op::Query;
+    use pektin_common::proto::rr::RData;
+
+    use super::*;
+    use crate::test_utils::{
+        a_entry, get_with_rrsig, name, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    fn key() -> TsigKey {
+        TsigKey::parse("update-key.:hmac-sha256:c2VjcmV0").unwrap()
+    }
+
+    /// A state with the zones example.com. and example.org., where the key may only update
+    /// example.com. and belongs to the client `test`.
+    async fn update_state() -> AppState {
+        let mut state = test_state();
+        state.skip_auth = "yes, I really want to disable authentication".into();
+        state.tsig_keys = vec![key()];
+        state
+            .tsig_key_clients
+            .insert(name("update-key."), "test".into());
+        state
+            .tsig_key_zones
+            .insert(name("update-key."), vec![name("example.com.")]);
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        set_records(&[soa_entry("example.org.", 1)], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+        state
+    }
+
+    fn a_record(record_name: &str, ip: [u8; 4]) -> Record {
+        Record::from_rdata(name(record_name), 300, RData::A(Ipv4Addr::from(ip)))
+    }
+
+    /// A record without rdata with the given class, as used for deletions and prerequisites.
+    fn empty_record(record_name: &str, rr_type: RecordType, class: DNSClass) -> Record {
+        let mut record = Record::with(name(record_name), rr_type, 0);
+        record.set_dns_class(class);
+        record
+    }
+
+    fn update(zone: &str, prerequisites: Vec<Record>, updates: Vec<Record>) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(1)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update)
+            .add_query(Query::query(name(zone), RecordType::SOA));
+        message.add_answers(prerequisites);
+        message.add_name_servers(updates);
+        message
+    }
+
+    async fn send(state: &AppState, mut message: Message, key: Option<&TsigKey>) -> ResponseCode {
+        if let Some(key) = key {
+            sign_message(&mut message, key, None, true).unwrap();
+        }
+        let request = message.to_vec().unwrap();
+        let peer = (Ipv4Addr::LOCALHOST, 5353).into();
+        let response = handle_update(state, &request, peer).await.unwrap();
+        Message::from_vec(&response).unwrap().response_code()
+    }
+
+    async fn a_values(record_name: &str, state: &AppState) -> Option<Vec<Ipv4Addr>> {
+        let (entry, _) = get_with_rrsig(record_name, RecordType::A, state).await;
+        entry.map(|entry| match entry.rr_set {
+            RrSet::A { rr_set } => rr_set.into_iter().map(|r| r.value).collect(),
+            _ => panic!("not an A entry"),
+        })
+    }
+
+    #[actix_web::test]
+    async fn updates_add_and_delete_records() {
+        let state = update_state().await;
+        let message = update(
+            "example.com.",
+            vec![],
+            vec![
+                a_record("www.example.com.", [192, 0, 2, 2]),
+                a_record("mail.example.com.", [192, 0, 2, 3]),
+            ],
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::NoError
+        );
+        assert_eq!(
+            a_values("www.example.com.", &state).await,
+            Some(vec![[192, 0, 2, 1].into(), [192, 0, 2, 2].into()])
+        );
+        assert_eq!(
+            a_values("mail.example.com.", &state).await,
+            Some(vec![[192, 0, 2, 3].into()])
+        );
+        // all changes are written with a single new serial
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+        let (_, rrsig) = get_with_rrsig("mail.example.com.", RecordType::A, &state).await;
+        assert!(rrsig.is_some());
+
+        let mut remove_record = a_record("www.example.com.", [192, 0, 2, 1]);
+        remove_record.set_dns_class(DNSClass::NONE).set_ttl(0);
+        let message = update(
+            "example.com.",
+            vec![],
+            vec![
+                remove_record,
+                empty_record("mail.example.com.", RecordType::ANY, DNSClass::ANY),
+                // the apex NS and SOA records can't be deleted
+                empty_record("example.com.", RecordType::SOA, DNSClass::ANY),
+            ],
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::NoError
+        );
+        assert_eq!(
+            a_values("www.example.com.", &state).await,
+            Some(vec![[192, 0, 2, 2].into()])
+        );
+        assert_eq!(a_values("mail.example.com.", &state).await, None);
+        assert_eq!(zone_serial("example.com.", &state).await, Some(4));
+    }
+
+    #[actix_web::test]
+    async fn prerequisites_are_checked() {
+        let state = update_state().await;
+        let add_mail = || vec![a_record("mail.example.com.", [192, 0, 2, 3])];
+        let expect = |prerequisite: Record, code: ResponseCode| {
+            let state = state.clone();
+            async move {
+                let message = update("example.com.", vec![prerequisite], add_mail());
+                assert_eq!(send(&state, message, Some(&key())).await, code);
+            }
+        };
+
+        // name is in use
+        expect(
+            empty_record("ftp.example.com.", RecordType::ANY, DNSClass::ANY),
+            ResponseCode::NXDomain,
+        )
+        .await;
+        // name is not in use
+        expect(
+            empty_record("www.example.com.", RecordType::ANY, DNSClass::NONE),
+            ResponseCode::YXDomain,
+        )
+        .await;
+        // RR set exists (value independent)
+        expect(
+            empty_record("www.example.com.", RecordType::AAAA, DNSClass::ANY),
+            ResponseCode::NXRRSet,
+        )
+        .await;
+        // RR set does not exist
+        expect(
+            empty_record("www.example.com.", RecordType::A, DNSClass::NONE),
+            ResponseCode::YXRRSet,
+        )
+        .await;
+        // RR set exists (value dependent)
+        let mut wrong_value = a_record("www.example.com.", [192, 0, 2, 9]);
+        wrong_value.set_ttl(0);
+        expect(wrong_value, ResponseCode::NXRRSet).await;
+        // prerequisites must have a TTL of 0
+        expect(
+            a_record("www.example.com.", [192, 0, 2, 1]),
+            ResponseCode::FormErr,
+        )
+        .await;
+        // prerequisites must be in the zone
+        expect(
+            empty_record("www.example.org.", RecordType::ANY, DNSClass::ANY),
+            ResponseCode::NotZone,
+        )
+        .await;
+        assert_eq!(a_values("mail.example.com.", &state).await, None);
+        assert_eq!(zone_serial("example.com.", &state).await, Some(2));
+
+        let mut right_value = a_record("www.example.com.", [192, 0, 2, 1]);
+        right_value.set_ttl(0);
+        let message = update(
+            "example.com.",
+            vec![
+                right_value,
+                empty_record("www.example.com.", RecordType::A, DNSClass::ANY),
+                empty_record("mail.example.com.", RecordType::ANY, DNSClass::NONE),
+            ],
+            add_mail(),
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::NoError
+        );
+        assert_eq!(
+            a_values("mail.example.com.", &state).await,
+            Some(vec![[192, 0, 2, 3].into()])
+        );
+    }
+
+    #[actix_web::test]
+    async fn requests_must_be_signed_with_a_known_key() {
+        let state = update_state().await;
+        let add_mail = || {
+            update(
+                "example.com.",
+                vec![],
+                vec![a_record("mail.example.com.", [192, 0, 2, 3])],
+            )
+        };
+        assert_eq!(send(&state, add_mail(), None).await, ResponseCode::NotAuth);
+        let wrong_secret = TsigKey::parse("update-key.:hmac-sha256:b3RoZXI=").unwrap();
+        assert_eq!(
+            send(&state, add_mail(), Some(&wrong_secret)).await,
+            ResponseCode::NotAuth
+        );
+        let unknown_key = TsigKey::parse("other-key.:hmac-sha256:c2VjcmV0").unwrap();
+        assert_eq!(
+            send(&state, add_mail(), Some(&unknown_key)).await,
+            ResponseCode::NotAuth
+        );
+        assert_eq!(a_values("mail.example.com.", &state).await, None);
+
+        // the response to a signed request is signed with the same key
+        let mut message = add_mail();
+        let request_mac = sign_message(&mut message, &key(), None, true).unwrap();
+        let peer = (Ipv4Addr::LOCALHOST, 5353).into();
+        let response = handle_update(&state, &message.to_vec().unwrap(), peer)
+            .await
+            .unwrap();
+        let (used_key, _) =
+            verify_message(&response, &state.tsig_keys, Some(&request_mac), true).unwrap();
+        assert_eq!(used_key.name, key().name);
+    }
+
+    #[actix_web::test]
+    async fn keys_may_only_update_their_zones() {
+        let mut state = update_state().await;
+        let message = update(
+            "example.org.",
+            vec![],
+            vec![a_record("www.example.org.", [192, 0, 2, 1])],
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::Refused
+        );
+        assert_eq!(a_values("www.example.org.", &state).await, None);
+
+        // records outside of the zone
+        let message = update(
+            "example.com.",
+            vec![],
+            vec![a_record("www.example.org.", [192, 0, 2, 1])],
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::NotZone
+        );
+
+        // DNSSEC records are maintained by pektin
+        let message = update(
+            "example.com.",
+            vec![],
+            vec![empty_record(
+                "www.example.com.",
+                RecordType::RRSIG,
+                DNSClass::ANY,
+            )],
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::Refused
+        );
+
+        // zones that don't exist
+        state
+            .tsig_key_zones
+            .insert(name("update-key."), vec![name("example.net.")]);
+        let message = update(
+            "example.net.",
+            vec![],
+            vec![a_record("www.example.net.", [192, 0, 2, 1])],
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::NotAuth
+        );
+
+        // keys must belong to a client
+        state.tsig_key_clients.clear();
+        state
+            .tsig_key_zones
+            .insert(name("update-key."), vec![name("example.com.")]);
+        let message = update(
+            "example.com.",
+            vec![],
+            vec![a_record("mail.example.com.", [192, 0, 2, 3])],
+        );
+        assert_eq!(
+            send(&state, message, Some(&key())).await,
+            ResponseCode::Refused
+        );
+        assert_eq!(a_values("mail.example.com.", &state).await, None);
+    }
+}
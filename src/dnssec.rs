@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 
 use data_encoding::BASE64;
-use pektin_common::deadpool_redis::Connection;
 use pektin_common::proto::rr::dnssec::rdata::{DNSSECRData, SIG};
 use pektin_common::proto::rr::dnssec::tbs::rrset_tbs_with_sig;
 use pektin_common::proto::rr::dnssec::Algorithm::ECDSAP256SHA256;
@@ -13,8 +12,9 @@ use pektin_common::{
 };
 use tracing::instrument;
 
-use crate::db::get_zone_keys;
+use crate::db::{get_or_mget_records, get_zone_keys};
 use crate::errors_and_responses::PektinApiError;
+use crate::storage::Connection;
 use crate::types::RecordIdentifier;
 use crate::{errors_and_responses::PektinApiResult, vault};
 
@@ -58,24 +58,7 @@ pub async fn sign_db_entry(
     let sig_valid_from = chrono::Utc::now();
     let sig_valid_until = sig_valid_from + chrono::Duration::days(2);
 
-    let dnskey_record: Vec<Record> = DbEntry {
-        name: Name::root(),
-        meta: "".to_string(),
-        ttl: 3600,
-        rr_set: RrSet::DNSKEY {
-            rr_set: vec![dnskey.clone()],
-        },
-    }
-    .try_into()
-    .expect("Could not convert DNSKEY DbEntry to trust-dns Record");
-    let dnskey_record = dnskey_record.get(0).expect("Could not get DNSKEY record");
-    let dnskey = match dnskey_record.data() {
-        Some(RData::DNSSEC(DNSSECRData::DNSKEY(dnskey))) => dnskey,
-        _ => panic!("DNSKEY record does not contain a DNSKEY"),
-    };
-    let key_tag = dnskey
-        .calculate_key_tag()
-        .expect("Could not calculate key tag");
+    let key_tag = dnskey_key_tag(dnskey);
 
     let sig = SIG::new(
         entry.rr_type(),
@@ -116,27 +99,218 @@ pub async fn sign_db_entry(
     })
 }
 
-/// Takes a zone name and constructs NSEC3 records for all records in the zone. The corresponding NSEC3PARAM record is also returned.
+/// Checks whether the RRSIG is a valid signature of the RR set by the DNSKEY and hasn't expired.
+pub fn verify_rrsig(entry: &DbEntry, rrsig: &RrsigRecord, dnskey: &DnskeyRecord) -> bool {
+    use p256::ecdsa::signature::Verifier;
+
+    let now = chrono::Utc::now().timestamp();
+    if rrsig.type_covered != entry.rr_type()
+        || rrsig.key_tag != dnskey_key_tag(dnskey)
+        || (rrsig.signature_expiration as i64) < now
+    {
+        return false;
+    }
+
+    let sig = SIG::new(
+        rrsig.type_covered,
+        ECDSAP256SHA256,
+        rrsig.labels,
+        rrsig.original_ttl,
+        rrsig.signature_expiration,
+        rrsig.signature_inception,
+        rrsig.key_tag,
+        rrsig.signer_name.clone(),
+        vec![],
+    );
+    let records_tbs: Vec<Record> = match entry.clone().try_into() {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+    let tbs = match rrset_tbs_with_sig(&entry.name, DNSClass::IN, &sig, &records_tbs) {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+
+    // the DNSKEY contains the point without the leading SEC1 tag byte (0x04 for an uncompressed
+    // point) and the signature is the raw encoded point coordinates, see `get_dnskey_for_zone()`
+    // and `vault::sign_with_vault()`
+    let key = match BASE64.decode(dnskey.key.as_bytes()) {
+        Ok(k) => [&[0x04], k.as_slice()].concat(),
+        Err(_) => return false,
+    };
+    let signature = BASE64
+        .decode(rrsig.signature.as_bytes())
+        .ok()
+        .and_then(|s| p256::ecdsa::Signature::try_from(s.as_slice()).ok());
+    match (p256::ecdsa::VerifyingKey::from_sec1_bytes(&key), signature) {
+        (Ok(key), Some(signature)) => key.verify(tbs.as_ref(), &signature).is_ok(),
+        _ => false,
+    }
+}
+
+/// Calculates the key tag of the DNSKEY (RFC 4034, appendix B).
+pub fn dnskey_key_tag(dnskey: &DnskeyRecord) -> u16 {
+    let dnskey_record: Vec<Record> = DbEntry {
+        name: Name::root(),
+        meta: "".to_string(),
+        ttl: 3600,
+        rr_set: RrSet::DNSKEY {
+            rr_set: vec![dnskey.clone()],
+        },
+    }
+    .try_into()
+    .expect("Could not convert DNSKEY DbEntry to trust-dns Record");
+    let dnskey_record = dnskey_record.get(0).expect("Could not get DNSKEY record");
+    let dnskey = match dnskey_record.data() {
+        Some(RData::DNSSEC(DNSSECRData::DNSKEY(dnskey))) => dnskey,
+        _ => panic!("DNSKEY record does not contain a DNSKEY"),
+    };
+    dnskey
+        .calculate_key_tag()
+        .expect("Could not calculate key tag")
+}
+
+/// The TTL of the NSEC3 and NSEC3PARAM records.
+// TODO: use the SOA minimum TTL as required by RFC 5155, section 3
+pub const NSEC3_TTL: u32 = 600;
+
+/// The NSEC3 and NSEC3PARAM records that must be set and deleted to bring a zone's NSEC3 chain up
+/// to date.
+#[derive(Debug, Default)]
+pub struct Nsec3ChainUpdate {
+    pub set: Vec<DbEntry>,
+    pub delete: Vec<RecordIdentifier>,
+}
+
+/// Takes a zone name and the changes that are about to be written to the zone and constructs the
+/// NSEC3 records for all names in the zone after the change. The corresponding NSEC3PARAM record
+/// is also created. Only the NSEC3(PARAM) records that differ from the ones in the db are returned,
+/// together with the NSEC3 records that no longer exist.
 ///
-/// `ttl` is the TTL for the generated NSEC3 and NSEC3PARAM records.
+/// `set` are the RR sets that are set and `deleted` the RR sets that are deleted; all of them must
+/// belong to the zone. The zone doesn't need to exist yet, e.g. if its SOA record is in `set`.
 ///
-/// The salt is newly generated.
-#[instrument(skip(con))]
-pub async fn create_nsec3_chain(
+/// The salt of the zone's existing NSEC3PARAM record is reused. If there is none, a new salt is
+/// generated.
+#[instrument(skip(set, deleted, con))]
+pub async fn update_nsec3_chain(
     zone: &Name,
-    ttl: u32,
+    set: &[DbEntry],
+    deleted: &[RecordIdentifier],
     con: &mut Connection,
-) -> PektinApiResult<(Vec<DbEntry>, DbEntry)> {
+) -> PektinApiResult<Nsec3ChainUpdate> {
+    let zone_keys = get_zone_keys(&[zone], con)
+        .await?
+        .pop()
+        .flatten()
+        .unwrap_or_default();
+    let mut owner_names_and_types: HashMap<Name, Vec<RecordType>> = HashMap::new();
+    let mut existing_nsec3_keys = vec![];
+    let mut existing_nsec3_param = None;
+    for key in zone_keys {
+        let ident = RecordIdentifier::from_db_key(&key)?;
+        // the NSEC3(PARAM) records are not part of the chain themselves
+        match ident.rr_type {
+            RecordType::NSEC3 => existing_nsec3_keys.push(key),
+            RecordType::NSEC3PARAM => existing_nsec3_param = Some(key),
+            _ => owner_names_and_types
+                .entry(ident.name)
+                .or_default()
+                .push(ident.rr_type),
+        }
+    }
+    for ident in deleted {
+        if let Some(types) = owner_names_and_types.get_mut(&ident.name) {
+            types.retain(|t| *t != ident.rr_type);
+            if types.is_empty() {
+                owner_names_and_types.remove(&ident.name);
+            }
+        }
+    }
+    for entry in set {
+        let types = owner_names_and_types.entry(entry.name.clone()).or_default();
+        if !types.contains(&entry.rr_type()) {
+            types.push(entry.rr_type());
+        }
+    }
+    // the zone was deleted completely
+    if owner_names_and_types.is_empty() {
+        return Ok(Nsec3ChainUpdate {
+            set: vec![],
+            delete: existing_nsec3_keys
+                .into_iter()
+                .chain(existing_nsec3_param)
+                .map(RecordIdentifier::from_db_key)
+                .collect::<Result<_, _>>()?,
+        });
+    }
+
+    let existing_nsec3_param = match existing_nsec3_param {
+        Some(key) => get_or_mget_records(&[key], con).await?.pop().flatten(),
+        None => None,
+    };
+    let nsec3_param = match existing_nsec3_param.as_ref().map(|e| &e.rr_set) {
+        Some(RrSet::NSEC3PARAM { rr_set }) => rr_set.first().cloned(),
+        _ => None,
+    };
     // see https://datatracker.ietf.org/doc/html/rfc5155#section-7.1
-    let hash_algorithm = HashAlgorithm::SHA1;
-    let iterations = 16;
-    let salt: [u8; 16] = rand::random();
-
-    let mut hashed_names_and_nsec3_records: Vec<(_, _)> =
-        create_hashed_names_and_nsec3_records(zone, hash_algorithm, &salt, iterations, con)
-            .await?
-            .into_iter()
-            .collect();
+    let nsec3_param = nsec3_param.unwrap_or_else(|| Nsec3ParamRecord {
+        hash_algorithm: HashAlgorithm::SHA1,
+        iterations: 16,
+        salt: Some(rand::random::<[u8; 16]>().into()),
+    });
+    let (nsec3, nsec3_param) =
+        create_nsec3_chain(zone, NSEC3_TTL, nsec3_param, owner_names_and_types)?;
+
+    let existing_nsec3 = get_or_mget_records(&existing_nsec3_keys, con).await?;
+    let mut existing: HashMap<_, _> = existing_nsec3_keys
+        .into_iter()
+        .zip(existing_nsec3)
+        .chain(std::iter::once((
+            nsec3_param.db_key(),
+            existing_nsec3_param,
+        )))
+        .collect();
+    let mut update = Nsec3ChainUpdate::default();
+    for entry in nsec3.into_iter().chain(std::iter::once(nsec3_param)) {
+        let unchanged = match existing.remove(&entry.db_key()).flatten() {
+            Some(e) => e.serialize_for_db()? == entry.serialize_for_db()?,
+            None => false,
+        };
+        if !unchanged {
+            update.set.push(entry);
+        }
+    }
+    for key in existing.into_keys() {
+        update.delete.push(RecordIdentifier::from_db_key(key)?);
+    }
+    Ok(update)
+}
+
+/// Constructs NSEC3 records for the given owner names and their RR types, which must all belong to
+/// the zone, using the hash parameters of `nsec3_param`. The corresponding NSEC3PARAM record is
+/// also returned.
+///
+/// `ttl` is the TTL for the generated NSEC3 and NSEC3PARAM records.
+fn create_nsec3_chain(
+    zone: &Name,
+    ttl: u32,
+    nsec3_param: Nsec3ParamRecord,
+    owner_names_and_types: HashMap<Name, Vec<RecordType>>,
+) -> PektinApiResult<(Vec<DbEntry>, DbEntry)> {
+    let hash_algorithm = nsec3_param.hash_algorithm;
+    let iterations = nsec3_param.iterations;
+    let salt = nsec3_param.salt.clone().unwrap_or_default();
+
+    let mut hashed_names_and_nsec3_records: Vec<(_, _)> = create_hashed_names_and_nsec3_records(
+        zone,
+        hash_algorithm,
+        &salt,
+        iterations,
+        owner_names_and_types,
+    )?
+    .into_iter()
+    .collect();
     hashed_names_and_nsec3_records.sort_unstable_by(|a, b| a.0.cmp(&b.0));
 
     let len = hashed_names_and_nsec3_records.len();
@@ -146,11 +320,6 @@ pub async fn create_nsec3_chain(
             hashed_names_and_nsec3_records[next_index].0.clone();
     }
 
-    let nsec3_param = Nsec3ParamRecord {
-        hash_algorithm,
-        iterations,
-        salt: Some(salt.into()),
-    };
     let nsec3_param = DbEntry {
         name: zone.clone(),
         ttl,
@@ -183,39 +352,15 @@ pub async fn create_nsec3_chain(
     Ok((nsec3.collect(), nsec3_param))
 }
 
-async fn get_unique_owner_names_and_types(
-    zone: &Name,
-    con: &mut Connection,
-) -> PektinApiResult<HashMap<Name, Vec<RecordType>>> {
-    let zone_keys = get_zone_keys(&[zone], con)
-        .await?
-        .pop()
-        .unwrap()
-        .ok_or(PektinApiError::NoSoaRecord)?;
-    let owner_idents: Result<Vec<_>, _> = zone_keys
-        .into_iter()
-        .map(RecordIdentifier::from_db_key)
-        .collect();
-    let mut unique_owner_names_and_types: HashMap<Name, Vec<RecordType>> = HashMap::new();
-    for ident in owner_idents? {
-        unique_owner_names_and_types
-            .entry(ident.name)
-            .or_default()
-            .push(ident.rr_type);
-    }
-
-    Ok(unique_owner_names_and_types)
-}
-
-async fn create_hashed_names_and_nsec3_records(
+fn create_hashed_names_and_nsec3_records(
     zone: &Name,
     hash_algorithm: HashAlgorithm,
     salt: &[u8],
     iterations: u16,
-    con: &mut Connection,
+    owner_names_and_types: HashMap<Name, Vec<RecordType>>,
 ) -> PektinApiResult<HashMap<Vec<u8>, (Nsec3Record, Name)>> {
     let mut hashed_names_and_nsec3_records = HashMap::new();
-    for (mut name, mut types) in get_unique_owner_names_and_types(zone, con).await? {
+    for (mut name, mut types) in owner_names_and_types {
         // RRSIG records are in the separate DNSSEC db, but are always present
         types.push(RecordType::RRSIG);
 
@@ -223,6 +368,9 @@ async fn create_hashed_names_and_nsec3_records(
         if (&name == zone) && !types.contains(&RecordType::NSEC3PARAM) {
             types.push(RecordType::NSEC3PARAM);
         }
+        // the order of the types is arbitrary, so we sort them to get the same record for the
+        // same types
+        types.sort_by_key(|t| u16::from(*t));
 
         let nsec3 = Nsec3Record {
             // TODO: think about NSEC3 opt out flag
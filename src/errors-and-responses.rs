@@ -33,6 +33,29 @@ pub fn err(
     ))
 }
 
+/// Creates a conflict error response with a message for each item in the request, like `err()`.
+/// It is used when the versions of one or more RR sets don't match the expected versions.
+pub fn conflict_err(
+    toplevel_message: impl Serialize,
+    messages: Vec<Option<impl Serialize>>,
+) -> HttpResponse {
+    let messages: Vec<_> = messages
+        .into_iter()
+        .map(|msg| match msg {
+            Some(m) => response(ResponseType::Error, json!(m)),
+            None => response(
+                ResponseType::Ignored,
+                json!("ignored because another part of the request caused a conflict"),
+            ),
+        })
+        .collect();
+    HttpResponse::Conflict().json(response_with_data(
+        ResponseType::Error,
+        toplevel_message,
+        messages,
+    ))
+}
+
 /// Creates an authentication error response.
 pub fn auth_err(message: impl Serialize) -> HttpResponse {
     HttpResponse::Unauthorized().json(response_with_data(
@@ -122,14 +145,26 @@ pub enum PektinApiError {
     ApiTokenRotation,
     #[error("No SOA record found for this zone")]
     NoSoaRecord,
+    #[error("No DNSKEY record found for zone {0}")]
+    NoDnskeyRecord(String),
     #[error("Db key has invalid format")]
     InvalidDbKey,
+    #[error("The RR set {0} in the db can't be converted to DNS records")]
+    InvalidDbEntry(String),
     #[error("The queried domain name is invalid")]
     InvalidDomainName,
     #[error("Invalid username or password")]
     InvalidCredentials,
     #[error("Error while hashing")]
     CouldNotHash,
+    #[error("Timed out waiting for the lock on {0}")]
+    LockTimeout(String),
+    #[error("The zone {0} changed too often while taking a snapshot")]
+    SnapshotInconsistent(String),
+    #[error("Invalid db config: {0}")]
+    InvalidDbConfig(String),
+    #[error("None of the sentinels knows the db master {0}")]
+    NoDbMaster(String),
 
     // FIXME/TODO: differentiate between vault and ribston errors
     #[error("Failed to query Ribston")]
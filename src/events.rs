@@ -0,0 +1,254 @@
+//! Change events that are published after every committed change of a zone, so that caches (like
+//! the one of pektin-server) and secondaries can react immediately instead of polling the db.
+//!
+//! Each event is published as JSON on the pub/sub channel `CHANGE_EVENTS_CHANNEL`.
+
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, RrSet};
+use serde::{Deserialize, Serialize};
+use tracing::{instrument, warn};
+
+use crate::journal::{soa_serial, JournalEntry};
+use crate::storage::{Connection, Storage};
+
+/// The pub/sub channel on which the change events are published.
+pub const CHANGE_EVENTS_CHANNEL: &str = "changes";
+
+/// What happened to a zone.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZoneOperation {
+    /// The zone was created. All of its RR sets are new, so `changes` is empty.
+    Created,
+    /// RR sets of the zone were set or deleted, or its RRSIGs were renewed.
+    Changed,
+    /// The zone was deleted together with all of its RR sets, so `changes` is empty.
+    Deleted,
+}
+
+/// What happened to an RR set.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RrSetOperation {
+    Set,
+    Delete,
+}
+
+/// A change of a single RR set of the zone.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RrSetEvent {
+    pub name: Name,
+    pub rr_type: RecordType,
+    /// For RRSIGs, the type of the RR set they cover.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub type_covered: Option<RecordType>,
+    pub operation: RrSetOperation,
+}
+
+/// A committed change of a zone.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub zone: Name,
+    pub operation: ZoneOperation,
+    /// The SOA serial of the zone after the change, `None` if the zone was deleted.
+    pub serial: Option<u32>,
+    pub changes: Vec<RrSetEvent>,
+}
+
+impl ChangeEvent {
+    /// The event for the change that is described by the journal entry. The SOA record is always
+    /// part of the changes because its serial was incremented.
+    pub fn from_journal_entry(zone: &Name, entry: &JournalEntry) -> Self {
+        let key = |e: &DbEntry| (e.name.clone(), e.rr_type(), type_covered(e));
+        let added: Vec<_> = entry.added.iter().map(key).collect();
+        let deleted = entry
+            .removed
+            .iter()
+            .map(key)
+            .filter(|removed| !added.contains(removed))
+            .map(|key| (key, RrSetOperation::Delete));
+        let soa = (
+            (entry.soa_after.name.clone(), RecordType::SOA, None),
+            RrSetOperation::Set,
+        );
+
+        let changes = std::iter::once(soa)
+            .chain(added.iter().cloned().map(|key| (key, RrSetOperation::Set)))
+            .chain(deleted)
+            .map(|((name, rr_type, type_covered), operation)| RrSetEvent {
+                name,
+                rr_type,
+                type_covered,
+                operation,
+            })
+            .collect();
+
+        Self {
+            zone: zone.clone(),
+            operation: ZoneOperation::Changed,
+            serial: Some(entry.serial_after()),
+            changes,
+        }
+    }
+
+    /// The event for a zone that was created with the given SOA record.
+    pub fn zone_created(zone: &Name, soa: &DbEntry) -> Self {
+        Self {
+            zone: zone.clone(),
+            operation: ZoneOperation::Created,
+            serial: soa_serial(soa),
+            changes: vec![],
+        }
+    }
+
+    pub fn zone_deleted(zone: &Name) -> Self {
+        Self {
+            zone: zone.clone(),
+            operation: ZoneOperation::Deleted,
+            serial: None,
+            changes: vec![],
+        }
+    }
+}
+
+fn type_covered(entry: &DbEntry) -> Option<RecordType> {
+    match &entry.rr_set {
+        RrSet::RRSIG { rr_set } => rr_set.first().map(|rrsig| rrsig.type_covered),
+        _ => None,
+    }
+}
+
+/// Publishes the events on `CHANGE_EVENTS_CHANNEL`.
+///
+/// The changes are already written when the events are published, so a failure is only logged.
+#[instrument(skip(events, con))]
+pub async fn publish_change_events(events: &[ChangeEvent], con: &mut Connection) {
+    for event in events {
+        let message = match serde_json::to_string(event) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!(
+                    "Could not serialize the change event for {}: {}",
+                    event.zone, e
+                );
+                continue;
+            }
+        };
+        if let Err(e) = con.publish(CHANGE_EVENTS_CHANNEL, &message).await {
+            warn!(
+                "Could not publish the change event for {}: {}",
+                event.zone, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::broadcast::Receiver;
+
+    use super::*;
+    use crate::delete::delete_rr_sets;
+    use crate::storage::StoragePool;
+    use crate::test_utils::{a_entry, author, name, set_records, soa_entry, test_state};
+    use crate::types::RecordIdentifier;
+
+    /// The change events that were published since the last call.
+    fn published_events(messages: &mut Receiver<(String, String)>) -> Vec<Value> {
+        std::iter::from_fn(|| messages.try_recv().ok())
+            .filter(|(channel, _)| channel == CHANGE_EVENTS_CHANNEL)
+            .map(|(_, message)| serde_json::from_str(&message).unwrap())
+            .collect()
+    }
+
+    fn has_change(event: &Value, change: Value) -> bool {
+        event["changes"].as_array().unwrap().contains(&change)
+    }
+
+    #[actix_web::test]
+    async fn events_are_published_for_every_change() {
+        let state = test_state();
+        let mut messages = match &state.db_pool {
+            StoragePool::Memory(storage) => storage.subscribe(),
+            StoragePool::Redis(_) => unreachable!(),
+        };
+
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let events = published_events(&mut messages);
+        assert_eq!(
+            events,
+            vec![json!({
+                "zone": "example.com.",
+                "operation": "created",
+                "serial": 1,
+                "changes": [],
+            })]
+        );
+
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+        let events = published_events(&mut messages);
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event["zone"], "example.com.");
+        assert_eq!(event["operation"], "changed");
+        assert_eq!(event["serial"], 2);
+        let set = |name: &str, rr_type: &str| json!({ "name": name, "rr_type": rr_type, "operation": "set" });
+        assert!(has_change(event, set("example.com.", "SOA")));
+        assert!(has_change(event, set("www.example.com.", "A")));
+        assert!(has_change(
+            event,
+            json!({
+                "name": "www.example.com.",
+                "rr_type": "RRSIG",
+                "type_covered": "A",
+                "operation": "set",
+            })
+        ));
+        // the changes of the NSEC3 chain are part of the event
+        let nsec3_sets = event["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|c| c["rr_type"] == "NSEC3" && c["operation"] == "set")
+            .count();
+        assert_eq!(nsec3_sets, 2);
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        let www = RecordIdentifier {
+            name: name("www.example.com."),
+            rr_type: RecordType::A,
+        };
+        delete_rr_sets(&[www], &[], &author(), &state, &mut con, &mut dnssec_con)
+            .await
+            .unwrap();
+        let events = published_events(&mut messages);
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event["operation"], "changed");
+        assert_eq!(event["serial"], 3);
+        assert!(has_change(
+            event,
+            json!({ "name": "www.example.com.", "rr_type": "A", "operation": "delete" })
+        ));
+        assert!(has_change(
+            event,
+            json!({
+                "name": "www.example.com.",
+                "rr_type": "RRSIG",
+                "type_covered": "A",
+                "operation": "delete",
+            })
+        ));
+        let nsec3_operations: Vec<_> = event["changes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|c| c["rr_type"] == "NSEC3")
+            .map(|c| c["operation"].as_str().unwrap())
+            .collect();
+        assert_eq!(nsec3_operations.len(), 2);
+        assert!(nsec3_operations.contains(&"set") && nsec3_operations.contains(&"delete"));
+    }
+}
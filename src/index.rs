@@ -0,0 +1,571 @@
+//! The indexes that the redis storage backend maintains so that it doesn't have to scan the
+//! keyspace to find the zones, the records of a zone, or expiring RRSIGs.
+
+use pektin_common::deadpool_redis;
+use pektin_common::deadpool_redis::redis::{AsyncCommands, Pipeline};
+use pektin_common::proto::rr::Name;
+use pektin_common::{DbEntry, PektinCommonError, RrSet};
+use tracing::{debug, info, instrument};
+
+use crate::errors_and_responses::PektinApiResult;
+use crate::redis_storage::RedisConnection;
+use crate::storage::Storage;
+use crate::types::AppState;
+
+/// The set in db 0 that contains the names of all zones, i.e. of all names with a SOA record.
+pub const ZONES_KEY: &str = "zones";
+/// The prefix of the sets in db 0 that contain the keys of all records in a zone, including the
+/// records of its child zones (see `zone_index_key()`).
+const ZONE_INDEX_PREFIX: &str = "zone/";
+/// The sorted set in the DNSSEC db that contains the keys of all RRSIG records, scored by the
+/// time (in seconds since the epoch) at which the first signature in the RR set expires.
+pub const RRSIG_EXPIRY_KEY: &str = "rrsig-expiry";
+/// The hash in each db that maps the names of the indexes in that db that were built for the
+/// existing data to their version. An index is only used if it has been built, otherwise we fall back to `SCAN`.
+const INDEXES_KEY: &str = "indexes";
+/// The name and version of the zone index in `INDEXES_KEY`. Incrementing the version rebuilds the
+/// index on the next start.
+const ZONE_INDEX: (&str, u32) = ("zones", 1);
+/// The name and version of the RRSIG expiry index in `INDEXES_KEY` of the DNSSEC db.
+const RRSIG_EXPIRY_INDEX: (&str, u32) = ("rrsig-expiry", 1);
+
+/// Brings the zone indexes up to date for the record keys in `KEYS[2..]`, which were set or
+/// deleted. `KEYS[1]` is the set of zones and `ARGV[1]` the prefix of the zone indexes.
+///
+/// A record key is added to the index of every zone that contains its name if the record exists
+/// and removed from them otherwise. SOA keys are handled first: if a zone is created, the keys
+/// below it are copied from the index of the closest enclosing zone; if a zone is deleted, its
+/// index is deleted as well. Dots that are escaped with a backslash are part of a label.
+const UPDATE_ZONE_INDEXES_SCRIPT: &str = r#"
+local zones, prefix = KEYS[1], ARGV[1]
+local function parse(key)
+  return string.match(key, '^(.*):([^:]*)$')
+end
+local function parent(name)
+  local i = 1
+  while i < #name do
+    local c = string.sub(name, i, i)
+    if c == '\\' then
+      i = i + 2
+    elseif c == '.' then
+      return string.sub(name, i + 1)
+    else
+      i = i + 1
+    end
+  end
+  return '.'
+end
+local function ancestors(name)
+  local names = {name}
+  while name ~= '.' do
+    name = parent(name)
+    table.insert(names, name)
+  end
+  return names
+end
+local function is_below(name, zone)
+  for _, ancestor in ipairs(ancestors(name)) do
+    if ancestor == zone then
+      return true
+    end
+  end
+  return false
+end
+for i = 2, #KEYS do
+  local name, rr_type = parse(KEYS[i])
+  if rr_type == 'SOA' then
+    local exists = redis.call('EXISTS', KEYS[i]) == 1
+    if exists and redis.call('SADD', zones, name) == 1 then
+      local names = ancestors(name)
+      for j = 2, #names do
+        if redis.call('SISMEMBER', zones, names[j]) == 1 then
+          for _, key in ipairs(redis.call('SMEMBERS', prefix .. names[j])) do
+            if is_below(parse(key), name) then
+              redis.call('SADD', prefix .. name, key)
+            end
+          end
+          break
+        end
+      end
+    elseif not exists and redis.call('SREM', zones, name) == 1 then
+      redis.call('DEL', prefix .. name)
+    end
+  end
+end
+for i = 2, #KEYS do
+  local name = parse(KEYS[i])
+  local exists = redis.call('EXISTS', KEYS[i]) == 1
+  for _, zone in ipairs(ancestors(name)) do
+    if not exists then
+      redis.call('SREM', prefix .. zone, KEYS[i])
+    elseif redis.call('SISMEMBER', zones, zone) == 1 then
+      redis.call('SADD', prefix .. zone, KEYS[i])
+    end
+  end
+end
+return 0
+"#;
+
+/// Removes the members `ARGV[1..]` of the RRSIG expiry set `KEYS[1]` whose RRSIG doesn't exist.
+/// `KEYS[i + 1]` is the key of the RRSIG of `ARGV[i]`, including the key prefix of the DNSSEC data.
+const REMOVE_MISSING_RRSIGS_SCRIPT: &str = r#"
+for i = 2, #KEYS do
+  if redis.call('EXISTS', KEYS[i]) == 0 then
+    redis.call('ZREM', KEYS[1], ARGV[i - 1])
+  end
+end
+return 0
+"#;
+
+/// The key of the set in db 0 that contains the keys of all records in the zone, including the
+/// records of its child zones. Like the journal key, it contains no colon so that it never
+/// matches the globs used for records.
+pub fn zone_index_key(zone: &Name) -> String {
+    format!("{ZONE_INDEX_PREFIX}{}", zone.to_lowercase())
+}
+
+/// Queues the update of the zone indexes for the given record keys of db 0, which were set or
+/// deleted earlier in the pipeline. The pipeline must have db 0 selected.
+pub fn queue_zone_index_update(pipe: &mut Pipeline, keys: &[String]) {
+    if keys.is_empty() {
+        return;
+    }
+    pipe.cmd("EVAL")
+        .arg(UPDATE_ZONE_INDEXES_SCRIPT)
+        .arg(1 + keys.len())
+        .arg(ZONES_KEY)
+        .arg(keys)
+        .arg(ZONE_INDEX_PREFIX)
+        .ignore();
+}
+
+/// Queues the update of the RRSIG expiry set for RRSIGs that are set and deleted. The pipeline
+/// must have the DNSSEC db selected and `prefix` is the key prefix of the DNSSEC data (see
+/// `DnssecKeys`). The members of the set are the RRSIG keys without the prefix.
+pub fn queue_rrsig_expiry_update(
+    pipe: &mut Pipeline,
+    prefix: &str,
+    set: &[DbEntry],
+    del: &[String],
+) {
+    let expiry_key = format!("{prefix}{RRSIG_EXPIRY_KEY}");
+    if !del.is_empty() {
+        pipe.cmd("ZREM").arg(&expiry_key).arg(del).ignore();
+    }
+    let expirations: Vec<_> = set
+        .iter()
+        .filter_map(|entry| match &entry.rr_set {
+            RrSet::RRSIG { rr_set } => rr_set
+                .iter()
+                .map(|record| record.signature_expiration)
+                .min()
+                .map(|expiration| (expiration, entry.db_key())),
+            _ => None,
+        })
+        .collect();
+    if !expirations.is_empty() {
+        pipe.zadd_multiple(expiry_key, &expirations).ignore();
+    }
+}
+
+/// Queues the removal of the RRSIG keys from the RRSIG expiry set unless their RRSIG exists when
+/// the pipeline is executed. `prefix` and the keys are like in `queue_rrsig_expiry_update()`.
+fn queue_remove_missing_rrsigs(pipe: &mut Pipeline, prefix: &str, keys: &[String]) {
+    if keys.is_empty() {
+        return;
+    }
+    pipe.cmd("EVAL")
+        .arg(REMOVE_MISSING_RRSIGS_SCRIPT)
+        .arg(1 + keys.len())
+        .arg(format!("{prefix}{RRSIG_EXPIRY_KEY}"))
+        .arg(
+            keys.iter()
+                .map(|key| format!("{prefix}{key}"))
+                .collect::<Vec<_>>(),
+        )
+        .arg(keys)
+        .ignore();
+}
+
+/// Whether the index has been built for the existing data.
+async fn index_available(index: (&str, u32), con: &mut RedisConnection) -> PektinApiResult<bool> {
+    let version: Option<u32> = con
+        .con
+        .hget(con.key(INDEXES_KEY), index.0)
+        .await
+        .map_err(PektinCommonError::from)?;
+    Ok(version == Some(index.1))
+}
+
+/// Records that the index has been built for the existing data.
+async fn mark_index_built(index: (&str, u32), con: &mut RedisConnection) -> PektinApiResult<()> {
+    let key = con.key(INDEXES_KEY);
+    con.con
+        .hset::<_, _, _, ()>(key, index.0, index.1)
+        .await
+        .map_err(PektinCommonError::from)?;
+    Ok(())
+}
+
+/// Whether the zone index has been built for the existing data, i.e. whether `ZONES_KEY` and the
+/// zone index sets can be used instead of scanning the keyspace.
+#[instrument(skip(con))]
+pub async fn zone_index_available(con: &mut RedisConnection) -> PektinApiResult<bool> {
+    index_available(ZONE_INDEX, con).await
+}
+
+/// Whether the RRSIG expiry set has been built for the existing RRSIGs, i.e. whether it can be
+/// used to find expiring RRSIGs instead of scanning the DNSSEC db. `dnssec_con` must be a
+/// connection to the DNSSEC db.
+#[instrument(skip(dnssec_con))]
+pub async fn rrsig_expiry_index_available(
+    dnssec_con: &mut RedisConnection,
+) -> PektinApiResult<bool> {
+    index_available(RRSIG_EXPIRY_INDEX, dnssec_con).await
+}
+
+/// Builds the indexes for data that was written before they existed, unless they have already
+/// been built. Data written in the meantime is indexed by `DbWriteBatch::execute()`, so this may
+/// run while the API is in use.
+#[instrument(skip(state))]
+pub async fn build_indexes(state: &AppState) -> PektinApiResult<()> {
+    let mut con = state.db_pool.get().await?;
+    let mut dnssec_con = state.db_pool_dnssec.get().await?;
+    con.build_zone_index().await?;
+    dnssec_con.build_rrsig_expiry_index().await
+}
+
+pub async fn build_zone_index(con: &mut RedisConnection) -> PektinApiResult<()> {
+    if zone_index_available(con).await? {
+        return Ok(());
+    }
+    info!("Building the zone index");
+
+    // the zones must exist before the records are added to their indexes
+    let soa_keys = con.scan_keys(None, "*:SOA").await?;
+    update_zone_index(&soa_keys, con).await?;
+
+    let mut indexed = 0;
+    let mut cursor = 0;
+    loop {
+        let (next, keys) = con.scan_step(None, cursor, "*:*").await?;
+        update_zone_index(&keys, con).await?;
+        indexed += keys.len();
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    debug!("Indexed {} record keys", indexed);
+
+    mark_index_built(ZONE_INDEX, con).await?;
+    info!("Built the zone index for {} zones", soa_keys.len());
+    Ok(())
+}
+
+async fn update_zone_index(keys: &[String], con: &mut RedisConnection) -> PektinApiResult<()> {
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = deadpool_redis::redis::pipe();
+    queue_zone_index_update(&mut pipe, keys);
+    pipe.query_async::<_, ()>(&mut con.con)
+        .await
+        .map_err(PektinCommonError::from)?;
+    Ok(())
+}
+
+/// Adds all RRSIGs in the DNSSEC db to the RRSIG expiry set. An RRSIG that is changed or deleted
+/// while we read it may end up with an outdated score or without RRSIG; the signing task corrects
+/// such entries when it comes across them (see `refresh_rrsig_expiry()`).
+pub async fn build_rrsig_expiry_index(dnssec_con: &mut RedisConnection) -> PektinApiResult<()> {
+    if rrsig_expiry_index_available(dnssec_con).await? {
+        return Ok(());
+    }
+    info!("Building the RRSIG expiry index");
+
+    let mut indexed = 0;
+    let mut cursor = 0;
+    loop {
+        let (next, keys) = dnssec_con.scan_step(None, cursor, "*:RRSIG:*").await?;
+        if !keys.is_empty() {
+            let entries = dnssec_con.get_records(&keys).await?;
+            let mut pipe = deadpool_redis::redis::pipe();
+            queue_rrsig_expiry_update(
+                &mut pipe,
+                dnssec_con.prefix(),
+                &entries.into_iter().flatten().collect::<Vec<_>>(),
+                &[],
+            );
+            pipe.query_async::<_, ()>(&mut dnssec_con.con)
+                .await
+                .map_err(PektinCommonError::from)?;
+            indexed += keys.len();
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+
+    mark_index_built(RRSIG_EXPIRY_INDEX, dnssec_con).await?;
+    info!("Built the RRSIG expiry index for {} RRSIGs", indexed);
+    Ok(())
+}
+
+/// Returns the keys of all RRSIGs in the RRSIG expiry set whose first signature expires before
+/// `before` (in seconds since the epoch). `dnssec_con` must be a connection to the DNSSEC db.
+#[instrument(skip(dnssec_con))]
+pub async fn get_expiring_rrsig_keys(
+    before: i64,
+    dnssec_con: &mut RedisConnection,
+) -> PektinApiResult<Vec<String>> {
+    Ok(deadpool_redis::redis::cmd("ZRANGEBYSCORE")
+        .arg(dnssec_con.key(RRSIG_EXPIRY_KEY))
+        .arg("-inf")
+        .arg(format!("({before}"))
+        .query_async(&mut dnssec_con.con)
+        .await
+        .map_err(PektinCommonError::from)?)
+}
+
+/// Sets the scores of the RRSIGs with the given keys in the RRSIG expiry set to their current
+/// expiration and removes the keys whose RRSIG doesn't exist. `entries` must contain the current
+/// RRSIG (or `None`) for each key. `dnssec_con` must be a connection to the DNSSEC db.
+#[instrument(skip(entries, dnssec_con))]
+pub async fn refresh_rrsig_expiry(
+    keys: &[String],
+    entries: &[Option<DbEntry>],
+    dnssec_con: &mut RedisConnection,
+) -> PektinApiResult<()> {
+    let (existing, missing): (Vec<_>, Vec<_>) = keys
+        .iter()
+        .zip(entries)
+        .partition(|(_, entry)| entry.is_some());
+    let existing: Vec<_> = existing
+        .into_iter()
+        .filter_map(|(_, entry)| entry.clone())
+        .collect();
+    let missing: Vec<_> = missing.into_iter().map(|(key, _)| key.clone()).collect();
+    if existing.is_empty() && missing.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = deadpool_redis::redis::pipe();
+    queue_rrsig_expiry_update(&mut pipe, dnssec_con.prefix(), &existing, &[]);
+    // the RRSIG may have been created since we read it
+    queue_remove_missing_rrsigs(&mut pipe, dnssec_con.prefix(), &missing);
+    pipe.query_async::<_, ()>(&mut dnssec_con.con)
+        .await
+        .map_err(PektinCommonError::from)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use pektin_common::deadpool_redis::{Config, Pool, Runtime};
+    use pektin_common::proto::rr::RecordType;
+    use pektin_common::{DnssecAlgorithm, RrsigRecord};
+
+    use super::*;
+
+    /// The arguments of the commands in the pipeline.
+    fn packed_commands(pipe: &Pipeline) -> Vec<Vec<String>> {
+        let packed = String::from_utf8(pipe.get_packed_pipeline()).unwrap();
+        let mut lines = packed.split("\r\n");
+        let mut commands = Vec::new();
+        while let Some(line) = lines.next() {
+            if let Some(n) = line.strip_prefix('*') {
+                let n: usize = n.parse().unwrap();
+                // each argument is preceded by its length
+                let args = (0..n).map(|_| lines.nth(1).unwrap().to_string());
+                commands.push(args.collect());
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn zone_index_update_declares_the_record_keys() {
+        let mut pipe = deadpool_redis::redis::pipe();
+        queue_zone_index_update(&mut pipe, &[]);
+        assert!(packed_commands(&pipe).is_empty());
+
+        let keys = [
+            "example.com.:SOA".to_string(),
+            "a\\.b.example.com.:A".into(),
+        ];
+        queue_zone_index_update(&mut pipe, &keys);
+        let commands = packed_commands(&pipe);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0][0], "EVAL");
+        assert_eq!(
+            commands[0][2..],
+            ["3", ZONES_KEY, &keys[0], &keys[1], ZONE_INDEX_PREFIX]
+        );
+    }
+
+    #[test]
+    fn missing_rrsig_removal_declares_the_prefixed_keys() {
+        let mut pipe = deadpool_redis::redis::pipe();
+        queue_remove_missing_rrsigs(&mut pipe, "dnssec/", &[]);
+        assert!(packed_commands(&pipe).is_empty());
+
+        let keys = ["www.example.com.:RRSIG:A".to_string()];
+        queue_remove_missing_rrsigs(&mut pipe, "dnssec/", &keys);
+        let commands = packed_commands(&pipe);
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0][0], "EVAL");
+        assert_eq!(
+            commands[0][2..],
+            [
+                "2",
+                "dnssec/rrsig-expiry",
+                "dnssec/www.example.com.:RRSIG:A",
+                "www.example.com.:RRSIG:A",
+            ]
+        );
+    }
+
+    #[test]
+    fn rrsigs_are_scored_by_their_first_expiration() {
+        let rrsig = |expirations: &[u32]| DbEntry {
+            name: Name::from_ascii("www.example.com.").unwrap(),
+            ttl: 300,
+            meta: "".into(),
+            rr_set: RrSet::RRSIG {
+                rr_set: expirations
+                    .iter()
+                    .map(|expiration| RrsigRecord {
+                        type_covered: RecordType::A,
+                        algorithm: DnssecAlgorithm::ECDSAP256SHA256,
+                        labels: 3,
+                        original_ttl: 300,
+                        signature_expiration: *expiration,
+                        signature_inception: 0,
+                        key_tag: 1,
+                        signer_name: Name::from_ascii("example.com.").unwrap(),
+                        signature: "".into(),
+                    })
+                    .collect(),
+            },
+        };
+
+        let mut pipe = deadpool_redis::redis::pipe();
+        queue_rrsig_expiry_update(&mut pipe, "dnssec/", &[], &[]);
+        assert!(packed_commands(&pipe).is_empty());
+
+        let set = [rrsig(&[300, 100, 200]), rrsig(&[])];
+        let del = ["mail.example.com.:RRSIG:A".to_string()];
+        queue_rrsig_expiry_update(&mut pipe, "dnssec/", &set, &del);
+        let commands = packed_commands(&pipe);
+        assert_eq!(
+            commands,
+            [
+                vec!["ZREM", "dnssec/rrsig-expiry", &del[0]],
+                vec!["ZADD", "dnssec/rrsig-expiry", "100", &set[0].db_key()],
+            ]
+        );
+    }
+
+    /// Sets and deletes the keys and updates the zone indexes for them.
+    async fn write(pool: &Pool, set: &[&str], del: &[&str]) {
+        let mut pipe = deadpool_redis::redis::pipe();
+        for key in set {
+            pipe.set(*key, "").ignore();
+        }
+        for key in del {
+            pipe.del(*key).ignore();
+        }
+        let keys: Vec<_> = set.iter().chain(del).map(|key| key.to_string()).collect();
+        queue_zone_index_update(&mut pipe, &keys);
+        let mut con = pool.get().await.unwrap();
+        pipe.query_async::<_, ()>(&mut con).await.unwrap();
+    }
+
+    /// Runs the index scripts on the redis db at `TEST_REDIS_URL`, which is flushed. Run with
+    /// `TEST_REDIS_URL=redis://localhost/15 cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs a redis server"]
+    async fn index_scripts_maintain_the_indexes() {
+        let url = std::env::var("TEST_REDIS_URL").expect("TEST_REDIS_URL is not set");
+        let pool = Config::from_url(url)
+            .create_pool(Some(Runtime::Tokio1))
+            .unwrap();
+        let mut con = pool.get().await.unwrap();
+        deadpool_redis::redis::cmd("FLUSHDB")
+            .query_async::<_, ()>(&mut con)
+            .await
+            .unwrap();
+
+        write(
+            &pool,
+            &[
+                "example.com.:SOA",
+                "www.example.com.:A",
+                "a\\.b.example.com.:A",
+            ],
+            &[],
+        )
+        .await;
+        // `a\.b.example.com.` is not part of `b.example.com.`
+        write(&pool, &["b.example.com.:SOA", "www.b.example.com.:A"], &[]).await;
+        write(&pool, &[], &["www.example.com.:A"]).await;
+
+        let members = |key: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let mut con = pool.get().await.unwrap();
+                con.smembers::<_, BTreeSet<String>>(key).await.unwrap()
+            }
+        };
+        let set = |keys: &[&str]| {
+            keys.iter()
+                .map(|key| key.to_string())
+                .collect::<BTreeSet<_>>()
+        };
+        assert_eq!(
+            members(ZONES_KEY).await,
+            set(&["b.example.com.", "example.com."])
+        );
+        assert_eq!(
+            members("zone/example.com.").await,
+            set(&[
+                "a\\.b.example.com.:A",
+                "b.example.com.:SOA",
+                "example.com.:SOA",
+                "www.b.example.com.:A",
+            ])
+        );
+        assert_eq!(
+            members("zone/b.example.com.").await,
+            set(&["b.example.com.:SOA", "www.b.example.com.:A"])
+        );
+
+        // deleting the child zone deletes its index
+        write(&pool, &[], &["b.example.com.:SOA", "www.b.example.com.:A"]).await;
+        assert_eq!(members(ZONES_KEY).await, set(&["example.com."]));
+        assert!(members("zone/b.example.com.").await.is_empty());
+
+        let mut con = pool.get().await.unwrap();
+        let mut pipe = deadpool_redis::redis::pipe();
+        pipe.zadd(RRSIG_EXPIRY_KEY, "x.example.com.:RRSIG:A", 1)
+            .ignore()
+            .zadd(RRSIG_EXPIRY_KEY, "y.example.com.:RRSIG:A", 1)
+            .ignore()
+            .set("y.example.com.:RRSIG:A", "")
+            .ignore();
+        queue_remove_missing_rrsigs(
+            &mut pipe,
+            "",
+            &[
+                "x.example.com.:RRSIG:A".into(),
+                "y.example.com.:RRSIG:A".into(),
+            ],
+        );
+        pipe.query_async::<_, ()>(&mut con).await.unwrap();
+        let remaining: Vec<String> = con.zrange(RRSIG_EXPIRY_KEY, 0, -1).await.unwrap();
+        assert_eq!(remaining, ["y.example.com.:RRSIG:A"]);
+    }
+}
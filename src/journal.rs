@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+
+use actix_web::HttpRequest;
+use pektin_common::proto::rr::rdata::SOA;
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, RrSet, SoaRecord};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use crate::auth::{request_ip, request_user_agent};
+use crate::db::{
+    acquire_zone_locks, get_or_mget_records, get_zone_dnskey, release_locks, DbLock, DbWriteBatch,
+};
+use crate::dnssec::sign_db_entry;
+use crate::errors_and_responses::PektinApiResult;
+use crate::events::{publish_change_events, ChangeEvent};
+use crate::notify::spawn_notify_zone;
+use crate::storage::{Connection, JournalRange, Storage, StoragePool};
+use crate::types::{AppState, HistoryQuery, RecordIdentifier};
+use crate::utils::find_authoritative_zone;
+
+/// The maximum number of journal entries that are kept per zone. Older entries are trimmed.
+pub const MAX_JOURNAL_LENGTH: usize = 10000;
+
+/// A single change of a zone, from one SOA serial to the next.
+///
+/// The lists of removed and added RR sets contain the RRSIGs of the changed RR sets, but never
+/// the SOA record itself.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub soa_before: DbEntry,
+    pub soa_after: DbEntry,
+    pub removed: Vec<DbEntry>,
+    pub added: Vec<DbEntry>,
+    /// The client that made the change, or `None` if pektin made it on its own, e.g. when
+    /// re-signing expiring RRSIGs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<ChangeAuthor>,
+}
+
+/// The client that made a change and where the request came from.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ChangeAuthor {
+    pub client_username: String,
+    pub ip: Option<String>,
+    pub user_agent: String,
+}
+
+impl ChangeAuthor {
+    pub fn from_request(req: &HttpRequest, client_username: &str) -> Self {
+        Self {
+            client_username: client_username.to_string(),
+            ip: request_ip(req),
+            user_agent: request_user_agent(req),
+        }
+    }
+}
+
+/// A journal entry as returned by `/history`.
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryEntry {
+    /// The id of the entry in the journal stream.
+    pub id: String,
+    /// When the change was made, in milliseconds since the Unix epoch.
+    pub utc_millis: u64,
+    /// The SOA serial of the zone after the change.
+    pub serial: u32,
+    pub author: Option<ChangeAuthor>,
+    pub removed: Vec<DbEntry>,
+    pub added: Vec<DbEntry>,
+}
+
+impl JournalEntry {
+    pub fn serial_before(&self) -> u32 {
+        soa_serial(&self.soa_before).expect("journal entry contains invalid SOA record")
+    }
+
+    pub fn serial_after(&self) -> u32 {
+        soa_serial(&self.soa_after).expect("journal entry contains invalid SOA record")
+    }
+}
+
+/// The state of (a part of) a zone before it is changed. Created by [`prepare_zone_changes`]
+/// and turned into a journal entry by [`commit_zone_changes`] when the change is written.
+#[derive(Debug, Clone)]
+struct ZoneChange {
+    zone: Name,
+    idents: Vec<RecordIdentifier>,
+    soa: DbEntry,
+    soa_rrsig: Option<DbEntry>,
+    before: Vec<DbEntry>,
+}
+
+/// The changes of zones prepared by [`prepare_zone_changes`] together with the locks of the
+/// zones. The locks are held until the changes are committed with [`commit_zone_changes`] or
+/// given up with [`ZoneChanges::abort`], so that no other change of the zones can be written in
+/// between and get the same SOA serial.
+#[derive(Debug)]
+pub struct ZoneChanges {
+    changes: Vec<ZoneChange>,
+    locks: Vec<DbLock>,
+}
+
+impl ZoneChanges {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Doesn't record the changes of the given zones, e.g. because they are deleted completely.
+    /// Their locks are held nevertheless.
+    pub fn skip_zones(&mut self, zones: &[Name]) {
+        self.changes.retain(|change| !zones.contains(&change.zone));
+    }
+
+    /// Records the current state of more RR sets that are changed, e.g. of NSEC3 records that are
+    /// only known once the zones are locked. RR sets that are not part of a prepared zone are
+    /// ignored.
+    pub async fn add_rr_sets(
+        &mut self,
+        idents: &[RecordIdentifier],
+        con: &mut Connection,
+        dnssec_con: &mut Connection,
+    ) -> PektinApiResult<()> {
+        let zones: Vec<_> = self.changes.iter().map(|c| c.zone.clone()).collect();
+        for change in &mut self.changes {
+            let idents: Vec<_> = idents
+                .iter()
+                .filter(|ident| {
+                    find_authoritative_zone(&ident.name, &zones).as_ref() == Some(&change.zone)
+                        && !change.idents.iter().any(|i| i.db_key() == ident.db_key())
+                })
+                .cloned()
+                .collect();
+            change
+                .before
+                .extend(get_rr_sets_with_rrsigs(&idents, con, dnssec_con).await?);
+            change.idents.extend(idents);
+        }
+        Ok(())
+    }
+
+    /// Releases the locks of the zones without committing the changes. This must be called if the
+    /// changes couldn't be written.
+    ///
+    /// The locks are released in the background, so this can be called while holding an error
+    /// response, which must not be held across an await point.
+    pub fn abort(self, pool: &StoragePool) {
+        if self.locks.is_empty() {
+            return;
+        }
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let res = match pool.get().await {
+                Ok(mut con) => release_locks(self.locks, &mut con).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = res {
+                warn!("Could not release the zone locks: {}", e);
+            }
+        });
+    }
+}
+
+/// The key in db 0 under which the journal of the zone is stored.
+///
+/// It intentionally contains no colon so that it never matches the globs used for records.
+pub fn journal_db_key(zone: &Name) -> String {
+    format!("journal/{}", zone.to_lowercase())
+}
+
+/// The key in db 0 under which the `serial_before` of the first entry that was ever added to the
+/// zone's journal is stored. It is set by `Storage::write()` and deleted together with the journal.
+///
+/// If the first entry of the journal has a different `serial_before`, older entries were trimmed.
+pub fn journal_start_db_key(zone: &Name) -> String {
+    format!("journal-start/{}", zone.to_lowercase())
+}
+
+/// Returns the serial of the SOA record in the given entry, if it is a SOA entry.
+pub fn soa_serial(entry: &DbEntry) -> Option<u32> {
+    match &entry.rr_set {
+        RrSet::SOA { rr_set } => rr_set.first().map(|soa| soa.value.serial()),
+        _ => None,
+    }
+}
+
+/// Returns `true` if serial `a` is greater than serial `b` according to RFC 1982.
+pub fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+pub fn with_serial(soa: &SOA, serial: u32) -> SOA {
+    SOA::new(
+        soa.mname().clone(),
+        soa.rname().clone(),
+        serial,
+        soa.refresh(),
+        soa.retry(),
+        soa.expire(),
+        soa.minimum(),
+    )
+}
+
+/// Returns all journal entries of the zone, oldest first.
+#[instrument(skip(con))]
+pub async fn get_journal(zone: &Name, con: &mut Connection) -> PektinApiResult<Vec<JournalEntry>> {
+    Ok(get_journal_with_times(zone, con)
+        .await?
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect())
+}
+
+/// Returns all journal entries of the zone together with the time they were added in
+/// milliseconds since the Unix epoch, oldest first.
+#[instrument(skip(con))]
+pub async fn get_journal_with_times(
+    zone: &Name,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<(u64, JournalEntry)>> {
+    Ok(con
+        .read_journal(zone, JournalRange::default())
+        .await?
+        .into_iter()
+        .map(|(id, entry)| (stream_id_millis(&id), entry))
+        .collect())
+}
+
+/// Returns the `serial_before` of the first entry that was ever added to the zone's journal, see
+/// [`journal_start_db_key`]. `None` if the zone has no journal or it was written by a version of
+/// pektin that didn't record it.
+#[instrument(skip(con))]
+pub async fn get_journal_start_serial(
+    zone: &Name,
+    con: &mut Connection,
+) -> PektinApiResult<Option<u32>> {
+    Ok(con
+        .get_value(&journal_start_db_key(zone))
+        .await?
+        .and_then(|serial| serial.parse().ok()))
+}
+
+/// Returns the journal entries of the zone that match the query, newest first.
+#[instrument(skip(con))]
+pub async fn get_history(
+    query: &HistoryQuery,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<HistoryEntry>> {
+    let range = JournalRange {
+        since: query.since,
+        until: query.until,
+        newest_first: true,
+    };
+
+    let matches_name = |entry: &JournalEntry| match &query.name {
+        Some(name) => entry
+            .removed
+            .iter()
+            .chain(&entry.added)
+            .any(|e| e.rr_type() != RecordType::RRSIG && &e.name == name),
+        None => true,
+    };
+    let matches_client = |entry: &JournalEntry| match &query.client {
+        Some(client) => entry
+            .author
+            .as_ref()
+            .is_some_and(|a| &a.client_username == client),
+        None => true,
+    };
+    Ok(con
+        .read_journal(&query.zone, range)
+        .await?
+        .into_iter()
+        .filter(|(_, entry)| matches_name(entry) && matches_client(entry))
+        .take(query.limit.unwrap_or(usize::MAX))
+        .map(|(id, entry)| HistoryEntry {
+            utc_millis: stream_id_millis(&id),
+            id,
+            serial: entry.serial_after(),
+            author: entry.author,
+            removed: entry.removed,
+            added: entry.added,
+        })
+        .collect())
+}
+
+/// The time a stream entry was added in milliseconds since the Unix epoch, which is the first
+/// part of its id.
+pub fn stream_id_millis(id: &str) -> u64 {
+    id.split('-')
+        .next()
+        .and_then(|millis| millis.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Deletes the journals of the given zones.
+#[instrument(skip(con))]
+pub async fn delete_journals(zones: &[Name], con: &mut Connection) -> PektinApiResult<()> {
+    con.delete_journals(zones).await
+}
+
+/// Locks the zones that contain the given RR sets and records the current state of the RR sets
+/// and of the SOA records of the zones. This must be called before the RR sets are changed, and
+/// the returned changes must be committed with [`commit_zone_changes`] or aborted afterwards.
+///
+/// RR sets that are not part of an existing zone (e.g. because the zone is newly created) are
+/// ignored, as are the SOA records themselves. The zones of SOA records are locked nevertheless,
+/// so that a zone can't be created twice at the same time.
+///
+/// The zone locks must be acquired after the locks of the RR sets (see `db::acquire_locks()`).
+#[instrument(skip(con, dnssec_con))]
+pub async fn prepare_zone_changes(
+    idents: &[RecordIdentifier],
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<ZoneChanges> {
+    let authoritative_zones: Vec<_> = con
+        .get_zones()
+        .await?
+        .into_iter()
+        .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
+        .collect();
+
+    let mut idents_for_zone: HashMap<Name, Vec<RecordIdentifier>> = HashMap::new();
+    for ident in idents {
+        if let Some(zone) = find_authoritative_zone(&ident.name, &authoritative_zones) {
+            let zone_idents = idents_for_zone.entry(zone).or_default();
+            if ident.rr_type != RecordType::SOA {
+                zone_idents.push(ident.clone());
+            }
+        }
+    }
+
+    let zones_to_lock: Vec<_> = idents_for_zone
+        .keys()
+        .cloned()
+        .chain(
+            idents
+                .iter()
+                .filter(|ident| ident.rr_type == RecordType::SOA)
+                .map(|ident| ident.name.clone()),
+        )
+        .collect();
+    let locks = acquire_zone_locks(&zones_to_lock, con).await?;
+
+    let res = async {
+        let mut changes = Vec::with_capacity(idents_for_zone.len());
+        for (zone, idents) in idents_for_zone {
+            let soa_ident = RecordIdentifier {
+                name: zone.clone(),
+                rr_type: RecordType::SOA,
+            };
+            let soa = match get_or_mget_records(&[soa_ident.db_key()], con).await?.pop() {
+                Some(Some(soa)) => soa,
+                _ => continue,
+            };
+            let soa_rrsig = get_or_mget_records(&[soa_ident.rrsig_db_key()], dnssec_con)
+                .await?
+                .pop()
+                .flatten();
+            let before = get_rr_sets_with_rrsigs(&idents, con, dnssec_con).await?;
+            changes.push(ZoneChange {
+                zone,
+                idents,
+                soa,
+                soa_rrsig,
+                before,
+            });
+        }
+        Ok(changes)
+    }
+    .await;
+    match res {
+        Ok(changes) => Ok(ZoneChanges { changes, locks }),
+        Err(e) => {
+            release_locks(locks, con).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Writes the batch together with the new SOA records of the changed zones, their RRSIGs, and an
+/// entry describing the change in each zone's journal in a single transaction, and releases the
+/// zone locks. Afterwards, a change event is published for each zone (see the `events` module)
+/// and the zone's secondaries are notified. The batch must contain the changes prepared with
+/// [`prepare_zone_changes`].
+///
+/// `author` is recorded in the journal entries; it is `None` for changes pektin makes on its own.
+///
+/// The SOA serial of every changed zone is incremented, unless a client sets a new SOA record
+/// with a greater serial as part of the change. Zones whose SOA record is deleted are skipped.
+#[instrument(skip(changes, batch, state, vault_api_token, con))]
+pub async fn commit_zone_changes(
+    changes: ZoneChanges,
+    mut batch: DbWriteBatch,
+    state: &AppState,
+    vault_api_token: &str,
+    author: Option<&ChangeAuthor>,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<JournalEntry>> {
+    let ZoneChanges { changes, locks } = changes;
+    let res = async {
+        add_journal_entries(changes, &mut batch, state, vault_api_token, author, con).await?;
+        batch.execute(con).await
+    }
+    .await;
+    release_locks(locks, con).await?;
+    res?;
+
+    for (zone, entry) in &batch.journal {
+        publish_change_events(&[ChangeEvent::from_journal_entry(zone, entry)], con).await;
+        // the change is already written, so failing to notify the secondaries is no error
+        if let Err(e) = spawn_notify_zone(zone, &entry.soa_after, &state.tsig_keys, con).await {
+            warn!("Could not notify the secondaries of {}: {}", zone, e);
+        }
+    }
+    Ok(batch.journal.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// Adds the new SOA records, their RRSIGs, and the journal entries of `commit_zone_changes()` to
+/// the batch.
+async fn add_journal_entries(
+    changes: Vec<ZoneChange>,
+    batch: &mut DbWriteBatch,
+    state: &AppState,
+    vault_api_token: &str,
+    author: Option<&ChangeAuthor>,
+    con: &mut Connection,
+) -> PektinApiResult<()> {
+    for change in changes {
+        let soa_ident = RecordIdentifier {
+            name: change.zone.clone(),
+            rr_type: RecordType::SOA,
+        };
+        let (soa_key, soa_rrsig_key) = (soa_ident.db_key(), soa_ident.rrsig_db_key());
+        if batch.del.contains(&soa_key) {
+            continue;
+        }
+        let current_soa = batch
+            .set
+            .iter()
+            .find(|e| e.db_key() == soa_key)
+            .unwrap_or(&change.soa)
+            .clone();
+
+        let old_serial = soa_serial(&change.soa).expect("SOA entry contains no SOA record");
+        let current_serial = soa_serial(&current_soa).expect("SOA entry contains no SOA record");
+        let new_serial = if serial_gt(current_serial, old_serial) {
+            current_serial
+        } else {
+            old_serial.wrapping_add(1)
+        };
+        let new_soa = match &current_soa.rr_set {
+            RrSet::SOA { rr_set } => DbEntry {
+                rr_set: RrSet::SOA {
+                    rr_set: rr_set
+                        .iter()
+                        .map(|soa| SoaRecord {
+                            value: with_serial(&soa.value, new_serial),
+                        })
+                        .collect(),
+                },
+                ..current_soa.clone()
+            },
+            _ => unreachable!(),
+        };
+
+        let dnskey = get_zone_dnskey(&change.zone, con).await?;
+        let new_soa_rrsig = sign_db_entry(
+            &change.zone,
+            new_soa.clone(),
+            &dnskey,
+            &state.vault_uri,
+            vault_api_token,
+        )
+        .await?;
+
+        let after = apply_batch(&change.idents, &change.before, batch);
+        let (mut removed, mut added) = diff_rr_sets(change.before, after)?;
+        removed.extend(change.soa_rrsig);
+        added.push(new_soa_rrsig.clone());
+
+        batch.set.retain(|e| e.db_key() != soa_key);
+        batch.set.push(new_soa.clone());
+        batch.dnssec_set.retain(|e| e.db_key() != soa_rrsig_key);
+        batch.dnssec_set.push(new_soa_rrsig);
+        debug!(
+            "zone {} changes from serial {} to {}",
+            change.zone, old_serial, new_serial
+        );
+        batch.journal.push((
+            change.zone,
+            JournalEntry {
+                soa_before: change.soa,
+                soa_after: new_soa,
+                removed,
+                added,
+                author: author.cloned(),
+            },
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the given RR sets and their RRSIGs as they are after writing the batch, given their
+/// state `before` the batch is written.
+fn apply_batch(
+    idents: &[RecordIdentifier],
+    before: &[DbEntry],
+    batch: &DbWriteBatch,
+) -> Vec<DbEntry> {
+    let mut after = Vec::with_capacity(before.len());
+    for ident in idents {
+        for (key, set, del) in [
+            (ident.db_key(), &batch.set, &batch.del),
+            (ident.rrsig_db_key(), &batch.dnssec_set, &batch.dnssec_del),
+        ] {
+            // keys that are both set and deleted are set
+            let entry = match set.iter().rev().find(|e| e.db_key() == key) {
+                Some(entry) => Some(entry),
+                None if del.contains(&key) => None,
+                None => before.iter().find(|e| e.db_key() == key),
+            };
+            after.extend(entry.cloned());
+        }
+    }
+    after
+}
+
+async fn get_rr_sets_with_rrsigs(
+    idents: &[RecordIdentifier],
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<Vec<DbEntry>> {
+    if idents.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys: Vec<_> = idents.iter().map(RecordIdentifier::db_key).collect();
+    let rrsig_keys: Vec<_> = idents.iter().map(RecordIdentifier::rrsig_db_key).collect();
+    let mut entries = get_or_mget_records(&keys, con).await?;
+    entries.extend(get_or_mget_records(&rrsig_keys, dnssec_con).await?);
+    Ok(entries.into_iter().flatten().collect())
+}
+
+/// Returns the RR sets that are only contained in `before` and those only contained in `after`.
+fn diff_rr_sets(
+    before: Vec<DbEntry>,
+    after: Vec<DbEntry>,
+) -> PektinApiResult<(Vec<DbEntry>, Vec<DbEntry>)> {
+    let serialize = |e: &DbEntry| e.serialize_for_db().map(|ser| (e.db_key(), ser));
+    let serialized_before: Vec<_> = before.iter().map(serialize).collect::<Result<_, _>>()?;
+    let serialized_after: Vec<_> = after.iter().map(serialize).collect::<Result<_, _>>()?;
+    let removed = before
+        .into_iter()
+        .zip(serialized_before.iter())
+        .filter(|(_, ser)| !serialized_after.contains(ser))
+        .map(|(entry, _)| entry)
+        .collect();
+    let added = after
+        .into_iter()
+        .zip(serialized_after.iter())
+        .filter(|(_, ser)| !serialized_before.contains(ser))
+        .map(|(entry, _)| entry)
+        .collect();
+    Ok((removed, added))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::get_zone_keys;
+    use crate::delete::delete_rr_sets;
+    use crate::test_utils::{a_entry, author, name, set_records, soa_entry, test_state};
+
+    #[actix_web::test]
+    async fn journal_entries_contain_the_nsec3_changes() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let journal = get_journal(&name("example.com."), &mut con).await.unwrap();
+        assert_eq!(journal.len(), 1);
+        // NSEC3 records and their RRSIGs
+        let count_nsec3 = |entries: &[DbEntry]| {
+            entries
+                .iter()
+                .filter(|e| match &e.rr_set {
+                    RrSet::RRSIG { rr_set } => rr_set[0].type_covered == RecordType::NSEC3,
+                    _ => e.rr_type() == RecordType::NSEC3,
+                })
+                .count()
+        };
+        // www gets an NSEC3 record and the NSEC3 record of the apex points to it
+        assert_eq!(count_nsec3(&journal[0].added), 4);
+        assert_eq!(count_nsec3(&journal[0].removed), 2);
+
+        // the entry describes the NSEC3 chain as it is in the db
+        let keys = get_zone_keys(&[&name("example.com.")], &mut con)
+            .await
+            .unwrap()
+            .pop()
+            .flatten()
+            .unwrap();
+        let nsec3_keys: Vec<_> = keys.iter().filter(|k| k.ends_with(":NSEC3")).collect();
+        assert_eq!(nsec3_keys.len(), 2);
+        for key in &nsec3_keys {
+            assert!(journal[0].added.iter().any(|e| &&e.db_key() == key));
+        }
+
+        // deleting www removes its NSEC3 record again
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        let www = RecordIdentifier {
+            name: name("www.example.com."),
+            rr_type: RecordType::A,
+        };
+        delete_rr_sets(&[www], &[], &author(), &state, &mut con, &mut dnssec_con)
+            .await
+            .unwrap();
+        let journal = get_journal(&name("example.com."), &mut con).await.unwrap();
+        assert_eq!(journal.len(), 2);
+        assert_eq!(count_nsec3(&journal[1].added), 2);
+        assert_eq!(count_nsec3(&journal[1].removed), 4);
+    }
+}
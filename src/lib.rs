@@ -2,28 +2,87 @@ pub mod ribston;
 
 pub mod vault;
 
+#[path = "methods/acme-cleanup.rs"]
+pub mod acme_cleanup;
+#[path = "methods/acme-present.rs"]
+pub mod acme_present;
+#[path = "methods/create-zone.rs"]
+pub mod create_zone;
 #[path = "methods/delete.rs"]
 pub mod delete;
+#[path = "methods/delete-zone.rs"]
+pub mod delete_zone;
+#[path = "methods/diff.rs"]
+pub mod diff;
+#[path = "methods/export-zone.rs"]
+pub mod export_zone;
 #[path = "methods/get.rs"]
 pub mod get;
+#[path = "methods/get-notify-targets.rs"]
+pub mod get_notify_targets;
 #[path = "methods/get-zone-records.rs"]
 pub mod get_zone_records;
 #[path = "methods/health.rs"]
 pub mod health;
+#[path = "methods/history.rs"]
+pub mod history;
+#[path = "methods/import-zone.rs"]
+pub mod import_zone;
+#[path = "methods/import-zone-transfer.rs"]
+pub mod import_zone_transfer;
+#[path = "methods/list-zones.rs"]
+pub mod list_zones;
+#[path = "methods/restore-snapshot.rs"]
+pub mod restore_snapshot;
+#[path = "methods/rollback.rs"]
+pub mod rollback;
 #[path = "methods/search.rs"]
 pub mod search;
 #[path = "methods/set.rs"]
 pub mod set;
+#[path = "methods/set-notify-targets.rs"]
+pub mod set_notify_targets;
+#[path = "methods/snapshot.rs"]
+pub mod snapshot;
+#[path = "methods/sync.rs"]
+pub mod sync;
+#[path = "methods/transaction.rs"]
+pub mod transaction;
+#[path = "methods/update.rs"]
+pub mod update;
 
+pub mod acme;
 pub mod auth;
+pub mod backup;
 pub mod config;
 pub mod db;
+#[path = "dns-update.rs"]
+pub mod dns_update;
 pub mod dnssec;
 #[path = "errors-and-responses.rs"]
 pub mod errors_and_responses;
+pub mod events;
+pub mod index;
+pub mod journal;
 pub mod macros;
+#[path = "memory-storage.rs"]
+pub mod memory_storage;
+pub mod notify;
+pub mod query;
+#[path = "redis-storage.rs"]
+pub mod redis_storage;
+pub mod repair;
 #[path = "signing-task.rs"]
 pub mod signing_task;
+pub mod storage;
+#[cfg(test)]
+#[path = "test-utils.rs"]
+mod test_utils;
+pub mod tsig;
 pub mod types;
 pub mod utils;
 pub mod validation;
+#[path = "zone-file.rs"]
+pub mod zone_file;
+#[path = "zone-transfer.rs"]
+pub mod zone_transfer;
@@ -31,5 +31,29 @@ macro_rules! return_if_err {
     };
 }
 
+/// Takes `var`, a `Vec<Result<T, E>>`, and turns it into a `Vec<T>` if all results are `Ok`.
+/// If any result is `Err`, a vector of all error messages is built and returned using `err()`
+/// together with the given error message.
+///
+/// The optional third argument is applied to the error response before it is returned, e.g. `Err`
+/// to use the macro in a function returning `Result<_, HttpResponse>`.
+#[doc(hidden)]
+macro_rules! unwrap_or_return_if_err {
+    ($var:ident, $err_msg:expr) => {
+        $crate::macros::unwrap_or_return_if_err!($var, $err_msg, std::convert::identity)
+    };
+    ($var:ident, $err_msg:expr, $wrap:expr) => {
+        if $var.iter().any(|r| r.is_err()) {
+            let messages = $var
+                .iter()
+                .map(|res| res.as_ref().err().map(|e| e.to_string()))
+                .collect();
+            return $wrap($crate::errors_and_responses::err($err_msg, messages));
+        }
+        let $var = $var.into_iter().map(|res| res.unwrap()).collect::<Vec<_>>();
+    };
+}
+
 pub(crate) use impl_from_request_body;
 pub(crate) use return_if_err;
+pub(crate) use unwrap_or_return_if_err;
@@ -9,25 +9,50 @@ use actix_cors::Cors;
 use actix_web::{http, web, App, HttpServer};
 use anyhow::{bail, Context};
 use chrono::Duration;
+use pektin_api::repair::repair_consistency;
 use pektin_api::signing_task::signing_task;
 use tokio::signal::unix::{signal, SignalKind};
-use tracing::{debug, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::EnvFilter;
 
+use pektin_api::acme_cleanup::acme_cleanup;
+use pektin_api::acme_present::acme_present;
 use pektin_api::config::Config;
+use pektin_api::create_zone::create_zone;
+use pektin_api::db::DNSSEC_DB;
 use pektin_api::delete::delete;
+use pektin_api::delete_zone::delete_zone;
+use pektin_api::diff::diff;
+use pektin_api::dns_update::dns_update_server;
 use pektin_api::errors_and_responses::json_error_handler;
+use pektin_api::export_zone::export_zone;
 use pektin_api::get::get;
+use pektin_api::get_notify_targets::get_notify_targets;
 use pektin_api::get_zone_records::get_zone_records;
 use pektin_api::health::health;
+use pektin_api::history::history;
+use pektin_api::import_zone::import_zone;
+use pektin_api::import_zone_transfer::import_zone_transfer;
+use pektin_api::index::build_indexes;
+use pektin_api::list_zones::list_zones;
+use pektin_api::memory_storage::MemoryStorage;
+use pektin_api::redis_storage::{create_redis_pools, watch_sentinel_master, RedisOptions};
+use pektin_api::restore_snapshot::restore_snapshot;
+use pektin_api::rollback::rollback;
 use pektin_api::search::search;
 use pektin_api::set::set;
+use pektin_api::set_notify_targets::set_notify_targets;
+use pektin_api::snapshot::snapshot;
+use pektin_api::storage::StoragePool;
+use pektin_api::sync::sync;
+use pektin_api::transaction::transaction;
+use pektin_api::tsig::{parse_key_clients, parse_key_zones, TsigKey};
 use pektin_api::types::AppState;
-use pektin_common::deadpool_redis;
-use pektin_common::deadpool_redis::redis::Client;
+use pektin_api::update::update;
+use pektin_api::zone_transfer::zone_transfer_server;
 
 use std::env;
 
@@ -39,55 +64,33 @@ async fn main() -> anyhow::Result<()> {
     let config = Config::from_env().context("Failed to load config")?;
     println!("Config loaded successfully.\n");
 
-    // the db pool needs to be created in the HttpServer::new closure because of trait bounds.
-    // in there, we cannot use the ? operator. to notify the user about a potentially invalid db
-    // uri in a nice way (i.e. not via .expect()), we create a client here that checks the uri
-    let db_uri = format!(
-        "redis://{}:{}@{}:{}/0",
-        config.db_username, config.db_password, config.db_hostname, config.db_port
-    );
-    debug!("Connecting to db at {}", db_uri);
-
-    let db_uri_dnssec = format!(
-        "redis://{}:{}@{}:{}/1",
-        config.db_username, config.db_password, config.db_hostname, config.db_port
-    );
-    debug!("Connecting to db for dnssec at {}", db_uri_dnssec);
-
-    let db_connection_info = if let Ok(client) = Client::open(db_uri) {
-        client.get_connection_info().clone()
-    } else {
-        bail!("Invalid db URI")
-    };
-
-    let db_connection_dnssec_info = if let Ok(client) = Client::open(db_uri_dnssec) {
-        client.get_connection_info().clone()
-    } else {
-        bail!("Invalid db URI")
-    };
-
-    let db_pool_conf = deadpool_redis::Config {
-        url: None,
-        connection: Some(db_connection_info.into()),
-        pool: None,
-    };
-
-    let db_pool_dnssec_conf = deadpool_redis::Config {
-        url: None,
-        connection: Some(db_connection_dnssec_info.into()),
-        pool: None,
+    let (db_pool, db_pool_dnssec, redis) = match config.db_backend.as_str() {
+        "redis" => {
+            let options = RedisOptions::from_config(&config)?;
+            let (db_pool, db_pool_dnssec) = create_redis_pools(&options)
+                .await
+                .context("Failed to create the db connection pools")?;
+            (
+                StoragePool::Redis(db_pool.clone()),
+                StoragePool::Redis(db_pool_dnssec.clone()),
+                Some((db_pool, db_pool_dnssec, options)),
+            )
+        }
+        "memory" => {
+            warn!("Using the in-memory db backend, all data is lost when pektin-api exits");
+            let storage = MemoryStorage::new();
+            (
+                StoragePool::Memory(storage.clone()),
+                StoragePool::Memory(storage.with_db(DNSSEC_DB)),
+                None,
+            )
+        }
+        _ => bail!("Invalid DB_BACKEND, must be redis or memory"),
     };
 
     let bind_addr = format!("{}:{}", &config.bind_address, &config.bind_port);
     info!("Binding to {}", bind_addr);
 
-    let db_pool = db_pool_conf
-        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
-        .expect("Failed to create db connection pool");
-    let db_pool_dnssec = db_pool_dnssec_conf
-        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
-        .expect("Failed to create db connection pool for dnssec");
-
     let state = AppState {
         db_pool,
         db_pool_dnssec,
@@ -96,6 +99,11 @@ async fn main() -> anyhow::Result<()> {
         vault_password: config.vault_password.clone(),
         vault_user_name: config.vault_user_name.clone(),
         skip_auth: config.skip_auth.clone(),
+        tsig_keys: TsigKey::parse_list(&config.tsig_keys).context("Invalid TSIG_KEYS")?,
+        tsig_key_clients: parse_key_clients(&config.tsig_key_clients)
+            .context("Invalid TSIG_KEY_CLIENTS")?,
+        tsig_key_zones: parse_key_zones(&config.tsig_key_zones)
+            .context("Invalid TSIG_KEY_ZONES")?,
     };
 
     let http_server_state = state.clone();
@@ -123,12 +131,79 @@ async fn main() -> anyhow::Result<()> {
             .service(delete)
             .service(search)
             .service(health)
+            .service(import_zone)
+            .service(export_zone)
+            .service(import_zone_transfer)
+            .service(get_notify_targets)
+            .service(set_notify_targets)
+            .service(acme_present)
+            .service(acme_cleanup)
+            .service(create_zone)
+            .service(delete_zone)
+            .service(list_zones)
+            .service(transaction)
+            .service(diff)
+            .service(sync)
+            .service(history)
+            .service(rollback)
+            .service(snapshot)
+            .service(restore_snapshot)
+            .service(update)
     })
     .bind(bind_addr)?
     .run();
 
-    // TODO: make this configurable, e.g. via env var
-    let signing_task = signing_task(state, Duration::minutes(15), Duration::hours(2));
+    let dns_update_bind_address = config.bind_address.clone();
+
+    // the zone transfer listener is optional, if it is disabled this future never completes
+    let zone_transfer_state = state.clone();
+    let zone_transfer_server = async move {
+        match config.zone_transfer_bind_port {
+            Some(port) => {
+                let bind_addr = format!("{}:{}", &config.bind_address, port);
+                zone_transfer_server(zone_transfer_state, bind_addr).await
+            }
+            None => std::future::pending().await,
+        }
+    };
+
+    // the DNS UPDATE listener is optional as well
+    let dns_update_state = state.clone();
+    let dns_update_server = async move {
+        match config.dns_update_bind_port {
+            Some(port) => {
+                let bind_addr = format!("{}:{}", dns_update_bind_address, port);
+                dns_update_server(dns_update_state, bind_addr).await
+            }
+            None => std::future::pending().await,
+        }
+    };
+
+    // the sentinels are only watched if they are configured, otherwise this future never completes
+    let sentinel_watch = async move {
+        match redis {
+            Some((db_pool, db_pool_dnssec, options)) if !options.sentinels.is_empty() => {
+                watch_sentinel_master(db_pool, db_pool_dnssec, options).await
+            }
+            _ => std::future::pending().await,
+        }
+    };
+
+    // index the data written by earlier versions and, if enabled, repair inconsistencies left
+    // behind by them before the first signing run
+    let repair_db_on_start = config.repair_db_on_start;
+    let signing_task = async move {
+        if let Err(e) = build_indexes(&state).await {
+            error!("Could not build the db indexes: {}", e);
+        }
+        if repair_db_on_start {
+            if let Err(e) = repair_consistency(&state).await {
+                error!("Could not repair the db: {}", e);
+            }
+        }
+        // TODO: make this configurable, e.g. via env var
+        signing_task(state, Duration::minutes(15), Duration::hours(2)).await
+    };
 
     // shutdown if we receive a SIGINT (Ctrl+C) or SIGTERM (sent by docker on shutdown)
     let mut sigint = signal(SignalKind::interrupt())?;
@@ -137,6 +212,9 @@ async fn main() -> anyhow::Result<()> {
     tokio::select! {
         res = http_server => res.map_err(Into::into),
         _ = signing_task => Ok(()),
+        res = zone_transfer_server => res.map_err(Into::into),
+        res = dns_update_server => res.map_err(Into::into),
+        _ = sentinel_watch => Ok(()),
         _ = sigint.recv() => Ok(()),
         _ = sigterm.recv() => Ok(()),
     }
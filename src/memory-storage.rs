@@ -0,0 +1,467 @@
+//! A storage backend that keeps everything in memory, for running pektin-api without a db (see
+//! the `DB_BACKEND` config) and for testing code that accesses the db. The data is lost when the
+//! process exits.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, RrSet};
+use regex::Regex;
+use tokio::sync::broadcast;
+
+use crate::db::{DbWriteBatch, DNSSEC_DB};
+use crate::errors_and_responses::PektinApiResult;
+use crate::journal::{
+    journal_db_key, journal_start_db_key, stream_id_millis, JournalEntry, MAX_JOURNAL_LENGTH,
+};
+use crate::query::glob_to_regex;
+use crate::storage::{JournalRange, Storage};
+use crate::types::RecordIdentifier;
+
+#[derive(Debug, Default)]
+struct MemoryStore {
+    /// The entries of db 0 and the DNSSEC db by key.
+    dbs: [BTreeMap<String, DbEntry>; 2],
+    /// The token and expiry time of each held lock by key.
+    locks: HashMap<String, (String, Instant)>,
+    /// The ids and entries of each journal by key, oldest first.
+    journals: HashMap<String, Vec<(String, JournalEntry)>>,
+    /// The id of the journal entry that was added last.
+    last_journal_id: (u64, u64),
+    values: HashMap<String, String>,
+}
+
+impl MemoryStore {
+    fn db(&self, db: u8) -> &BTreeMap<String, DbEntry> {
+        &self.dbs[usize::from(db == DNSSEC_DB)]
+    }
+
+    fn db_mut(&mut self, db: u8) -> &mut BTreeMap<String, DbEntry> {
+        &mut self.dbs[usize::from(db == DNSSEC_DB)]
+    }
+
+    /// A new journal entry id in the format used by redis streams.
+    fn next_journal_id(&mut self) -> String {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        self.last_journal_id = if millis > self.last_journal_id.0 {
+            (millis, 0)
+        } else {
+            (self.last_journal_id.0, self.last_journal_id.1 + 1)
+        };
+        format!("{}-{}", self.last_journal_id.0, self.last_journal_id.1)
+    }
+}
+
+/// How many published messages are buffered for a subscriber that doesn't keep up.
+const MESSAGE_BUFFER: usize = 1024;
+
+/// The dbs of the in-memory storage backend. Clones share the same data.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    store: Arc<Mutex<MemoryStore>>,
+    /// The channels and messages that are published, see `subscribe()`.
+    messages: broadcast::Sender<(String, String)>,
+    db: u8,
+}
+
+impl MemoryStorage {
+    /// Creates empty dbs and returns a handle to db 0.
+    pub fn new() -> Self {
+        Self {
+            store: Default::default(),
+            messages: broadcast::channel(MESSAGE_BUFFER).0,
+            db: 0,
+        }
+    }
+
+    /// A handle to the given db of the same dbs.
+    pub fn with_db(&self, db: u8) -> Self {
+        Self {
+            store: self.store.clone(),
+            messages: self.messages.clone(),
+            db,
+        }
+    }
+
+    /// Receives the channel and message of everything that is published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, String)> {
+        self.messages.subscribe()
+    }
+
+    pub fn connection(&self) -> MemoryConnection {
+        MemoryConnection {
+            storage: self.clone(),
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A connection to one of the dbs of a `MemoryStorage`.
+#[derive(Debug)]
+pub struct MemoryConnection {
+    storage: MemoryStorage,
+}
+
+impl MemoryConnection {
+    /// The keys in the db that match the glob (if given) and belong to a record in the zone (if
+    /// given), including its child zones.
+    fn keys(&self, zone: Option<&Name>, glob: Option<&Regex>) -> Vec<String> {
+        let store = self.storage.store.lock();
+        store
+            .db(self.storage.db)
+            .keys()
+            .filter(|key| glob.is_none_or(|glob| glob.is_match(key)))
+            .filter(|key| {
+                zone.is_none_or(|zone| {
+                    RecordIdentifier::from_db_key(key).is_ok_and(|ident| zone.zone_of(&ident.name))
+                })
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl Storage for MemoryConnection {
+    async fn get_records(&mut self, keys: &[String]) -> PektinApiResult<Vec<Option<DbEntry>>> {
+        let store = self.storage.store.lock();
+        let db = store.db(self.storage.db);
+        Ok(keys.iter().map(|key| db.get(key).cloned()).collect())
+    }
+
+    async fn count_existing(&mut self, keys: &[String]) -> PektinApiResult<usize> {
+        let store = self.storage.store.lock();
+        let db = store.db(self.storage.db);
+        Ok(keys.iter().filter(|key| db.contains_key(*key)).count())
+    }
+
+    async fn get_records_atomically(
+        &mut self,
+        keys: &[String],
+        dnssec_keys: &[String],
+    ) -> PektinApiResult<Vec<Option<DbEntry>>> {
+        let store = self.storage.store.lock();
+        let entries = keys
+            .iter()
+            .map(|key| store.db(0).get(key).cloned())
+            .chain(
+                dnssec_keys
+                    .iter()
+                    .map(|key| store.db(DNSSEC_DB).get(key).cloned()),
+            )
+            .collect();
+        Ok(entries)
+    }
+
+    async fn write(&mut self, batch: &DbWriteBatch) -> PektinApiResult<()> {
+        let mut store = self.storage.store.lock();
+        for (db, del, set) in [
+            (0, &batch.del, &batch.set),
+            (DNSSEC_DB, &batch.dnssec_del, &batch.dnssec_set),
+        ] {
+            let db = store.db_mut(db);
+            for key in del {
+                db.remove(key);
+            }
+            for entry in set {
+                db.insert(entry.db_key(), entry.clone());
+            }
+        }
+        for (zone, entry) in &batch.journal {
+            let id = store.next_journal_id();
+            let journal = store.journals.entry(journal_db_key(zone)).or_default();
+            journal.push((id, entry.clone()));
+            if journal.len() > MAX_JOURNAL_LENGTH {
+                journal.drain(..journal.len() - MAX_JOURNAL_LENGTH);
+            }
+            store
+                .values
+                .entry(journal_start_db_key(zone))
+                .or_insert_with(|| entry.serial_before().to_string());
+        }
+        Ok(())
+    }
+
+    async fn get_zones(&mut self) -> PektinApiResult<Vec<String>> {
+        let store = self.storage.store.lock();
+        Ok(store
+            .db(0)
+            .values()
+            .filter(|entry| entry.rr_type() == RecordType::SOA)
+            .map(|entry| entry.name.to_lowercase().to_string())
+            .collect())
+    }
+
+    async fn get_zone_index(&mut self, zone: &Name) -> PektinApiResult<Vec<String>> {
+        Ok(self.keys(Some(zone), None))
+    }
+
+    /// Returns all matching keys in a single step.
+    async fn scan_step(
+        &mut self,
+        zone: Option<&Name>,
+        _cursor: u64,
+        glob: &str,
+    ) -> PektinApiResult<(u64, Vec<String>)> {
+        let glob = Regex::new(&glob_to_regex(glob)).expect("glob converted to an invalid regex");
+        Ok((0, self.keys(zone, Some(&glob))))
+    }
+
+    /// The zones and expiring RRSIGs are found by looking at all entries, so there are no indexes.
+    async fn build_zone_index(&mut self) -> PektinApiResult<()> {
+        Ok(())
+    }
+
+    async fn build_rrsig_expiry_index(&mut self) -> PektinApiResult<()> {
+        Ok(())
+    }
+
+    async fn get_expiring_rrsigs(&mut self, before: i64) -> PektinApiResult<Vec<DbEntry>> {
+        let store = self.storage.store.lock();
+        Ok(store
+            .db(self.storage.db)
+            .values()
+            .filter(|entry| match &entry.rr_set {
+                RrSet::RRSIG { rr_set } => rr_set
+                    .iter()
+                    .any(|record| (record.signature_expiration as i64) < before),
+                _ => false,
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn try_lock(
+        &mut self,
+        key: &str,
+        token: &str,
+        expiry_millis: u64,
+    ) -> PektinApiResult<bool> {
+        let mut store = self.storage.store.lock();
+        let now = Instant::now();
+        if matches!(store.locks.get(key), Some((_, expiry)) if *expiry > now) {
+            return Ok(false);
+        }
+        let expiry = now + Duration::from_millis(expiry_millis);
+        store.locks.insert(key.into(), (token.into(), expiry));
+        Ok(true)
+    }
+
+    async fn unlock(&mut self, key: &str, token: &str) -> PektinApiResult<()> {
+        let mut store = self.storage.store.lock();
+        if matches!(store.locks.get(key), Some((held, _)) if held == token) {
+            store.locks.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn read_journal(
+        &mut self,
+        zone: &Name,
+        range: JournalRange,
+    ) -> PektinApiResult<Vec<(String, JournalEntry)>> {
+        let store = self.storage.store.lock();
+        let in_range = |id: &str| {
+            let millis = stream_id_millis(id);
+            range.since.is_none_or(|since| millis >= since)
+                && range.until.is_none_or(|until| millis <= until)
+        };
+        let mut journal: Vec<_> = store
+            .journals
+            .get(&journal_db_key(zone))
+            .into_iter()
+            .flatten()
+            .filter(|(id, _)| in_range(id))
+            .cloned()
+            .collect();
+        if range.newest_first {
+            journal.reverse();
+        }
+        Ok(journal)
+    }
+
+    async fn delete_journals(&mut self, zones: &[Name]) -> PektinApiResult<()> {
+        let mut store = self.storage.store.lock();
+        for zone in zones {
+            store.journals.remove(&journal_db_key(zone));
+            store.values.remove(&journal_start_db_key(zone));
+        }
+        Ok(())
+    }
+
+    async fn get_value(&mut self, key: &str) -> PektinApiResult<Option<String>> {
+        Ok(self.storage.store.lock().values.get(key).cloned())
+    }
+
+    async fn set_value(&mut self, key: &str, value: &str) -> PektinApiResult<()> {
+        self.storage
+            .store
+            .lock()
+            .values
+            .insert(key.into(), value.into());
+        Ok(())
+    }
+
+    async fn delete_value(&mut self, key: &str) -> PektinApiResult<()> {
+        self.storage.store.lock().values.remove(key);
+        Ok(())
+    }
+
+    async fn publish(&mut self, channel: &str, message: &str) -> PektinApiResult<()> {
+        // like with redis, a message without subscribers is dropped
+        let _ = self.storage.messages.send((channel.into(), message.into()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pektin_common::proto::rr::RecordType;
+
+    use super::*;
+    use crate::delete::delete_rr_sets;
+    use crate::journal::get_journal;
+    use crate::set::sign_and_write_changes;
+    use crate::test_utils::{
+        a_entry, author, get_with_rrsig, name, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    #[tokio::test]
+    async fn write_applies_deletions_before_sets() {
+        let storage = MemoryStorage::new();
+        let mut con = storage.connection();
+        let mut dnssec_con = storage.with_db(DNSSEC_DB).connection();
+        let entry = a_entry("www.example.com.", [192, 0, 2, 1]);
+        let batch = DbWriteBatch {
+            set: vec![entry.clone()],
+            del: vec![entry.db_key()],
+            ..Default::default()
+        };
+        con.write(&batch).await.unwrap();
+
+        let keys = [entry.db_key()];
+        assert_eq!(con.get_records(&keys).await.unwrap(), vec![Some(entry)]);
+        assert_eq!(dnssec_con.count_existing(&keys).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn locks_are_exclusive_until_released_or_expired() {
+        let mut con = MemoryStorage::new().connection();
+        assert!(con.try_lock("lock/a", "t1", 60_000).await.unwrap());
+        assert!(!con.try_lock("lock/a", "t2", 60_000).await.unwrap());
+        // only the holder can release the lock
+        con.unlock("lock/a", "t2").await.unwrap();
+        assert!(!con.try_lock("lock/a", "t2", 60_000).await.unwrap());
+        con.unlock("lock/a", "t1").await.unwrap();
+        assert!(con.try_lock("lock/a", "t2", 0).await.unwrap());
+        // the lock expired immediately
+        assert!(con.try_lock("lock/a", "t3", 60_000).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn set_signs_records_and_increments_the_serial() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let (dnskey, dnskey_rrsig) =
+            get_with_rrsig("example.com.", RecordType::DNSKEY, &state).await;
+        assert!(dnskey.is_some() && dnskey_rrsig.is_some());
+
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&www), &state).await;
+        let (entry, rrsig) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(www.clone()));
+        assert!(rrsig.is_some());
+        assert_eq!(zone_serial("example.com.", &state).await, Some(2));
+        let (_, soa_rrsig) = get_with_rrsig("example.com.", RecordType::SOA, &state).await;
+        assert!(soa_rrsig.is_some());
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let journal = get_journal(&name("example.com."), &mut con).await.unwrap();
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal[0].serial_before(), 1);
+        assert_eq!(journal[0].serial_after(), 2);
+        assert!(journal[0].added.contains(&www));
+    }
+
+    #[actix_web::test]
+    async fn delete_removes_rr_sets_with_their_rrsigs() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&www), &state).await;
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        let ident = RecordIdentifier {
+            name: www.name.clone(),
+            rr_type: RecordType::A,
+        };
+        let deleted = delete_rr_sets(&[ident], &[], &author(), &state, &mut con, &mut dnssec_con)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+
+        assert_eq!(
+            get_with_rrsig("www.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+        let journal = get_journal(&name("example.com."), &mut con).await.unwrap();
+        assert_eq!(journal.len(), 2);
+        assert!(journal[1].removed.contains(&www));
+    }
+
+    #[actix_web::test]
+    async fn changes_are_written_as_a_single_transaction() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+        let www = RecordIdentifier {
+            name: name("www.example.com."),
+            rr_type: RecordType::A,
+        };
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        sign_and_write_changes(
+            std::slice::from_ref(&mail),
+            &[www],
+            &[name("example.com.")],
+            &[],
+            &author(),
+            &state,
+            &mut con,
+            &mut dnssec_con,
+        )
+        .await
+        .unwrap();
+
+        let (entry, rrsig) = get_with_rrsig("mail.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(mail.clone()));
+        assert!(rrsig.is_some());
+        assert_eq!(
+            get_with_rrsig("www.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+        // both changes got the same serial and journal entry
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+        let journal = get_journal(&name("example.com."), &mut con).await.unwrap();
+        assert_eq!(journal.len(), 2);
+        assert!(journal[1].added.contains(&mail));
+        assert!(journal[1]
+            .removed
+            .iter()
+            .any(|e| e.name == name("www.example.com.")));
+    }
+}
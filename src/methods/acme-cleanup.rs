@@ -0,0 +1,32 @@
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    acme::{handle_challenge_request, ChallengeRequest, ChallengeUpdate},
+    types::{AcmeCleanupRequestBody, AppState},
+};
+
+#[post("/acme-cleanup")]
+pub async fn acme_cleanup(
+    req: HttpRequest,
+    req_body: web::Json<AcmeCleanupRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "acme-cleanup",
+        client_username = %req_body.client_username,
+        domain = %req_body.domain
+    );
+    let challenge = ChallengeRequest {
+        client_username: &req_body.client_username,
+        confidant_password: &req_body.confidant_password,
+        domain: &req_body.domain,
+        value: &req_body.value,
+        ttl: None,
+        update: ChallengeUpdate::Cleanup,
+        wait: req_body.wait,
+    };
+    handle_challenge_request(&req, challenge, &state)
+        .instrument(span)
+        .await
+}
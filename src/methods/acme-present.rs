@@ -0,0 +1,32 @@
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    acme::{handle_challenge_request, ChallengeRequest, ChallengeUpdate},
+    types::{AcmePresentRequestBody, AppState},
+};
+
+#[post("/acme-present")]
+pub async fn acme_present(
+    req: HttpRequest,
+    req_body: web::Json<AcmePresentRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "acme-present",
+        client_username = %req_body.client_username,
+        domain = %req_body.domain
+    );
+    let challenge = ChallengeRequest {
+        client_username: &req_body.client_username,
+        confidant_password: &req_body.confidant_password,
+        domain: &req_body.domain,
+        value: &req_body.value,
+        ttl: Some(req_body.ttl),
+        update: ChallengeUpdate::Present,
+        wait: req_body.wait,
+    };
+    handle_challenge_request(&req, challenge, &state)
+        .instrument(span)
+        .await
+}
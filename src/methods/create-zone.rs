@@ -0,0 +1,309 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use pektin_common::proto::error::ProtoResult;
+use pektin_common::proto::rr::rdata::SOA;
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, NsRecord, RrSet, SoaRecord};
+use tracing::{info_span, instrument, warn, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::{acquire_locks, release_locks},
+    errors_and_responses::{auth_err, err, internal_err, success_with_toplevel_data},
+    journal::ChangeAuthor,
+    macros::unwrap_or_return_if_err,
+    set::sign_and_set_records,
+    storage::{Connection, Storage},
+    types::{AppState, CreateZoneRequestBody, RecordIdentifier},
+    validation::{check_soa, validate_records},
+    vault,
+};
+
+#[post("/create-zone")]
+pub async fn create_zone(
+    req: HttpRequest,
+    req_body: web::Json<CreateZoneRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "create-zone",
+        client_username = %req_body.client_username,
+        zone = %req_body.zone,
+        nameservers = ?req_body.nameservers
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            let zone = &req_body.zone;
+            if !zone.is_fqdn() {
+                return err(
+                    "The zone name is not absolute (i.e. the root label at the end is missing).",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+            if req_body.nameservers.is_empty() {
+                return err(
+                    "A zone needs at least one name server.",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+
+            let nameservers: Result<Vec<_>, _> = req_body
+                .nameservers
+                .iter()
+                .map(|ns| relative_to_zone(ns, zone))
+                .collect();
+            let rname = req_body
+                .soa
+                .rname
+                .clone()
+                .map_or_else(|| Name::from_ascii("hostmaster"), Ok)
+                .and_then(|rname| relative_to_zone(&rname, zone));
+            let (nameservers, rname) = match (nameservers, rname) {
+                (Ok(ns), Ok(r)) => (ns, r),
+                _ => {
+                    return err(
+                        "One or more names are too long when made relative to the zone.",
+                        Vec::<Option<String>>::new(),
+                    )
+                }
+            };
+            let mname = match &req_body.soa.mname {
+                Some(mname) => match relative_to_zone(mname, zone) {
+                    Ok(m) => m,
+                    Err(_) => {
+                        return err(
+                            "The primary name server's name is too long when made relative to the zone.",
+                            Vec::<Option<String>>::new(),
+                        )
+                    }
+                },
+                None => nameservers[0].clone(),
+            };
+            let serial = req_body.soa.serial.unwrap_or_else(|| {
+                chrono::Utc::now()
+                    .format("%Y%m%d01")
+                    .to_string()
+                    .parse()
+                    .expect("Date serial does not fit into u32")
+            });
+
+            let records = vec![
+                DbEntry {
+                    name: zone.clone(),
+                    ttl: req_body.soa.ttl,
+                    meta: "".to_string(),
+                    rr_set: RrSet::SOA {
+                        rr_set: vec![SoaRecord {
+                            value: SOA::new(
+                                mname,
+                                rname,
+                                serial,
+                                req_body.soa.refresh,
+                                req_body.soa.retry,
+                                req_body.soa.expire,
+                                req_body.soa.minimum,
+                            ),
+                        }],
+                    },
+                },
+                DbEntry {
+                    name: zone.clone(),
+                    ttl: req_body.ns_ttl,
+                    meta: "".to_string(),
+                    rr_set: RrSet::NS {
+                        rr_set: nameservers
+                            .into_iter()
+                            .map(|value| NsRecord { value })
+                            .collect(),
+                    },
+                },
+            ];
+
+            let _valid = validate_records(&records);
+            unwrap_or_return_if_err!(_valid, "The zone's SOA or NS record is invalid.");
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            // the zone lock is taken when the records are written, so the lock of the zone's SOA
+            // RR set makes sure that no other request creates the zone after we checked that it
+            // doesn't exist yet
+            let soa_ident = RecordIdentifier {
+                name: zone.clone(),
+                rr_type: RecordType::SOA,
+            };
+            let locks = match acquire_locks(&[soa_ident], &mut con).await {
+                Ok(l) => l,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            let res = create_locked_zone(
+                zone,
+                &records,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await;
+            if let Err(e) = release_locks(locks, &mut con).await {
+                return internal_err(e.to_string());
+            }
+            if let Err(res) = res {
+                return res;
+            }
+
+            success_with_toplevel_data("created zone", records)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Creates the zone's DNSSEC key and writes its records, unless the zone already exists. The lock
+/// of the zone's SOA RR set must be held.
+///
+/// If the records can't be written, the DNSSEC key is deleted again.
+#[instrument(skip(records, state, con, dnssec_con))]
+async fn create_locked_zone(
+    zone: &Name,
+    records: &[DbEntry],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    match con.get_zones().await {
+        Ok(zones) if zones.contains(&zone.to_lowercase().to_string()) => {
+            return Err(err(
+                "The zone already exists.",
+                Vec::<Option<String>>::new(),
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => return Err(internal_err(e.to_string())),
+    }
+    let (_soa_check, used_zones, new_authoritative_zones) = match check_soa(records, con).await {
+        Ok(s) => s,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+
+    // the key has to exist before the zone's DNSKEY record can be created
+    let vault_api_token = match vault::ApiTokenCache::get(
+        &state.vault_uri,
+        &state.vault_user_name,
+        &state.vault_password,
+    )
+    .await
+    {
+        Ok(t) => t,
+        Err(_) => return Err(internal_err("Couldnt get vault api token")),
+    };
+    if let Err(e) = vault::create_zone_dnssec_key(zone, &state.vault_uri, &vault_api_token).await {
+        return Err(internal_err(format!(
+            "Could not create the zone's DNSSEC key: {e}"
+        )));
+    }
+
+    if let Err(res) = sign_and_set_records(
+        records,
+        &used_zones,
+        &new_authoritative_zones,
+        author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await
+    {
+        if let Err(e) =
+            vault::delete_zone_dnssec_key(zone, &state.vault_uri, &vault_api_token).await
+        {
+            warn!(
+                "Could not delete the DNSSEC key of {} after creating the zone failed: {}",
+                zone, e
+            );
+        }
+        return Err(res);
+    }
+    Ok(())
+}
+
+/// Appends the zone to relative names; absolute names are returned unchanged.
+fn relative_to_zone(name: &Name, zone: &Name) -> ProtoResult<Name> {
+    if name.is_fqdn() {
+        Ok(name.clone())
+    } else {
+        name.clone().append_domain(zone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::{call_endpoint, test_state_with_vault, zone_serial};
+
+    fn request(zone: &str) -> serde_json::Value {
+        json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "zone": zone,
+            "nameservers": ["ns1"],
+            "soa": { "serial": 1 },
+        })
+    }
+
+    #[actix_web::test]
+    async fn zones_are_created_once() {
+        let (state, vault) = test_state_with_vault();
+        let (status, body) =
+            call_endpoint(create_zone, &state, "/create-zone", request("example.com.")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(zone_serial("example.com.", &state).await, Some(1));
+        assert!(vault.lock().keys.contains("example.com-zsk"));
+
+        let (status, body) =
+            call_endpoint(create_zone, &state, "/create-zone", request("Example.com.")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "The zone already exists.");
+        assert_eq!(zone_serial("example.com.", &state).await, Some(1));
+    }
+
+    #[actix_web::test]
+    async fn key_is_deleted_if_the_zone_is_not_created() {
+        let (state, vault) = test_state_with_vault();
+        vault.lock().fail_signing = true;
+        let (status, body) =
+            call_endpoint(create_zone, &state, "/create-zone", request("example.com.")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "Could not sign one or more records.");
+        assert_eq!(zone_serial("example.com.", &state).await, None);
+        assert!(vault.lock().keys.is_empty());
+
+        // the lock of the zone was released
+        vault.lock().fail_signing = false;
+        let (status, body) =
+            call_endpoint(create_zone, &state, "/create-zone", request("example.com.")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+}
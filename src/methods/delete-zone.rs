@@ -0,0 +1,233 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::get_zone_keys,
+    delete::delete_rr_sets,
+    errors_and_responses::{auth_err, err, internal_err, partial_success_with_data, success},
+    journal::ChangeAuthor,
+    types::{AppState, DeleteZoneRequestBody, RecordIdentifier, ResponseType},
+    vault,
+};
+
+#[post("/delete-zone")]
+pub async fn delete_zone(
+    req: HttpRequest,
+    req_body: web::Json<DeleteZoneRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "delete-zone",
+        client_username = %req_body.client_username,
+        zones = ?req_body.zones
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if req_body.zones.is_empty() {
+                return success("deleted 0 zones", Vec::<String>::new());
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            // this doesn't include the records of child zones, they are kept
+            let zones: Vec<_> = req_body.zones.iter().collect();
+            let zones_keys = match get_zone_keys(&zones, &mut con).await {
+                Ok(z) => z,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            if zones_keys.iter().any(Option::is_none) {
+                let messages = zones_keys
+                    .iter()
+                    .map(|keys| keys.is_none().then_some("The zone does not exist."))
+                    .collect();
+                return err("One or more zones do not exist.", messages);
+            }
+            let zones_keys: Vec<_> = zones_keys.into_iter().flatten().collect();
+
+            // this includes the zones' DNSKEY, NSEC3, and NSEC3PARAM records; their RRSIGs are
+            // deleted together with the records they cover
+            let idents: Result<Vec<_>, _> = zones_keys
+                .iter()
+                .flatten()
+                .map(RecordIdentifier::from_db_key)
+                .collect();
+            let idents = match idents {
+                Ok(i) => i,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            if let Err(res) = delete_rr_sets(
+                &idents,
+                &req_body.zones,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await
+            {
+                return res;
+            }
+
+            // the records are already deleted, so failures to delete the keys are reported per zone
+            let vault_api_token = vault::ApiTokenCache::get(
+                &state.vault_uri,
+                &state.vault_user_name,
+                &state.vault_password,
+            )
+            .await;
+            let mut key_results = Vec::with_capacity(req_body.zones.len());
+            for zone in &req_body.zones {
+                key_results.push(match &vault_api_token {
+                    Ok(token) => vault::delete_zone_dnssec_key(zone, &state.vault_uri, token)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(_) => Err("Couldnt get vault api token".into()),
+                });
+            }
+
+            let toplevel_message = format!("deleted {} zones", req_body.zones.len());
+            if key_results.iter().all(Result::is_ok) {
+                let messages = zones_keys
+                    .iter()
+                    .map(|keys| format!("removed {} RR sets", keys.len()))
+                    .collect();
+                return success(toplevel_message, messages);
+            }
+            let messages = zones_keys
+                .iter()
+                .zip(key_results)
+                .map(|(keys, key_result)| match key_result {
+                    Ok(()) => (
+                        ResponseType::Success,
+                        format!("removed {} RR sets", keys.len()),
+                        (),
+                    ),
+                    Err(e) => (
+                        ResponseType::Error,
+                        format!(
+                            "removed {} RR sets, but could not delete the zone's DNSSEC key: {e}",
+                            keys.len()
+                        ),
+                        (),
+                    ),
+                })
+                .collect();
+            partial_success_with_data(ResponseType::PartialSuccess, toplevel_message, messages)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use serde_json::json;
+
+    use super::*;
+    use crate::create_zone::create_zone;
+    use crate::test_utils::{call_endpoint, test_state_with_vault, zone_serial};
+
+    async fn create(zone: &str, state: &AppState) {
+        let body = json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "zone": zone,
+            "nameservers": ["ns1"],
+            "soa": { "serial": 1 },
+        });
+        let (status, body) = call_endpoint(create_zone, state, "/create-zone", body).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    fn request(zones: &[&str]) -> serde_json::Value {
+        json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "zones": zones,
+        })
+    }
+
+    #[actix_web::test]
+    async fn zones_are_deleted_with_their_keys() {
+        let (state, vault) = test_state_with_vault();
+        create("example.com.", &state).await;
+        create("example.org.", &state).await;
+
+        let (status, body) = call_endpoint(
+            delete_zone,
+            &state,
+            "/delete-zone",
+            request(&["example.com.", "example.org."]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["type"], "success");
+        assert_eq!(body["data"].as_array().unwrap().len(), 2);
+        assert_eq!(zone_serial("example.com.", &state).await, None);
+        assert_eq!(zone_serial("example.org.", &state).await, None);
+        assert!(vault.lock().keys.is_empty());
+
+        let (status, _) = call_endpoint(
+            delete_zone,
+            &state,
+            "/delete-zone",
+            request(&["example.com."]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn key_deletion_failures_are_reported_per_zone() {
+        let (state, vault) = test_state_with_vault();
+        create("example.com.", &state).await;
+        create("example.org.", &state).await;
+        vault.lock().fail_deleting.insert("example.org-zsk".into());
+
+        let (status, body) = call_endpoint(
+            delete_zone,
+            &state,
+            "/delete-zone",
+            request(&["example.com.", "example.org."]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["type"], "partial-success");
+        assert_eq!(body["data"][0]["type"], "success");
+        assert_eq!(body["data"][1]["type"], "error");
+        assert!(body["data"][1]["message"]
+            .as_str()
+            .unwrap()
+            .contains("could not delete the zone's DNSSEC key"));
+        // the records of both zones are deleted nevertheless
+        assert_eq!(zone_serial("example.com.", &state).await, None);
+        assert_eq!(zone_serial("example.org.", &state).await, None);
+        let keys = vault.lock().keys.clone();
+        assert_eq!(
+            keys.into_iter().collect::<Vec<_>>(),
+            vec!["example.org-zsk"]
+        );
+    }
+}
@@ -1,15 +1,23 @@
 use std::ops::Deref;
 
-use actix_web::{post, web, HttpRequest, Responder};
-use pektin_common::{deadpool_redis::redis::AsyncCommands, proto::rr::RecordType};
-use tracing::{info_span, Instrument};
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use pektin_common::proto::rr::{Name, RecordType};
+use tracing::{info_span, instrument, Instrument};
 
 use crate::{
     auth::auth_ok,
-    db::get_zone_keys,
-    errors_and_responses::{auth_err, err, internal_err, success_with_toplevel_data},
-    types::{AppState, DeleteRequestBody},
+    db::{acquire_locks, check_versions, get_zone_keys, release_locks, DbWriteBatch},
+    errors_and_responses::{auth_err, conflict_err, err, internal_err, success_with_toplevel_data},
+    events::{publish_change_events, ChangeEvent},
+    journal::{
+        commit_zone_changes, delete_journals, prepare_zone_changes, ChangeAuthor, ZoneChanges,
+    },
+    notify::delete_notify_targets,
+    set::{add_nsec3_changes, plan_changes, sign_changes},
+    storage::{Connection, Storage},
+    types::{AppState, DeleteRequestBody, RecordIdentifier},
     validation::RecordValidationError,
+    vault,
 };
 
 #[post("/delete")]
@@ -32,18 +40,18 @@ pub async fn delete(
             if req_body.records.is_empty() {
                 return success_with_toplevel_data("removed 0 records", 0);
             }
+            let records: Vec<_> = req_body.records.iter().map(|r| r.ident.clone()).collect();
 
-            // TODO:
-            // - also delete RRSIG entries
-            // - update NSEC chain
-            // - if soa is deleted also delete dnskey
             let mut con = match state.db_pool.get().await {
                 Ok(c) => c,
                 Err(_) => return internal_err("No db connection."),
             };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
 
-            let valid: Vec<_> = req_body
-                .records
+            let valid: Vec<_> = records
                 .iter()
                 .map(|record| {
                     if record.name.is_fqdn() {
@@ -61,46 +69,57 @@ pub async fn delete(
                 return err("One or more records were invalid.", messages);
             }
 
-            let keys_to_delete: Vec<_> = req_body
-                .records
+            let mut idents_to_delete: Vec<_> = records
                 .iter()
                 .flat_map(|record| {
-                    let mut keys = vec![];
-                    keys.push(format!("{}:{:?}", record.name, record.rr_type));
+                    let mut idents = vec![record.clone()];
                     if record.rr_type == RecordType::SOA {
-                        keys.push(format!("{}:{:?}", record.name, RecordType::DNSKEY));
+                        idents.push(RecordIdentifier {
+                            name: record.name.clone(),
+                            rr_type: RecordType::DNSKEY,
+                        });
                     }
-                    keys
+                    idents
                 })
                 .collect();
+            let keys_to_delete: Vec<_> = idents_to_delete
+                .iter()
+                .map(|ident| format!("{}:{:?}", ident.name, ident.rr_type))
+                .collect();
 
             // we only check conditions that require communication with db if all records are valid,
             // i.e. we skip these checks if we reject the request anyways
 
             // check that if we delete a SOA record we also delete all other records in that zone.
             // this stores the names of SOA records that should be deleted
-            let zones_to_delete: Vec<_> = req_body
-                .records
+            let deleted_zones: Vec<_> = records
                 .iter()
                 .filter(|r| r.rr_type == RecordType::SOA)
                 .map(|r| &r.name)
                 .collect();
             // now this stores all keys of the zones that should be deleted
-            let zones_to_delete = match get_zone_keys(&zones_to_delete, &mut con).await {
+            let zones_to_delete = match get_zone_keys(&deleted_zones, &mut con).await {
                 Ok(z) => z,
                 Err(e) => return internal_err(e.to_string()),
             };
+            // the zone's NSEC3(PARAM) records are managed by pektin and deleted together with the
+            // zone
+            let is_nsec3 = |key: &&String| key.ends_with(":NSEC3") || key.ends_with(":NSEC3PARAM");
+            let zones_to_delete: Vec<_> = zones_to_delete.into_iter().flatten().collect();
             // true if all of the zone's records are also deleted
             let complete_zone_deleted: Vec<_> = zones_to_delete
-                .into_iter()
-                .flatten()
-                .map(|zone_keys| zone_keys.iter().all(|key| keys_to_delete.contains(key)))
+                .iter()
+                .map(|zone_keys| {
+                    zone_keys
+                        .iter()
+                        .filter(|key| !is_nsec3(key))
+                        .all(|key| keys_to_delete.contains(key))
+                })
                 .collect();
             // soa_idx counts the index into complete_zone_deleted for the following iter()
             let mut soa_idx = 0;
             if complete_zone_deleted.iter().any(|b| !b) {
-                let messages = req_body
-                    .records
+                let messages = records
                     .iter()
                     .map(|r| {
                         if r.rr_type != RecordType::SOA {
@@ -118,10 +137,74 @@ pub async fn delete(
                 return err("One or more records were invalid.", messages);
             }
 
-            match con.del::<_, u32>(&keys_to_delete).await {
-                Ok(n) => success_with_toplevel_data(format!("removed {n} records"), n),
-                Err(_) => internal_err("Could not delete records from database."),
+            let deleted_zones: Vec<_> = deleted_zones.into_iter().cloned().collect();
+            for key in zones_to_delete.iter().flatten().filter(is_nsec3) {
+                match RecordIdentifier::from_db_key(key) {
+                    Ok(ident) => idents_to_delete.push(ident),
+                    Err(e) => return internal_err(e.to_string()),
+                }
+            }
+
+            let expected_versions: Vec<_> =
+                req_body.records.iter().map(|r| r.version.clone()).collect();
+
+            if req_body.dry_run {
+                match check_versions(&records, &expected_versions, &mut con).await {
+                    Ok(c) if c.iter().any(Option::is_some) => {
+                        return conflict_err("One or more RR sets were changed in the meantime.", c)
+                    }
+                    Ok(_) => {}
+                    Err(e) => return internal_err(e.to_string()),
+                }
+                return match plan_changes(&[], &idents_to_delete, &[], &[], &mut con).await {
+                    Ok(plan) => success_with_toplevel_data("planned changes", plan),
+                    Err(e) => internal_err(e.to_string()),
+                };
+            }
+
+            // if the client expects versions, the RR sets must not change between checking the
+            // versions and deleting them
+            let locks = if expected_versions.iter().any(Option::is_some) {
+                let locks = match acquire_locks(&records, &mut con).await {
+                    Ok(l) => l,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+                let conflicts = check_versions(&records, &expected_versions, &mut con).await;
+                let conflicted = !matches!(&conflicts, Ok(c) if c.iter().all(Option::is_none));
+                if conflicted {
+                    if let Err(e) = release_locks(locks, &mut con).await {
+                        return internal_err(e.to_string());
+                    }
+                    return match conflicts {
+                        Ok(c) => {
+                            conflict_err("One or more RR sets were changed in the meantime.", c)
+                        }
+                        Err(e) => internal_err(e.to_string()),
+                    };
+                }
+                locks
+            } else {
+                vec![]
+            };
+
+            let res = delete_rr_sets(
+                &idents_to_delete,
+                &deleted_zones,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await;
+            if let Err(e) = release_locks(locks, &mut con).await {
+                return internal_err(e.to_string());
             }
+            let n = match res {
+                Ok(n) => n,
+                Err(res) => return res,
+            };
+
+            success_with_toplevel_data(format!("removed {n} records"), n)
         } else {
             auth.message.push('\n');
             auth_err(auth.message)
@@ -130,3 +213,190 @@ pub async fn delete(
     .instrument(span)
     .await
 }
+
+/// Deletes the given RR sets and their RRSIGs from the db and returns the number of deleted RR
+/// sets. `deleted_zones` are the zones whose SOA record is deleted, i.e. that are deleted
+/// completely; their journals and notify targets are deleted as well.
+///
+/// The SOA serials of all other changed zones are incremented and the changes are recorded in
+/// the zone journals as made by `author`. A change event is published for every changed and
+/// deleted zone.
+///
+/// If something goes wrong, the response that should be returned to the client is returned as
+/// the error.
+#[instrument(skip(state, con, dnssec_con))]
+pub async fn delete_rr_sets(
+    idents: &[RecordIdentifier],
+    deleted_zones: &[Name],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<usize, HttpResponse> {
+    let mut zone_changes = match prepare_zone_changes(idents, con, dnssec_con).await {
+        Ok(c) => c,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    // the serials of zones that are deleted completely don't need to be incremented
+    zone_changes.skip_zones(deleted_zones);
+
+    let (n, batch, vault_api_token) = match delete_batch(idents, &zone_changes, state, con).await {
+        Ok(b) => b,
+        Err(res) => {
+            zone_changes.abort(&state.db_pool);
+            return Err(res);
+        }
+    };
+    if let Err(e) = add_nsec3_changes(&mut zone_changes, &batch, con, dnssec_con).await {
+        zone_changes.abort(&state.db_pool);
+        return Err(internal_err(e.to_string()));
+    }
+    // without changed zones, no vault api token is needed
+    if let Err(e) = commit_zone_changes(
+        zone_changes,
+        batch,
+        state,
+        vault_api_token.as_deref().unwrap_or_default(),
+        Some(author),
+        con,
+    )
+    .await
+    {
+        return Err(internal_err(e.to_string()));
+    }
+
+    if let Err(e) = delete_journals(deleted_zones, con).await {
+        return Err(internal_err(e.to_string()));
+    }
+    if let Err(e) = delete_notify_targets(deleted_zones, con).await {
+        return Err(internal_err(e.to_string()));
+    }
+    let deleted_zone_events: Vec<_> = deleted_zones
+        .iter()
+        .map(ChangeEvent::zone_deleted)
+        .collect();
+    publish_change_events(&deleted_zone_events, con).await;
+
+    Ok(n)
+}
+
+/// Returns the number of existing RR sets to delete and the batch that deletes them, together
+/// with the NSEC3 changes and the Vault API token they were signed with if any zones are changed.
+async fn delete_batch(
+    idents: &[RecordIdentifier],
+    zone_changes: &ZoneChanges,
+    state: &AppState,
+    con: &mut Connection,
+) -> Result<(usize, DbWriteBatch, Option<String>), HttpResponse> {
+    let vault_api_token = if zone_changes.is_empty() {
+        None
+    } else {
+        match vault::ApiTokenCache::get(
+            &state.vault_uri,
+            &state.vault_user_name,
+            &state.vault_password,
+        )
+        .await
+        {
+            Ok(t) => Some(t),
+            Err(_) => return Err(internal_err("Couldnt get vault api token")),
+        }
+    };
+
+    let keys_to_delete: Vec<_> = idents.iter().map(RecordIdentifier::db_key).collect();
+    let n = match con.count_existing(&keys_to_delete).await {
+        Ok(n) => n,
+        Err(_) => return Err(internal_err("Could not delete records from database.")),
+    };
+    // the NSEC3 chains only need to be updated (and signed) in zones that still exist afterwards
+    let batch = match &vault_api_token {
+        Some(vault_api_token) => {
+            sign_changes(&[], idents, &[], &[], state, vault_api_token, con).await?
+        }
+        None => DbWriteBatch {
+            del: keys_to_delete,
+            dnssec_del: idents.iter().map(RecordIdentifier::rrsig_db_key).collect(),
+            ..Default::default()
+        },
+    };
+    Ok((n, batch, vault_api_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::db::rr_set_version;
+    use crate::test_utils::{
+        a_entry, call_endpoint, get_with_rrsig, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    fn request(records: &[(&str, Option<String>)]) -> Value {
+        let records: Vec<_> = records
+            .iter()
+            .map(|(record_name, version)| {
+                json!({
+                    "name": record_name,
+                    "rr_type": "A",
+                    "version": version,
+                })
+            })
+            .collect();
+        json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "records": records,
+        })
+    }
+
+    #[actix_web::test]
+    async fn rr_sets_are_only_deleted_if_the_versions_match() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+        set_records(&[www.clone(), mail.clone()], &state).await;
+
+        let (status, body) = call_endpoint(
+            delete,
+            &state,
+            "/delete",
+            request(&[
+                ("www.example.com.", None),
+                ("mail.example.com.", Some("outdated".into())),
+            ]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        assert_eq!(body["data"][0]["type"], "ignored");
+        assert_eq!(body["data"][1]["type"], "error");
+        for record in [&www, &mail] {
+            let (entry, rrsig) =
+                get_with_rrsig(&record.name.to_string(), RecordType::A, &state).await;
+            assert_eq!(entry.as_ref(), Some(record));
+            assert!(rrsig.is_some());
+        }
+        assert_eq!(zone_serial("example.com.", &state).await, Some(2));
+
+        let (status, body) = call_endpoint(
+            delete,
+            &state,
+            "/delete",
+            request(&[
+                ("www.example.com.", None),
+                ("mail.example.com.", Some(rr_set_version(&mail).unwrap())),
+            ]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["data"], 2);
+        for record_name in ["www.example.com.", "mail.example.com."] {
+            assert_eq!(
+                get_with_rrsig(record_name, RecordType::A, &state).await,
+                (None, None)
+            );
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, RrSet, SoaRecord};
+use tracing::{info_span, instrument, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::get_zone_rr_sets,
+    errors_and_responses::{
+        auth_err, err, internal_err, success_with_toplevel_data, PektinApiResult,
+    },
+    journal::{soa_serial, with_serial},
+    storage::{Connection, Storage},
+    types::{AppState, DiffRequestBody, RrSetChange, ZoneDiff},
+    utils::find_authoritative_zone,
+    validation::validate_records,
+};
+
+#[post("/diff")]
+pub async fn diff(
+    req: HttpRequest,
+    req_body: web::Json<DiffRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "diff",
+        client_username = %req_body.client_username,
+        zone = %req_body.zone
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let valid =
+                match check_desired_records(&req_body.zone, &req_body.records, &mut con).await {
+                    Ok(v) => v,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+            if valid.iter().any(|r| r.is_err()) {
+                let messages = valid.into_iter().map(Result::err).collect();
+                return err("One or more records were invalid.", messages);
+            }
+
+            match diff_zone(&req_body.zone, &req_body.records, &mut con, &mut dnssec_con).await {
+                Ok(Some(zone_diff)) => success_with_toplevel_data("diffed zone", zone_diff),
+                Ok(None) => err(
+                    "The zone does not exist; create it with create-zone first.",
+                    Vec::<Option<String>>::new(),
+                ),
+                Err(e) => internal_err(e.to_string()),
+            }
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Checks that the desired RR sets are valid, that each of them belongs to the zone (and not to
+/// one of its child zones), that no RR set is given twice, and that the zone's SOA record is
+/// among them. Returns a result for each RR set.
+#[instrument(skip(records, con))]
+pub async fn check_desired_records(
+    zone: &Name,
+    records: &[DbEntry],
+    con: &mut Connection,
+) -> PektinApiResult<Vec<Result<(), String>>> {
+    if !zone.is_fqdn() {
+        return Ok(vec![Err(
+            "The zone name is not absolute (i.e. the root label at the end is missing).".into(),
+        )]);
+    }
+    if !records
+        .iter()
+        .any(|r| &r.name == zone && r.rr_type() == RecordType::SOA)
+    {
+        return Ok(vec![Err("The zone's SOA record is missing.".into())]);
+    }
+
+    let authoritative_zones: Vec<_> = con
+        .get_zones()
+        .await?
+        .into_iter()
+        .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
+        .chain(std::iter::once(zone.clone()))
+        .collect();
+    let mut seen = HashSet::new();
+    Ok(records
+        .iter()
+        .zip(validate_records(records))
+        .map(|(record, valid)| {
+            valid.map_err(|e| e.to_string())?;
+            match find_authoritative_zone(&record.name, &authoritative_zones) {
+                Some(z) if &z == zone => {}
+                Some(z) => return Err(format!("{} belongs to the zone {z}", record.name)),
+                None => return Err(format!("{} is not part of the zone", record.name)),
+            }
+            if !seen.insert(record.db_key()) {
+                return Err(format!(
+                    "The RR set {} {:?} is given more than once",
+                    record.name,
+                    record.rr_type()
+                ));
+            }
+            Ok(())
+        })
+        .collect())
+}
+
+/// Compares the non-DNSSEC RR sets of the zone in the db with the desired RR sets. Returns `None`
+/// if the zone doesn't exist. The desired RR sets must have been checked with
+/// `check_desired_records()`.
+///
+/// The SOA serial is managed by pektin, so the serial of the desired SOA record is ignored and
+/// replaced by the current serial.
+#[instrument(skip(desired, con, dnssec_con))]
+pub async fn diff_zone(
+    zone: &Name,
+    desired: &[DbEntry],
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<Option<ZoneDiff>> {
+    let current = match get_zone_rr_sets(&[zone], false, con, dnssec_con)
+        .await?
+        .pop()
+        .flatten()
+    {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let mut current: BTreeMap<_, _> = current.into_iter().map(|e| (e.db_key(), e)).collect();
+
+    let mut zone_diff = ZoneDiff::default();
+    for entry in desired {
+        let mut entry = entry.clone();
+        match current.remove(&entry.db_key()) {
+            None => zone_diff.added.push(entry),
+            Some(before) => {
+                keep_soa_serial(&mut entry, &before);
+                if before.serialize_for_db()? != entry.serialize_for_db()? {
+                    zone_diff.modified.push(RrSetChange {
+                        before,
+                        after: entry,
+                    });
+                }
+            }
+        }
+    }
+    zone_diff.removed = current.into_values().collect();
+    Ok(Some(zone_diff))
+}
+
+/// Replaces the serial of the SOA records in `entry` with the serial of `current` if both are SOA
+/// entries, so that they only differ if the other fields of the SOA record differ.
+pub fn keep_soa_serial(entry: &mut DbEntry, current: &DbEntry) {
+    if let (RrSet::SOA { rr_set }, Some(serial)) = (&mut entry.rr_set, soa_serial(current)) {
+        for soa in rr_set {
+            *soa = SoaRecord {
+                value: with_serial(&soa.value, serial),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{a_entry, name, set_records, soa_entry, test_state};
+
+    #[actix_web::test]
+    async fn diff_contains_added_modified_and_removed_rr_sets() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+        let old = a_entry("old.example.com.", [192, 0, 2, 3]);
+        set_records(&[www.clone(), mail.clone(), old.clone()], &state).await;
+
+        let new_mail = a_entry("mail.example.com.", [192, 0, 2, 20]);
+        let new = a_entry("new.example.com.", [192, 0, 2, 4]);
+        // the serial of the desired SOA record is ignored
+        let desired = [
+            soa_entry("example.com.", 100),
+            www,
+            new_mail.clone(),
+            new.clone(),
+        ];
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        let zone_diff = diff_zone(&name("example.com."), &desired, &mut con, &mut dnssec_con)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(zone_diff.added, vec![new]);
+        assert_eq!(zone_diff.modified.len(), 1);
+        assert_eq!(zone_diff.modified[0].before, mail);
+        assert_eq!(zone_diff.modified[0].after, new_mail);
+        // the DNSKEY and NSEC3 records are managed by pektin and not removed
+        assert_eq!(zone_diff.removed, vec![old]);
+
+        let res = diff_zone(&name("example.org."), &desired, &mut con, &mut dnssec_con).await;
+        assert!(matches!(res, Ok(None)));
+    }
+
+    #[actix_web::test]
+    async fn desired_records_must_belong_to_the_zone() {
+        let state = test_state();
+        set_records(
+            &[
+                soa_entry("example.com.", 1),
+                soa_entry("sub.example.com.", 1),
+            ],
+            &state,
+        )
+        .await;
+        let mut con = state.db_pool.get().await.unwrap();
+        let zone = name("example.com.");
+
+        let desired = [a_entry("www.example.com.", [192, 0, 2, 1])];
+        let valid = check_desired_records(&zone, &desired, &mut con)
+            .await
+            .unwrap();
+        assert_eq!(valid, vec![Err("The zone's SOA record is missing.".into())]);
+
+        let desired = [
+            soa_entry("example.com.", 1),
+            a_entry("www.example.com.", [192, 0, 2, 1]),
+            a_entry("www.sub.example.com.", [192, 0, 2, 2]),
+            a_entry("www.example.org.", [192, 0, 2, 3]),
+            a_entry("www.example.com.", [192, 0, 2, 4]),
+        ];
+        let valid = check_desired_records(&zone, &desired, &mut con)
+            .await
+            .unwrap();
+        assert_eq!(
+            valid,
+            vec![
+                Ok(()),
+                Ok(()),
+                Err("www.sub.example.com. belongs to the zone sub.example.com.".into()),
+                Err("www.example.org. is not part of the zone".into()),
+                Err("The RR set www.example.com. A is given more than once".into()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use serde_json::json;
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::get_zone_rr_sets,
+    errors_and_responses::{
+        auth_err, internal_err, partial_success_with_data, success_with_toplevel_data,
+    },
+    types::{AppState, ExportZoneRequestBody, ResponseType},
+    zone_file::write_zone_file,
+};
+
+#[post("/export-zone")]
+pub async fn export_zone(
+    req: HttpRequest,
+    req_body: web::Json<ExportZoneRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "export-zone",
+        client_username = %req_body.client_username,
+        names = ?req_body.names,
+        include_dnssec = %req_body.include_dnssec
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if req_body.names.is_empty() {
+                return success_with_toplevel_data("exported zones", json!([]));
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let names: Vec<_> = req_body.names.iter().collect();
+            let zones_rr_sets =
+                match get_zone_rr_sets(&names, req_body.include_dnssec, &mut con, &mut dnssec_con)
+                    .await
+                {
+                    Ok(z) => z,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+
+            let mut messages = Vec::with_capacity(zones_rr_sets.len());
+            for (zone, entries_opt) in req_body.names.iter().zip(zones_rr_sets) {
+                let entries = match entries_opt {
+                    Some(entries) => entries,
+                    None if !zone.is_fqdn() => {
+                        messages.push((ResponseType::Error, "non-absolute name", None));
+                        continue;
+                    }
+                    None => {
+                        messages.push((ResponseType::Error, "not found", None));
+                        continue;
+                    }
+                };
+
+                messages.push((
+                    ResponseType::Success,
+                    "exported zone",
+                    Some(write_zone_file(zone, &entries)),
+                ));
+            }
+
+            let all_success = messages.iter().all(|(t, _, _)| *t == ResponseType::Success);
+            let all_error = messages.iter().all(|(t, _, _)| *t == ResponseType::Error);
+            let toplevel_response_type = match (all_success, all_error) {
+                (true, false) => ResponseType::Success,
+                (false, true) => ResponseType::Error,
+                (false, false) => ResponseType::PartialSuccess,
+                (true, true) => unreachable!(),
+            };
+            let toplevel_message = match toplevel_response_type {
+                ResponseType::Success => "exported zones",
+                ResponseType::PartialSuccess => "couldn't export all zones",
+                ResponseType::Error => "couldn't export zones",
+                ResponseType::Ignored => unreachable!(),
+            };
+            partial_success_with_data(toplevel_response_type, toplevel_message, messages)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,87 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use serde_json::json;
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    errors_and_responses::{
+        auth_err, internal_err, partial_success_with_data, success_with_toplevel_data,
+    },
+    notify::get_notify_targets as get_notify_targets_from_db,
+    storage::Storage,
+    types::{AppState, GetNotifyTargetsRequestBody, ResponseType},
+};
+
+#[post("/get-notify-targets")]
+pub async fn get_notify_targets(
+    req: HttpRequest,
+    req_body: web::Json<GetNotifyTargetsRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "get-notify-targets",
+        client_username = %req_body.client_username,
+        zones = ?req_body.zones
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if req_body.zones.is_empty() {
+                return success_with_toplevel_data("got notify targets", json!([]));
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let available_zones = match con.get_zones().await {
+                Ok(z) => z,
+                Err(e) => return internal_err(e.to_string()),
+            };
+
+            let mut messages = Vec::with_capacity(req_body.zones.len());
+            for zone in &req_body.zones {
+                if !available_zones.contains(&zone.to_lowercase().to_string()) {
+                    messages.push((ResponseType::Error, "zone not found", None));
+                    continue;
+                }
+                match get_notify_targets_from_db(zone, &mut con).await {
+                    Ok(targets) => {
+                        messages.push((ResponseType::Success, "got notify targets", Some(targets)))
+                    }
+                    Err(e) => return internal_err(e.to_string()),
+                }
+            }
+
+            let all_success = messages.iter().all(|(t, _, _)| *t == ResponseType::Success);
+            let all_error = messages.iter().all(|(t, _, _)| *t == ResponseType::Error);
+            let toplevel_response_type = match (all_success, all_error) {
+                (true, false) => ResponseType::Success,
+                (false, true) => ResponseType::Error,
+                (false, false) => ResponseType::PartialSuccess,
+                (true, true) => unreachable!(),
+            };
+            let toplevel_message = match toplevel_response_type {
+                ResponseType::Success => "got notify targets",
+                ResponseType::PartialSuccess => "couldn't get the notify targets of all zones",
+                ResponseType::Error => "couldn't get notify targets",
+                ResponseType::Ignored => unreachable!(),
+            };
+            partial_success_with_data(toplevel_response_type, toplevel_message, messages)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -10,7 +10,7 @@ use crate::{
     errors_and_responses::{
         auth_err, internal_err, partial_success_with_data, success_with_toplevel_data,
     },
-    types::{AppState, GetZoneRecordsRequestBody, ResponseType},
+    types::{AppState, GetZoneRecordsRequestBody, ResponseType, VersionedDbEntry},
 };
 
 #[post("/get-zone-records")]
@@ -74,9 +74,15 @@ pub async fn get_zone_records(
             let mut internal_error = None;
             for (idx, keys_opt) in zones_record_keys.iter().enumerate() {
                 if let Some(keys) = keys_opt {
-                    let get_res = get_or_mget_records(keys, &mut con)
-                        .await
-                        .map_err(|e| e.to_string());
+                    let get_res = match get_or_mget_records(keys, &mut con).await {
+                        Ok(entries) => entries
+                            .into_iter()
+                            .flatten()
+                            .map(VersionedDbEntry::new)
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
                     if let Err(ref err) = get_res {
                         internal_error = Some(err.clone());
                     }
@@ -10,7 +10,7 @@ use crate::{
     errors_and_responses::{
         auth_err, internal_err, partial_success_with_data, success_with_toplevel_data,
     },
-    types::{AppState, GetRequestBody, RecordIdentifier, ResponseType},
+    types::{AppState, GetRequestBody, RecordIdentifier, ResponseType, VersionedDbEntry},
 };
 
 #[post("/get")]
@@ -49,13 +49,18 @@ pub async fn get(
 
             match get_or_mget_records(&record_keys, &mut con).await {
                 Ok(records) => {
-                    let messages: Vec<_> = records
+                    let messages: Result<Vec<_>, _> = records
                         .into_iter()
                         .map(|entry| match entry {
-                            Some(e) => (ResponseType::Success, "record found", Some(e)),
-                            None => (ResponseType::Error, "no record found", None),
+                            Some(e) => VersionedDbEntry::new(e)
+                                .map(|e| (ResponseType::Success, "record found", Some(e))),
+                            None => Ok((ResponseType::Error, "no record found", None)),
                         })
                         .collect();
+                    let messages = match messages {
+                        Ok(m) => m,
+                        Err(e) => return internal_err(e.to_string()),
+                    };
                     let all_success = messages.iter().all(|(t, _, _)| *t == ResponseType::Success);
                     let all_error = messages.iter().all(|(t, _, _)| *t == ResponseType::Error);
                     let toplevel_response_type = match (all_success, all_error) {
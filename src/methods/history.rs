@@ -0,0 +1,60 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    errors_and_responses::{auth_err, err, internal_err, success_with_toplevel_data},
+    journal::get_history,
+    types::{AppState, HistoryRequestBody},
+};
+
+#[post("/history")]
+pub async fn history(
+    req: HttpRequest,
+    req_body: web::Json<HistoryRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "history",
+        client_username = %req_body.client_username,
+        query = ?req_body.query
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if !req_body.query.zone.is_fqdn() {
+                return err(
+                    "The zone name is not absolute (i.e. the root label at the end is missing).",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            match get_history(&req_body.query, &mut con).await {
+                Ok(entries) => success_with_toplevel_data(
+                    format!("found {} journal entries", entries.len()),
+                    entries,
+                ),
+                Err(e) => internal_err(e.to_string()),
+            }
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,155 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    errors_and_responses::{auth_err, err, internal_err, success},
+    journal::ChangeAuthor,
+    set::sign_and_set_records,
+    tsig::TsigKey,
+    types::{AppState, ImportZoneTransferRequestBody},
+    validation::{check_soa, validate_records},
+    zone_file::records_to_db_entries,
+    zone_transfer::request_axfr,
+};
+
+#[post("/import-zone-transfer")]
+pub async fn import_zone_transfer(
+    req: HttpRequest,
+    req_body: web::Json<ImportZoneTransferRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    // the TSIG key is not logged because it contains the secret
+    let span = info_span!(
+        "import-zone-transfer",
+        client_username = %req_body.client_username,
+        zone = %req_body.zone,
+        primary = %req_body.primary
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if !req_body.zone.is_fqdn() {
+                return err(
+                    "The zone name is not absolute (i.e. the root label at the end is missing).",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+            let tsig_key = match req_body.tsig_key.as_deref().map(TsigKey::parse) {
+                Some(Ok(key)) => Some(key),
+                Some(Err(_)) => {
+                    return err(
+                        "Invalid TSIG key, expected 'name:algorithm:base64-secret'.",
+                        Vec::<Option<String>>::new(),
+                    )
+                }
+                None => None,
+            };
+
+            let records =
+                match request_axfr(&req_body.zone, req_body.primary, tsig_key.as_ref()).await {
+                    Ok(r) => r,
+                    Err(e) => {
+                        return err(
+                            format!("Zone transfer from {} failed.", req_body.primary),
+                            vec![Some(e.to_string())],
+                        )
+                    }
+                };
+            // the DNSSEC records of the primary are dropped, we sign the zone ourselves
+            let (records, skipped_dnssec_records) = match records_to_db_entries(&records) {
+                Ok(r) => r,
+                Err(errors) => {
+                    let messages = errors.into_iter().map(Some).collect();
+                    return err("Could not convert the transferred records.", messages);
+                }
+            };
+
+            let in_zone: Vec<_> = records
+                .iter()
+                .map(|record| {
+                    if req_body.zone.zone_of(&record.name) {
+                        Ok(())
+                    } else {
+                        Err(format!("{} is not part of the zone", record.name))
+                    }
+                })
+                .collect();
+            if in_zone.iter().any(|r| r.is_err()) {
+                let messages = in_zone.into_iter().map(Result::err).collect();
+                return err("One or more records are outside of the zone.", messages);
+            }
+
+            let valid = validate_records(&records);
+            if valid.iter().any(|r| r.is_err()) {
+                let messages = records
+                    .iter()
+                    .zip(valid.iter())
+                    .map(|(record, res)| {
+                        res.as_ref()
+                            .err()
+                            .map(|e| format!("{} {}: {}", record.name, record.rr_type(), e))
+                    })
+                    .collect();
+                return err("One or more records were invalid.", messages);
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            // the transfer always contains the SOA record, so this can't fail
+            let (_soa_check, used_zones, new_authoritative_zones) =
+                match check_soa(&records, &mut con).await {
+                    Ok(s) => s,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+
+            if let Err(res) = sign_and_set_records(
+                &records,
+                &used_zones,
+                &new_authoritative_zones,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await
+            {
+                return res;
+            }
+
+            let messages = records
+                .iter()
+                .map(|record| format!("{} {}: set record", record.name, record.rr_type()))
+                .collect();
+            success(
+                format!(
+                    "imported {} RR sets, skipped {} DNSSEC records",
+                    records.len(),
+                    skipped_dnssec_records
+                ),
+                messages,
+            )
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,171 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    errors_and_responses::{auth_err, err, internal_err, success, success_with_toplevel_data},
+    journal::ChangeAuthor,
+    set::{plan_changes, sign_and_set_records},
+    types::{AppState, ImportZoneRequestBody, RequestBody},
+    validation::{check_soa, validate_records},
+    zone_file::parse_zone_file,
+};
+
+#[post("/import-zone")]
+pub async fn import_zone(
+    req: HttpRequest,
+    req_body: web::Json<ImportZoneRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "import-zone",
+        client_username = %req_body.client_username,
+        zone = %req_body.zone
+    );
+    async move {
+        // parse before authenticating so that the policy can evaluate the records that would be set
+        let parsed = parse_zone_file(&req_body.zone_file, &req_body.zone);
+        let request_body = RequestBody::ImportZone {
+            zone: req_body.zone.clone(),
+            records: parsed
+                .as_ref()
+                .map(|p| p.entries.clone())
+                .unwrap_or_default(),
+        };
+
+        let mut auth = auth_ok(
+            &req,
+            request_body,
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if !req_body.zone.is_fqdn() {
+                return err(
+                    "The zone name is not absolute (i.e. the root label at the end is missing).",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+
+            let parsed = match parsed {
+                Ok(p) => p,
+                Err(errors) => {
+                    let messages = errors.iter().map(|e| Some(e.to_string())).collect();
+                    return err("Could not parse the zone file.", messages);
+                }
+            };
+            let records = parsed.entries;
+            let lines = parsed.entry_lines;
+            if records.is_empty() {
+                return err(
+                    "The zone file does not contain any records.",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+
+            // reports the given per-record errors together with the line the record started on
+            macro_rules! return_if_err_with_lines {
+                ($results:expr, $err_msg:expr) => {
+                    let results = $results;
+                    if results.iter().any(|r| r.is_err()) {
+                        let messages = results
+                            .iter()
+                            .zip(lines.iter())
+                            .map(|(res, line)| {
+                                res.as_ref().err().map(|e| format!("line {line}: {e}"))
+                            })
+                            .collect();
+                        return err($err_msg, messages);
+                    }
+                };
+            }
+
+            let in_zone: Vec<_> = records
+                .iter()
+                .map(|record| {
+                    if req_body.zone.zone_of(&record.name) {
+                        Ok(())
+                    } else {
+                        Err(format!("{} is not part of the zone", record.name))
+                    }
+                })
+                .collect();
+            return_if_err_with_lines!(in_zone, "One or more records are outside of the zone.");
+
+            return_if_err_with_lines!(
+                validate_records(&records),
+                "One or more records were invalid."
+            );
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let (soa_check, used_zones, new_authoritative_zones) =
+                match check_soa(&records, &mut con).await {
+                    Ok(s) => s,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+            return_if_err_with_lines!(
+                soa_check,
+                "The zone file does not contain a SOA record and the zone does not exist yet."
+            );
+
+            if req_body.dry_run {
+                return match plan_changes(
+                    &records,
+                    &[],
+                    &used_zones,
+                    &new_authoritative_zones,
+                    &mut con,
+                )
+                .await
+                {
+                    Ok(plan) => success_with_toplevel_data("planned changes", plan),
+                    Err(e) => internal_err(e.to_string()),
+                };
+            }
+
+            if let Err(res) = sign_and_set_records(
+                &records,
+                &used_zones,
+                &new_authoritative_zones,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await
+            {
+                return res;
+            }
+
+            let messages = lines
+                .iter()
+                .map(|line| format!("line {line}: set record"))
+                .collect();
+            success(
+                format!(
+                    "imported {} RR sets, skipped {} DNSSEC records",
+                    records.len(),
+                    parsed.skipped_dnssec_records
+                ),
+                messages,
+            )
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,176 @@
+use std::ops::Deref;
+
+use actix_web::{http::header, post, web, HttpRequest, Responder};
+use pektin_common::proto::rr::Name;
+use pektin_common::{DnssecAlgorithm, Nsec3ParamRecord, RrSet};
+use serde::Serialize;
+use serde_json::json;
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::{auth_ok, auth_ok_authenticated},
+    db::get_zone_rr_sets,
+    dnssec::dnskey_key_tag,
+    errors_and_responses::{auth_err, internal_err, success_with_toplevel_data},
+    journal::soa_serial,
+    storage::Storage,
+    types::{AppState, ListZonesRequestBody, RequestBody},
+};
+
+#[derive(Serialize, Debug)]
+struct ZoneInfo {
+    zone: Name,
+    /// The number of records in the zone, not counting DNSSEC records.
+    record_count: usize,
+    serial: Option<u32>,
+    dnssec: Option<DnssecInfo>,
+    nsec3: Option<Nsec3ParamRecord>,
+}
+
+#[derive(Serialize, Debug)]
+struct DnssecInfo {
+    algorithms: Vec<DnssecAlgorithm>,
+    key_tags: Vec<u16>,
+    /// The earliest expiration of all RRSIGs in the zone as Unix timestamp.
+    next_rrsig_expiration: Option<u32>,
+}
+
+#[post("/list-zones")]
+pub async fn list_zones(
+    req: HttpRequest,
+    req_body: web::Json<ListZonesRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!("list-zones", client_username = %req_body.client_username);
+    async move {
+        // this checks that the client may list zones at all, the zones it may see are filtered
+        // below
+        let mut auth = auth_ok(
+            &req,
+            RequestBody::ListZones { zones: vec![] },
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let zones = match con.get_zones().await {
+                Ok(z) => z,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            let mut zones: Vec<_> = zones
+                .into_iter()
+                .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
+                .collect();
+            zones.sort();
+
+            // the policy is evaluated for each zone on its own so that clients only see the zones
+            // they are allowed to see
+            let ip = req
+                .connection_info()
+                .realip_remote_addr()
+                .map(|s| s.to_string());
+            let user_agent = req
+                .headers()
+                .get(header::USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let mut allowed_zones = Vec::with_capacity(zones.len());
+            for zone in zones {
+                let zone_auth = auth_ok_authenticated(
+                    RequestBody::ListZones {
+                        zones: vec![zone.clone()],
+                    },
+                    &state,
+                    &req_body.client_username,
+                    ip.clone(),
+                    &user_agent,
+                )
+                .await;
+                if zone_auth.success {
+                    allowed_zones.push(zone);
+                }
+            }
+            if allowed_zones.is_empty() {
+                return success_with_toplevel_data("listed zones", json!([]));
+            }
+
+            let zone_refs: Vec<_> = allowed_zones.iter().collect();
+            let zones_rr_sets =
+                match get_zone_rr_sets(&zone_refs, true, &mut con, &mut dnssec_con).await {
+                    Ok(z) => z,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+
+            let infos: Vec<_> = allowed_zones
+                .into_iter()
+                .zip(zones_rr_sets)
+                .map(|(zone, entries)| {
+                    let mut info = ZoneInfo {
+                        zone,
+                        record_count: 0,
+                        serial: None,
+                        dnssec: None,
+                        nsec3: None,
+                    };
+                    let mut dnssec = DnssecInfo {
+                        algorithms: vec![],
+                        key_tags: vec![],
+                        next_rrsig_expiration: None,
+                    };
+                    // the zone may have been deleted in the meantime
+                    for entry in entries.unwrap_or_default() {
+                        match &entry.rr_set {
+                            RrSet::SOA { .. } => info.serial = soa_serial(&entry),
+                            RrSet::DNSKEY { rr_set } => {
+                                for dnskey in rr_set {
+                                    if !dnssec.algorithms.contains(&dnskey.algorithm) {
+                                        dnssec.algorithms.push(dnskey.algorithm);
+                                    }
+                                    dnssec.key_tags.push(dnskey_key_tag(dnskey));
+                                }
+                            }
+                            RrSet::RRSIG { rr_set } => {
+                                for rrsig in rr_set {
+                                    dnssec.next_rrsig_expiration = Some(
+                                        dnssec
+                                            .next_rrsig_expiration
+                                            .map_or(rrsig.signature_expiration, |e| {
+                                                e.min(rrsig.signature_expiration)
+                                            }),
+                                    );
+                                }
+                            }
+                            RrSet::NSEC3PARAM { rr_set } => info.nsec3 = rr_set.first().cloned(),
+                            _ => {}
+                        }
+                        if !entry.rr_type().is_dnssec() {
+                            info.record_count += entry.rr_set.len();
+                        }
+                    }
+                    if !dnssec.key_tags.is_empty() {
+                        info.dnssec = Some(dnssec);
+                    }
+                    info
+                })
+                .collect();
+
+            success_with_toplevel_data("listed zones", infos)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
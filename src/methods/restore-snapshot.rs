@@ -0,0 +1,127 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    backup::{check_zone_snapshot, restore_zone, SNAPSHOT_VERSION},
+    errors_and_responses::{auth_err, err, internal_err, success},
+    journal::ChangeAuthor,
+    types::{AppState, RequestBody, RestoreSnapshotRequestBody},
+    vault,
+};
+
+#[post("/restore-snapshot")]
+pub async fn restore_snapshot(
+    req: HttpRequest,
+    req_body: web::Json<RestoreSnapshotRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "restore-snapshot",
+        client_username = %req_body.client_username,
+        zones = ?req_body.zones,
+        resign = %req_body.resign
+    );
+    async move {
+        let zone_snapshots: Vec<_> = req_body
+            .snapshot
+            .zones
+            .iter()
+            .filter(|z| req_body.zones.is_empty() || req_body.zones.contains(&z.zone))
+            .collect();
+
+        let mut auth = auth_ok(
+            &req,
+            RequestBody::RestoreSnapshot {
+                zones: zone_snapshots.iter().map(|z| z.zone.clone()).collect(),
+                resign: req_body.resign,
+            },
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if req_body.snapshot.version > SNAPSHOT_VERSION {
+                return err(
+                    format!(
+                        "The snapshot has version {}, but only versions up to {SNAPSHOT_VERSION} are supported.",
+                        req_body.snapshot.version
+                    ),
+                    Vec::<Option<String>>::new(),
+                );
+            }
+            let missing: Vec<_> = req_body
+                .zones
+                .iter()
+                .map(|zone| {
+                    (!zone_snapshots.iter().any(|z| &z.zone == zone))
+                        .then(|| format!("The snapshot doesn't contain the zone {zone}."))
+                })
+                .collect();
+            if missing.iter().any(Option::is_some) {
+                return err("One or more zones are not part of the snapshot.", missing);
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let vault_api_token = match vault::ApiTokenCache::get(
+                &state.vault_uri,
+                &state.vault_user_name,
+                &state.vault_password,
+            )
+            .await
+            {
+                Ok(t) => t,
+                Err(_) => return internal_err("Couldnt get vault api token"),
+            };
+
+            // check all zones before restoring any of them
+            let mut checked = Vec::with_capacity(zone_snapshots.len());
+            for zone_snapshot in &zone_snapshots {
+                match check_zone_snapshot(zone_snapshot, req_body.resign, &state, &vault_api_token)
+                    .await
+                {
+                    Ok(c) => checked.push(c),
+                    Err(e) => return internal_err(e.to_string()),
+                }
+            }
+            if checked.iter().any(Result::is_err) {
+                let messages = checked.into_iter().map(Result::err).collect();
+                return err("One or more zones can't be restored.", messages);
+            }
+
+            let author = ChangeAuthor::from_request(&req, &req_body.client_username);
+            let mut messages = Vec::with_capacity(checked.len());
+            for checked_zone in checked.into_iter().flatten() {
+                if let Err(res) = restore_zone(
+                    &checked_zone,
+                    &author,
+                    &state,
+                    &vault_api_token,
+                    &mut con,
+                    &mut dnssec_con,
+                )
+                .await
+                {
+                    return res;
+                }
+                messages.push(format!("restored zone {}", checked_zone.zone));
+            }
+            success("restored snapshot", messages)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
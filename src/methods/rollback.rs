@@ -0,0 +1,552 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::DbEntry;
+use tracing::{info_span, instrument, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::{acquire_locks, get_or_mget_records, release_locks},
+    diff::keep_soa_serial,
+    errors_and_responses::{
+        auth_err, conflict_err, err, internal_err, success_with_toplevel_data, PektinApiResult,
+    },
+    journal::{get_journal_start_serial, get_journal_with_times, soa_serial, ChangeAuthor},
+    macros::unwrap_or_return_if_err,
+    set::sign_and_write_changes,
+    storage::Connection,
+    types::{
+        AppState, RecordIdentifier, RollbackRequestBody, RollbackTarget, RrSetChange, ZoneDiff,
+    },
+    validation::check_soa,
+};
+
+#[post("/rollback")]
+pub async fn rollback(
+    req: HttpRequest,
+    req_body: web::Json<RollbackRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "rollback",
+        client_username = %req_body.client_username,
+        zone = %req_body.zone,
+        to = ?req_body.to
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if !req_body.zone.is_fqdn() {
+                return err(
+                    "The zone name is not absolute (i.e. the root label at the end is missing).",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+            if let Some(rr_set) = &req_body.rr_set {
+                if !req_body.zone.zone_of(&rr_set.name) {
+                    return err(
+                        "The RR set is not part of the zone.",
+                        Vec::<Option<String>>::new(),
+                    );
+                }
+                if rr_set.rr_type.is_dnssec() {
+                    return err(
+                        "DNSSEC records are managed by pektin and can't be rolled back.",
+                        Vec::<Option<String>>::new(),
+                    );
+                }
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let zone_diff = match rollback_diff(
+                &req_body.zone,
+                req_body.rr_set.as_ref(),
+                req_body.to,
+                &mut con,
+            )
+            .await
+            {
+                Ok(Ok(d)) => d,
+                Ok(Err(e)) => return err(e, Vec::<Option<String>>::new()),
+                Err(e) => return internal_err(e.to_string()),
+            };
+            let idents = changed_idents(&zone_diff);
+            if idents.is_empty() {
+                return success_with_toplevel_data("nothing to roll back", zone_diff);
+            }
+
+            let locks = match acquire_locks(&idents, &mut con).await {
+                Ok(l) => l,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            let res = apply_rollback(
+                &req_body,
+                &idents,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await;
+            if let Err(e) = release_locks(locks, &mut con).await {
+                return internal_err(e.to_string());
+            }
+            match res {
+                Ok(zone_diff) => success_with_toplevel_data("rolled back zone", zone_diff),
+                Err(res) => res,
+            }
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Computes the changes that restore the zone (or only the RR set `rr_set`) to its state at `to`
+/// by undoing the journal entries that were recorded since then. `added` contains the RR sets that
+/// are recreated and `removed` those that are deleted.
+///
+/// DNSSEC records are not restored, they are recreated when the restored RR sets are signed. This
+/// includes the NSEC3 chain, whose changes are recorded in the journal entry of the rollback like
+/// those of any other change. The SOA serial is never decreased, only the other fields of the SOA
+/// record are restored.
+///
+/// Returns an error message if the zone doesn't exist or its journal doesn't reach back to `to`.
+#[instrument(skip(con))]
+pub async fn rollback_diff(
+    zone: &Name,
+    rr_set: Option<&RecordIdentifier>,
+    to: RollbackTarget,
+    con: &mut Connection,
+) -> PektinApiResult<Result<ZoneDiff, String>> {
+    let soa_ident = RecordIdentifier {
+        name: zone.clone(),
+        rr_type: RecordType::SOA,
+    };
+    let current_soa = match get_or_mget_records(&[soa_ident.db_key()], con)
+        .await?
+        .pop()
+        .flatten()
+    {
+        Some(soa) => soa,
+        None => return Ok(Err("The zone does not exist.".into())),
+    };
+    let current_serial = soa_serial(&current_soa).expect("SOA entry contains no SOA record");
+
+    // only the uninterrupted chain of journal entries leading to the current serial can be undone
+    let journal = get_journal_with_times(zone, con).await?;
+    if matches!(journal.last(), Some((_, last)) if last.serial_after() != current_serial) {
+        return Ok(Err(
+            "The journal of the zone doesn't contain its latest change.".into(),
+        ));
+    }
+    let chain_start = journal
+        .windows(2)
+        .rposition(|w| w[0].1.serial_after() != w[1].1.serial_before())
+        .map_or(0, |i| i + 1);
+    let chain = &journal[chain_start..];
+
+    let undo_from = match to {
+        RollbackTarget::Serial(serial) if serial == current_serial => chain.len(),
+        RollbackTarget::Serial(serial) => {
+            match chain
+                .iter()
+                .rposition(|(_, entry)| entry.serial_before() == serial)
+            {
+                Some(i) => i,
+                None => {
+                    return Ok(Err(format!(
+                        "The journal of the zone doesn't reach back to serial {serial}."
+                    )))
+                }
+            }
+        }
+        RollbackTarget::Timestamp(millis) => {
+            let i = chain
+                .iter()
+                .position(|(time, _)| *time > millis)
+                .unwrap_or(chain.len());
+            // if older entries were trimmed or the chain is interrupted, the zone may have been
+            // changed between the given time and the first entry of the chain. Redis trims the
+            // journal with `MAXLEN ~`, so its length doesn't tell whether it was trimmed, but the
+            // first entry is then no longer the one that started the journal.
+            let truncated = chain_start > 0 || {
+                let start_serial = get_journal_start_serial(zone, con).await?;
+                chain.first().map(|(_, first)| first.serial_before()) != start_serial
+            };
+            if i == 0 && !chain.is_empty() && truncated {
+                return Ok(Err(
+                    "The journal of the zone doesn't reach back to the given time.".into(),
+                ));
+            }
+            i
+        }
+    };
+    let undone = &chain[undo_from..];
+
+    let in_scope = |entry: &DbEntry| {
+        !entry.rr_type().is_dnssec()
+            && rr_set
+                .is_none_or(|ident| ident.name == entry.name && ident.rr_type == entry.rr_type())
+    };
+    // the state of each RR set before the first undone change of it
+    let mut restored: BTreeMap<String, Option<DbEntry>> = BTreeMap::new();
+    for (_, entry) in undone {
+        for changed in entry.removed.iter().chain(&entry.added) {
+            if in_scope(changed) {
+                let key = changed.db_key();
+                let before = entry.removed.iter().find(|r| r.db_key() == key).cloned();
+                restored.entry(key).or_insert(before);
+            }
+        }
+    }
+    if let Some((_, first)) = undone.first() {
+        if rr_set.is_none_or(|ident| ident.name == *zone && ident.rr_type == RecordType::SOA) {
+            restored.insert(soa_ident.db_key(), Some(first.soa_before.clone()));
+        }
+    }
+
+    let keys: Vec<_> = restored.keys().cloned().collect();
+    let current = get_or_mget_records(&keys, con).await?;
+    let mut zone_diff = ZoneDiff::default();
+    for (current, restored) in current.into_iter().zip(restored.into_values()) {
+        match (current, restored) {
+            (None, Some(restored)) => zone_diff.added.push(restored),
+            (Some(current), None) => zone_diff.removed.push(current),
+            (Some(current), Some(mut restored)) => {
+                // the serial is incremented when the rollback is written
+                keep_soa_serial(&mut restored, &current);
+                if current.serialize_for_db()? != restored.serialize_for_db()? {
+                    zone_diff.modified.push(RrSetChange {
+                        before: current,
+                        after: restored,
+                    });
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    Ok(Ok(zone_diff))
+}
+
+/// Recomputes the rollback and applies it in a single db transaction. The locks of the RR sets
+/// `locked` must be held; if the rollback now changes other RR sets, it fails with a conflict.
+#[instrument(skip(req_body, locked, state, con, dnssec_con))]
+async fn apply_rollback(
+    req_body: &RollbackRequestBody,
+    locked: &[RecordIdentifier],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<ZoneDiff, HttpResponse> {
+    // the zone may have changed while we were waiting for the locks
+    let zone_diff =
+        match rollback_diff(&req_body.zone, req_body.rr_set.as_ref(), req_body.to, con).await {
+            Ok(Ok(d)) => d,
+            Ok(Err(e)) => return Err(err(e, Vec::<Option<String>>::new())),
+            Err(e) => return Err(internal_err(e.to_string())),
+        };
+    let locked: HashSet<_> = locked.iter().map(RecordIdentifier::db_key).collect();
+    if changed_idents(&zone_diff)
+        .iter()
+        .any(|ident| !locked.contains(&ident.db_key()))
+    {
+        return Err(conflict_err(
+            "The zone was changed in the meantime.",
+            Vec::<Option<String>>::new(),
+        ));
+    }
+
+    let to_set: Vec<_> = zone_diff
+        .added
+        .iter()
+        .cloned()
+        .chain(zone_diff.modified.iter().map(|change| change.after.clone()))
+        .collect();
+    let to_delete: Vec<_> = zone_diff
+        .removed
+        .iter()
+        .map(|r| RecordIdentifier {
+            name: r.name.clone(),
+            rr_type: r.rr_type(),
+        })
+        .collect();
+    if to_set.is_empty() && to_delete.is_empty() {
+        return Ok(zone_diff);
+    }
+
+    let (_soa_check, used_zones, new_authoritative_zones) = match check_soa(&to_set, con).await {
+        Ok(s) => s,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    unwrap_or_return_if_err!(
+        _soa_check,
+        "Tried to restore one or more records for a zone that does not have a SOA record.",
+        Err
+    );
+
+    sign_and_write_changes(
+        &to_set,
+        &to_delete,
+        &used_zones,
+        &new_authoritative_zones,
+        author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await?;
+
+    Ok(zone_diff)
+}
+
+/// The RR sets that are set or deleted to apply the diff.
+fn changed_idents(zone_diff: &ZoneDiff) -> Vec<RecordIdentifier> {
+    zone_diff
+        .added
+        .iter()
+        .chain(zone_diff.modified.iter().map(|change| &change.after))
+        .chain(&zone_diff.removed)
+        .map(|r| RecordIdentifier {
+            name: r.name.clone(),
+            rr_type: r.rr_type(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use pektin_common::RrSet;
+
+    use super::*;
+    use crate::db::get_zone_keys;
+    use crate::journal::{get_journal, journal_start_db_key};
+    use crate::storage::Storage;
+    use crate::test_utils::{
+        a_entry, author, get_with_rrsig, name, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    fn now_millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    fn a_value(entry: &DbEntry) -> Vec<[u8; 4]> {
+        match &entry.rr_set {
+            RrSet::A { rr_set } => rr_set.iter().map(|r| r.value.octets()).collect(),
+            _ => panic!("not an A entry"),
+        }
+    }
+
+    async fn nsec3_entries(zone: &str, con: &mut Connection) -> Vec<String> {
+        let keys = get_zone_keys(&[&name(zone)], con)
+            .await
+            .unwrap()
+            .pop()
+            .flatten()
+            .unwrap();
+        let keys: Vec<_> = keys.into_iter().filter(|k| k.ends_with(":NSEC3")).collect();
+        get_or_mget_records(&keys, con)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.unwrap().serialize_for_db().unwrap())
+            .collect()
+    }
+
+    fn request(to: RollbackTarget) -> RollbackRequestBody {
+        RollbackRequestBody {
+            client_username: "test".into(),
+            confidant_password: String::new(),
+            zone: name("example.com."),
+            rr_set: None,
+            to,
+        }
+    }
+
+    async fn roll_back(to: RollbackTarget, state: &AppState) -> ZoneDiff {
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        let zone_diff = rollback_diff(&name("example.com."), None, to, &mut con)
+            .await
+            .unwrap()
+            .unwrap();
+        apply_rollback(
+            &request(to),
+            &changed_idents(&zone_diff),
+            &author(),
+            state,
+            &mut con,
+            &mut dnssec_con,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn rolls_back_to_serial() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+        let mut con = state.db_pool.get().await.unwrap();
+        let nsec3_before = nsec3_entries("example.com.", &mut con).await;
+        set_records(
+            &[
+                a_entry("www.example.com.", [192, 0, 2, 2]),
+                a_entry("mail.example.com.", [192, 0, 2, 3]),
+            ],
+            &state,
+        )
+        .await;
+        assert_ne!(nsec3_entries("example.com.", &mut con).await, nsec3_before);
+
+        let zone_diff = roll_back(RollbackTarget::Serial(2), &state).await;
+        assert_eq!(zone_diff.removed.len(), 1);
+        assert_eq!(zone_diff.removed[0].name, name("mail.example.com."));
+        assert_eq!(zone_diff.modified.len(), 1);
+
+        let (www, rrsig) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(a_value(&www.unwrap()), vec![[192, 0, 2, 1]]);
+        assert!(rrsig.is_some());
+        let (mail, rrsig) = get_with_rrsig("mail.example.com.", RecordType::A, &state).await;
+        assert!(mail.is_none() && rrsig.is_none());
+        // the serial is incremented instead of restored
+        assert_eq!(zone_serial("example.com.", &state).await, Some(4));
+        // the NSEC3 chain is recomputed and its changes are journaled
+        assert_eq!(nsec3_entries("example.com.", &mut con).await, nsec3_before);
+        let journal = get_journal(&name("example.com."), &mut con).await.unwrap();
+        assert!(journal[2]
+            .removed
+            .iter()
+            .any(|e| e.rr_type() == RecordType::NSEC3));
+
+        // rolling back to the current serial changes nothing
+        let zone_diff = rollback_diff(
+            &name("example.com."),
+            None,
+            RollbackTarget::Serial(4),
+            &mut con,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(changed_idents(&zone_diff).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn rolls_back_to_timestamp() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let before_www = now_millis();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let after_www = now_millis();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 2])], &state).await;
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let zone_diff = rollback_diff(
+            &name("example.com."),
+            None,
+            RollbackTarget::Timestamp(after_www),
+            &mut con,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(zone_diff.added.is_empty() && zone_diff.removed.is_empty());
+        assert_eq!(zone_diff.modified.len(), 1);
+        assert_eq!(a_value(&zone_diff.modified[0].after), vec![[192, 0, 2, 1]]);
+
+        // the journal starts with the creation of www, so it reaches back to before that
+        let zone_diff = roll_back(RollbackTarget::Timestamp(before_www), &state).await;
+        assert_eq!(zone_diff.removed.len(), 1);
+        let (www, _) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert!(www.is_none());
+    }
+
+    #[actix_web::test]
+    async fn fails_if_the_journal_does_not_reach_back() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let before_www = now_millis();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 2])], &state).await;
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let res = rollback_diff(
+            &name("example.com."),
+            None,
+            RollbackTarget::Serial(0),
+            &mut con,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            res.unwrap_err(),
+            "The journal of the zone doesn't reach back to serial 0."
+        );
+
+        // the journal was started at serial 1
+        assert_eq!(
+            con.get_value(&journal_start_db_key(&name("example.com.")))
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("1")
+        );
+        // pretend that the journal was started earlier and its oldest entries were trimmed, so
+        // the zone may have been changed between the given time and the oldest entry
+        con.set_value(&journal_start_db_key(&name("example.com.")), "0")
+            .await
+            .unwrap();
+        let res = rollback_diff(
+            &name("example.com."),
+            None,
+            RollbackTarget::Timestamp(before_www),
+            &mut con,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            res.unwrap_err(),
+            "The journal of the zone doesn't reach back to the given time."
+        );
+        // entries that are still in the journal can be undone nevertheless
+        let res = rollback_diff(
+            &name("example.com."),
+            None,
+            RollbackTarget::Serial(2),
+            &mut con,
+        )
+        .await
+        .unwrap();
+        assert!(res.is_ok());
+    }
+}
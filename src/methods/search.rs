@@ -1,18 +1,34 @@
+use std::convert::Infallible;
 use std::ops::Deref;
 
-use actix_web::{post, web, HttpRequest, Responder};
-use pektin_common::deadpool_redis::redis::AsyncCommands;
+use actix_web::{post, web, web::Bytes, HttpRequest, HttpResponse, Responder};
+use data_encoding::BASE64URL_NOPAD;
+use pektin_common::proto::rr::Name;
 use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tracing::{info_span, Instrument};
 
 use crate::{
     auth::auth_ok,
+    db::{get_or_mget_records, rr_set_version},
     errors_and_responses::{
-        auth_err, err, internal_err, partial_success_with_data, success_with_toplevel_data,
+        auth_err, err, internal_err, partial_success_with_data, response_with_data,
+        success_with_toplevel_data, PektinApiResult,
+    },
+    query::CompiledQuery,
+    storage::{Connection, Storage},
+    types::{
+        AppState, Glob, RecordIdentifier, ResponseType, SearchPage, SearchQuery, SearchRequestBody,
+        SearchResult, SearchStreamItem, VersionedRecordIdentifier,
     },
-    types::{AppState, Glob, RecordIdentifier, ResponseType, SearchRequestBody},
 };
 
+/// How many results are sent to the client at once when streaming.
+const STREAM_BATCH_SIZE: usize = 100;
+/// How many batches of streamed results may be buffered until the client receives them.
+const STREAM_BUFFER: usize = 4;
+
 #[post("/search")]
 pub async fn search(
     req: HttpRequest,
@@ -31,7 +47,7 @@ pub async fn search(
         )
         .await;
         if auth.success {
-            if req_body.globs.is_empty() {
+            if req_body.globs.is_empty() && req_body.queries.is_empty() {
                 return success_with_toplevel_data("Searched keys", json!([]));
             }
 
@@ -43,24 +59,72 @@ pub async fn search(
                     .collect();
                 return err("One or more globs were invalid.", messages);
             }
+            let queries: Vec<_> = req_body.queries.iter().map(SearchQuery::compile).collect();
+            if queries.iter().any(|q| q.is_err()) {
+                let messages = queries.into_iter().map(Result::err).collect();
+                return err("One or more queries were invalid.", messages);
+            }
+            let queries: Vec<_> = queries.into_iter().flatten().collect();
+
+            if req_body.limit == Some(0) {
+                return err(
+                    "The limit must be greater than zero.",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+            if req_body.cursor.is_some() && req_body.limit.is_none() && !req_body.stream {
+                return err(
+                    "A cursor can only be used together with a limit or streaming.",
+                    Vec::<Option<String>>::new(),
+                );
+            }
+            let cursor = match &req_body.cursor {
+                Some(c) => match SearchCursor::decode(c) {
+                    Some(c) => c,
+                    None => return err("The cursor is invalid.", Vec::<Option<String>>::new()),
+                },
+                None => SearchCursor::default(),
+            };
 
             let mut con = match state.db_pool.get().await {
                 Ok(c) => c,
                 Err(_) => return internal_err("No db connection."),
             };
 
-            let mut found_keys = Vec::with_capacity(req_body.globs.len());
-            for glob in &req_body.globs {
-                match con.keys::<_, Vec<String>>(&glob.as_db_glob()).await {
-                    Ok(keys) => {
-                        let records: Result<Vec<_>, _> =
-                            keys.iter().map(RecordIdentifier::from_db_key).collect();
-                        match records {
-                            Ok(r) => found_keys.push((ResponseType::Success, "Searched glob", r)),
-                            Err(e) => return internal_err(e.to_string()),
-                        }
-                    }
+            let key_search = match KeySearch::new(&req_body.globs, queries, cursor, &mut con).await
+            {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => return err(e, Vec::<Option<String>>::new()),
+                Err(e) => return internal_err(e.to_string()),
+            };
+            if req_body.stream {
+                return stream_search(key_search, req_body.limit, req_body.include_records, con);
+            }
+            if let Some(limit) = req_body.limit {
+                return match search_page(key_search, limit, req_body.include_records, &mut con)
+                    .await
+                {
+                    Ok(page) => success_with_toplevel_data("Searched globs", page),
+                    Err(e) => internal_err(e.to_string()),
+                };
+            }
+
+            let mut found_keys = Vec::with_capacity(key_search.sources.len());
+            for source in &key_search.sources {
+                let message = match source.query {
+                    Some(_) => "Searched query",
+                    None => "Searched glob",
+                };
+                let keys = match con.scan_keys(source.zone.as_ref(), &source.glob).await {
+                    Ok(k) => k,
                     Err(_) => return internal_err("Could not search the database."),
+                };
+                match key_search
+                    .results(source, &keys, req_body.include_records, &mut con)
+                    .await
+                {
+                    Ok(r) => found_keys.push((ResponseType::Success, message, r)),
+                    Err(e) => return internal_err(e.to_string()),
                 }
             }
             partial_success_with_data(ResponseType::Success, "Searched globs", found_keys)
@@ -72,3 +136,484 @@ pub async fn search(
     .instrument(span)
     .await
 }
+
+/// The position of a search in its sources and the keyspace: the index of the current source, the
+/// `SCAN` cursor, and the number of keys that were already returned from the current `SCAN` step.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SearchCursor {
+    source: usize,
+    scan: u64,
+    skip: usize,
+}
+
+impl SearchCursor {
+    /// Encodes the cursor as an opaque string for the client.
+    fn encode(&self) -> String {
+        BASE64URL_NOPAD.encode(format!("{}.{}.{}", self.source, self.scan, self.skip).as_bytes())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let decoded = BASE64URL_NOPAD.decode(cursor.as_bytes()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let mut parts = decoded.split('.').map(str::parse::<u64>);
+        let cursor = Self {
+            source: parts.next()?.ok()?.try_into().ok()?,
+            scan: parts.next()?.ok()?,
+            skip: parts.next()?.ok()?.try_into().ok()?,
+        };
+        parts.next().is_none().then_some(cursor)
+    }
+}
+
+/// The keys that are searched for a glob or query: those matching `glob`, either in the whole db
+/// or in `zone` (the zone that a query is limited to). The keys of a query are then filtered by
+/// the query.
+struct SearchSource {
+    zone: Option<Name>,
+    glob: String,
+    query: Option<CompiledQuery>,
+}
+
+/// Iterates over the keys of the sources, one source after another, using `Storage::scan_step()`.
+struct KeySearch {
+    sources: Vec<SearchSource>,
+    /// All zones, if a query is limited to a zone.
+    zones: Vec<Name>,
+    cursor: SearchCursor,
+}
+
+impl KeySearch {
+    /// Creates a search for the globs followed by the queries, or returns an error message if a
+    /// query is limited to a zone that doesn't exist.
+    async fn new(
+        globs: &[Glob],
+        queries: Vec<CompiledQuery>,
+        cursor: SearchCursor,
+        con: &mut Connection,
+    ) -> PektinApiResult<Result<Self, String>> {
+        let mut sources: Vec<_> = globs
+            .iter()
+            .map(|glob| SearchSource {
+                zone: None,
+                glob: glob.as_db_glob(),
+                query: None,
+            })
+            .collect();
+
+        let mut zones = vec![];
+        if queries.iter().any(|q| q.zone().is_some()) {
+            zones = con
+                .get_zones()
+                .await?
+                .into_iter()
+                .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
+                .collect();
+        }
+        for query in queries {
+            if let Some(zone) = query.zone() {
+                if !zones.contains(zone) {
+                    return Ok(Err(format!("The zone {zone} does not exist.")));
+                }
+            }
+            sources.push(SearchSource {
+                zone: query.zone().cloned(),
+                glob: query.db_glob().to_string(),
+                query: Some(query),
+            });
+        }
+        Ok(Ok(Self {
+            sources,
+            zones,
+            cursor,
+        }))
+    }
+
+    /// Returns the index of a source and up to `max` keys of it, or `None` if all sources have
+    /// been searched. A key may be returned more than once.
+    async fn next_keys(
+        &mut self,
+        max: usize,
+        con: &mut Connection,
+    ) -> PektinApiResult<Option<(usize, Vec<String>)>> {
+        loop {
+            let source_idx = self.cursor.source;
+            let source = match self.sources.get(source_idx) {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            let (next, keys) = con
+                .scan_step(source.zone.as_ref(), self.cursor.scan, &source.glob)
+                .await?;
+            // the keys of this step that were already returned are skipped; if the step returns
+            // fewer keys than before, the db was changed and some keys may be missed
+            let mut keys: Vec<_> = keys.into_iter().skip(self.cursor.skip).collect();
+            if keys.len() > max {
+                keys.truncate(max);
+                self.cursor.skip += max;
+                return Ok(Some((source_idx, keys)));
+            }
+            self.cursor.skip = 0;
+            if next == 0 {
+                self.cursor.source += 1;
+                self.cursor.scan = 0;
+            } else {
+                self.cursor.scan = next;
+            }
+            if !keys.is_empty() {
+                return Ok(Some((source_idx, keys)));
+            }
+        }
+    }
+
+    /// Returns the index of a source and the results for up to `max` of its keys (see
+    /// `next_keys()`). There may be fewer results than keys if the source is a query.
+    async fn next_results(
+        &mut self,
+        max: usize,
+        include_records: bool,
+        con: &mut Connection,
+    ) -> PektinApiResult<Option<(usize, Vec<SearchResult>)>> {
+        match self.next_keys(max, con).await? {
+            Some((source_idx, keys)) => {
+                let source = &self.sources[source_idx];
+                let results = self.results(source, &keys, include_records, con).await?;
+                Ok(Some((source_idx, results)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Creates the results for the given keys of the source, see `search_results()`.
+    async fn results(
+        &self,
+        source: &SearchSource,
+        keys: &[String],
+        include_records: bool,
+        con: &mut Connection,
+    ) -> PektinApiResult<Vec<SearchResult>> {
+        let query = match &source.query {
+            Some(q) => q,
+            None => return search_results(keys, include_records, con).await,
+        };
+
+        let mut matching_keys = Vec::with_capacity(keys.len());
+        for key in keys {
+            if query.matches_ident(&RecordIdentifier::from_db_key(key)?, &self.zones) {
+                matching_keys.push(key.clone());
+            }
+        }
+        let mut results = search_results(&matching_keys, true, con).await?;
+        // RR sets that were deleted in the meantime don't match
+        let mut matching = Vec::with_capacity(results.len());
+        for result in &results {
+            matching.push(match &result.record {
+                Some(entry) => query.matches_entry(entry)?,
+                None => false,
+            });
+        }
+        let mut matching = matching.into_iter();
+        results.retain(|_| matching.next().unwrap_or(false));
+        if !include_records {
+            for result in &mut results {
+                result.record = None;
+            }
+        }
+        Ok(results)
+    }
+
+    /// The cursor to continue the search with, or `None` if it is complete.
+    fn next_cursor(&self) -> Option<String> {
+        (self.cursor.source < self.sources.len()).then(|| self.cursor.encode())
+    }
+}
+
+/// Returns up to `limit` results of the search.
+async fn search_page(
+    mut key_search: KeySearch,
+    limit: usize,
+    include_records: bool,
+    con: &mut Connection,
+) -> PektinApiResult<SearchPage> {
+    let mut results = vec![vec![]; key_search.sources.len()];
+    let mut found = 0;
+    while found < limit {
+        match key_search
+            .next_results(limit - found, include_records, con)
+            .await?
+        {
+            Some((source_idx, source_results)) => {
+                found += source_results.len();
+                results[source_idx].extend(source_results);
+            }
+            None => break,
+        }
+    }
+    Ok(SearchPage {
+        results,
+        cursor: key_search.next_cursor(),
+    })
+}
+
+/// Streams the results of the search (up to `limit` if given) as newline-delimited JSON. The
+/// search runs in a separate task that stops when the client disconnects.
+fn stream_search(
+    mut key_search: KeySearch,
+    limit: Option<usize>,
+    include_records: bool,
+    mut con: Connection,
+) -> HttpResponse {
+    let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(STREAM_BUFFER);
+    tokio::spawn(async move {
+        let mut found = 0;
+        let res: PektinApiResult<()> = async {
+            while limit.is_none_or(|limit| found < limit) {
+                let max = limit.map_or(STREAM_BATCH_SIZE, |limit| {
+                    (limit - found).min(STREAM_BATCH_SIZE)
+                });
+                let (source_idx, results) = match key_search
+                    .next_results(max, include_records, &mut con)
+                    .await?
+                {
+                    Some(r) => r,
+                    None => break,
+                };
+                if results.is_empty() {
+                    continue;
+                }
+                found += results.len();
+                let mut lines = Vec::new();
+                for result in results {
+                    let item = SearchStreamItem {
+                        glob: source_idx,
+                        result,
+                    };
+                    serde_json::to_writer(&mut lines, &item)?;
+                    lines.push(b'\n');
+                }
+                if tx.send(Ok(lines.into())).await.is_err() {
+                    // the client disconnected
+                    return Ok(());
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        let last = match res {
+            Ok(()) => json!(response_with_data(
+                ResponseType::Success,
+                "Searched globs",
+                json!({ "cursor": key_search.next_cursor() }),
+            )),
+            Err(e) => json!(response_with_data(
+                ResponseType::Error,
+                e.to_string(),
+                json!(null)
+            )),
+        };
+        let _ = tx.send(Ok(format!("{last}\n").into())).await;
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(ReceiverStream::new(rx))
+}
+
+/// Creates the results for the RR sets with the given keys: their identifiers together with their
+/// versions and, if `include_records` is true, the RR sets themselves. The version and RR set are
+/// `None` if the RR set was deleted after its key was found.
+async fn search_results(
+    keys: &[String],
+    include_records: bool,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<SearchResult>> {
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+    let entries = get_or_mget_records(keys, con).await?;
+    keys.iter()
+        .zip(entries)
+        .map(|(key, entry)| {
+            Ok(SearchResult {
+                ident: VersionedRecordIdentifier {
+                    ident: RecordIdentifier::from_db_key(key)?,
+                    version: entry.as_ref().map(rr_set_version).transpose()?,
+                },
+                record: entry.filter(|_| include_records),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use actix_web::http::StatusCode;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_utils::{
+        a_entry, call_endpoint, call_endpoint_raw, set_records, soa_entry, test_state,
+    };
+
+    fn request(limit: Option<usize>, cursor: Option<&str>, stream: bool) -> Value {
+        json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "globs": [
+                { "name_glob": "*.example.com.", "rr_type_glob": "A" },
+                { "name_glob": "example.com.", "rr_type_glob": "SOA" },
+            ],
+            "limit": limit,
+            "cursor": cursor,
+            "stream": stream,
+        })
+    }
+
+    async fn test_zone() -> AppState {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let records: Vec<_> = (1..=5)
+            .map(|i| a_entry(&format!("host{i}.example.com."), [192, 0, 2, i]))
+            .collect();
+        set_records(&records, &state).await;
+        state
+    }
+
+    /// The names and types of the results of each glob.
+    fn result_keys(results: &Value) -> Vec<BTreeSet<String>> {
+        results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|results| {
+                results
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|r| {
+                        format!(
+                            "{}:{}",
+                            r["name"].as_str().unwrap(),
+                            r["rr_type"].as_str().unwrap()
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cursors_are_opaque_and_checked() {
+        let cursor = SearchCursor {
+            source: 1,
+            scan: 42,
+            skip: 3,
+        };
+        assert_eq!(SearchCursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(SearchCursor::decode("not a cursor"), None);
+        for invalid in ["1.2", "1.2.3.4", "1.-2.3", "a.b.c"] {
+            assert_eq!(
+                SearchCursor::decode(&BASE64URL_NOPAD.encode(invalid.as_bytes())),
+                None
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn pages_continue_at_the_cursor() {
+        let state = test_zone().await;
+        let mut cursor = None;
+        let mut pages = vec![];
+        loop {
+            let (status, body) = call_endpoint(
+                search,
+                &state,
+                "/search",
+                request(Some(2), cursor.as_deref(), false),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+            pages.push(result_keys(&body["data"]["results"]));
+            match body["data"]["cursor"].as_str() {
+                Some(c) => cursor = Some(c.to_string()),
+                None => break,
+            }
+        }
+        // the last page contains the rest of the A records and the SOA record of the second glob
+        assert_eq!(
+            pages
+                .iter()
+                .map(|p| p.iter().map(BTreeSet::len).collect())
+                .collect::<Vec<Vec<_>>>(),
+            vec![vec![2, 0], vec![2, 0], vec![1, 1]]
+        );
+        let a_records: BTreeSet<_> = pages.iter().flat_map(|p| p[0].clone()).collect();
+        assert_eq!(
+            a_records,
+            (1..=5).map(|i| format!("host{i}.example.com.:A")).collect()
+        );
+
+        let (status, body) = call_endpoint(
+            search,
+            &state,
+            "/search",
+            request(Some(2), Some("invalid"), false),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], "The cursor is invalid.");
+        let (status, body) = call_endpoint(
+            search,
+            &state,
+            "/search",
+            request(None, cursor.as_deref(), false),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body["message"],
+            "A cursor can only be used together with a limit or streaming."
+        );
+    }
+
+    #[actix_web::test]
+    async fn streams_end_with_the_cursor() {
+        let state = test_zone().await;
+        let stream = |limit, cursor: Option<String>| {
+            let state = state.clone();
+            async move {
+                let (status, body) = call_endpoint_raw(
+                    search,
+                    &state,
+                    "/search",
+                    request(limit, cursor.as_deref(), true),
+                )
+                .await;
+                assert_eq!(status, StatusCode::OK);
+                let mut lines: Vec<Value> = body
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(|line| serde_json::from_slice(line).unwrap())
+                    .collect();
+                let last = lines.pop().unwrap();
+                assert_eq!(last["type"], "success");
+                (lines, last["data"]["cursor"].as_str().map(str::to_string))
+            }
+        };
+
+        let (lines, cursor) = stream(None, None).await;
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines.iter().filter(|l| l["glob"] == 0).count(), 5);
+        assert_eq!(lines[5]["glob"], 1);
+        assert_eq!(cursor, None);
+
+        let (lines, cursor) = stream(Some(3), None).await;
+        assert_eq!(lines.len(), 3);
+        assert!(cursor.is_some());
+        let (lines, cursor) = stream(Some(3), cursor).await;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[2]["glob"], 1);
+        assert_eq!(cursor, None);
+    }
+}
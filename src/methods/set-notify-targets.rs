@@ -0,0 +1,98 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, Responder};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    errors_and_responses::{auth_err, err, internal_err, success_with_toplevel_data},
+    notify::set_notify_targets as set_notify_targets_in_db,
+    storage::Storage,
+    tsig::key_allowed_for_zone,
+    types::{AppState, SetNotifyTargetsRequestBody},
+};
+
+#[post("/set-notify-targets")]
+pub async fn set_notify_targets(
+    req: HttpRequest,
+    req_body: web::Json<SetNotifyTargetsRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "set-notify-targets",
+        client_username = %req_body.client_username,
+        zone = %req_body.zone,
+        targets = ?req_body.targets
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            // the messages correspond to the targets
+            let valid: Vec<_> = req_body
+                .targets
+                .iter()
+                .map(|target| {
+                    let key_name = target.tsig_key.as_ref()?;
+                    match state
+                        .tsig_keys
+                        .iter()
+                        .find(|k| k.name.to_lowercase() == key_name.to_lowercase())
+                    {
+                        None => Some(format!("No TSIG key named {} is configured", key_name)),
+                        Some(key)
+                            if !key_allowed_for_zone(
+                                &state.tsig_key_zones,
+                                key,
+                                &req_body.zone,
+                            ) =>
+                        {
+                            Some(format!(
+                                "The TSIG key {} is not allowed for zone {}",
+                                key_name, req_body.zone
+                            ))
+                        }
+                        Some(_) => None,
+                    }
+                })
+                .collect();
+            if valid.iter().any(Option::is_some) {
+                return err("One or more notify targets were invalid.", valid);
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            match con.get_zones().await {
+                Ok(zones) if zones.contains(&req_body.zone.to_lowercase().to_string()) => {}
+                Ok(_) => {
+                    return err(
+                        "The zone does not exist.",
+                        req_body.targets.iter().map(|_| None::<String>).collect(),
+                    )
+                }
+                Err(e) => return internal_err(e.to_string()),
+            }
+
+            match set_notify_targets_in_db(&req_body.zone, &req_body.targets, &mut con).await {
+                Ok(()) => success_with_toplevel_data(
+                    format!("set {} notify targets", req_body.targets.len()),
+                    req_body.targets.len(),
+                ),
+                Err(e) => internal_err(e.to_string()),
+            }
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -1,40 +1,33 @@
 use std::{collections::HashMap, ops::Deref};
 
-use actix_web::{post, web, HttpRequest, Responder};
-use pektin_common::deadpool_redis::redis::AsyncCommands;
-use pektin_common::{DbEntry, PektinCommonError, RrSet};
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, RrSet};
 use serde_json::json;
-use tracing::{info_span, Instrument};
+use tracing::{info_span, instrument, Instrument};
 
-use crate::db::get_zone_dnskey_records;
-use crate::dnssec::create_nsec3_chain;
+use crate::db::{
+    acquire_locks, check_versions, get_or_mget_records, get_zone_dnskey_records, release_locks,
+    rr_set_version, DbWriteBatch,
+};
+use crate::dnssec::{update_nsec3_chain, Nsec3ChainUpdate};
+use crate::errors_and_responses::{PektinApiError, PektinApiResult};
+use crate::events::{publish_change_events, ChangeEvent};
+use crate::journal::{commit_zone_changes, prepare_zone_changes, ChangeAuthor, ZoneChanges};
+use crate::macros::unwrap_or_return_if_err;
 use crate::utils::find_authoritative_zone;
 use crate::{
     auth::auth_ok,
     dnssec::{get_dnskey_for_zone, sign_db_entry},
-    errors_and_responses::{auth_err, err, internal_err, success, success_with_toplevel_data},
-    types::{AppState, SetRequestBody},
+    errors_and_responses::{
+        auth_err, conflict_err, internal_err, partial_success_with_data, success_with_toplevel_data,
+    },
+    storage::{Connection, Storage},
+    types::{AppState, ChangePlan, RecordIdentifier, ResponseType, RrSetChange, SetRequestBody},
     validation::{check_soa, validate_records},
     vault,
 };
 
-// TODO: this is probably also useful for all other methods
-/// Takes `var`, a `Vec<Result<T, E>>`, and turns it into a `Vec<T>` if all results are `Ok`.
-/// If any result is `Err`, a vector of all error messages is built and returned using `err()`
-/// together with the given error message.
-macro_rules! unwrap_or_return_if_err {
-    ($var:ident, $err_msg:expr) => {
-        if $var.iter().any(|r| r.is_err()) {
-            let messages = $var
-                .iter()
-                .map(|res| res.as_ref().err().map(|e| e.to_string()))
-                .collect();
-            return err($err_msg, messages);
-        }
-        let $var = $var.into_iter().map(|res| res.unwrap()).collect::<Vec<_>>();
-    };
-}
-
 #[post("/set")]
 pub async fn set(
     req: HttpRequest,
@@ -59,6 +52,7 @@ pub async fn set(
             if req_body.records.is_empty() {
                 return success_with_toplevel_data("set records", json!([]));
             }
+            let records: Vec<_> = req_body.records.iter().map(|r| r.entry.clone()).collect();
 
             // get db cons
             let mut con = match state.db_pool.get().await {
@@ -72,150 +66,105 @@ pub async fn set(
             };
 
             // validate
-            let _valid = validate_records(&req_body.records);
+            let _valid = validate_records(&records);
             unwrap_or_return_if_err!(_valid, "One or more records were invalid.");
 
             let (_soa_check, used_zones, new_authoritative_zones) =
-                match check_soa(&req_body.records, &mut con).await {
+                match check_soa(&records, &mut con).await {
                     Ok(s) => s,
                     Err(e) => return internal_err(e.to_string()),
                 };
-            unwrap_or_return_if_err!(_soa_check, "Tried to set one or more records for a zone that does not have a SOA record.");
+            unwrap_or_return_if_err!(
+                _soa_check,
+                "Tried to set one or more records for a zone that does not have a SOA record."
+            );
 
-            let vault_api_token = match vault::ApiTokenCache::get(
-                &state.vault_uri,
-                &state.vault_user_name,
-                &state.vault_password,
-            )
-            .await
-            {
-                Ok(t) => t,
-                Err(_) => return internal_err("Couldnt get vault api token"),
-            };
-
-            let zones_to_fetch_dnskeys_for: Vec<_> = used_zones
+            let idents: Vec<_> = records
                 .iter()
-                .filter(|zone| !new_authoritative_zones.contains(zone))
-                .cloned()
-                .collect();
-            let dnskeys = match get_zone_dnskey_records(
-                &zones_to_fetch_dnskeys_for,
-                &mut con,
-            ).await {
-                Ok(d) => d,
-                Err(e) => return internal_err(e.to_string()),
-            };
-
-            let mut dnskeys_for_new_zones = Vec::with_capacity(new_authoritative_zones.len());
-            for zone in &new_authoritative_zones {
-                let dnskey = get_dnskey_for_zone(zone, &state.vault_uri, &vault_api_token).await;
-                dnskeys_for_new_zones.push(dnskey.map(|d| (zone.clone(), d)));
-            }
-            unwrap_or_return_if_err!(dnskeys_for_new_zones, "Couldn't set DNSKEY for one or more newly created zones because Vault has no signer for this zone.");
-
-            let dnskey_for_zone: HashMap<_, _> = dnskeys
-                .into_iter()
-                .chain(dnskeys_for_new_zones.clone().into_iter())
-                .collect();
-
-            let new_dnskey_records: Vec<_> = dnskeys_for_new_zones
-                .into_iter()
-                .map(|(zone, dnskey)| DbEntry {
-                    name: zone,
-                    meta:"".to_string(),
-                    // TODO: don't hardcode DNSKEY TTL
-                    ttl: 3600,
-                    rr_set: RrSet::DNSKEY {
-                        rr_set: vec![dnskey],
-                    },
-                })
-                .collect();
-
-            // TODO once we support separate KSK and ZSK, sign the ZSK with the KSK
-            // until then we just sign the KSK with itself
-
-            let mut rrsig_records = Vec::with_capacity(req_body.records.len());
-            for record in req_body.records.iter().chain(new_dnskey_records.iter()) {
-                let record_zone = find_authoritative_zone(&record.name, &used_zones).expect("no zone is authoritative for record");
-                let dnskey = dnskey_for_zone.get(&record_zone).expect("failed to get dnskey for zone");
-                let rec = sign_db_entry(
-                    &record_zone,
-                    record.clone(),
-                    dnskey,
-                    &state.vault_uri,
-                    &vault_api_token,
-                )
-                .await;
-                rrsig_records.push(rec);
-            }
-
-            unwrap_or_return_if_err!(rrsig_records, "Could not sign one or more records.");
-
-            // TODO:
-            // - re-generate and re-sign NSEC records
-
-            let entries_length = req_body.records.len();
-            let entries: Result<Vec<_>, _> = req_body
-                .records
-                .iter()
-                .chain(new_dnskey_records.iter())
-                .map(|e| match e.serialize_for_db() {
-                    Ok(ser) => Ok((e.db_key(), ser)),
-                    Err(e) => Err(e),
-                })
-                .collect();
-
-            let rrsig_records: Result<Vec<_>, _> = rrsig_records
-                .iter()
-                .map(|e| match e.serialize_for_db() {
-                    Ok(ser) => Ok((e.db_key(), ser)),
-                    Err(e) => Err(e),
+                .map(|r| RecordIdentifier {
+                    name: r.name.clone(),
+                    rr_type: r.rr_type(),
                 })
                 .collect();
+            let expected_versions: Vec<_> =
+                req_body.records.iter().map(|r| r.version.clone()).collect();
 
-            match rrsig_records {
-                Err(e) => return internal_err(e.to_string()),
-                Ok(rrsig_records) if !rrsig_records.is_empty() => {
-                    if let Err(e) = dnssec_con.set_multiple::<_, _, ()>(&rrsig_records).await {
-                        return internal_err(PektinCommonError::from(e).to_string());
+            if req_body.dry_run {
+                match check_versions(&idents, &expected_versions, &mut con).await {
+                    Ok(c) if c.iter().any(Option::is_some) => {
+                        return conflict_err("One or more RR sets were changed in the meantime.", c)
                     }
+                    Ok(_) => {}
+                    Err(e) => return internal_err(e.to_string()),
                 }
-                _ => {
-                    println!("{:?}", req_body.records);
-                }
+                return match plan_changes(
+                    &records,
+                    &[],
+                    &used_zones,
+                    &new_authoritative_zones,
+                    &mut con,
+                )
+                .await
+                {
+                    Ok(plan) => success_with_toplevel_data("planned changes", plan),
+                    Err(e) => internal_err(e.to_string()),
+                };
             }
 
-            let res = match entries {
-                Err(e) => internal_err(e.to_string()),
-                Ok(entries) => match con.set_multiple(&entries).await {
-                    Ok(()) => {
-                        let messages = entries[0..entries_length]
-                            .iter()
-                            .map(|_| "set record")
-                            .collect();
-                        success("set records", messages)
+            // if the client expects versions, the RR sets must not change between checking the
+            // versions and setting the records
+            let locks = if expected_versions.iter().any(Option::is_some) {
+                let locks = match acquire_locks(&idents, &mut con).await {
+                    Ok(l) => l,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+                let conflicts = check_versions(&idents, &expected_versions, &mut con).await;
+                let conflicted = !matches!(&conflicts, Ok(c) if c.iter().all(Option::is_none));
+                if conflicted {
+                    if let Err(e) = release_locks(locks, &mut con).await {
+                        return internal_err(e.to_string());
                     }
-                    Err(e) => {
-                        let to_be_deleted: Vec<String> = entries[entries_length..]
-                            .to_vec()
-                            .iter()
-                            .map(|e| e.0.clone())
-                            .collect();
-                        match dnssec_con.del::<_, u32>(to_be_deleted).await {
-                            Err(ee) => internal_err(format!("FATAL: POSSIBLE INCONSISTENCY: Setting non DNSSEC records failed, while setting DNSSEC records succeeded. The removal of the successful set DNSSEC records failed again. {}{}", PektinCommonError::from(e), ee)),
-                            Ok(_) => internal_err(PektinCommonError::from(e).to_string()),
+                    return match conflicts {
+                        Ok(c) => {
+                            conflict_err("One or more RR sets were changed in the meantime.", c)
                         }
-                    }
-                },
+                        Err(e) => internal_err(e.to_string()),
+                    };
+                }
+                locks
+            } else {
+                vec![]
             };
 
-            // TODO: set NSEC3(PARAM) records
-            for zone in &new_authoritative_zones {
-                let chain = format!("{:#?}", create_nsec3_chain(zone, 600, &mut con).await);
-                tracing::debug!(%chain, "NSEC3 chain for {zone}");
+            let res = sign_and_set_records(
+                &records,
+                &used_zones,
+                &new_authoritative_zones,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await;
+            if let Err(e) = release_locks(locks, &mut con).await {
+                return internal_err(e.to_string());
+            }
+            if let Err(res) = res {
+                return res;
             }
 
-            res
+            let mut messages = Vec::with_capacity(records.len());
+            for record in &records {
+                match rr_set_version(record) {
+                    Ok(version) => messages.push((
+                        ResponseType::Success,
+                        "set record",
+                        json!({ "version": version }),
+                    )),
+                    Err(e) => return internal_err(e.to_string()),
+                }
+            }
+            partial_success_with_data(ResponseType::Success, "set records", messages)
         } else {
             auth.message.push('\n');
             auth_err(auth.message)
@@ -224,3 +173,521 @@ pub async fn set(
     .instrument(span)
     .await
 }
+
+/// Signs the given records and stores them and their RRSIGs in the db. For all zones in
+/// `new_authoritative_zones`, a DNSKEY record is created and signed as well.
+///
+/// `used_zones` and `new_authoritative_zones` are the values returned by `check_soa()`. This must
+/// only be called after `validate_records()` and `check_soa()` succeeded for the records.
+///
+/// If something goes wrong, the response that should be returned to the client is returned as
+/// the error.
+///
+/// The SOA serials of all changed zones that already existed are incremented and the changes are
+/// recorded in the zone journals as made by `author`. A change event is published for every
+/// changed and created zone.
+#[instrument(skip(state, con, dnssec_con))]
+pub async fn sign_and_set_records(
+    records: &[DbEntry],
+    used_zones: &[Name],
+    new_authoritative_zones: &[Name],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    sign_and_write_changes(
+        records,
+        &[],
+        used_zones,
+        new_authoritative_zones,
+        author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await
+}
+
+/// Like `sign_and_set_records()`, but additionally deletes the RR sets `deleted` and their RRSIGs.
+/// The records, RRSIGs, NSEC3 records, new SOA serials, and journal entries are written in a single
+/// db transaction, so either all of the changes are visible or none. The changed zones are locked
+/// while the changes are computed and written.
+///
+/// The deleted RR sets must not include SOA records, i.e. whole zones can't be deleted with this.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(state, con, dnssec_con))]
+pub async fn sign_and_write_changes(
+    records: &[DbEntry],
+    deleted: &[RecordIdentifier],
+    used_zones: &[Name],
+    new_authoritative_zones: &[Name],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    let vault_api_token = match vault::ApiTokenCache::get(
+        &state.vault_uri,
+        &state.vault_user_name,
+        &state.vault_password,
+    )
+    .await
+    {
+        Ok(t) => t,
+        Err(_) => return Err(internal_err("Couldnt get vault api token")),
+    };
+
+    // lock the changed zones and remember the previous state of the changed RR sets for the zone
+    // journals
+    let idents: Vec<_> = records
+        .iter()
+        .map(|r| RecordIdentifier {
+            name: r.name.clone(),
+            rr_type: r.rr_type(),
+        })
+        .chain(deleted.iter().cloned())
+        .collect();
+    let mut zone_changes = match prepare_zone_changes(&idents, con, dnssec_con).await {
+        Ok(c) => c,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+
+    let batch = match sign_changes(
+        records,
+        deleted,
+        used_zones,
+        new_authoritative_zones,
+        state,
+        &vault_api_token,
+        con,
+    )
+    .await
+    {
+        Ok(b) => b,
+        Err(res) => {
+            zone_changes.abort(&state.db_pool);
+            return Err(res);
+        }
+    };
+    if let Err(e) = add_nsec3_changes(&mut zone_changes, &batch, con, dnssec_con).await {
+        zone_changes.abort(&state.db_pool);
+        return Err(internal_err(e.to_string()));
+    }
+    if let Err(e) = commit_zone_changes(
+        zone_changes,
+        batch,
+        state,
+        &vault_api_token,
+        Some(author),
+        con,
+    )
+    .await
+    {
+        return Err(internal_err(e.to_string()));
+    }
+
+    let created_zone_events: Vec<_> = new_authoritative_zones
+        .iter()
+        .filter_map(|zone| {
+            records
+                .iter()
+                .find(|r| &r.name == zone && r.rr_type() == RecordType::SOA)
+                .map(|soa| ChangeEvent::zone_created(zone, soa))
+        })
+        .collect();
+    publish_change_events(&created_zone_events, con).await;
+
+    Ok(())
+}
+
+/// Records the current state of the NSEC3 RR sets that the batch changes in `zone_changes`, so
+/// that they are part of the journal entries. They are only known once the batch is computed.
+pub async fn add_nsec3_changes(
+    zone_changes: &mut ZoneChanges,
+    batch: &DbWriteBatch,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<()> {
+    let nsec3_idents = batch.nsec3_idents()?;
+    zone_changes
+        .add_rr_sets(&nsec3_idents, con, dnssec_con)
+        .await
+}
+
+/// Signs the given records and updates the NSEC3 chains of all zones that are changed by setting
+/// `records` and deleting `deleted`. For all zones in `new_authoritative_zones`, a DNSKEY record is
+/// created and signed as well.
+///
+/// Returns the batch that writes the records, the new DNSKEY records, the NSEC3 records, and all
+/// of their RRSIGs, and that deletes the deleted RR sets and NSEC3 records together with their
+/// RRSIGs. Nothing is written yet. `used_zones` and `new_authoritative_zones` are the values
+/// returned by `check_soa()` for `records`. Deleted RR sets in zones whose SOA record is deleted as
+/// well are deleted without updating the NSEC3 chain.
+///
+/// If something goes wrong, the response that should be returned to the client is returned as
+/// the error.
+#[instrument(skip(state, vault_api_token, con))]
+pub async fn sign_changes(
+    records: &[DbEntry],
+    deleted: &[RecordIdentifier],
+    used_zones: &[Name],
+    new_authoritative_zones: &[Name],
+    state: &AppState,
+    vault_api_token: &str,
+    con: &mut Connection,
+) -> Result<DbWriteBatch, HttpResponse> {
+    let changed_zones = match changed_zones(deleted, used_zones, con).await {
+        Ok(z) => z,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+
+    let zones_to_fetch_dnskeys_for: Vec<_> = changed_zones
+        .iter()
+        .filter(|zone| !new_authoritative_zones.contains(zone))
+        .cloned()
+        .collect();
+    let dnskeys = match get_zone_dnskey_records(&zones_to_fetch_dnskeys_for, con).await {
+        Ok(d) => d,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+
+    let mut dnskeys_for_new_zones = Vec::with_capacity(new_authoritative_zones.len());
+    for zone in new_authoritative_zones {
+        let dnskey = get_dnskey_for_zone(zone, &state.vault_uri, vault_api_token).await;
+        dnskeys_for_new_zones.push(dnskey.map(|d| (zone.clone(), d)));
+    }
+    unwrap_or_return_if_err!(
+        dnskeys_for_new_zones,
+        "Couldn't set DNSKEY for one or more newly created zones because Vault has no signer for this zone.",
+        Err
+    );
+
+    let dnskey_for_zone: HashMap<_, _> = dnskeys
+        .into_iter()
+        .chain(dnskeys_for_new_zones.clone().into_iter())
+        .collect();
+
+    let new_dnskey_records: Vec<_> = dnskeys_for_new_zones
+        .into_iter()
+        .map(|(zone, dnskey)| DbEntry {
+            name: zone,
+            meta: "".to_string(),
+            // TODO: don't hardcode DNSKEY TTL
+            ttl: 3600,
+            rr_set: RrSet::DNSKEY {
+                rr_set: vec![dnskey],
+            },
+        })
+        .collect();
+
+    let mut batch = DbWriteBatch {
+        set: records.iter().cloned().chain(new_dnskey_records).collect(),
+        del: deleted.iter().map(RecordIdentifier::db_key).collect(),
+        dnssec_del: deleted.iter().map(RecordIdentifier::rrsig_db_key).collect(),
+        ..Default::default()
+    };
+
+    // the NSEC3 records are signed together with the other records below
+    let nsec3_updates = match update_nsec3_chains(&batch.set, deleted, &changed_zones, con).await {
+        Ok(u) => u,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    for update in nsec3_updates {
+        batch.set.extend(update.set);
+        batch
+            .del
+            .extend(update.delete.iter().map(RecordIdentifier::db_key));
+        batch
+            .dnssec_del
+            .extend(update.delete.iter().map(RecordIdentifier::rrsig_db_key));
+    }
+
+    // TODO once we support separate KSK and ZSK, sign the ZSK with the KSK
+    // until then we just sign the KSK with itself
+
+    let mut rrsig_records = Vec::with_capacity(batch.set.len());
+    for record in &batch.set {
+        let record_zone = match find_authoritative_zone(&record.name, &changed_zones) {
+            Some(z) => z,
+            None => {
+                return Err(internal_err(format!(
+                    "No zone is authoritative for {}.",
+                    record.name
+                )))
+            }
+        };
+        let dnskey = match dnskey_for_zone.get(&record_zone) {
+            Some(d) => d,
+            None => {
+                return Err(internal_err(
+                    PektinApiError::NoDnskeyRecord(record_zone.to_string()).to_string(),
+                ))
+            }
+        };
+        let rec = sign_db_entry(
+            &record_zone,
+            record.clone(),
+            dnskey,
+            &state.vault_uri,
+            vault_api_token,
+        )
+        .await;
+        rrsig_records.push(rec);
+    }
+
+    unwrap_or_return_if_err!(rrsig_records, "Could not sign one or more records.", Err);
+    batch.dnssec_set = rrsig_records;
+
+    Ok(batch)
+}
+
+/// Returns the zones that are changed by setting RR sets in `used_zones` and deleting `deleted`,
+/// except for zones whose SOA record is deleted, i.e. that are deleted completely.
+async fn changed_zones(
+    deleted: &[RecordIdentifier],
+    used_zones: &[Name],
+    con: &mut Connection,
+) -> PektinApiResult<Vec<Name>> {
+    let authoritative_zones: Vec<_> = con
+        .get_zones()
+        .await?
+        .into_iter()
+        .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
+        .collect();
+    let deleted_zones: Vec<_> = deleted
+        .iter()
+        .filter(|ident| ident.rr_type == RecordType::SOA)
+        .map(|ident| ident.name.clone())
+        .collect();
+    let mut changed_zones = used_zones.to_vec();
+    for ident in deleted {
+        if let Some(zone) = find_authoritative_zone(&ident.name, &authoritative_zones) {
+            if !deleted_zones.contains(&zone) && !changed_zones.contains(&zone) {
+                changed_zones.push(zone);
+            }
+        }
+    }
+    Ok(changed_zones)
+}
+
+/// Computes the NSEC3 chain updates of the `changed_zones` after setting `records` and deleting
+/// `deleted`.
+async fn update_nsec3_chains(
+    records: &[DbEntry],
+    deleted: &[RecordIdentifier],
+    changed_zones: &[Name],
+    con: &mut Connection,
+) -> PektinApiResult<Vec<Nsec3ChainUpdate>> {
+    let mut updates = Vec::with_capacity(changed_zones.len());
+    for zone in changed_zones {
+        let in_zone =
+            |name: &Name| find_authoritative_zone(name, changed_zones).as_ref() == Some(zone);
+        let set_in_zone: Vec<_> = records
+            .iter()
+            .filter(|e| in_zone(&e.name))
+            .cloned()
+            .collect();
+        let deleted_in_zone: Vec<_> = deleted
+            .iter()
+            .filter(|ident| in_zone(&ident.name))
+            .cloned()
+            .collect();
+        updates.push(update_nsec3_chain(zone, &set_in_zone, &deleted_in_zone, con).await?);
+    }
+    Ok(updates)
+}
+
+/// Computes what `sign_changes()` and writing its result would change, without signing anything
+/// or writing to the db. The arguments are the same as for `sign_changes()`.
+#[instrument(skip(con))]
+pub async fn plan_changes(
+    records: &[DbEntry],
+    deleted: &[RecordIdentifier],
+    used_zones: &[Name],
+    new_authoritative_zones: &[Name],
+    con: &mut Connection,
+) -> PektinApiResult<ChangePlan> {
+    let mut plan = ChangePlan {
+        new_zones: new_authoritative_zones.to_vec(),
+        ..Default::default()
+    };
+
+    let keys: Vec<_> = records.iter().map(DbEntry::db_key).collect();
+    let existing = if keys.is_empty() {
+        vec![]
+    } else {
+        get_or_mget_records(&keys, con).await?
+    };
+    for (after, before) in records.iter().zip(existing) {
+        match before {
+            None => plan.created.push(after.clone()),
+            Some(before) if before.serialize_for_db()? != after.serialize_for_db()? => {
+                plan.changed.push(RrSetChange {
+                    before,
+                    after: after.clone(),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    let keys: Vec<_> = deleted.iter().map(RecordIdentifier::db_key).collect();
+    if !keys.is_empty() {
+        plan.deleted = get_or_mget_records(&keys, con)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+    }
+
+    // the DNSKEYs of new zones are only fetched from Vault when the change is applied, but for
+    // the NSEC3 chain only their name and type matter
+    let new_dnskey_records: Vec<_> = new_authoritative_zones
+        .iter()
+        .map(|zone| DbEntry {
+            name: zone.clone(),
+            meta: "".to_string(),
+            ttl: 3600,
+            rr_set: RrSet::DNSKEY { rr_set: vec![] },
+        })
+        .collect();
+    let to_sign: Vec<_> = records.iter().cloned().chain(new_dnskey_records).collect();
+    let changed_zones = changed_zones(deleted, used_zones, con).await?;
+    let nsec3_updates = update_nsec3_chains(&to_sign, deleted, &changed_zones, con).await?;
+
+    // the SOA records of the existing zones are re-signed when their serials are incremented
+    let soa_idents = changed_zones
+        .iter()
+        .filter(|zone| !new_authoritative_zones.contains(zone))
+        .map(|zone| RecordIdentifier {
+            name: zone.clone(),
+            rr_type: RecordType::SOA,
+        });
+    plan.signatures = to_sign
+        .iter()
+        .chain(nsec3_updates.iter().flat_map(|u| &u.set))
+        .map(|entry| RecordIdentifier {
+            name: entry.name.clone(),
+            rr_type: entry.rr_type(),
+        })
+        .chain(soa_idents)
+        .collect();
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::db::ABSENT_VERSION;
+    use crate::test_utils::{
+        a_entry, call_endpoint, get_with_rrsig, set_records, soa_entry, test_state,
+    };
+    use crate::types::VersionedDbEntry;
+
+    fn request(records: &[(DbEntry, Option<String>)]) -> Value {
+        let records: Vec<_> = records
+            .iter()
+            .map(|(entry, version)| VersionedDbEntry {
+                entry: entry.clone(),
+                version: version.clone(),
+            })
+            .collect();
+        json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "records": records,
+        })
+    }
+
+    #[actix_web::test]
+    async fn versions_of_the_set_records_are_returned() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+
+        let (status, body) = call_endpoint(
+            set,
+            &state,
+            "/set",
+            request(&[(www.clone(), None), (mail.clone(), None)]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["type"], "success");
+        assert_eq!(
+            body["data"],
+            json!([
+                {
+                    "type": "success",
+                    "message": "set record",
+                    "data": { "version": rr_set_version(&www).unwrap() },
+                },
+                {
+                    "type": "success",
+                    "message": "set record",
+                    "data": { "version": rr_set_version(&mail).unwrap() },
+                },
+            ])
+        );
+    }
+
+    #[actix_web::test]
+    async fn records_are_only_set_if_the_versions_match() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&www), &state).await;
+        let new_www = a_entry("www.example.com.", [192, 0, 2, 10]);
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+
+        // www already exists, so it doesn't have the version of an absent RR set
+        let (status, body) = call_endpoint(
+            set,
+            &state,
+            "/set",
+            request(&[
+                (mail.clone(), Some(ABSENT_VERSION.into())),
+                (new_www.clone(), Some(ABSENT_VERSION.into())),
+            ]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        assert_eq!(
+            body["message"],
+            "One or more RR sets were changed in the meantime."
+        );
+        assert_eq!(body["data"][0]["type"], "ignored");
+        assert_eq!(body["data"][1]["type"], "error");
+        let www_version = rr_set_version(&www).unwrap();
+        assert_eq!(
+            body["data"][1]["message"],
+            format!("conflict: expected version {ABSENT_VERSION}, but the current version is {www_version}")
+        );
+        assert_eq!(
+            get_with_rrsig("mail.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+        let (entry, _) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(www.clone()));
+
+        let (status, body) = call_endpoint(
+            set,
+            &state,
+            "/set",
+            request(&[
+                (mail.clone(), Some(ABSENT_VERSION.into())),
+                (new_www.clone(), Some(www_version)),
+            ]),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (entry, _) = get_with_rrsig("mail.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(mail));
+        let (entry, _) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(new_www));
+    }
+}
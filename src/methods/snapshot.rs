@@ -0,0 +1,92 @@
+use std::ops::Deref;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{post, web, HttpRequest, Responder};
+use pektin_common::proto::rr::Name;
+use tracing::{info_span, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    backup::{snapshot_zone, Snapshot, SNAPSHOT_VERSION},
+    errors_and_responses::{auth_err, err, internal_err, success_with_toplevel_data},
+    storage::Storage,
+    types::{AppState, SnapshotRequestBody},
+};
+
+#[post("/snapshot")]
+pub async fn snapshot(
+    req: HttpRequest,
+    req_body: web::Json<SnapshotRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "snapshot",
+        client_username = %req_body.client_username,
+        zones = ?req_body.zones,
+        format = ?req_body.format
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let zones = if req_body.zones.is_empty() {
+                match con.get_zones().await {
+                    Ok(z) => z
+                        .into_iter()
+                        .map(|zone| {
+                            Name::from_utf8(zone).expect("Key in db is not a valid DNS name")
+                        })
+                        .collect(),
+                    Err(e) => return internal_err(e.to_string()),
+                }
+            } else {
+                req_body.zones.clone()
+            };
+
+            let utc_millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64;
+            let mut zone_snapshots = Vec::with_capacity(zones.len());
+            let mut messages = Vec::with_capacity(zones.len());
+            for zone in &zones {
+                match snapshot_zone(zone, req_body.format, &mut con).await {
+                    Ok(Some(zone_snapshot)) => {
+                        zone_snapshots.push(zone_snapshot);
+                        messages.push(None);
+                    }
+                    Ok(None) => messages.push(Some(format!("The zone {zone} does not exist."))),
+                    Err(e) => return internal_err(e.to_string()),
+                }
+            }
+            if messages.iter().any(Option::is_some) {
+                return err("One or more zones do not exist.", messages);
+            }
+
+            success_with_toplevel_data(
+                format!("created snapshot of {} zones", zone_snapshots.len()),
+                Snapshot {
+                    version: SNAPSHOT_VERSION,
+                    utc_millis,
+                    zones: zone_snapshots,
+                },
+            )
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
@@ -0,0 +1,238 @@
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use pektin_common::proto::rr::Name;
+use pektin_common::DbEntry;
+use tracing::{info_span, instrument, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::{acquire_locks, release_locks},
+    diff::{check_desired_records, diff_zone},
+    errors_and_responses::{auth_err, err, internal_err, success_with_toplevel_data},
+    journal::ChangeAuthor,
+    macros::unwrap_or_return_if_err,
+    set::sign_and_write_changes,
+    storage::Connection,
+    types::{AppState, RecordIdentifier, SyncRequestBody, ZoneDiff},
+    validation::check_soa,
+};
+
+#[post("/sync")]
+pub async fn sync(
+    req: HttpRequest,
+    req_body: web::Json<SyncRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "sync",
+        client_username = %req_body.client_username,
+        zone = %req_body.zone
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let valid =
+                match check_desired_records(&req_body.zone, &req_body.records, &mut con).await {
+                    Ok(v) => v,
+                    Err(e) => return internal_err(e.to_string()),
+                };
+            if valid.iter().any(|r| r.is_err()) {
+                let messages = valid.into_iter().map(Result::err).collect();
+                return err("One or more records were invalid.", messages);
+            }
+
+            // lock all RR sets that may be changed, i.e. the desired ones and the ones currently
+            // in the zone
+            let zone_diff =
+                match diff_zone(&req_body.zone, &req_body.records, &mut con, &mut dnssec_con).await
+                {
+                    Ok(Some(d)) => d,
+                    Ok(None) => {
+                        return err(
+                            "The zone does not exist; create it with create-zone first.",
+                            Vec::<Option<String>>::new(),
+                        )
+                    }
+                    Err(e) => return internal_err(e.to_string()),
+                };
+            let idents: Vec<_> = req_body
+                .records
+                .iter()
+                .chain(&zone_diff.removed)
+                .map(|r| RecordIdentifier {
+                    name: r.name.clone(),
+                    rr_type: r.rr_type(),
+                })
+                .collect();
+            let locks = match acquire_locks(&idents, &mut con).await {
+                Ok(l) => l,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            let res = sync_zone(
+                &req_body.zone,
+                &req_body.records,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await;
+            if let Err(e) = release_locks(locks, &mut con).await {
+                return internal_err(e.to_string());
+            }
+            match res {
+                Ok(zone_diff) => success_with_toplevel_data("synced zone", zone_diff),
+                Err(res) => res,
+            }
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Applies the differences between the zone in the db and the desired RR sets in a single db
+/// transaction and returns them. The locks of all RR sets in the zone and of the desired RR sets
+/// must be held.
+#[instrument(skip(desired, state, con, dnssec_con))]
+async fn sync_zone(
+    zone: &Name,
+    desired: &[DbEntry],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<ZoneDiff, HttpResponse> {
+    // the zone may have changed while we were waiting for the locks
+    let zone_diff = match diff_zone(zone, desired, con, dnssec_con).await {
+        Ok(Some(d)) => d,
+        Ok(None) => return Err(internal_err("The zone was deleted in the meantime.")),
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+
+    let to_set: Vec<_> = zone_diff
+        .added
+        .iter()
+        .cloned()
+        .chain(zone_diff.modified.iter().map(|change| change.after.clone()))
+        .collect();
+    let to_delete: Vec<_> = zone_diff
+        .removed
+        .iter()
+        .map(|r| RecordIdentifier {
+            name: r.name.clone(),
+            rr_type: r.rr_type(),
+        })
+        .collect();
+    if to_set.is_empty() && to_delete.is_empty() {
+        return Ok(zone_diff);
+    }
+
+    let (_soa_check, used_zones, new_authoritative_zones) = match check_soa(&to_set, con).await {
+        Ok(s) => s,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    unwrap_or_return_if_err!(
+        _soa_check,
+        "Tried to set one or more records for a zone that does not have a SOA record.",
+        Err
+    );
+
+    sign_and_write_changes(
+        &to_set,
+        &to_delete,
+        &used_zones,
+        &new_authoritative_zones,
+        author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await?;
+
+    Ok(zone_diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use pektin_common::proto::rr::RecordType;
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_utils::{
+        a_entry, call_endpoint, get_with_rrsig, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    #[actix_web::test]
+    async fn sync_applies_the_diff() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+        let old = a_entry("old.example.com.", [192, 0, 2, 3]);
+        set_records(&[www.clone(), mail, old], &state).await;
+        let (dnskey, _) = get_with_rrsig("example.com.", RecordType::DNSKEY, &state).await;
+
+        let new_mail = a_entry("mail.example.com.", [192, 0, 2, 20]);
+        let new = a_entry("new.example.com.", [192, 0, 2, 4]);
+        let request = json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "zone": "example.com.",
+            "records": [soa_entry("example.com.", 1), www.clone(), new_mail.clone(), new.clone()],
+        });
+        let (status, body) = call_endpoint(sync, &state, "/sync", request.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["data"]["added"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"]["modified"].as_array().unwrap().len(), 1);
+        assert_eq!(body["data"]["removed"].as_array().unwrap().len(), 1);
+
+        for record in [www, new_mail, new] {
+            let (entry, rrsig) =
+                get_with_rrsig(&record.name.to_string(), RecordType::A, &state).await;
+            assert_eq!(entry, Some(record));
+            assert!(rrsig.is_some());
+        }
+        assert_eq!(
+            get_with_rrsig("old.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+        assert_eq!(
+            get_with_rrsig("example.com.", RecordType::DNSKEY, &state)
+                .await
+                .0,
+            dnskey
+        );
+        // all changes are made at once
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+
+        // syncing again changes nothing
+        let (status, body) = call_endpoint(sync, &state, "/sync", request).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            body["data"],
+            json!({ "added": [], "modified": [], "removed": [] })
+        );
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+    }
+}
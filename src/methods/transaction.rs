@@ -0,0 +1,435 @@
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use pektin_common::proto::rr::RecordType;
+use pektin_common::DbEntry;
+use serde_json::json;
+use tracing::{info_span, instrument, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::{acquire_locks, get_or_mget_records, release_locks, rr_set_version, version_conflict},
+    errors_and_responses::{
+        auth_err, conflict_err, err, internal_err, partial_success_with_data,
+        success_with_toplevel_data,
+    },
+    journal::ChangeAuthor,
+    macros::unwrap_or_return_if_err,
+    set::sign_and_write_changes,
+    storage::Connection,
+    types::{
+        AppState, RecordIdentifier, ResponseType, TransactionOperation, TransactionRequestBody,
+    },
+    update::changed_rr_sets,
+    validation::{check_soa, validate_records, RecordValidationError},
+};
+
+#[post("/transaction")]
+pub async fn transaction(
+    req: HttpRequest,
+    req_body: web::Json<TransactionRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "transaction",
+        client_username = %req_body.client_username,
+        operations = ?req_body.operations
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if req_body.operations.is_empty() {
+                return success_with_toplevel_data("applied transaction", json!([]));
+            }
+
+            let valid: Vec<_> = req_body.operations.iter().map(check_operation).collect();
+            if valid.iter().any(|r| r.is_err()) {
+                let messages = valid.into_iter().map(Result::err).collect();
+                return err("One or more operations were invalid.", messages);
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let idents: Vec<_> = req_body.operations.iter().map(|op| op.ident()).collect();
+            let locks = match acquire_locks(&idents, &mut con).await {
+                Ok(l) => l,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            let res = apply_transaction(
+                &req_body.operations,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await;
+            if let Err(e) = release_locks(locks, &mut con).await {
+                return internal_err(e.to_string());
+            }
+            let versions = match res {
+                Ok(v) => v,
+                Err(res) => return res,
+            };
+
+            let messages = req_body
+                .operations
+                .iter()
+                .zip(versions)
+                .map(|(op, version)| {
+                    let message = match op {
+                        TransactionOperation::Set(_) => "set record",
+                        TransactionOperation::Delete(_) => "deleted record",
+                    };
+                    (
+                        ResponseType::Success,
+                        message,
+                        json!({ "version": version }),
+                    )
+                })
+                .collect();
+            partial_success_with_data(ResponseType::Success, "applied transaction", messages)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+impl TransactionOperation {
+    fn ident(&self) -> RecordIdentifier {
+        match self {
+            Self::Set(entry) => RecordIdentifier {
+                name: entry.entry.name.clone(),
+                rr_type: entry.entry.rr_type(),
+            },
+            Self::Delete(ident) => ident.ident.clone(),
+        }
+    }
+
+    fn version(&self) -> Option<&str> {
+        match self {
+            Self::Set(entry) => entry.version.as_deref(),
+            Self::Delete(ident) => ident.version.as_deref(),
+        }
+    }
+}
+
+/// Checks everything about the operation that doesn't require the db.
+fn check_operation(op: &TransactionOperation) -> Result<(), String> {
+    match op {
+        TransactionOperation::Set(entry) => {
+            match validate_records(std::slice::from_ref(&entry.entry)).pop() {
+                Some(Err(e)) => Err(e.to_string()),
+                _ => Ok(()),
+            }
+        }
+        TransactionOperation::Delete(ident) => {
+            let ident = &ident.ident;
+            if !ident.name.is_fqdn() {
+                Err(RecordValidationError::NameNotAbsolute.to_string())
+            } else if ident.rr_type == RecordType::SOA {
+                Err("The SOA record can only be deleted with delete-zone.".into())
+            } else if ident.rr_type.is_dnssec() {
+                Err("DNSSEC records are managed by pektin and can't be deleted.".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Applies the operations in order to the current RR sets and writes and signs the result in a
+/// single db transaction. The locks of the RR sets touched by the operations must be held.
+///
+/// Either all operations are applied or none. The version of each operation is compared with the
+/// version of its RR set after all previous operations were applied. Returns the version of the RR
+/// set after each operation (`None` if it was deleted).
+#[instrument(skip(state, con, dnssec_con))]
+async fn apply_transaction(
+    operations: &[TransactionOperation],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<Vec<Option<String>>, HttpResponse> {
+    let idents: BTreeMap<_, _> = operations
+        .iter()
+        .map(|op| {
+            let ident = op.ident();
+            (ident.db_key(), ident)
+        })
+        .collect();
+    let keys: Vec<_> = idents.keys().cloned().collect();
+    let existing = match get_or_mget_records(&keys, con).await {
+        Ok(e) => e,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    let mut current: BTreeMap<_, Option<DbEntry>> =
+        keys.into_iter().zip(existing.clone()).collect();
+
+    let mut conflicts = Vec::with_capacity(operations.len());
+    let mut versions = Vec::with_capacity(operations.len());
+    for op in operations {
+        let entry = current
+            .get_mut(&op.ident().db_key())
+            .expect("RR set was fetched");
+        let conflict = match op.version() {
+            Some(expected) => version_conflict(entry.as_ref(), expected),
+            None => Ok(None),
+        };
+        match conflict {
+            Ok(c) => conflicts.push(c),
+            Err(e) => return Err(internal_err(e.to_string())),
+        }
+        *entry = match op {
+            TransactionOperation::Set(e) => Some(e.entry.clone()),
+            TransactionOperation::Delete(_) => None,
+        };
+        match entry.as_ref().map(rr_set_version).transpose() {
+            Ok(v) => versions.push(v),
+            Err(e) => return Err(internal_err(e.to_string())),
+        }
+    }
+    if conflicts.iter().any(Option::is_some) {
+        return Err(conflict_err(
+            "One or more RR sets were changed in the meantime.",
+            conflicts,
+        ));
+    }
+
+    let (to_set, to_delete) = match changed_rr_sets(&idents, existing, current) {
+        Ok(c) => c,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    if to_set.is_empty() && to_delete.is_empty() {
+        return Ok(versions);
+    }
+
+    let (_soa_check, used_zones, new_authoritative_zones) = match check_soa(&to_set, con).await {
+        Ok(s) => s,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    unwrap_or_return_if_err!(
+        _soa_check,
+        "Tried to set one or more records for a zone that does not have a SOA record.",
+        Err
+    );
+
+    sign_and_write_changes(
+        &to_set,
+        &to_delete,
+        &used_zones,
+        &new_authoritative_zones,
+        author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await?;
+
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+
+    use super::*;
+    use crate::db::ABSENT_VERSION;
+    use crate::test_utils::{
+        a_entry, author, call_endpoint, get_with_rrsig, set_records, soa_entry, test_state,
+        test_state_with_vault, zone_serial,
+    };
+    use crate::types::{VersionedDbEntry, VersionedRecordIdentifier};
+
+    fn request(operations: &[TransactionOperation]) -> serde_json::Value {
+        json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "operations": operations,
+        })
+    }
+
+    fn set(entry: DbEntry, version: Option<String>) -> TransactionOperation {
+        TransactionOperation::Set(VersionedDbEntry { entry, version })
+    }
+
+    fn delete(entry: &DbEntry, version: Option<String>) -> TransactionOperation {
+        TransactionOperation::Delete(VersionedRecordIdentifier {
+            ident: RecordIdentifier {
+                name: entry.name.clone(),
+                rr_type: entry.rr_type(),
+            },
+            version,
+        })
+    }
+
+    #[actix_web::test]
+    async fn transaction_with_conflict_changes_nothing() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&www), &state).await;
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        let operations = [
+            set(mail.clone(), None),
+            delete(&www, Some("outdated".into())),
+        ];
+        let res =
+            apply_transaction(&operations, &author(), &state, &mut con, &mut dnssec_con).await;
+        assert_eq!(res.unwrap_err().status(), StatusCode::CONFLICT);
+        assert_eq!(
+            get_with_rrsig("mail.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+
+        let www_version = rr_set_version(&www).unwrap();
+        let operations = [set(mail.clone(), None), delete(&www, Some(www_version))];
+        let versions = apply_transaction(&operations, &author(), &state, &mut con, &mut dnssec_con)
+            .await
+            .unwrap();
+        assert_eq!(versions, vec![Some(rr_set_version(&mail).unwrap()), None]);
+        let (entry, rrsig) = get_with_rrsig("mail.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(mail));
+        assert!(rrsig.is_some());
+        assert_eq!(
+            get_with_rrsig("www.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+    }
+
+    #[actix_web::test]
+    async fn records_are_renamed_atomically() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let old = a_entry("old.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&old), &state).await;
+        let new = a_entry("new.example.com.", [192, 0, 2, 1]);
+
+        let operations = [
+            delete(&old, Some(rr_set_version(&old).unwrap())),
+            set(new.clone(), None),
+        ];
+        let (status, body) =
+            call_endpoint(transaction, &state, "/transaction", request(&operations)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["data"][0]["message"], "deleted record");
+        assert_eq!(body["data"][0]["data"]["version"], json!(null));
+        assert_eq!(body["data"][1]["message"], "set record");
+        assert_eq!(
+            body["data"][1]["data"]["version"],
+            rr_set_version(&new).unwrap()
+        );
+        assert_eq!(
+            get_with_rrsig("old.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+        let (entry, rrsig) = get_with_rrsig("new.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(new));
+        assert!(rrsig.is_some());
+        // both operations are a single change of the zone
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+    }
+
+    #[actix_web::test]
+    async fn operations_see_the_effects_of_earlier_operations() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let first = a_entry("www.example.com.", [192, 0, 2, 1]);
+        let second = a_entry("www.example.com.", [192, 0, 2, 2]);
+
+        let operations = [
+            set(first.clone(), None),
+            set(second.clone(), Some(rr_set_version(&first).unwrap())),
+            delete(&second, Some(rr_set_version(&second).unwrap())),
+            set(first.clone(), Some(ABSENT_VERSION.into())),
+        ];
+        let (status, body) =
+            call_endpoint(transaction, &state, "/transaction", request(&operations)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (entry, _) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(first.clone()));
+
+        // the second operation expects the version before the first operation
+        let operations = [
+            set(second.clone(), Some(rr_set_version(&first).unwrap())),
+            set(first.clone(), Some(rr_set_version(&first).unwrap())),
+        ];
+        let (status, body) =
+            call_endpoint(transaction, &state, "/transaction", request(&operations)).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        assert_eq!(body["data"][0]["type"], "ignored");
+        assert_eq!(body["data"][1]["type"], "error");
+        let (entry, _) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(first));
+    }
+
+    #[actix_web::test]
+    async fn failed_transactions_change_nothing() {
+        let (state, vault) = test_state_with_vault();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(std::slice::from_ref(&www), &state).await;
+        let mail = a_entry("mail.example.com.", [192, 0, 2, 2]);
+        let serial = zone_serial("example.com.", &state).await;
+
+        // signing fails after the operations were checked
+        vault.lock().fail_signing = true;
+        let operations = [delete(&www, None), set(mail.clone(), None)];
+        let (status, body) =
+            call_endpoint(transaction, &state, "/transaction", request(&operations)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        vault.lock().fail_signing = false;
+
+        // the records of a zone without SOA record can't be set
+        let other = a_entry("www.example.org.", [192, 0, 2, 3]);
+        let operations = [delete(&www, None), set(other, None)];
+        let (status, body) =
+            call_endpoint(transaction, &state, "/transaction", request(&operations)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(
+            body["message"],
+            "Tried to set one or more records for a zone that does not have a SOA record."
+        );
+
+        // the SOA record can't be deleted in a transaction
+        let operations = [
+            delete(&www, None),
+            delete(&soa_entry("example.com.", 1), None),
+        ];
+        let (status, body) =
+            call_endpoint(transaction, &state, "/transaction", request(&operations)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(body["data"][0]["type"], "ignored");
+        assert_eq!(body["data"][1]["type"], "error");
+
+        let (entry, rrsig) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert_eq!(entry, Some(www));
+        assert!(rrsig.is_some());
+        assert_eq!(
+            get_with_rrsig("mail.example.com.", RecordType::A, &state).await,
+            (None, None)
+        );
+        assert_eq!(zone_serial("example.com.", &state).await, serial);
+    }
+}
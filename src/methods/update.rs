@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use pektin_common::proto::rr::RecordType;
+use pektin_common::DbEntry;
+use tracing::{info_span, instrument, Instrument};
+
+use crate::{
+    auth::auth_ok,
+    db::{acquire_locks, get_or_mget_records, release_locks},
+    errors_and_responses::{auth_err, err, internal_err, success, PektinApiResult},
+    journal::ChangeAuthor,
+    macros::unwrap_or_return_if_err,
+    set::sign_and_write_changes,
+    storage::Connection,
+    types::{
+        default_zone_ttl, AppState, RecordIdentifier, RecordUpdate, RecordUpdateOperation,
+        UpdateRequestBody,
+    },
+    validation::{check_soa, validate_records},
+    zone_file::{merge_rr_sets, remove_from_rr_set},
+};
+
+#[post("/update")]
+pub async fn update(
+    req: HttpRequest,
+    req_body: web::Json<UpdateRequestBody>,
+    state: web::Data<AppState>,
+) -> impl Responder {
+    let span = info_span!(
+        "update",
+        client_username = %req_body.client_username,
+        updates = ?req_body.updates
+    );
+    async move {
+        let mut auth = auth_ok(
+            &req,
+            req_body.clone().into(),
+            state.deref(),
+            &req_body.client_username,
+            &req_body.confidant_password,
+        )
+        .await;
+        if auth.success {
+            if req_body.updates.is_empty() {
+                return success("updated 0 RR sets", Vec::<String>::new());
+            }
+
+            let valid: Vec<_> = req_body.updates.iter().map(check_update).collect();
+            if valid.iter().any(|r| r.is_err()) {
+                let messages = valid.into_iter().map(Result::err).collect();
+                return err("One or more updates were invalid.", messages);
+            }
+
+            let mut con = match state.db_pool.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+            let mut dnssec_con = match state.db_pool_dnssec.get().await {
+                Ok(c) => c,
+                Err(_) => return internal_err("No db connection."),
+            };
+
+            let idents: Vec<_> = req_body
+                .updates
+                .iter()
+                .map(|record_update| RecordIdentifier {
+                    name: record_update.name.clone(),
+                    rr_type: record_update.rr_set_type(),
+                })
+                .collect();
+            let locks = match acquire_locks(&idents, &mut con).await {
+                Ok(l) => l,
+                Err(e) => return internal_err(e.to_string()),
+            };
+            let res = apply_updates(
+                &req_body.updates,
+                &ChangeAuthor::from_request(&req, &req_body.client_username),
+                &state,
+                &mut con,
+                &mut dnssec_con,
+            )
+            .await;
+            if let Err(e) = release_locks(locks, &mut con).await {
+                return internal_err(e.to_string());
+            }
+            if let Err(res) = res {
+                return res;
+            }
+
+            let messages = req_body.updates.iter().map(|_| "applied update").collect();
+            success("updated RR sets", messages)
+        } else {
+            auth.message.push('\n');
+            auth_err(auth.message)
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+impl RecordUpdate {
+    fn rr_set_type(&self) -> RecordType {
+        DbEntry {
+            name: self.name.clone(),
+            ttl: 0,
+            meta: "".to_string(),
+            rr_set: self.rr_set.clone(),
+        }
+        .rr_type()
+    }
+}
+
+/// Checks everything about the update that doesn't require the db.
+fn check_update(record_update: &RecordUpdate) -> Result<(), String> {
+    if !record_update.name.is_fqdn() {
+        return Err(
+            "The record's name is not absolute (i.e. the root label at the end is missing).".into(),
+        );
+    }
+    if record_update.rr_set_type().is_dnssec() {
+        return Err("DNSSEC records are managed by pektin and can't be updated.".into());
+    }
+    match (record_update.operation, &record_update.old) {
+        (RecordUpdateOperation::ReplaceRdata, None) => {
+            Err("replace-rdata requires the records to replace in old.".into())
+        }
+        (RecordUpdateOperation::ReplaceRdata, Some(old)) => {
+            let mut old = old.clone();
+            // fails if the types don't match
+            merge_rr_sets(&mut old, record_update.rr_set.clone()).map(|_| ())
+        }
+        (_, Some(_)) => Err("old is only allowed for replace-rdata.".into()),
+        (_, None) => Ok(()),
+    }
+}
+
+/// Applies the updates to the current RR sets and writes and signs the result. The locks of the RR
+/// sets touched by the updates must be held.
+///
+/// Either all updates are applied or none.
+#[instrument(skip(state, con, dnssec_con))]
+async fn apply_updates(
+    updates: &[RecordUpdate],
+    author: &ChangeAuthor,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> Result<(), HttpResponse> {
+    let idents: BTreeMap<_, _> = updates
+        .iter()
+        .map(|record_update| {
+            let ident = RecordIdentifier {
+                name: record_update.name.clone(),
+                rr_type: record_update.rr_set_type(),
+            };
+            (ident.db_key(), ident)
+        })
+        .collect();
+    let keys: Vec<_> = idents.keys().cloned().collect();
+    let existing = match get_or_mget_records(&keys, con).await {
+        Ok(e) => e,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    let mut current: BTreeMap<_, _> = keys.into_iter().zip(existing.clone()).collect();
+
+    let mut results = Vec::with_capacity(updates.len());
+    for record_update in updates {
+        let ident = RecordIdentifier {
+            name: record_update.name.clone(),
+            rr_type: record_update.rr_set_type(),
+        };
+        let entry = current
+            .get_mut(&ident.db_key())
+            .expect("RR set was fetched");
+        results.push(apply_update(record_update, entry));
+    }
+    if results.iter().any(|r| r.is_err()) {
+        let messages = results.into_iter().map(Result::err).collect();
+        return Err(err("One or more updates could not be applied.", messages));
+    }
+
+    let (to_set, to_delete) = match changed_rr_sets(&idents, existing, current) {
+        Ok(c) => c,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    if to_set.is_empty() && to_delete.is_empty() {
+        return Ok(());
+    }
+
+    let _valid = validate_records(&to_set);
+    unwrap_or_return_if_err!(_valid, "One or more resulting RR sets were invalid.", Err);
+    let (_soa_check, used_zones, new_authoritative_zones) = match check_soa(&to_set, con).await {
+        Ok(s) => s,
+        Err(e) => return Err(internal_err(e.to_string())),
+    };
+    unwrap_or_return_if_err!(
+        _soa_check,
+        "Tried to update one or more records for a zone that does not have a SOA record.",
+        Err
+    );
+
+    sign_and_write_changes(
+        &to_set,
+        &to_delete,
+        &used_zones,
+        &new_authoritative_zones,
+        author,
+        state,
+        con,
+        dnssec_con,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Compares the RR sets before and after a change. Returns the RR sets that must be set and the
+/// identifiers of the RR sets that must be deleted; unchanged RR sets are omitted.
+///
+/// `idents` and `after` are keyed by the db keys of the RR sets and `before` contains the previous
+/// RR sets in the same order.
+pub fn changed_rr_sets(
+    idents: &BTreeMap<String, RecordIdentifier>,
+    before: Vec<Option<DbEntry>>,
+    after: BTreeMap<String, Option<DbEntry>>,
+) -> PektinApiResult<(Vec<DbEntry>, Vec<RecordIdentifier>)> {
+    let mut to_set = Vec::new();
+    let mut to_delete = Vec::new();
+    for ((key, entry), before) in after.into_iter().zip(before) {
+        let changed = match (&before, &entry) {
+            (Some(before), Some(entry)) => {
+                before.serialize_for_db()? != entry.serialize_for_db()?
+            }
+            (None, None) => false,
+            _ => true,
+        };
+        match entry {
+            Some(entry) if changed => to_set.push(entry),
+            None if changed => to_delete.push(idents[&key].clone()),
+            _ => {}
+        }
+    }
+    Ok((to_set, to_delete))
+}
+
+/// Applies a single update to the RR set. An RR set that becomes empty is set to `None`.
+fn apply_update(record_update: &RecordUpdate, entry: &mut Option<DbEntry>) -> Result<(), String> {
+    match record_update.operation {
+        RecordUpdateOperation::AddRdata => {}
+        RecordUpdateOperation::RemoveRdata => {
+            if let Some(e) = entry {
+                remove_from_rr_set(&mut e.rr_set, &record_update.rr_set)?;
+                if e.rr_set.is_empty() {
+                    if e.rr_type() == RecordType::SOA {
+                        return Err("The SOA record can only be removed with delete-zone.".into());
+                    }
+                    *entry = None;
+                } else if let Some(ttl) = record_update.ttl {
+                    e.ttl = ttl;
+                }
+            }
+            return Ok(());
+        }
+        RecordUpdateOperation::ReplaceRdata => {
+            let old = record_update.old.as_ref().expect("old was checked");
+            let e = entry
+                .as_mut()
+                .ok_or("The RR set containing the records to replace does not exist.")?;
+            if remove_from_rr_set(&mut e.rr_set, old)? != old.len() {
+                return Err("Not all records to replace exist in the RR set.".into());
+            }
+        }
+    }
+
+    match entry {
+        Some(e) => {
+            merge_rr_sets(&mut e.rr_set, record_update.rr_set.clone())?;
+            if let Some(ttl) = record_update.ttl {
+                e.ttl = ttl;
+            }
+        }
+        None => {
+            *entry = Some(DbEntry {
+                name: record_update.name.clone(),
+                ttl: record_update.ttl.unwrap_or_else(default_zone_ttl),
+                meta: "".to_string(),
+                rr_set: record_update.rr_set.clone(),
+            })
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::net::Ipv4Addr;
+
+    use actix_web::http::StatusCode;
+    use pektin_common::RrSet;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::test_utils::{
+        a_entry, call_endpoint, get_with_rrsig, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    fn a_update(operation: &str, ips: &[&str]) -> Value {
+        let rr_set: Vec<_> = ips.iter().map(|ip| json!({ "value": ip })).collect();
+        json!({
+            "name": "www.example.com.",
+            "operation": operation,
+            "rr_type": "A",
+            "rr_set": rr_set,
+        })
+    }
+
+    async fn send(updates: Vec<Value>, state: &AppState) -> (StatusCode, Value) {
+        let body = json!({
+            "client_username": "test",
+            "confidant_password": "",
+            "updates": updates,
+        });
+        call_endpoint(update, state, "/update", body).await
+    }
+
+    /// The TTL and the addresses of www.example.com. A, if it exists.
+    async fn www(state: &AppState) -> Option<(u32, BTreeSet<Ipv4Addr>)> {
+        let (entry, rrsig) = get_with_rrsig("www.example.com.", RecordType::A, state).await;
+        assert_eq!(entry.is_some(), rrsig.is_some());
+        entry.map(|entry| match entry.rr_set {
+            RrSet::A { rr_set } => (entry.ttl, rr_set.into_iter().map(|r| r.value).collect()),
+            _ => panic!("not an A entry"),
+        })
+    }
+
+    fn ips(ips: &[&str]) -> BTreeSet<Ipv4Addr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    async fn zone_with_www() -> AppState {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+        state
+    }
+
+    #[actix_web::test]
+    async fn records_are_added_replaced_and_removed() {
+        let state = zone_with_www().await;
+
+        let mut add = a_update("add-rdata", &["192.0.2.2"]);
+        add["ttl"] = json!(600);
+        let (status, body) = send(vec![add], &state).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            www(&state).await,
+            Some((600, ips(&["192.0.2.1", "192.0.2.2"])))
+        );
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+
+        // the TTL is kept if it is omitted
+        let mut replace = a_update("replace-rdata", &["192.0.2.3"]);
+        replace["old"] = json!({ "rr_type": "A", "rr_set": [{ "value": "192.0.2.1" }] });
+        let (status, body) = send(vec![replace], &state).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            www(&state).await,
+            Some((600, ips(&["192.0.2.2", "192.0.2.3"])))
+        );
+
+        // an RR set without records is deleted
+        let (status, body) = send(
+            vec![
+                a_update("remove-rdata", &["192.0.2.2"]),
+                a_update("remove-rdata", &["192.0.2.3"]),
+            ],
+            &state,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(www(&state).await, None);
+        assert_eq!(zone_serial("example.com.", &state).await, Some(5));
+
+        // adding to an RR set that doesn't exist creates it with the default TTL
+        let (status, body) = send(vec![a_update("add-rdata", &["192.0.2.4"])], &state).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(
+            www(&state).await,
+            Some((default_zone_ttl(), ips(&["192.0.2.4"])))
+        );
+    }
+
+    #[actix_web::test]
+    async fn unchanged_rr_sets_are_not_written() {
+        let state = zone_with_www().await;
+        let (status, body) = send(
+            vec![
+                a_update("add-rdata", &["192.0.2.1"]),
+                a_update("remove-rdata", &["192.0.2.9"]),
+            ],
+            &state,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(zone_serial("example.com.", &state).await, Some(2));
+    }
+
+    #[actix_web::test]
+    async fn updates_are_applied_all_or_nothing() {
+        let state = zone_with_www().await;
+
+        let mut replace = a_update("replace-rdata", &["192.0.2.3"]);
+        replace["old"] = json!({ "rr_type": "A", "rr_set": [{ "value": "192.0.2.9" }] });
+        let (status, body) =
+            send(vec![a_update("add-rdata", &["192.0.2.2"]), replace], &state).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["data"][0]["type"], "ignored");
+        assert_eq!(body["data"][1]["type"], "error");
+        assert_eq!(www(&state).await, Some((300, ips(&["192.0.2.1"]))));
+        assert_eq!(zone_serial("example.com.", &state).await, Some(2));
+    }
+
+    #[actix_web::test]
+    async fn invalid_updates_are_rejected() {
+        let state = zone_with_www().await;
+        let mut relative = a_update("add-rdata", &["192.0.2.2"]);
+        relative["name"] = json!("www.example.com");
+        let missing_old = a_update("replace-rdata", &["192.0.2.2"]);
+        let mut old_for_add = a_update("add-rdata", &["192.0.2.2"]);
+        old_for_add["old"] = json!({ "rr_type": "A", "rr_set": [{ "value": "192.0.2.1" }] });
+        let mut old_of_other_type = a_update("replace-rdata", &["192.0.2.2"]);
+        old_of_other_type["old"] = json!({ "rr_type": "TXT", "rr_set": [{ "value": "x" }] });
+        for invalid in [relative, missing_old, old_for_add, old_of_other_type] {
+            let (status, body) = send(vec![invalid], &state).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        }
+
+        // the SOA record can't be removed
+        let soa = serde_json::to_value(soa_entry("example.com.", 2)).unwrap();
+        let remove_soa = json!({
+            "name": "example.com.",
+            "operation": "remove-rdata",
+            "rr_type": "SOA",
+            "rr_set": soa["rr_set"],
+        });
+        let (status, body) = send(vec![remove_soa], &state).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+        assert_eq!(zone_serial("example.com.", &state).await, Some(2));
+
+        // records outside of all zones
+        let mut outside = a_update("add-rdata", &["192.0.2.2"]);
+        outside["name"] = json!("www.example.org.");
+        let (status, body) = send(vec![outside], &state).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+    }
+}
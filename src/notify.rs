@@ -0,0 +1,268 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use pektin_common::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use pektin_common::proto::rr::{DNSClass, Name, Record, RecordType};
+use pektin_common::DbEntry;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, instrument, warn};
+
+use crate::errors_and_responses::PektinApiResult;
+use crate::storage::{Connection, Storage};
+use crate::tsig::{sign_message, verify_message, TsigError, TsigKey};
+
+/// How often we try to send a NOTIFY to a target before giving up.
+const NOTIFY_ATTEMPTS: u32 = 5;
+/// How long we wait for the answer to the first NOTIFY. The timeout doubles with every retry.
+const NOTIFY_INITIAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A secondary name server that is informed about changes of a zone via NOTIFY (RFC 1996).
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NotifyTarget {
+    pub address: SocketAddr,
+    /// The name of the TSIG key (from the `TSIG_KEYS` config) to sign the NOTIFY with.
+    #[serde(default)]
+    pub tsig_key: Option<Name>,
+}
+
+#[derive(Debug, Error)]
+pub enum NotifyError {
+    #[error("No TSIG key named {0} is configured")]
+    UnknownTsigKey(Name),
+    #[error("TSIG error: {0}")]
+    Tsig(#[from] TsigError),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The target answered with {0}")]
+    ErrorResponse(ResponseCode),
+    #[error("The target did not answer after {0} attempts")]
+    NoResponse(u32),
+}
+
+/// The key in db 0 under which the notify targets of the zone are stored.
+///
+/// Like the journal key, it contains no colon so that it never matches the globs used for records.
+pub fn notify_targets_db_key(zone: &Name) -> String {
+    format!("notify/{}", zone.to_lowercase())
+}
+
+#[instrument(skip(con))]
+pub async fn get_notify_targets(
+    zone: &Name,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<NotifyTarget>> {
+    match con.get_value(&notify_targets_db_key(zone)).await? {
+        Some(targets) => Ok(serde_json::from_str(&targets)?),
+        None => Ok(vec![]),
+    }
+}
+
+/// Replaces the notify targets of the zone. An empty list removes all targets.
+#[instrument(skip(con))]
+pub async fn set_notify_targets(
+    zone: &Name,
+    targets: &[NotifyTarget],
+    con: &mut Connection,
+) -> PektinApiResult<()> {
+    let key = notify_targets_db_key(zone);
+    if targets.is_empty() {
+        con.delete_value(&key).await
+    } else {
+        con.set_value(&key, &serde_json::to_string(targets)?).await
+    }
+}
+
+/// Deletes the notify targets of the given zones.
+#[instrument(skip(con))]
+pub async fn delete_notify_targets(zones: &[Name], con: &mut Connection) -> PektinApiResult<()> {
+    for zone in zones {
+        con.delete_value(&notify_targets_db_key(zone)).await?;
+    }
+    Ok(())
+}
+
+/// Sends a NOTIFY for the zone to all of its notify targets in the background.
+///
+/// `soa` is the new SOA record of the zone, which is included in the NOTIFY as a hint for the
+/// secondaries. Failures are only logged.
+#[instrument(skip(soa, tsig_keys, con))]
+pub async fn spawn_notify_zone(
+    zone: &Name,
+    soa: &DbEntry,
+    tsig_keys: &[TsigKey],
+    con: &mut Connection,
+) -> PektinApiResult<()> {
+    let targets = get_notify_targets(zone, con).await?;
+    if targets.is_empty() {
+        return Ok(());
+    }
+    let soa: Vec<Record> = soa
+        .clone()
+        .try_into()
+        .expect("Could not convert SOA DbEntry to trust-dns Record");
+
+    for target in targets {
+        let zone = zone.clone();
+        let soa = soa.first().cloned();
+        let tsig_keys = tsig_keys.to_vec();
+        tokio::spawn(async move {
+            match send_notify(&zone, soa, &target, &tsig_keys).await {
+                Ok(()) => debug!("Sent NOTIFY for {} to {}", zone, target.address),
+                Err(e) => warn!(
+                    "Could not send NOTIFY for {} to {}: {}",
+                    zone, target.address, e
+                ),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Sends a NOTIFY for the zone to the target via UDP and waits for the answer, retrying with an
+/// exponential backoff if the target doesn't answer.
+#[instrument(skip(soa, tsig_keys))]
+pub async fn send_notify(
+    zone: &Name,
+    soa: Option<Record>,
+    target: &NotifyTarget,
+    tsig_keys: &[TsigKey],
+) -> Result<(), NotifyError> {
+    let key = match &target.tsig_key {
+        Some(key_name) => Some(
+            tsig_keys
+                .iter()
+                .find(|k| k.name.to_lowercase() == key_name.to_lowercase())
+                .ok_or_else(|| NotifyError::UnknownTsigKey(key_name.clone()))?,
+        ),
+        None => None,
+    };
+
+    let mut query = Query::query(zone.clone(), RecordType::SOA);
+    query.set_query_class(DNSClass::IN);
+    let mut message = Message::new();
+    message
+        .set_id(random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Notify)
+        .set_authoritative(true)
+        .add_query(query);
+    if let Some(soa) = soa {
+        message.add_answer(soa);
+    }
+    let request_mac = match key {
+        Some(key) => Some(sign_message(&mut message, key, None, true)?),
+        None => None,
+    };
+    let request = message.to_vec().map_err(TsigError::from)?;
+
+    let bind_addr: SocketAddr = if target.address.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target.address).await?;
+
+    let mut response_timeout = NOTIFY_INITIAL_TIMEOUT;
+    let mut buf = vec![0; 4096];
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send(&request).await?;
+        // ignore everything that isn't the response to our NOTIFY until the timeout expires
+        let response = timeout(response_timeout, async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                match Message::from_vec(&buf[..len]) {
+                    Ok(response)
+                        if response.id() == message.id()
+                            && response.message_type() == MessageType::Response =>
+                    {
+                        return Ok::<_, std::io::Error>((response, len));
+                    }
+                    _ => continue,
+                }
+            }
+        })
+        .await;
+
+        match response {
+            Ok(Ok((response, len))) => {
+                if let (Some(key), Some(request_mac)) = (key, &request_mac) {
+                    verify_message(
+                        &buf[..len],
+                        std::slice::from_ref(key),
+                        Some(request_mac),
+                        true,
+                    )?;
+                }
+                return match response.response_code() {
+                    ResponseCode::NoError => Ok(()),
+                    code => Err(NotifyError::ErrorResponse(code)),
+                };
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => response_timeout *= 2,
+        }
+    }
+    Err(NotifyError::NoResponse(NOTIFY_ATTEMPTS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn notify_is_signed_and_retried() {
+        let secondary = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let key = TsigKey::parse("notify-key.:hmac-sha256:c2VjcmV0").unwrap();
+        let zone = Name::from_ascii("example.com.").unwrap();
+        let target = NotifyTarget {
+            address: secondary.local_addr().unwrap(),
+            tsig_key: Some(key.name.clone()),
+        };
+        let notify = tokio::spawn({
+            let (zone, keys) = (zone.clone(), vec![key.clone()]);
+            async move { send_notify(&zone, None, &target, &keys).await }
+        });
+
+        // the first NOTIFY isn't answered, so it is sent again
+        let mut buf = vec![0; 4096];
+        let (len, _) = secondary.recv_from(&mut buf).await.unwrap();
+        let first_request = buf[..len].to_vec();
+        let (len, peer) = secondary.recv_from(&mut buf).await.unwrap();
+        assert_eq!(buf[..len], first_request[..]);
+
+        let request = Message::from_vec(&buf[..len]).unwrap();
+        assert_eq!(request.op_code(), OpCode::Notify);
+        assert_eq!(request.message_type(), MessageType::Query);
+        assert_eq!(request.queries().len(), 1);
+        assert_eq!(request.queries()[0].name(), &zone);
+        assert_eq!(request.queries()[0].query_type(), RecordType::SOA);
+        let (used_key, request_mac) =
+            verify_message(&buf[..len], std::slice::from_ref(&key), None, true).unwrap();
+        assert_eq!(used_key.name, key.name);
+
+        let mut response = Message::error_msg(request.id(), OpCode::Notify, ResponseCode::NoError);
+        response.add_query(request.queries()[0].clone());
+        sign_message(&mut response, &key, Some(&request_mac), true).unwrap();
+        secondary
+            .send_to(&response.to_vec().unwrap(), peer)
+            .await
+            .unwrap();
+
+        notify.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn notify_with_unknown_key_is_not_sent() {
+        let target = NotifyTarget {
+            address: (Ipv4Addr::LOCALHOST, 53).into(),
+            tsig_key: Some(Name::from_ascii("unknown-key.").unwrap()),
+        };
+        let zone = Name::from_ascii("example.com.").unwrap();
+        let res = send_notify(&zone, None, &target, &[]).await;
+        assert!(matches!(res, Err(NotifyError::UnknownTsigKey(_))));
+    }
+}
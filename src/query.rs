@@ -0,0 +1,430 @@
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::DbEntry;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::errors_and_responses::PektinApiResult;
+use crate::types::{RecordIdentifier, SearchQuery, TextMatch, TtlRange};
+use crate::utils::{escape_glob, find_authoritative_zone};
+
+/// A `SearchQuery` whose globs and regexes have been compiled, see `SearchQuery::compile()`.
+#[derive(Debug, Clone)]
+pub struct CompiledQuery {
+    zone: Option<Name>,
+    name: Option<Matcher>,
+    rr_types: Vec<RecordType>,
+    ttl: Option<TtlRange>,
+    /// The path of each rdata field together with its matcher.
+    rdata: Vec<(Vec<String>, Matcher)>,
+    db_glob: String,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(text_match: &TextMatch) -> Result<Self, String> {
+        match text_match {
+            TextMatch::Exact(s) => Ok(Self::Exact(s.clone())),
+            TextMatch::Glob(glob) => Regex::new(&glob_to_regex(glob))
+                .map(Self::Regex)
+                .map_err(|e| format!("Invalid glob '{glob}': {e}")),
+            TextMatch::Regex(regex) => Regex::new(regex)
+                .map(Self::Regex)
+                .map_err(|e| format!("Invalid regex '{regex}': {e}")),
+        }
+    }
+
+    fn is_match(&self, s: &str) -> bool {
+        match self {
+            Self::Exact(exact) => exact == s,
+            Self::Regex(regex) => regex.is_match(s),
+        }
+    }
+}
+
+impl SearchQuery {
+    /// Validates the query and compiles its globs and regexes.
+    pub fn compile(&self) -> Result<CompiledQuery, String> {
+        if let Some(zone) = &self.zone {
+            if !zone.is_fqdn() {
+                return Err(
+                    "The zone name is not absolute (i.e. the root label at the end is missing)."
+                        .into(),
+                );
+            }
+        }
+        if let Some(range) = self.ttl {
+            if matches!((range.min, range.max), (Some(min), Some(max)) if min > max) {
+                return Err("The minimum TTL is greater than the maximum TTL.".into());
+            }
+        }
+
+        // names are stored in lowercase
+        let name = match &self.name {
+            Some(TextMatch::Exact(name)) => Some(TextMatch::Exact(name.to_ascii_lowercase())),
+            name => name.clone(),
+        };
+        // only the keys matching this glob are read from the db
+        let name_glob = match &name {
+            Some(TextMatch::Exact(name)) => Some(escape_glob(name)),
+            Some(TextMatch::Glob(glob)) => Some(glob.clone()),
+            _ => None,
+        };
+        if name_glob.as_ref().is_some_and(|glob| glob.contains(':')) {
+            return Err("Invalid name: must not contain ':'".into());
+        }
+        let name_glob = name_glob.unwrap_or_else(|| match &self.zone {
+            Some(zone) => format!("*{}", escape_glob(&zone.to_lowercase().to_string())),
+            None => "*".into(),
+        });
+        let rr_type_glob = match self.rr_types.as_slice() {
+            [rr_type] => format!("{:?}", rr_type),
+            _ => "*".into(),
+        };
+
+        Ok(CompiledQuery {
+            zone: self.zone.as_ref().map(Name::to_lowercase),
+            name: name.as_ref().map(Matcher::new).transpose()?,
+            rr_types: self.rr_types.clone(),
+            ttl: self.ttl,
+            rdata: self
+                .rdata
+                .iter()
+                .map(|rdata| {
+                    let path = rdata.field.split('.').map(String::from).collect();
+                    Ok((path, Matcher::new(&rdata.value)?))
+                })
+                .collect::<Result<_, String>>()?,
+            db_glob: format!("{name_glob}:{rr_type_glob}"),
+        })
+    }
+}
+
+impl CompiledQuery {
+    /// The zone the query is limited to, if any.
+    pub fn zone(&self) -> Option<&Name> {
+        self.zone.as_ref()
+    }
+
+    /// A glob that matches the keys of all RR sets that may match the query, and possibly others.
+    pub fn db_glob(&self) -> &str {
+        &self.db_glob
+    }
+
+    /// Whether the name and type of the RR set match the query. `zones` must contain all zones.
+    pub fn matches_ident(&self, ident: &RecordIdentifier, zones: &[Name]) -> bool {
+        if !self.rr_types.is_empty() && !self.rr_types.contains(&ident.rr_type) {
+            return false;
+        }
+        if let Some(zone) = &self.zone {
+            if find_authoritative_zone(&ident.name, zones).as_ref() != Some(zone) {
+                return false;
+            }
+        }
+        let name = ident.name.to_lowercase().to_string();
+        self.name.as_ref().is_none_or(|m| m.is_match(&name))
+    }
+
+    /// Whether the TTL and the rdata of the RR set match the query.
+    pub fn matches_entry(&self, entry: &DbEntry) -> PektinApiResult<bool> {
+        if let Some(range) = self.ttl {
+            if range.min.is_some_and(|min| entry.ttl < min)
+                || range.max.is_some_and(|max| entry.ttl > max)
+            {
+                return Ok(false);
+            }
+        }
+        if self.rdata.is_empty() {
+            return Ok(true);
+        }
+
+        let entry = serde_json::to_value(entry)?;
+        let records = match entry.get("rr_set") {
+            Some(Value::Array(records)) => records,
+            _ => return Ok(false),
+        };
+        Ok(records.iter().any(|record| {
+            self.rdata.iter().all(|(path, matcher)| {
+                let field = path
+                    .iter()
+                    .try_fold(record, |value, field| value.get(field.as_str()));
+                match field {
+                    Some(Value::String(s)) => matcher.is_match(s),
+                    Some(value) => matcher.is_match(&value.to_string()),
+                    None => false,
+                }
+            })
+        }))
+    }
+}
+
+/// Converts a glob as used in db `SCAN`s to an equivalent regex that matches the whole string.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    regex.push_str(&regex::escape(&c.to_string()));
+                }
+            }
+            '[' => {
+                regex.push('[');
+                let mut first = true;
+                for c in chars.by_ref() {
+                    match c {
+                        ']' => break,
+                        '^' if first => regex.push('^'),
+                        '-' => regex.push('-'),
+                        c => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                    first = false;
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use pektin_common::proto::rr::rdata::SOA;
+    use pektin_common::{ARecord, RrSet, SoaRecord};
+
+    use super::*;
+    use crate::types::RdataMatch;
+
+    fn name(name: &str) -> Name {
+        Name::from_ascii(name).unwrap()
+    }
+
+    fn ident(record_name: &str, rr_type: RecordType) -> RecordIdentifier {
+        RecordIdentifier {
+            name: name(record_name),
+            rr_type,
+        }
+    }
+
+    fn query() -> SearchQuery {
+        SearchQuery {
+            zone: None,
+            name: None,
+            rr_types: vec![],
+            ttl: None,
+            rdata: vec![],
+        }
+    }
+
+    fn a_entry(ttl: u32) -> DbEntry {
+        DbEntry {
+            name: name("www.example.com."),
+            ttl,
+            meta: "".into(),
+            rr_set: RrSet::A {
+                rr_set: vec![
+                    ARecord {
+                        value: [192, 0, 2, 1].into(),
+                    },
+                    ARecord {
+                        value: [192, 0, 2, 2].into(),
+                    },
+                ],
+            },
+        }
+    }
+
+    fn is_glob_match(glob: &str, s: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(s)
+    }
+
+    #[test]
+    fn globs_are_converted_to_anchored_regexes() {
+        assert!(is_glob_match("*.example.com.", "www.example.com."));
+        assert!(!is_glob_match("*.example.com.", "www.example.com.evil."));
+        assert!(!is_glob_match("*.example.com.", "wwwXexample.com."));
+        assert!(is_glob_match("w?w.example.com.", "wow.example.com."));
+        assert!(!is_glob_match("w?w.example.com.", "ww.example.com."));
+
+        assert!(is_glob_match("ns[0-9].example.com.", "ns1.example.com."));
+        assert!(!is_glob_match("ns[0-9].example.com.", "nsa.example.com."));
+        assert!(is_glob_match("ns[^0-9].example.com.", "nsa.example.com."));
+        assert!(!is_glob_match("ns[^0-9].example.com.", "ns1.example.com."));
+        // regex syntax in classes is escaped
+        assert!(is_glob_match("ns[.].example.com.", "ns..example.com."));
+        assert!(!is_glob_match("ns[.].example.com.", "nsx.example.com."));
+        // `^` only negates at the start of a class
+        assert!(is_glob_match("ns[a^].example.com.", "ns^.example.com."));
+
+        assert!(is_glob_match("\\*.example.com.", "*.example.com."));
+        assert!(!is_glob_match("\\*.example.com.", "www.example.com."));
+        assert!(is_glob_match("a\\?b", "a?b"));
+        assert!(!is_glob_match("a\\?b", "axb"));
+        assert!(is_glob_match("a+b(c)", "a+b(c)"));
+    }
+
+    #[test]
+    fn exact_names_are_case_insensitive() {
+        let compiled = SearchQuery {
+            name: Some(TextMatch::Exact("WWW.Example.com.".into())),
+            ..query()
+        }
+        .compile()
+        .unwrap();
+        assert_eq!(compiled.db_glob(), "www.example.com.:*");
+        assert!(compiled.matches_ident(&ident("www.example.com.", RecordType::A), &[]));
+        assert!(compiled.matches_ident(&ident("WWW.example.com.", RecordType::A), &[]));
+        assert!(!compiled.matches_ident(&ident("www2.example.com.", RecordType::A), &[]));
+
+        // glob characters in exact names are escaped
+        let compiled = SearchQuery {
+            name: Some(TextMatch::Exact("*.example.com.".into())),
+            rr_types: vec![RecordType::TXT],
+            ..query()
+        }
+        .compile()
+        .unwrap();
+        assert_eq!(compiled.db_glob(), "\\*.example.com.:TXT");
+        assert!(!compiled.matches_ident(&ident("www.example.com.", RecordType::TXT), &[]));
+    }
+
+    #[test]
+    fn invalid_queries_are_rejected() {
+        let relative_zone = SearchQuery {
+            zone: Some(Name::from_ascii("example.com").unwrap()),
+            ..query()
+        };
+        assert!(relative_zone.compile().is_err());
+        let inverted_ttl = SearchQuery {
+            ttl: Some(TtlRange {
+                min: Some(600),
+                max: Some(300),
+            }),
+            ..query()
+        };
+        assert!(inverted_ttl.compile().is_err());
+        let colon = SearchQuery {
+            name: Some(TextMatch::Glob("*:A".into())),
+            ..query()
+        };
+        assert!(colon.compile().is_err());
+        let invalid_regex = SearchQuery {
+            name: Some(TextMatch::Regex("(".into())),
+            ..query()
+        };
+        assert!(invalid_regex.compile().is_err());
+    }
+
+    #[test]
+    fn ttl_ranges_are_inclusive() {
+        let compiled = SearchQuery {
+            ttl: Some(TtlRange {
+                min: Some(300),
+                max: Some(600),
+            }),
+            ..query()
+        }
+        .compile()
+        .unwrap();
+        assert!(!compiled.matches_entry(&a_entry(299)).unwrap());
+        assert!(compiled.matches_entry(&a_entry(300)).unwrap());
+        assert!(compiled.matches_entry(&a_entry(600)).unwrap());
+        assert!(!compiled.matches_entry(&a_entry(601)).unwrap());
+
+        let open_ended = SearchQuery {
+            ttl: Some(TtlRange {
+                min: None,
+                max: Some(300),
+            }),
+            ..query()
+        }
+        .compile()
+        .unwrap();
+        assert!(open_ended.matches_entry(&a_entry(0)).unwrap());
+        assert!(!open_ended.matches_entry(&a_entry(301)).unwrap());
+    }
+
+    #[test]
+    fn rdata_matches_any_record_and_nested_fields() {
+        let rdata = |field: &str, value: TextMatch| SearchQuery {
+            rdata: vec![RdataMatch {
+                field: field.into(),
+                value,
+            }],
+            ..query()
+        };
+        // one of the records has to match
+        let compiled = rdata("value", TextMatch::Exact("192.0.2.2".into()))
+            .compile()
+            .unwrap();
+        assert!(compiled.matches_entry(&a_entry(300)).unwrap());
+        let compiled = rdata("value", TextMatch::Glob("198.51.100.*".into()))
+            .compile()
+            .unwrap();
+        assert!(!compiled.matches_entry(&a_entry(300)).unwrap());
+        let compiled = rdata("missing", TextMatch::Regex(".*".into()))
+            .compile()
+            .unwrap();
+        assert!(!compiled.matches_entry(&a_entry(300)).unwrap());
+
+        let soa = DbEntry {
+            name: name("example.com."),
+            ttl: 3600,
+            meta: "".into(),
+            rr_set: RrSet::SOA {
+                rr_set: vec![SoaRecord {
+                    value: SOA::new(
+                        name("ns1.example.com."),
+                        name("hostmaster.example.com."),
+                        2022010101,
+                        3600,
+                        600,
+                        604800,
+                        300,
+                    ),
+                }],
+            },
+        };
+        // numbers are matched against their JSON representation
+        let compiled = rdata("value.serial", TextMatch::Exact("2022010101".into()))
+            .compile()
+            .unwrap();
+        assert!(compiled.matches_entry(&soa).unwrap());
+        let compiled = rdata("value.mname", TextMatch::Regex("^ns1\\.".into()))
+            .compile()
+            .unwrap();
+        assert!(compiled.matches_entry(&soa).unwrap());
+        let compiled = rdata("value.serial.x", TextMatch::Regex(".*".into()))
+            .compile()
+            .unwrap();
+        assert!(!compiled.matches_entry(&soa).unwrap());
+    }
+
+    #[test]
+    fn zones_do_not_include_child_zones() {
+        let zones = [name("example.com."), name("sub.example.com.")];
+        let compiled = SearchQuery {
+            zone: Some(name("Example.com.")),
+            rr_types: vec![RecordType::A, RecordType::AAAA],
+            ..query()
+        }
+        .compile()
+        .unwrap();
+        assert_eq!(compiled.zone(), Some(&name("example.com.")));
+        assert_eq!(compiled.db_glob(), "*example.com.:*");
+        assert!(compiled.matches_ident(&ident("www.example.com.", RecordType::A), &zones));
+        assert!(compiled.matches_ident(&ident("example.com.", RecordType::AAAA), &zones));
+        assert!(!compiled.matches_ident(&ident("www.example.com.", RecordType::TXT), &zones));
+        assert!(!compiled.matches_ident(&ident("www.sub.example.com.", RecordType::A), &zones));
+        assert!(!compiled.matches_ident(&ident("www.example.org.", RecordType::A), &zones));
+    }
+}
@@ -0,0 +1,796 @@
+//! The storage backend that keeps the records in redis. Records are stored in db 0 under their db
+//! key, RRSIGs in the DNSSEC db (see `DNSSEC_DB`) or in db 0 with a key prefix (see
+//! `DnssecKeys`). Journals are redis streams.
+//!
+//! The db is either a single redis server or the master of a group of servers that is monitored by
+//! Redis Sentinel. In the latter case the master is discovered via the sentinels and the pools are
+//! switched to the new master after a failover (see `watch_sentinel_master()`).
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::RwLock;
+use pektin_common::deadpool_redis::redis::{
+    AsyncCommands, Client, ConnectionAddr, ConnectionInfo, FromRedisValue, RedisConnectionInfo,
+    Value,
+};
+use pektin_common::deadpool_redis::{self, Pool, PoolConfig, PoolError, Runtime, Timeouts};
+use pektin_common::proto::rr::Name;
+use pektin_common::{DbEntry, PektinCommonError, RrSet};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::config::Config;
+use crate::db::{DbWriteBatch, DNSSEC_DB};
+use crate::errors_and_responses::{PektinApiError, PektinApiResult};
+use crate::index::{
+    self, get_expiring_rrsig_keys, queue_rrsig_expiry_update, queue_zone_index_update,
+    refresh_rrsig_expiry, rrsig_expiry_index_available, zone_index_available, zone_index_key,
+    ZONES_KEY,
+};
+use crate::journal::{journal_db_key, journal_start_db_key, JournalEntry, MAX_JOURNAL_LENGTH};
+use crate::storage::{JournalRange, Storage};
+use crate::utils::escape_glob;
+
+/// How many keys `SCAN` looks at per call.
+const SCAN_COUNT: usize = 1000;
+/// How long to wait for a sentinel to answer before trying the next one.
+const SENTINEL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the sentinels are asked for the current master.
+const SENTINEL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where the DNSSEC data (the RRSIGs and the RRSIG expiry index) is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnssecKeys {
+    /// In the DNSSEC db (see `DNSSEC_DB`) under the same keys as the other data in db 0.
+    Db,
+    /// In db 0 with this prefix in front of each key, for managed redis offerings that only
+    /// provide db 0. This doesn't make Redis Cluster work, see `Config::db_dnssec_key_prefix`.
+    Prefix(String),
+}
+
+impl DnssecKeys {
+    fn prefix(&self) -> &str {
+        match self {
+            Self::Db => "",
+            Self::Prefix(prefix) => prefix,
+        }
+    }
+}
+
+/// The options for connecting to redis, see the `DB_*` config.
+#[derive(Clone)]
+pub struct RedisOptions {
+    pub hostname: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    /// Whether to connect to the db and the sentinels via TLS (`rediss://`).
+    pub tls: bool,
+    /// The addresses of the sentinels. If empty, `hostname` and `port` are used.
+    pub sentinels: Vec<(String, u16)>,
+    pub sentinel_master: String,
+    pub sentinel_password: String,
+    pub pool: PoolConfig,
+    pub dnssec_keys: DnssecKeys,
+}
+
+impl RedisOptions {
+    pub fn from_config(config: &Config) -> PektinApiResult<Self> {
+        let sentinels = config
+            .db_sentinels
+            .split(',')
+            .map(str::trim)
+            .filter(|sentinel| !sentinel.is_empty())
+            .map(|sentinel| {
+                sentinel
+                    .rsplit_once(':')
+                    .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+                    .map(|(host, port)| (host.trim_matches(['[', ']']).to_string(), port))
+                    .ok_or_else(|| {
+                        PektinApiError::InvalidDbConfig(format!(
+                            "invalid sentinel address {sentinel}, must be host:port"
+                        ))
+                    })
+            })
+            .collect::<PektinApiResult<_>>()?;
+
+        let mut pool = PoolConfig::default();
+        if let Some(max_size) = config.db_pool_max_size {
+            pool.max_size = max_size;
+        }
+        pool.timeouts = Timeouts {
+            wait: config
+                .db_pool_wait_timeout_millis
+                .map(Duration::from_millis),
+            create: config
+                .db_pool_create_timeout_millis
+                .map(Duration::from_millis),
+            recycle: config
+                .db_pool_recycle_timeout_millis
+                .map(Duration::from_millis),
+        };
+
+        let dnssec_keys = if config.db_dnssec_key_prefix.is_empty() {
+            DnssecKeys::Db
+        } else {
+            DnssecKeys::Prefix(config.db_dnssec_key_prefix.clone())
+        };
+
+        Ok(Self {
+            hostname: config.db_hostname.clone(),
+            port: config.db_port,
+            username: config.db_username.clone(),
+            password: config.db_password.clone(),
+            tls: config.db_tls,
+            sentinels,
+            sentinel_master: config.db_sentinel_master.clone(),
+            sentinel_password: config.db_sentinel_password.clone(),
+            pool,
+            dnssec_keys,
+        })
+    }
+
+    fn connection_info(
+        &self,
+        (host, port): (&str, u16),
+        db: u8,
+        username: &str,
+        password: &str,
+    ) -> ConnectionInfo {
+        let addr = if self.tls {
+            ConnectionAddr::TcpTls {
+                host: host.into(),
+                port,
+                insecure: false,
+            }
+        } else {
+            ConnectionAddr::Tcp(host.into(), port)
+        };
+        let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+        ConnectionInfo {
+            addr,
+            redis: RedisConnectionInfo {
+                db: db.into(),
+                username: non_empty(username),
+                password: non_empty(password),
+            },
+        }
+    }
+}
+
+/// A connection to db 0 or to the DNSSEC data of the redis backend.
+pub struct RedisConnection {
+    pub con: deadpool_redis::Connection,
+    dnssec_keys: Arc<DnssecKeys>,
+    /// Whether this is a connection to the DNSSEC data.
+    dnssec: bool,
+}
+
+impl RedisConnection {
+    /// The prefix of the keys of the data this connection is for. It is only non-empty for a
+    /// connection to the DNSSEC data if that is stored in db 0 with a key prefix.
+    pub fn prefix(&self) -> &str {
+        if self.dnssec {
+            self.dnssec_keys.prefix()
+        } else {
+            ""
+        }
+    }
+
+    /// The key prefix of the DNSSEC data, which is empty if it is stored in the DNSSEC db.
+    pub fn dnssec_prefix(&self) -> &str {
+        self.dnssec_keys.prefix()
+    }
+
+    /// The key under which `key` of the data this connection is for is stored.
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix())
+    }
+
+    fn keys(&self, keys: &[String]) -> Vec<String> {
+        prefix_keys(self.prefix(), keys)
+    }
+}
+
+struct PoolState {
+    pool: Pool,
+    /// The address of the server the pool connects to.
+    address: (String, u16),
+}
+
+/// A connection pool for db 0 or the DNSSEC data of the redis backend. Clones share the same
+/// pool, which is replaced when the sentinels report a new master.
+#[derive(Clone)]
+pub struct RedisPool {
+    state: Arc<RwLock<PoolState>>,
+    dnssec_keys: Arc<DnssecKeys>,
+    dnssec: bool,
+}
+
+impl RedisPool {
+    pub async fn get(&self) -> Result<RedisConnection, PoolError> {
+        let pool = self.state.read().pool.clone();
+        Ok(RedisConnection {
+            con: pool.get().await?,
+            dnssec_keys: self.dnssec_keys.clone(),
+            dnssec: self.dnssec,
+        })
+    }
+
+    fn address(&self) -> (String, u16) {
+        self.state.read().address.clone()
+    }
+
+    fn replace(&self, pool: Pool, address: (String, u16)) {
+        *self.state.write() = PoolState { pool, address };
+    }
+}
+
+/// Creates the connection pools for db 0 and the DNSSEC data. If sentinels are configured, the
+/// pools connect to the master they report.
+pub async fn create_redis_pools(options: &RedisOptions) -> PektinApiResult<(RedisPool, RedisPool)> {
+    let address = if options.sentinels.is_empty() {
+        (options.hostname.clone(), options.port)
+    } else {
+        discover_master(options).await?
+    };
+    let (pool, dnssec_pool) = create_pools(options, &address)?;
+
+    let dnssec_keys = Arc::new(options.dnssec_keys.clone());
+    let redis_pool = |pool, dnssec| RedisPool {
+        state: Arc::new(RwLock::new(PoolState {
+            pool,
+            address: address.clone(),
+        })),
+        dnssec_keys: dnssec_keys.clone(),
+        dnssec,
+    };
+    Ok((redis_pool(pool, false), redis_pool(dnssec_pool, true)))
+}
+
+/// Creates the pools for db 0 and the DNSSEC data on the server with the given address. If the
+/// DNSSEC data is stored with a key prefix, both pools connect to db 0.
+fn create_pools(options: &RedisOptions, address: &(String, u16)) -> PektinApiResult<(Pool, Pool)> {
+    let dnssec_db = match options.dnssec_keys {
+        DnssecKeys::Db => DNSSEC_DB,
+        DnssecKeys::Prefix(_) => 0,
+    };
+    Ok((
+        create_pool(options, address, 0)?,
+        create_pool(options, address, dnssec_db)?,
+    ))
+}
+
+fn create_pool(options: &RedisOptions, address: &(String, u16), db: u8) -> PektinApiResult<Pool> {
+    debug!(
+        "Connecting to db {} at {}://{}:{}",
+        db,
+        if options.tls { "rediss" } else { "redis" },
+        address.0,
+        address.1
+    );
+    let connection_info = options.connection_info(
+        (&address.0, address.1),
+        db,
+        &options.username,
+        &options.password,
+    );
+    deadpool_redis::Config {
+        url: None,
+        connection: Some(connection_info.into()),
+        pool: Some(options.pool),
+    }
+    .create_pool(Some(Runtime::Tokio1))
+    .map_err(|e| PektinApiError::InvalidDbConfig(e.to_string()))
+}
+
+/// Asks the sentinels one after another for the address of the master.
+async fn discover_master(options: &RedisOptions) -> PektinApiResult<(String, u16)> {
+    for (host, port) in &options.sentinels {
+        match query_sentinel(options, (host, *port)).await {
+            Ok(Some(address)) => return Ok(address),
+            Ok(None) => warn!(
+                "Sentinel {}:{} doesn't know the master {}",
+                host, port, options.sentinel_master
+            ),
+            Err(e) => warn!("Could not query sentinel {}:{}: {}", host, port, e),
+        }
+    }
+    Err(PektinApiError::NoDbMaster(options.sentinel_master.clone()))
+}
+
+async fn query_sentinel(
+    options: &RedisOptions,
+    sentinel: (&str, u16),
+) -> PektinApiResult<Option<(String, u16)>> {
+    let connection_info = options.connection_info(sentinel, 0, "", &options.sentinel_password);
+    let client = Client::open(connection_info).map_err(PektinCommonError::from)?;
+    let query = async {
+        let mut con = client.get_async_connection().await?;
+        deadpool_redis::redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&options.sentinel_master)
+            .query_async::<_, Option<(String, u16)>>(&mut con)
+            .await
+    };
+    match tokio::time::timeout(SENTINEL_TIMEOUT, query).await {
+        Ok(res) => Ok(res.map_err(PektinCommonError::from)?),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "sentinel didn't answer").into()),
+    }
+}
+
+/// Periodically asks the sentinels for the master and switches the pools to the new master after
+/// a failover. Never returns.
+pub async fn watch_sentinel_master(
+    db_pool: RedisPool,
+    dnssec_pool: RedisPool,
+    options: RedisOptions,
+) {
+    let mut interval = tokio::time::interval(SENTINEL_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let address = match discover_master(&options).await {
+            Ok(address) => address,
+            Err(e) => {
+                error!("Could not discover the db master: {}", e);
+                continue;
+            }
+        };
+        if address == db_pool.address() {
+            continue;
+        }
+        info!("The db master changed to {}:{}", address.0, address.1);
+        match create_pools(&options, &address) {
+            Ok((pool, dnssec)) => {
+                db_pool.replace(pool, address.clone());
+                dnssec_pool.replace(dnssec, address);
+            }
+            Err(e) => error!("Could not connect to the new db master: {}", e),
+        }
+    }
+}
+
+impl Storage for RedisConnection {
+    #[instrument(skip(self))]
+    async fn get_records(&mut self, keys: &[String]) -> PektinApiResult<Vec<Option<DbEntry>>> {
+        // if only one key comes back in the response, db returns an error because it cannot parse the reponse as a vector,
+        // and there were also issues with a "too many arguments for a GET command" error. we therefore roll our own implementation
+        // using only low-level commands.
+        if keys.len() == 1 {
+            debug!("using GET command");
+            match deadpool_redis::redis::cmd("GET")
+                .arg(self.key(&keys[0]))
+                .query_async::<_, String>(&mut self.con)
+                .await
+            {
+                Ok(s) => Ok(vec![Some(DbEntry::deserialize_from_db(&keys[0], &s)?)]),
+                Err(_) => Ok(vec![None]),
+            }
+        } else {
+            debug!("using MGET command");
+            let values = deadpool_redis::redis::cmd("MGET")
+                .arg(self.keys(keys))
+                .query_async::<_, Vec<Value>>(&mut self.con)
+                .await
+                .map_err(PektinCommonError::from)?;
+            parse_entries(keys, values)
+        }
+    }
+
+    async fn count_existing(&mut self, keys: &[String]) -> PektinApiResult<usize> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let keys = self.keys(keys);
+        Ok(self
+            .con
+            .exists::<_, usize>(keys)
+            .await
+            .map_err(PektinCommonError::from)?)
+    }
+
+    /// Reads both dbs in a single MULTI/EXEC transaction. `self` must be a connection to db 0; the
+    /// DNSSEC db is selected within the transaction and db 0 is selected again before it ends,
+    /// unless the DNSSEC data is stored in db 0 with a key prefix.
+    #[instrument(skip(self))]
+    async fn get_records_atomically(
+        &mut self,
+        keys: &[String],
+        dnssec_keys: &[String],
+    ) -> PektinApiResult<Vec<Option<DbEntry>>> {
+        let mut pipe = deadpool_redis::redis::pipe();
+        pipe.atomic();
+        // MGET fails without keys
+        if !keys.is_empty() {
+            pipe.cmd("MGET").arg(keys);
+        }
+        let prefix = self.dnssec_prefix().to_string();
+        let select_dnssec_db = prefix.is_empty();
+        if select_dnssec_db {
+            pipe.cmd("SELECT").arg(DNSSEC_DB).ignore();
+        }
+        if !dnssec_keys.is_empty() {
+            pipe.cmd("MGET").arg(prefix_keys(&prefix, dnssec_keys));
+        }
+        if select_dnssec_db {
+            pipe.cmd("SELECT").arg(0).ignore();
+        }
+        let mut results = pipe
+            .query_async::<_, Vec<Vec<Value>>>(&mut self.con)
+            .await
+            .map_err(PektinCommonError::from)?
+            .into_iter();
+
+        let mut parse = |keys: &[String]| -> PektinApiResult<Vec<Option<DbEntry>>> {
+            if keys.is_empty() {
+                return Ok(vec![]);
+            }
+            parse_entries(keys, results.next().unwrap_or_default())
+        };
+        let mut entries = parse(keys)?;
+        entries.extend(parse(dnssec_keys)?);
+        Ok(entries)
+    }
+
+    /// Applies all writes in a single MULTI/EXEC transaction, so that either all of them or none
+    /// are visible to other clients. `self` must be a connection to db 0; the DNSSEC db is
+    /// selected within the transaction and db 0 is selected again before it ends, unless the
+    /// DNSSEC data is stored in db 0 with a key prefix.
+    #[instrument(skip(self))]
+    async fn write(&mut self, batch: &DbWriteBatch) -> PektinApiResult<()> {
+        let serialize = |entries: &[DbEntry]| -> PektinApiResult<Vec<(String, String)>> {
+            entries
+                .iter()
+                .map(|e| Ok((e.db_key(), e.serialize_for_db()?)))
+                .collect()
+        };
+        let prefix = self.dnssec_prefix().to_string();
+        let select_dnssec_db = prefix.is_empty();
+        let set = serialize(&batch.set)?;
+        let dnssec_set: Vec<_> = serialize(&batch.dnssec_set)?
+            .into_iter()
+            .map(|(key, value)| (format!("{prefix}{key}"), value))
+            .collect();
+
+        let mut pipe = deadpool_redis::redis::pipe();
+        pipe.atomic();
+        if !batch.del.is_empty() {
+            pipe.del(&batch.del).ignore();
+        }
+        if !set.is_empty() {
+            pipe.set_multiple(&set).ignore();
+        }
+        let changed_keys: Vec<_> = batch
+            .del
+            .iter()
+            .cloned()
+            .chain(set.iter().map(|(key, _)| key.clone()))
+            .collect();
+        queue_zone_index_update(&mut pipe, &changed_keys);
+        // the journal is trimmed with `MAXLEN ~`, so it may contain slightly more entries
+        for (zone, entry) in &batch.journal {
+            pipe.cmd("XADD")
+                .arg(self.key(&journal_db_key(zone)))
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(MAX_JOURNAL_LENGTH)
+                .arg("*")
+                .arg("entry")
+                .arg(serde_json::to_string(entry)?)
+                .ignore();
+            pipe.cmd("SET")
+                .arg(self.key(&journal_start_db_key(zone)))
+                .arg(entry.serial_before())
+                .arg("NX")
+                .ignore();
+        }
+        if select_dnssec_db {
+            pipe.cmd("SELECT").arg(DNSSEC_DB).ignore();
+        }
+        if !batch.dnssec_del.is_empty() {
+            pipe.del(prefix_keys(&prefix, &batch.dnssec_del)).ignore();
+        }
+        if !dnssec_set.is_empty() {
+            pipe.set_multiple(&dnssec_set).ignore();
+        }
+        queue_rrsig_expiry_update(&mut pipe, &prefix, &batch.dnssec_set, &batch.dnssec_del);
+        if select_dnssec_db {
+            pipe.cmd("SELECT").arg(0).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut self.con)
+            .await
+            .map_err(PektinCommonError::from)?;
+        Ok(())
+    }
+
+    /// Reads the zones from `ZONES_KEY` if the zone index is available and scans for SOA records
+    /// otherwise.
+    #[instrument(skip(self))]
+    async fn get_zones(&mut self) -> PektinApiResult<Vec<String>> {
+        if zone_index_available(self).await? {
+            Ok(self
+                .con
+                .smembers(ZONES_KEY)
+                .await
+                .map_err(PektinCommonError::from)?)
+        } else {
+            Ok(self
+                .scan_keys(None, "*:SOA")
+                .await?
+                .into_iter()
+                .filter_map(|key| key.strip_suffix(":SOA").map(Into::into))
+                .collect())
+        }
+    }
+
+    /// Reads the keys from the zone index if it is available.
+    #[instrument(skip(self))]
+    async fn get_zone_index(&mut self, zone: &Name) -> PektinApiResult<Vec<String>> {
+        if zone_index_available(self).await? {
+            Ok(self
+                .con
+                .smembers(zone_index_key(zone))
+                .await
+                .map_err(PektinCommonError::from)?)
+        } else {
+            self.scan_keys(None, &format!("*{}:*", zone.to_lowercase()))
+                .await
+        }
+    }
+
+    /// Uses `SSCAN` on the zone index if `zone` is given and the zone index is available, and
+    /// `SCAN`, which, unlike `KEYS`, doesn't block the db while it iterates over the keyspace,
+    /// otherwise.
+    ///
+    /// If the DNSSEC data is stored in db 0 with a key prefix, the prefix is added to the glob
+    /// and removed from the returned keys on a connection to the DNSSEC data, and the prefixed
+    /// keys are skipped on a connection to db 0.
+    async fn scan_step(
+        &mut self,
+        zone: Option<&Name>,
+        cursor: u64,
+        glob: &str,
+    ) -> PektinApiResult<(u64, Vec<String>)> {
+        let mut cmd = match zone {
+            Some(zone) if zone_index_available(self).await? => {
+                let mut cmd = deadpool_redis::redis::cmd("SSCAN");
+                cmd.arg(zone_index_key(zone));
+                cmd.arg(cursor).arg("MATCH").arg(glob);
+                cmd
+            }
+            _ => {
+                let mut cmd = deadpool_redis::redis::cmd("SCAN");
+                cmd.arg(cursor)
+                    .arg("MATCH")
+                    .arg(format!("{}{glob}", escape_glob(self.prefix())));
+                cmd
+            }
+        };
+        let (next, keys) = cmd
+            .arg("COUNT")
+            .arg(SCAN_COUNT)
+            .query_async::<_, (u64, Vec<String>)>(&mut self.con)
+            .await
+            .map_err(PektinCommonError::from)?;
+
+        let keys = if self.dnssec {
+            let prefix = self.prefix();
+            keys.into_iter()
+                .filter_map(|key| key.strip_prefix(prefix).map(Into::into))
+                .collect()
+        } else {
+            let dnssec_prefix = self.dnssec_prefix();
+            keys.into_iter()
+                .filter(|key| dnssec_prefix.is_empty() || !key.starts_with(dnssec_prefix))
+                .collect()
+        };
+        Ok((next, keys))
+    }
+
+    async fn build_zone_index(&mut self) -> PektinApiResult<()> {
+        index::build_zone_index(self).await
+    }
+
+    async fn build_rrsig_expiry_index(&mut self) -> PektinApiResult<()> {
+        index::build_rrsig_expiry_index(self).await
+    }
+
+    /// If the RRSIG expiry index is available, only the RRSIGs it lists as expiring are read;
+    /// otherwise all RRSIGs are scanned.
+    #[instrument(skip(self))]
+    async fn get_expiring_rrsigs(&mut self, before: i64) -> PektinApiResult<Vec<DbEntry>> {
+        let indexed = rrsig_expiry_index_available(self).await?;
+        let keys = if indexed {
+            get_expiring_rrsig_keys(before, self).await?
+        } else {
+            self.scan_keys(None, "*:RRSIG:*").await?
+        };
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let entries = self.get_records(&keys).await?;
+
+        let is_expiring = |entry: &DbEntry| match &entry.rr_set {
+            RrSet::RRSIG { rr_set } => rr_set
+                .iter()
+                .any(|record| (record.signature_expiration as i64) < before),
+            _ => false,
+        };
+
+        // the index may be outdated for RRSIGs that were changed or deleted while it was built
+        if indexed {
+            let (outdated_keys, outdated): (Vec<_>, Vec<_>) = keys
+                .iter()
+                .cloned()
+                .zip(entries.iter().cloned())
+                .filter(|(_, entry)| !entry.as_ref().is_some_and(is_expiring))
+                .unzip();
+            refresh_rrsig_expiry(&outdated_keys, &outdated, self).await?;
+        }
+
+        Ok(entries
+            .into_iter()
+            .flatten()
+            .filter(|entry| is_expiring(entry))
+            .collect())
+    }
+
+    async fn try_lock(
+        &mut self,
+        key: &str,
+        token: &str,
+        expiry_millis: u64,
+    ) -> PektinApiResult<bool> {
+        let acquired = deadpool_redis::redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(token)
+            .arg("NX")
+            .arg("PX")
+            .arg(expiry_millis)
+            .query_async::<_, Option<String>>(&mut self.con)
+            .await
+            .map_err(PektinCommonError::from)?;
+        Ok(acquired.is_some())
+    }
+
+    async fn unlock(&mut self, key: &str, token: &str) -> PektinApiResult<()> {
+        deadpool_redis::redis::cmd("EVAL")
+            .arg(
+                r#"if redis.call("GET", KEYS[1]) == ARGV[1] then
+                    return redis.call("DEL", KEYS[1])
+                else
+                    return 0
+                end"#,
+            )
+            .arg(1)
+            .arg(self.key(key))
+            .arg(token)
+            .query_async::<_, u32>(&mut self.con)
+            .await
+            .map_err(PektinCommonError::from)?;
+        Ok(())
+    }
+
+    /// Stream ids start with the time the entry was added in milliseconds, so the time range is
+    /// selected by redis.
+    async fn read_journal(
+        &mut self,
+        zone: &Name,
+        range: JournalRange,
+    ) -> PektinApiResult<Vec<(String, JournalEntry)>> {
+        let start = range.since.map_or_else(|| "-".into(), |s| s.to_string());
+        let end = range.until.map_or_else(|| "+".into(), |u| u.to_string());
+        let key = self.key(&journal_db_key(zone));
+        let cmd = if range.newest_first {
+            let mut cmd = deadpool_redis::redis::cmd("XREVRANGE");
+            cmd.arg(&key).arg(end).arg(start);
+            cmd
+        } else {
+            let mut cmd = deadpool_redis::redis::cmd("XRANGE");
+            cmd.arg(&key).arg(start).arg(end);
+            cmd
+        };
+        let stream_entries = cmd
+            .query_async::<_, Vec<Value>>(&mut self.con)
+            .await
+            .map_err(PektinCommonError::from)?;
+
+        // each stream entry is an array of the entry id and a flat array of field-value pairs
+        let mut journal = Vec::with_capacity(stream_entries.len());
+        for stream_entry in stream_entries {
+            let (id, fields) = match stream_entry {
+                Value::Bulk(mut id_and_fields) if id_and_fields.len() == 2 => {
+                    let fields = id_and_fields.remove(1);
+                    let id = String::from_redis_value(&id_and_fields[0])
+                        .map_err(PektinCommonError::from)?;
+                    (id, fields)
+                }
+                _ => continue,
+            };
+            if let Value::Bulk(fields) = fields {
+                for pair in fields.chunks(2) {
+                    if let [field, value] = pair {
+                        if String::from_redis_value(field).ok().as_deref() == Some("entry") {
+                            let value =
+                                String::from_redis_value(value).map_err(PektinCommonError::from)?;
+                            journal.push((id.clone(), serde_json::from_str(&value)?));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(journal)
+    }
+
+    async fn delete_journals(&mut self, zones: &[Name]) -> PektinApiResult<()> {
+        if zones.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<_> = zones
+            .iter()
+            .flat_map(|zone| [journal_db_key(zone), journal_start_db_key(zone)])
+            .map(|key| self.key(&key))
+            .collect();
+        self.con
+            .del::<_, u32>(keys)
+            .await
+            .map_err(PektinCommonError::from)?;
+        Ok(())
+    }
+
+    async fn get_value(&mut self, key: &str) -> PektinApiResult<Option<String>> {
+        let key = self.key(key);
+        Ok(self
+            .con
+            .get::<_, Option<String>>(key)
+            .await
+            .map_err(PektinCommonError::from)?)
+    }
+
+    async fn set_value(&mut self, key: &str, value: &str) -> PektinApiResult<()> {
+        let key = self.key(key);
+        self.con
+            .set::<_, _, ()>(key, value)
+            .await
+            .map_err(PektinCommonError::from)?;
+        Ok(())
+    }
+
+    async fn delete_value(&mut self, key: &str) -> PektinApiResult<()> {
+        let key = self.key(key);
+        self.con
+            .del::<_, u32>(key)
+            .await
+            .map_err(PektinCommonError::from)?;
+        Ok(())
+    }
+
+    /// Uses redis pub/sub, so messages are only delivered to clients that are subscribed when
+    /// they are published.
+    async fn publish(&mut self, channel: &str, message: &str) -> PektinApiResult<()> {
+        self.con
+            .publish::<_, _, u32>(channel, message)
+            .await
+            .map_err(PektinCommonError::from)?;
+        Ok(())
+    }
+}
+
+/// Parses the values returned by `MGET` for the given keys.
+fn parse_entries(keys: &[String], values: Vec<Value>) -> PektinApiResult<Vec<Option<DbEntry>>> {
+    keys.iter()
+        .zip(values)
+        .map(|(key, val)| {
+            if val == Value::Nil {
+                return Ok(None);
+            }
+            let val = String::from_redis_value(&val).map_err(PektinCommonError::from)?;
+            Ok(Some(DbEntry::deserialize_from_db(key, &val)?))
+        })
+        .collect()
+}
+
+fn prefix_keys(prefix: &str, keys: &[String]) -> Vec<String> {
+    keys.iter().map(|key| format!("{prefix}{key}")).collect()
+}
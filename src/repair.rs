@@ -0,0 +1,313 @@
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{DbEntry, DnskeyRecord, RrSet};
+use tracing::{info, instrument, warn};
+
+use crate::db::{
+    acquire_locks, get_or_mget_records, get_zone_dnskey, get_zone_keys, release_locks, DbWriteBatch,
+};
+use crate::dnssec::{sign_db_entry, update_nsec3_chain, verify_rrsig};
+use crate::errors_and_responses::{PektinApiError, PektinApiResult};
+use crate::journal::{commit_zone_changes, prepare_zone_changes, ZoneChanges};
+use crate::storage::{Connection, Storage};
+use crate::types::{AppState, RecordIdentifier};
+use crate::utils::random_string;
+
+/// What `repair_consistency()` changed.
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    /// RRSIGs in the DNSSEC db whose RR set doesn't exist.
+    pub deleted_rrsigs: usize,
+    /// RR sets whose RRSIG was missing, invalid, or expired.
+    pub resigned_rr_sets: usize,
+    /// NSEC3(PARAM) records that were missing, outdated, or no longer part of the NSEC3 chain.
+    pub changed_nsec3_records: usize,
+}
+
+/// The key of the lock that makes sure that only one instance repairs the db at a time. Like the
+/// other lock keys, it contains no colon.
+const REPAIR_LOCK_KEY: &str = "lock/repair";
+/// How long the repair lock is held at most, in case its holder crashes while repairing.
+const REPAIR_LOCK_EXPIRY_MILLIS: u64 = 60 * 60 * 1000;
+
+/// Finds and repairs inconsistencies between the records in db 0 and the RRSIGs in the DNSSEC db,
+/// e.g. left behind by versions of pektin that didn't write them atomically:
+///
+/// - RRSIGs whose RR set doesn't exist are deleted.
+/// - RR sets in a zone without a valid RRSIG are re-signed.
+/// - The NSEC3 chain of every zone is brought up to date.
+///
+/// The affected RR sets and zones are locked while they are repaired, so this may run while the
+/// API is in use. The SOA serials of changed zones are incremented.
+///
+/// Only one instance repairs the db at a time. If another instance is already repairing it, this
+/// returns `None` without changing anything.
+#[instrument(skip(state))]
+pub async fn repair_consistency(state: &AppState) -> PektinApiResult<Option<RepairReport>> {
+    let mut con = state.db_pool.get().await?;
+    let token = random_string();
+    if !con
+        .try_lock(REPAIR_LOCK_KEY, &token, REPAIR_LOCK_EXPIRY_MILLIS)
+        .await?
+    {
+        info!("Not repairing the db because another instance is already repairing it");
+        return Ok(None);
+    }
+    let res = repair(state, &mut con).await;
+    con.unlock(REPAIR_LOCK_KEY, &token).await?;
+    res.map(Some)
+}
+
+async fn repair(state: &AppState, con: &mut Connection) -> PektinApiResult<RepairReport> {
+    let mut dnssec_con = state.db_pool_dnssec.get().await?;
+    let mut report = RepairReport {
+        deleted_rrsigs: delete_orphaned_rrsigs(con, &mut dnssec_con).await?,
+        ..Default::default()
+    };
+
+    let zones: Vec<_> = con
+        .get_zones()
+        .await?
+        .into_iter()
+        .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
+        .collect();
+    for zone in zones {
+        let (resigned, nsec3) = repair_zone(&zone, state, con, &mut dnssec_con).await?;
+        report.resigned_rr_sets += resigned;
+        report.changed_nsec3_records += nsec3;
+    }
+
+    info!(
+        "Repaired db: deleted {} orphaned RRSIGs, re-signed {} RR sets, changed {} NSEC3 records",
+        report.deleted_rrsigs, report.resigned_rr_sets, report.changed_nsec3_records
+    );
+    Ok(report)
+}
+
+async fn delete_orphaned_rrsigs(
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<usize> {
+    let rrsig_keys = dnssec_con.scan_keys(None, "*:RRSIG:*").await?;
+    let idents = rrsig_keys
+        .iter()
+        .map(|key| RecordIdentifier::from_db_key(key.replace(":RRSIG", "")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let orphaned = find_missing(idents, con).await?;
+    if orphaned.is_empty() {
+        return Ok(0);
+    }
+
+    // the RR set may have been created since we checked
+    let locks = acquire_locks(&orphaned, con).await?;
+    let res = async {
+        let orphaned = find_missing(orphaned, con).await?;
+        DbWriteBatch {
+            dnssec_del: orphaned
+                .iter()
+                .map(RecordIdentifier::rrsig_db_key)
+                .collect(),
+            ..Default::default()
+        }
+        .execute(con)
+        .await?;
+        Ok(orphaned.len())
+    }
+    .await;
+    release_locks(locks, con).await?;
+    res
+}
+
+/// Returns the RR sets that don't exist in db 0.
+async fn find_missing(
+    idents: Vec<RecordIdentifier>,
+    con: &mut Connection,
+) -> PektinApiResult<Vec<RecordIdentifier>> {
+    if idents.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys: Vec<_> = idents.iter().map(RecordIdentifier::db_key).collect();
+    let entries = get_or_mget_records(&keys, con).await?;
+    Ok(idents
+        .into_iter()
+        .zip(entries)
+        .filter(|(_, entry)| entry.is_none())
+        .map(|(ident, _)| ident)
+        .collect())
+}
+
+/// Re-signs the RR sets of the zone that have no valid RRSIG and updates the zone's NSEC3 chain.
+/// Returns the number of re-signed RR sets and changed NSEC3 records.
+#[instrument(skip(state, con, dnssec_con))]
+async fn repair_zone(
+    zone: &Name,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<(usize, usize)> {
+    let idents = match get_zone_keys(&[zone], con).await?.pop().flatten() {
+        Some(keys) => keys
+            .into_iter()
+            .map(RecordIdentifier::from_db_key)
+            .collect::<Result<Vec<_>, _>>()?,
+        None => return Ok((0, 0)),
+    };
+    let dnskey = match get_zone_dnskey(zone, con).await {
+        Ok(dnskey) => dnskey,
+        // e.g. zones transferred from a primary before they were signed
+        Err(PektinApiError::NoDnskeyRecord(_)) => {
+            warn!("Not repairing {} because it has no DNSKEY record", zone);
+            return Ok((0, 0));
+        }
+        Err(e) => return Err(e),
+    };
+    let unsigned = find_unsigned(&idents, &dnskey, con, dnssec_con).await?;
+    let nsec3 = update_nsec3_chain(zone, &[], &[], con).await?;
+    if unsigned.is_empty() && nsec3.set.is_empty() && nsec3.delete.is_empty() {
+        return Ok((0, 0));
+    }
+
+    let locked: Vec<_> = unsigned
+        .iter()
+        .map(|entry| RecordIdentifier {
+            name: entry.name.clone(),
+            rr_type: entry.rr_type(),
+        })
+        .collect();
+    let locks = acquire_locks(&locked, con).await?;
+    let res = async {
+        // the zone is locked through its SOA record even if no RR set needs to be re-signed, so that
+        // the NSEC3 chain can't change while it is repaired
+        let zone_idents: Vec<_> = locked
+            .iter()
+            .cloned()
+            .chain(std::iter::once(RecordIdentifier {
+                name: zone.clone(),
+                rr_type: RecordType::SOA,
+            }))
+            .collect();
+        let mut zone_changes = prepare_zone_changes(&zone_idents, con, dnssec_con).await?;
+        let repaired = repair_batch(
+            zone,
+            &locked,
+            &dnskey,
+            &mut zone_changes,
+            state,
+            con,
+            dnssec_con,
+        )
+        .await;
+        let (batch, vault_api_token, repaired) = match repaired {
+            Ok(Some(r)) => r,
+            Ok(None) => {
+                zone_changes.abort(&state.db_pool);
+                return Ok((0, 0));
+            }
+            Err(e) => {
+                zone_changes.abort(&state.db_pool);
+                return Err(e);
+            }
+        };
+        commit_zone_changes(zone_changes, batch, state, &vault_api_token, None, con).await?;
+        Ok(repaired)
+    }
+    .await;
+    release_locks(locks, con).await?;
+    res
+}
+
+/// Returns the batch that re-signs the RR sets in `locked` that have no valid RRSIG and updates the
+/// zone's NSEC3 chain, together with the Vault API token and the numbers of re-signed RR sets and
+/// changed NSEC3 records, or `None` if there is nothing to repair. The changed NSEC3 records are
+/// added to `zone_changes`.
+async fn repair_batch(
+    zone: &Name,
+    locked: &[RecordIdentifier],
+    dnskey: &DnskeyRecord,
+    zone_changes: &mut ZoneChanges,
+    state: &AppState,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<Option<(DbWriteBatch, String, (usize, usize))>> {
+    // the RR sets may have been changed (and signed) since we checked
+    let unsigned = find_unsigned(locked, dnskey, con, dnssec_con).await?;
+    let nsec3 = update_nsec3_chain(zone, &[], &[], con).await?;
+    if unsigned.is_empty() && nsec3.set.is_empty() && nsec3.delete.is_empty() {
+        return Ok(None);
+    }
+
+    let nsec3_idents: Vec<_> = nsec3
+        .set
+        .iter()
+        .map(|entry| RecordIdentifier {
+            name: entry.name.clone(),
+            rr_type: entry.rr_type(),
+        })
+        .chain(nsec3.delete.iter().cloned())
+        .collect();
+    zone_changes
+        .add_rr_sets(&nsec3_idents, con, dnssec_con)
+        .await?;
+
+    let vault_api_token = crate::vault::ApiTokenCache::get(
+        &state.vault_uri,
+        &state.vault_user_name,
+        &state.vault_password,
+    )
+    .await?;
+    let mut rrsigs = Vec::with_capacity(unsigned.len() + nsec3.set.len());
+    for entry in unsigned.iter().chain(&nsec3.set) {
+        rrsigs.push(
+            sign_db_entry(
+                zone,
+                entry.clone(),
+                dnskey,
+                &state.vault_uri,
+                &vault_api_token,
+            )
+            .await?,
+        );
+    }
+    let repaired = (unsigned.len(), nsec3.set.len() + nsec3.delete.len());
+    let batch = DbWriteBatch {
+        del: nsec3.delete.iter().map(RecordIdentifier::db_key).collect(),
+        dnssec_del: nsec3
+            .delete
+            .iter()
+            .map(RecordIdentifier::rrsig_db_key)
+            .collect(),
+        set: nsec3.set,
+        dnssec_set: rrsigs,
+        ..Default::default()
+    };
+    Ok(Some((batch, vault_api_token, repaired)))
+}
+
+/// Returns the RR sets that exist but have no valid RRSIG by the DNSKEY.
+async fn find_unsigned(
+    idents: &[RecordIdentifier],
+    dnskey: &DnskeyRecord,
+    con: &mut Connection,
+    dnssec_con: &mut Connection,
+) -> PektinApiResult<Vec<DbEntry>> {
+    if idents.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys: Vec<_> = idents.iter().map(RecordIdentifier::db_key).collect();
+    let rrsig_keys: Vec<_> = idents.iter().map(RecordIdentifier::rrsig_db_key).collect();
+    let entries = get_or_mget_records(&keys, con).await?;
+    let rrsigs = get_or_mget_records(&rrsig_keys, dnssec_con).await?;
+    Ok(entries
+        .into_iter()
+        .zip(rrsigs)
+        .filter_map(|(entry, rrsig)| {
+            let entry = entry?;
+            let signed = match rrsig.map(|r| r.rr_set) {
+                Some(RrSet::RRSIG { rr_set }) => rr_set
+                    .iter()
+                    .any(|rrsig| verify_rrsig(&entry, rrsig, dnskey)),
+                _ => false,
+            };
+            (!signed).then_some(entry)
+        })
+        .collect())
+}
@@ -1,17 +1,17 @@
 use std::collections::HashMap;
 
-use chrono::{Duration, TimeZone, Utc};
-use pektin_common::deadpool_redis::redis::AsyncCommands;
-use pektin_common::deadpool_redis::Connection;
+use chrono::{Duration, Utc};
 use pektin_common::proto::rr::Name;
-use pektin_common::{get_authoritative_zones, DbEntry, PektinCommonError, RrSet};
+use pektin_common::DbEntry;
 use tokio::time::sleep;
 use tracing::{debug, error};
 
-use crate::db::{get_or_mget_records, get_zone_dnskey_records};
+use crate::db::{get_or_mget_records, get_zone_dnskey_records, DbWriteBatch};
 use crate::dnssec::sign_db_entry;
 use crate::errors_and_responses::PektinApiResult;
-use crate::types::AppState;
+use crate::journal::{commit_zone_changes, prepare_zone_changes};
+use crate::storage::{Connection, Storage};
+use crate::types::{AppState, RecordIdentifier};
 use crate::utils::find_authoritative_zone;
 
 // `interval` is the duration between two runs (check which RRSIGs need to be recreated and actually recreate them)
@@ -47,7 +47,8 @@ async fn signing_task_run(state: &AppState, threshold: Duration) -> PektinApiRes
     )
     .await?;
 
-    let authoritative_zones: Vec<_> = get_authoritative_zones(&mut con)
+    let authoritative_zones: Vec<_> = con
+        .get_zones()
         .await?
         .into_iter()
         .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
@@ -68,6 +69,16 @@ async fn signing_task_run(state: &AppState, threshold: Duration) -> PektinApiRes
             .into_iter()
             .collect();
 
+    // the new RRSIGs change the zones, so they get new serials and journal entries
+    let idents: Vec<_> = records_to_be_resigned
+        .iter()
+        .map(|r| RecordIdentifier {
+            name: r.name.clone(),
+            rr_type: r.rr_type(),
+        })
+        .collect();
+    let zone_changes = prepare_zone_changes(&idents, &mut con, &mut dnssec_con).await?;
+
     let mut rrsig_records = Vec::with_capacity(records_to_be_resigned.len());
     for record in records_to_be_resigned {
         let record_zone = find_authoritative_zone(&record.name, &authoritative_zones)
@@ -85,61 +96,120 @@ async fn signing_task_run(state: &AppState, threshold: Duration) -> PektinApiRes
         .await;
         rrsig_records.push(rec);
     }
-    let rrsig_records: Result<Vec<_>, _> = rrsig_records.into_iter().collect();
-    let rrsig_records: Result<Vec<_>, _> = rrsig_records?
-        .iter()
-        .map(|e| match e.serialize_for_db() {
-            Ok(ser) => Ok((e.db_key(), ser)),
-            Err(e) => Err(e),
-        })
-        .collect();
-
-    dnssec_con
-        .set_multiple::<_, _, ()>(&rrsig_records?)
-        .await
-        .map_err(PektinCommonError::from)?;
+    let rrsig_records = match rrsig_records.into_iter().collect::<Result<Vec<_>, _>>() {
+        Ok(r) => r,
+        Err(e) => {
+            zone_changes.abort(&state.db_pool);
+            return Err(e);
+        }
+    };
+    let batch = DbWriteBatch {
+        dnssec_set: rrsig_records,
+        ..Default::default()
+    };
+    commit_zone_changes(zone_changes, batch, state, &vault_api_token, None, &mut con).await?;
 
     Ok(())
 }
 
+/// Returns the records whose RRSIG expires in less than `threshold`.
 async fn get_records_to_be_resigned(
     threshold: Duration,
     con: &mut Connection,
     dnssec_con: &mut Connection,
 ) -> PektinApiResult<Vec<DbEntry>> {
-    let glob = "*:RRSIG:*";
-    let rrsig_record_db_keys = dnssec_con
-        .keys::<_, Vec<String>>(glob)
-        .await
-        .map_err(PektinCommonError::from)?;
-    let rrsig_records = get_or_mget_records(&rrsig_record_db_keys, dnssec_con).await?;
-
-    let now = Utc::now();
-    let expiring_rrsig_record_db_keys = rrsig_records.iter().filter_map(|r| match r {
-        Some(
-            entry @ DbEntry {
-                rr_set: RrSet::RRSIG { rr_set },
-                ..
-            },
-        ) => {
-            let expiring_records = rr_set.iter().filter(|record| {
-                let expiration = Utc.timestamp(record.signature_expiration as i64, 0);
-                expiration - now < threshold
-            });
-            if expiring_records.count() > 0 {
-                Some(entry.db_key())
-            } else {
-                None
-            }
-        }
-        _ => None,
-    });
+    let rrsig_records = dnssec_con
+        .get_expiring_rrsigs((Utc::now() + threshold).timestamp())
+        .await?;
 
-    let records_to_be_resigned_db_keys: Vec<_> = expiring_rrsig_record_db_keys
-        .map(|key| key.replace(":RRSIG", ""))
+    let records_to_be_resigned_db_keys: Vec<_> = rrsig_records
+        .iter()
+        .map(|entry| entry.db_key().replace(":RRSIG", ""))
         .collect();
+    if records_to_be_resigned_db_keys.is_empty() {
+        return Ok(vec![]);
+    }
 
     let records_to_be_resigned = get_or_mget_records(&records_to_be_resigned_db_keys, con).await?;
 
     Ok(records_to_be_resigned.into_iter().flatten().collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use pektin_common::proto::rr::RecordType;
+    use pektin_common::RrSet;
+
+    use super::*;
+    use crate::journal::get_journal;
+    use crate::test_utils::{
+        a_entry, get_with_rrsig, name, set_records, soa_entry, test_state, zone_serial,
+    };
+
+    #[actix_web::test]
+    async fn expiring_rrsigs_are_recreated() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+
+        // the RRSIGs are valid for two days, so none of them expires within an hour
+        signing_task_run(&state, Duration::hours(1)).await.unwrap();
+        assert_eq!(zone_serial("example.com.", &state).await, Some(2));
+
+        // signatures are deterministic, so RRSIGs created in the same second as the old ones
+        // would be identical and not show up as changed in the journal
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        signing_task_run(&state, Duration::days(3)).await.unwrap();
+        assert_eq!(zone_serial("example.com.", &state).await, Some(3));
+        let (_, rrsig) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        assert!(rrsig.is_some());
+
+        let mut con = state.db_pool.get().await.unwrap();
+        let journal = get_journal(&name("example.com."), &mut con).await.unwrap();
+        assert_eq!(journal.len(), 2);
+        assert!(journal[1].author.is_none());
+        assert!(journal[1]
+            .added
+            .iter()
+            .any(|e| e.name == name("www.example.com.") && e.rr_type() == RecordType::RRSIG));
+    }
+
+    #[actix_web::test]
+    async fn only_rrsigs_expiring_within_the_threshold_are_selected() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        let www = a_entry("www.example.com.", [192, 0, 2, 1]);
+        set_records(
+            &[www.clone(), a_entry("mail.example.com.", [192, 0, 2, 2])],
+            &state,
+        )
+        .await;
+
+        // the RRSIG of www expires in an hour, all others in two days
+        let (_, rrsig) = get_with_rrsig("www.example.com.", RecordType::A, &state).await;
+        let mut rrsig = rrsig.unwrap();
+        if let RrSet::RRSIG { rr_set } = &mut rrsig.rr_set {
+            for record in rr_set {
+                record.signature_expiration = (Utc::now() + Duration::hours(1)).timestamp() as u32;
+            }
+        }
+        let mut con = state.db_pool.get().await.unwrap();
+        let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+        DbWriteBatch {
+            dnssec_set: vec![rrsig],
+            ..Default::default()
+        }
+        .execute(&mut con)
+        .await
+        .unwrap();
+
+        let selected = get_records_to_be_resigned(Duration::minutes(30), &mut con, &mut dnssec_con)
+            .await
+            .unwrap();
+        assert!(selected.is_empty());
+        let selected = get_records_to_be_resigned(Duration::hours(2), &mut con, &mut dnssec_con)
+            .await
+            .unwrap();
+        assert_eq!(selected, vec![www]);
+    }
+}
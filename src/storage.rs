@@ -0,0 +1,258 @@
+use pektin_common::deadpool_redis::PoolError;
+use pektin_common::proto::rr::Name;
+use pektin_common::DbEntry;
+
+use crate::db::DbWriteBatch;
+use crate::errors_and_responses::PektinApiResult;
+use crate::journal::JournalEntry;
+use crate::memory_storage::{MemoryConnection, MemoryStorage};
+use crate::redis_storage::{RedisConnection, RedisPool};
+
+/// The operations that pektin-api needs from the db. All handlers access the db through this
+/// trait, so that it can be backed by redis (see the `redis_storage` module) or held in memory
+/// (see the `memory_storage` module).
+///
+/// A connection is a connection to a single db, either db 0 or the DNSSEC db. Unless noted
+/// otherwise, methods must be called on a connection to db 0.
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    /// Returns the entries with the given keys, `None` for keys that don't exist.
+    async fn get_records(&mut self, keys: &[String]) -> PektinApiResult<Vec<Option<DbEntry>>>;
+
+    /// Returns how many of the given keys exist. Can be called on either db.
+    async fn count_existing(&mut self, keys: &[String]) -> PektinApiResult<usize>;
+
+    /// Gets the records with the given keys from db 0 and the RRSIGs with the given keys from the
+    /// DNSSEC db as a consistent state of both dbs. The entries are returned in the order of
+    /// `keys` followed by `dnssec_keys`.
+    async fn get_records_atomically(
+        &mut self,
+        keys: &[String],
+        dnssec_keys: &[String],
+    ) -> PektinApiResult<Vec<Option<DbEntry>>>;
+
+    /// Applies all writes of the batch atomically, together with the updates of the zone indexes
+    /// and the RRSIG expiry index. Journals are trimmed to about `journal::MAX_JOURNAL_LENGTH`
+    /// entries. The first entry added to a journal also sets the zone's
+    /// `journal::journal_start_db_key()`. See `DbWriteBatch::execute()`.
+    async fn write(&mut self, batch: &DbWriteBatch) -> PektinApiResult<()>;
+
+    /// Returns the names of all zones, i.e. of all names with a SOA record.
+    async fn get_zones(&mut self) -> PektinApiResult<Vec<String>>;
+
+    /// Returns the keys of all records in the zone, including those of its child zones. The zone
+    /// must exist.
+    async fn get_zone_index(&mut self, zone: &Name) -> PektinApiResult<Vec<String>>;
+
+    /// A single step of iterating over the keys matching the glob, returning the next cursor and
+    /// the matching keys of this step. The iteration starts with cursor 0 and is complete when
+    /// the returned cursor is 0. A key may be returned more than once.
+    ///
+    /// If `zone` is given, only the keys of records in the zone (including its child zones) are
+    /// guaranteed to be returned, so the glob must only match such keys.
+    ///
+    /// Can be called on either db, but `zone` must be `None` for the DNSSEC db.
+    async fn scan_step(
+        &mut self,
+        zone: Option<&Name>,
+        cursor: u64,
+        glob: &str,
+    ) -> PektinApiResult<(u64, Vec<String>)>;
+
+    /// Returns all keys matching the glob without duplicates, see `scan_step()`.
+    async fn scan_keys(&mut self, zone: Option<&Name>, glob: &str) -> PektinApiResult<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) = self.scan_step(zone, cursor, glob).await?;
+            keys.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        keys.sort_unstable();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// Builds the zone indexes for data that was written before they existed, unless they have
+    /// already been built.
+    async fn build_zone_index(&mut self) -> PektinApiResult<()>;
+
+    /// Builds the RRSIG expiry index for RRSIGs that were written before it existed, unless it
+    /// has already been built. Must be called on a connection to the DNSSEC db.
+    async fn build_rrsig_expiry_index(&mut self) -> PektinApiResult<()>;
+
+    /// Returns all RRSIG entries of which at least one signature expires before `before` (in
+    /// seconds since the epoch). Must be called on a connection to the DNSSEC db.
+    async fn get_expiring_rrsigs(&mut self, before: i64) -> PektinApiResult<Vec<DbEntry>>;
+
+    /// Sets `key` to `token` if `key` is not set, returns whether it was set. The key is deleted
+    /// after `expiry_millis` milliseconds.
+    async fn try_lock(
+        &mut self,
+        key: &str,
+        token: &str,
+        expiry_millis: u64,
+    ) -> PektinApiResult<bool>;
+
+    /// Deletes `key` if it is still set to `token`.
+    async fn unlock(&mut self, key: &str, token: &str) -> PektinApiResult<()>;
+
+    /// Returns the ids and entries of the zone's journal that were added in the given range.
+    async fn read_journal(
+        &mut self,
+        zone: &Name,
+        range: JournalRange,
+    ) -> PektinApiResult<Vec<(String, JournalEntry)>>;
+
+    /// Deletes the journals of the given zones together with their start serials.
+    async fn delete_journals(&mut self, zones: &[Name]) -> PektinApiResult<()>;
+
+    /// Returns the value of a key that is not a record key, like the notify targets of a zone.
+    async fn get_value(&mut self, key: &str) -> PektinApiResult<Option<String>>;
+
+    /// Sets the value of a key that is not a record key.
+    async fn set_value(&mut self, key: &str, value: &str) -> PektinApiResult<()>;
+
+    /// Deletes a key that is not a record key.
+    async fn delete_value(&mut self, key: &str) -> PektinApiResult<()>;
+
+    /// Sends the message to all current subscribers of the channel.
+    async fn publish(&mut self, channel: &str, message: &str) -> PektinApiResult<()>;
+}
+
+/// Which entries of a journal to read, see `Storage::read_journal()`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JournalRange {
+    /// Only entries that were added at or after this time in milliseconds since the Unix epoch.
+    pub since: Option<u64>,
+    /// Only entries that were added at or before this time in milliseconds since the Unix epoch.
+    pub until: Option<u64>,
+    /// Whether the newest entry comes first instead of the oldest.
+    pub newest_first: bool,
+}
+
+/// A connection to one of the dbs of the configured storage backend.
+pub enum Connection {
+    Redis(RedisConnection),
+    Memory(MemoryConnection),
+}
+
+macro_rules! delegate {
+    ($self:ident, $con:ident => $call:expr) => {
+        match $self {
+            Connection::Redis($con) => $call.await,
+            Connection::Memory($con) => $call.await,
+        }
+    };
+}
+
+impl Storage for Connection {
+    async fn get_records(&mut self, keys: &[String]) -> PektinApiResult<Vec<Option<DbEntry>>> {
+        delegate!(self, con => con.get_records(keys))
+    }
+
+    async fn count_existing(&mut self, keys: &[String]) -> PektinApiResult<usize> {
+        delegate!(self, con => con.count_existing(keys))
+    }
+
+    async fn get_records_atomically(
+        &mut self,
+        keys: &[String],
+        dnssec_keys: &[String],
+    ) -> PektinApiResult<Vec<Option<DbEntry>>> {
+        delegate!(self, con => con.get_records_atomically(keys, dnssec_keys))
+    }
+
+    async fn write(&mut self, batch: &DbWriteBatch) -> PektinApiResult<()> {
+        delegate!(self, con => con.write(batch))
+    }
+
+    async fn get_zones(&mut self) -> PektinApiResult<Vec<String>> {
+        delegate!(self, con => con.get_zones())
+    }
+
+    async fn get_zone_index(&mut self, zone: &Name) -> PektinApiResult<Vec<String>> {
+        delegate!(self, con => con.get_zone_index(zone))
+    }
+
+    async fn scan_step(
+        &mut self,
+        zone: Option<&Name>,
+        cursor: u64,
+        glob: &str,
+    ) -> PektinApiResult<(u64, Vec<String>)> {
+        delegate!(self, con => con.scan_step(zone, cursor, glob))
+    }
+
+    async fn build_zone_index(&mut self) -> PektinApiResult<()> {
+        delegate!(self, con => con.build_zone_index())
+    }
+
+    async fn build_rrsig_expiry_index(&mut self) -> PektinApiResult<()> {
+        delegate!(self, con => con.build_rrsig_expiry_index())
+    }
+
+    async fn get_expiring_rrsigs(&mut self, before: i64) -> PektinApiResult<Vec<DbEntry>> {
+        delegate!(self, con => con.get_expiring_rrsigs(before))
+    }
+
+    async fn try_lock(
+        &mut self,
+        key: &str,
+        token: &str,
+        expiry_millis: u64,
+    ) -> PektinApiResult<bool> {
+        delegate!(self, con => con.try_lock(key, token, expiry_millis))
+    }
+
+    async fn unlock(&mut self, key: &str, token: &str) -> PektinApiResult<()> {
+        delegate!(self, con => con.unlock(key, token))
+    }
+
+    async fn read_journal(
+        &mut self,
+        zone: &Name,
+        range: JournalRange,
+    ) -> PektinApiResult<Vec<(String, JournalEntry)>> {
+        delegate!(self, con => con.read_journal(zone, range))
+    }
+
+    async fn delete_journals(&mut self, zones: &[Name]) -> PektinApiResult<()> {
+        delegate!(self, con => con.delete_journals(zones))
+    }
+
+    async fn get_value(&mut self, key: &str) -> PektinApiResult<Option<String>> {
+        delegate!(self, con => con.get_value(key))
+    }
+
+    async fn set_value(&mut self, key: &str, value: &str) -> PektinApiResult<()> {
+        delegate!(self, con => con.set_value(key, value))
+    }
+
+    async fn delete_value(&mut self, key: &str) -> PektinApiResult<()> {
+        delegate!(self, con => con.delete_value(key))
+    }
+
+    async fn publish(&mut self, channel: &str, message: &str) -> PektinApiResult<()> {
+        delegate!(self, con => con.publish(channel, message))
+    }
+}
+
+/// Hands out connections to one of the dbs of the configured storage backend.
+#[derive(Clone)]
+pub enum StoragePool {
+    Redis(RedisPool),
+    Memory(MemoryStorage),
+}
+
+impl StoragePool {
+    pub async fn get(&self) -> Result<Connection, PoolError> {
+        match self {
+            Self::Redis(pool) => pool.get().await.map(Connection::Redis),
+            Self::Memory(storage) => Ok(Connection::Memory(storage.connection())),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Helpers for tests of code that changes zones: an `AppState` that uses the in-memory storage
+//! backend and a mock of the vault endpoints that pektin-api signs records with.
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{delete, get, post, test, web, App, HttpResponse, HttpServer};
+use data_encoding::BASE64;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use p256::pkcs8::{EncodePublicKey, LineEnding};
+use parking_lot::Mutex;
+use pektin_common::proto::rr::rdata::SOA;
+use pektin_common::proto::rr::{Name, RecordType};
+use pektin_common::{ARecord, DbEntry, RrSet, SoaRecord};
+use serde_json::{json, Value};
+
+use crate::db::{get_or_mget_records, DNSSEC_DB};
+use crate::errors_and_responses::json_error_handler;
+use crate::journal::{soa_serial, ChangeAuthor};
+use crate::memory_storage::MemoryStorage;
+use crate::set::sign_and_set_records;
+use crate::storage::StoragePool;
+use crate::types::{AppState, RecordIdentifier};
+use crate::validation::check_soa;
+
+/// The key that the mock vault signs with, for all zones.
+fn signing_key() -> SigningKey {
+    SigningKey::from_bytes(&[7; 32]).expect("invalid signing key")
+}
+
+#[post("/v1/auth/userpass/login/{username}")]
+async fn login() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "auth": { "client_token": "test-token", "lease_duration": 3600, "renewable": false }
+    }))
+}
+
+#[get("/v1/pektin-transit/keys/{key_name}")]
+async fn keys() -> HttpResponse {
+    let public_key = signing_key()
+        .verifying_key()
+        .to_public_key_pem(LineEnding::LF)
+        .expect("could not encode public key");
+    HttpResponse::Ok().json(json!({ "data": { "keys": { "1": { "public_key": public_key } } } }))
+}
+
+/// The state of the mock vault, shared with the test.
+#[derive(Debug, Default)]
+pub struct MockVaultState {
+    /// The names of the transit keys that were created and not deleted.
+    pub keys: BTreeSet<String>,
+    /// If set, signing fails.
+    pub fail_signing: bool,
+    /// The names of the transit keys whose deletion fails.
+    pub fail_deleting: HashSet<String>,
+}
+
+pub type MockVault = Arc<Mutex<MockVaultState>>;
+
+#[post("/v1/pektin-transit/keys/{key_name}")]
+async fn create_key(key_name: web::Path<String>, vault: web::Data<MockVault>) -> HttpResponse {
+    vault.lock().keys.insert(key_name.into_inner());
+    HttpResponse::NoContent().finish()
+}
+
+#[post("/v1/pektin-transit/keys/{key_name}/config")]
+async fn config_key(key_name: web::Path<String>, vault: web::Data<MockVault>) -> HttpResponse {
+    let vault = vault.lock();
+    if vault.fail_deleting.contains(key_name.as_str()) {
+        HttpResponse::InternalServerError().finish()
+    } else if vault.keys.contains(key_name.as_str()) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[delete("/v1/pektin-transit/keys/{key_name}")]
+async fn delete_key(key_name: web::Path<String>, vault: web::Data<MockVault>) -> HttpResponse {
+    vault.lock().keys.remove(key_name.as_str());
+    HttpResponse::NoContent().finish()
+}
+
+#[post("/v1/pektin-transit/sign/{key_name}/sha2-256")]
+async fn sign(body: web::Json<Value>, vault: web::Data<MockVault>) -> HttpResponse {
+    if vault.lock().fail_signing {
+        return HttpResponse::InternalServerError().finish();
+    }
+    let input = body["input"].as_str().unwrap_or_default();
+    let tbs = BASE64
+        .decode(input.as_bytes())
+        .expect("input is not base64");
+    let signature: Signature = signing_key().sign(&tbs);
+    let signature = BASE64.encode(signature.to_der().as_bytes());
+    HttpResponse::Ok().json(json!({ "data": { "signature": format!("vault:v1:{signature}") } }))
+}
+
+/// Starts the mock vault and returns its URI. Must be called within an actix runtime (e.g. in an
+/// `#[actix_web::test]`).
+fn start_mock_vault(vault: MockVault) -> String {
+    let vault = web::Data::new(vault);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(vault.clone())
+            .service(login)
+            .service(keys)
+            .service(create_key)
+            .service(config_key)
+            .service(delete_key)
+            .service(sign)
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .expect("could not bind mock vault");
+    let addr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{addr}")
+}
+
+/// An `AppState` with empty in-memory dbs that signs with the mock vault.
+pub fn test_state() -> AppState {
+    test_state_with_vault().0
+}
+
+/// Like `test_state()`, but also returns the state of the mock vault.
+pub fn test_state_with_vault() -> (AppState, MockVault) {
+    let storage = MemoryStorage::new();
+    let vault = MockVault::default();
+    let state = AppState {
+        db_pool: StoragePool::Memory(storage.clone()),
+        db_pool_dnssec: StoragePool::Memory(storage.with_db(DNSSEC_DB)),
+        vault_uri: start_mock_vault(vault.clone()),
+        ribston_uri: String::new(),
+        vault_password: "password".into(),
+        vault_user_name: "pektin-api".into(),
+        skip_auth: "false".into(),
+        tsig_keys: vec![],
+        tsig_key_clients: Default::default(),
+        tsig_key_zones: Default::default(),
+    };
+    (state, vault)
+}
+
+/// Sends the JSON body to the endpoint `service` at `path` with authentication disabled and
+/// returns the status and the JSON body of the response.
+pub async fn call_endpoint(
+    service: impl HttpServiceFactory + 'static,
+    state: &AppState,
+    path: &str,
+    body: Value,
+) -> (StatusCode, Value) {
+    let (status, body) = call_endpoint_raw(service, state, path, body).await;
+    let body = serde_json::from_slice(&body).expect("response is not JSON");
+    (status, body)
+}
+
+/// Like `call_endpoint()`, but returns the body as it is, e.g. for streamed responses.
+pub async fn call_endpoint_raw(
+    service: impl HttpServiceFactory + 'static,
+    state: &AppState,
+    path: &str,
+    body: Value,
+) -> (StatusCode, Bytes) {
+    let mut state = state.clone();
+    state.skip_auth = "yes, I really want to disable authentication".into();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .app_data(web::Data::new(state))
+            .service(service),
+    )
+    .await;
+    let req = test::TestRequest::post()
+        .uri(path)
+        .set_json(body)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    let status = res.status();
+    (status, test::read_body(res).await)
+}
+
+pub fn author() -> ChangeAuthor {
+    ChangeAuthor {
+        client_username: "test".into(),
+        ip: None,
+        user_agent: "test".into(),
+    }
+}
+
+pub fn name(name: &str) -> Name {
+    Name::from_ascii(name).expect("invalid name")
+}
+
+pub fn soa_entry(zone: &str, serial: u32) -> DbEntry {
+    let soa = SOA::new(
+        name(&format!("ns1.{zone}")),
+        name(&format!("hostmaster.{zone}")),
+        serial,
+        3600,
+        600,
+        604800,
+        300,
+    );
+    DbEntry {
+        name: name(zone),
+        ttl: 3600,
+        meta: "".into(),
+        rr_set: RrSet::SOA {
+            rr_set: vec![SoaRecord { value: soa }],
+        },
+    }
+}
+
+pub fn a_entry(record_name: &str, ip: [u8; 4]) -> DbEntry {
+    DbEntry {
+        name: name(record_name),
+        ttl: 300,
+        meta: "".into(),
+        rr_set: RrSet::A {
+            rr_set: vec![ARecord { value: ip.into() }],
+        },
+    }
+}
+
+/// Sets the records like the `/set` endpoint does and panics if that fails.
+pub async fn set_records(records: &[DbEntry], state: &AppState) {
+    let mut con = state.db_pool.get().await.unwrap();
+    let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+    let (soa_check, used_zones, new_authoritative_zones) =
+        check_soa(records, &mut con).await.unwrap();
+    assert!(soa_check.iter().all(Result::is_ok));
+    sign_and_set_records(
+        records,
+        &used_zones,
+        &new_authoritative_zones,
+        &author(),
+        state,
+        &mut con,
+        &mut dnssec_con,
+    )
+    .await
+    .unwrap();
+}
+
+/// The entry of the RR set and of its RRSIG.
+pub async fn get_with_rrsig(
+    record_name: &str,
+    rr_type: RecordType,
+    state: &AppState,
+) -> (Option<DbEntry>, Option<DbEntry>) {
+    let ident = RecordIdentifier {
+        name: name(record_name),
+        rr_type,
+    };
+    let mut con = state.db_pool.get().await.unwrap();
+    let mut dnssec_con = state.db_pool_dnssec.get().await.unwrap();
+    let entry = get_or_mget_records(&[ident.db_key()], &mut con)
+        .await
+        .unwrap()
+        .pop()
+        .flatten();
+    let rrsig = get_or_mget_records(&[ident.rrsig_db_key()], &mut dnssec_con)
+        .await
+        .unwrap()
+        .pop()
+        .flatten();
+    (entry, rrsig)
+}
+
+pub async fn zone_serial(zone: &str, state: &AppState) -> Option<u32> {
+    let (soa, _) = get_with_rrsig(zone, RecordType::SOA, state).await;
+    soa.as_ref().and_then(soa_serial)
+}
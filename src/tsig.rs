@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use data_encoding::BASE64;
+use pektin_common::proto::error::ProtoError;
+use pektin_common::proto::op::Message;
+use pektin_common::proto::rr::dnssec::rdata::tsig::{
+    make_tsig_record, signed_bitmessage_to_buf, TsigAlgorithm, TSIG,
+};
+use pektin_common::proto::rr::dnssec::rdata::DNSSECRData;
+use pektin_common::proto::rr::{Name, RData, Record};
+use pektin_common::proto::serialize::binary::BinEncoder;
+use thiserror::Error;
+use tracing::instrument;
+
+/// The fudge (allowed clock skew in seconds) we use for the TSIGs we create.
+const FUDGE: u16 = 300;
+
+#[derive(Debug, Error)]
+pub enum TsigError {
+    #[error("Invalid TSIG key definition '{0}', expected 'name:algorithm:base64-secret'")]
+    InvalidKeyDefinition(String),
+    #[error("Invalid TSIG key client mapping '{0}', expected 'key-name=client-username'")]
+    InvalidKeyClient(String),
+    #[error("Invalid TSIG key zone mapping '{0}', expected 'key-name=zone'")]
+    InvalidKeyZone(String),
+    #[error("Unsupported TSIG algorithm '{0}'")]
+    UnsupportedAlgorithm(String),
+    #[error("The message is not signed with TSIG")]
+    Unsigned,
+    #[error("The message is signed with an unknown TSIG key")]
+    BadKey,
+    #[error("The TSIG signature of the message is invalid")]
+    BadSig,
+    #[error("The TSIG signature of the message was created too long ago or in the future")]
+    BadTime,
+    #[error("Could not encode or decode DNS message: {0}")]
+    Proto(#[from] ProtoError),
+}
+pub type TsigResult<T> = Result<T, TsigError>;
+
+/// A shared secret used to authenticate DNS messages with TSIG (RFC 8945).
+#[derive(Debug, Clone)]
+pub struct TsigKey {
+    pub name: Name,
+    pub algorithm: TsigAlgorithm,
+    pub secret: Vec<u8>,
+}
+
+impl TsigKey {
+    /// Parses a TSIG key in the format `name:algorithm:base64-secret`, e.g.
+    /// `transfer-key.:hmac-sha256:c2VjcmV0`.
+    pub fn parse(definition: &str) -> TsigResult<Self> {
+        let invalid = || TsigError::InvalidKeyDefinition(definition.into());
+        let mut parts = definition.trim().splitn(3, ':');
+        let (name, algorithm, secret) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(n), Some(a), Some(s)) => (n, a, s),
+            _ => return Err(invalid()),
+        };
+        let mut name = Name::from_utf8(name).map_err(|_| invalid())?;
+        name.set_fqdn(true);
+        let algorithm =
+            TsigAlgorithm::from_name(Name::from_ascii(algorithm).map_err(|_| invalid())?);
+        if !algorithm.supported() {
+            return Err(TsigError::UnsupportedAlgorithm(algorithm.to_string()));
+        }
+        let secret = BASE64.decode(secret.as_bytes()).map_err(|_| invalid())?;
+        Ok(Self {
+            name,
+            algorithm,
+            secret,
+        })
+    }
+
+    /// Parses a comma-separated list of TSIG keys (see [`TsigKey::parse`]). The empty string
+    /// yields an empty list.
+    pub fn parse_list(definitions: &str) -> TsigResult<Vec<Self>> {
+        definitions
+            .split(',')
+            .filter(|d| !d.trim().is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+}
+
+/// Parses a comma-separated list of mappings from TSIG key names to pektin clients in the format
+/// `key-name=client-username`. The key names in the returned map are lowercase.
+pub fn parse_key_clients(mappings: &str) -> TsigResult<HashMap<Name, String>> {
+    mappings
+        .split(',')
+        .filter(|m| !m.trim().is_empty())
+        .map(|mapping| {
+            let invalid = || TsigError::InvalidKeyClient(mapping.into());
+            let (name, client) = mapping.trim().split_once('=').ok_or_else(invalid)?;
+            let mut name = Name::from_utf8(name).map_err(|_| invalid())?;
+            name.set_fqdn(true);
+            if client.is_empty() {
+                return Err(invalid());
+            }
+            Ok((name.to_lowercase(), client.to_string()))
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of mappings from TSIG key names to the zones they may be used
+/// for in the format `key-name=zone`. A key is mapped to several zones by listing it once per zone.
+/// The key names and zones in the returned map are lowercase.
+pub fn parse_key_zones(mappings: &str) -> TsigResult<HashMap<Name, Vec<Name>>> {
+    let mut key_zones: HashMap<Name, Vec<Name>> = HashMap::new();
+    for mapping in mappings.split(',').filter(|m| !m.trim().is_empty()) {
+        let invalid = || TsigError::InvalidKeyZone(mapping.into());
+        let (name, zone) = mapping.trim().split_once('=').ok_or_else(invalid)?;
+        let mut name = Name::from_utf8(name).map_err(|_| invalid())?;
+        name.set_fqdn(true);
+        let mut zone = Name::from_utf8(zone).map_err(|_| invalid())?;
+        zone.set_fqdn(true);
+        key_zones
+            .entry(name.to_lowercase())
+            .or_default()
+            .push(zone.to_lowercase());
+    }
+    Ok(key_zones)
+}
+
+/// Returns `true` if the key may be used for zone transfers and DNS UPDATEs of the zone according
+/// to the mappings returned by [`parse_key_zones`]. Keys without any mapping can't be used at all.
+pub fn key_allowed_for_zone(
+    key_zones: &HashMap<Name, Vec<Name>>,
+    key: &TsigKey,
+    zone: &Name,
+) -> bool {
+    key_zones
+        .get(&key.name.to_lowercase())
+        .is_some_and(|zones| zones.contains(&zone.to_lowercase()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Signs the message with the given key by adding a TSIG record to it and returns the MAC.
+///
+/// `previous_mac` is the MAC of the request when signing a response, or the MAC of the previous
+/// message when signing a message that is not the first one of a multi-message response (e.g. a
+/// zone transfer). For those following messages, `first_message` must be false.
+#[instrument(skip(message, key, previous_mac))]
+pub fn sign_message(
+    message: &mut Message,
+    key: &TsigKey,
+    previous_mac: Option<&[u8]>,
+    first_message: bool,
+) -> TsigResult<Vec<u8>> {
+    let pre_tsig = TSIG::new(
+        key.algorithm.clone(),
+        now(),
+        FUDGE,
+        vec![],
+        message.id(),
+        0,
+        vec![],
+    );
+
+    // the message is encoded on its own and not after the previous MAC (like trust-dns'
+    // `message_tbs` does), otherwise the offsets of its compressed names would be wrong
+    let mut tbs = Vec::new();
+    let mut encoder = BinEncoder::new(&mut tbs);
+    if let Some(previous_mac) = previous_mac {
+        encoder.emit_u16(previous_mac.len() as u16)?;
+        encoder.emit_vec(previous_mac)?;
+    }
+    encoder.emit_vec(&message.to_vec()?)?;
+    if first_message {
+        pre_tsig.emit_tsig_for_mac(&mut encoder, &key.name)?;
+    } else {
+        // see RFC 8945, section 5.3.1: the following messages only include the TSIG timers
+        encoder.emit_u16((pre_tsig.time() >> 32) as u16)?;
+        encoder.emit_u32(pre_tsig.time() as u32)?;
+        encoder.emit_u16(pre_tsig.fudge())?;
+    }
+
+    let mac = key.algorithm.mac_data(&key.secret, &tbs)?;
+    message.add_tsig(make_tsig_record(
+        key.name.clone(),
+        pre_tsig.set_mac(mac.clone()),
+    ));
+    Ok(mac)
+}
+
+/// Verifies the TSIG of a message in wire format and returns the key that was used together with
+/// the MAC of the message.
+///
+/// For `previous_mac` and `first_message`, see [`sign_message`].
+#[instrument(skip(bytes, keys, previous_mac))]
+pub fn verify_message<'k>(
+    bytes: &[u8],
+    keys: &'k [TsigKey],
+    previous_mac: Option<&[u8]>,
+    first_message: bool,
+) -> TsigResult<(&'k TsigKey, Vec<u8>)> {
+    let (tbs, record) = match signed_bitmessage_to_buf(previous_mac, bytes, first_message) {
+        Ok(r) => r,
+        Err(_) => return Err(TsigError::Unsigned),
+    };
+    verify_tbs(&tbs, &record, keys)
+}
+
+/// Verifies the TSIG of a message that is not the first one of a multi-message response (e.g. a
+/// zone transfer) and returns its MAC.
+///
+/// `unsigned_messages` are the concatenated messages without a TSIG that were received since the
+/// last signed message, which are covered by this message's MAC (see RFC 8945, section 5.3.1).
+#[instrument(skip(bytes, key, previous_mac, unsigned_messages))]
+pub fn verify_subsequent_message(
+    bytes: &[u8],
+    key: &TsigKey,
+    previous_mac: &[u8],
+    unsigned_messages: &[u8],
+) -> TsigResult<Vec<u8>> {
+    let (message_tbs, record) = match signed_bitmessage_to_buf(None, bytes, false) {
+        Ok(r) => r,
+        Err(_) => return Err(TsigError::Unsigned),
+    };
+    let mut tbs =
+        Vec::with_capacity(2 + previous_mac.len() + unsigned_messages.len() + message_tbs.len());
+    tbs.extend_from_slice(&(previous_mac.len() as u16).to_be_bytes());
+    tbs.extend_from_slice(previous_mac);
+    tbs.extend_from_slice(unsigned_messages);
+    tbs.extend_from_slice(&message_tbs);
+    verify_tbs(&tbs, &record, std::slice::from_ref(key)).map(|(_, mac)| mac)
+}
+
+fn verify_tbs<'k>(
+    tbs: &[u8],
+    record: &Record,
+    keys: &'k [TsigKey],
+) -> TsigResult<(&'k TsigKey, Vec<u8>)> {
+    let tsig = match record.data() {
+        Some(RData::DNSSEC(DNSSECRData::TSIG(tsig))) => tsig,
+        _ => return Err(TsigError::Unsigned),
+    };
+
+    let key = keys
+        .iter()
+        .find(|key| key.name.to_lowercase() == record.name().to_lowercase())
+        .filter(|key| &key.algorithm == tsig.algorithm())
+        .ok_or(TsigError::BadKey)?;
+    if key
+        .algorithm
+        .verify_mac(&key.secret, tbs, tsig.mac())
+        .is_err()
+    {
+        return Err(TsigError::BadSig);
+    }
+    if now().abs_diff(tsig.time()) > tsig.fudge() as u64 {
+        return Err(TsigError::BadTime);
+    }
+
+    Ok((key, tsig.mac().to_vec()))
+}
@@ -1,11 +1,18 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
 use pektin_common::{
-    deadpool_redis::Pool,
     proto::rr::{Name, RecordType},
-    DbEntry,
+    DbEntry, RrSet,
 };
 use serde::{Deserialize, Serialize};
 
+use crate::acme::default_acme_ttl;
+use crate::backup::{Snapshot, SnapshotFormat};
 use crate::macros::impl_from_request_body;
+use crate::notify::NotifyTarget;
+use crate::storage::StoragePool;
+use crate::tsig::TsigKey;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct RecordIdentifier {
@@ -13,14 +20,117 @@ pub struct RecordIdentifier {
     pub rr_type: RecordType,
 }
 
+/// A `DbEntry` together with the version of its RR set (see `rr_set_version()`).
+///
+/// When setting records, the version is the version the RR set is expected to have in the db.
+/// If it has a different version, e.g. because another client changed it in the meantime, the
+/// request fails with a conflict. If no version is given, the RR set is set unconditionally.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VersionedDbEntry {
+    #[serde(flatten)]
+    pub entry: DbEntry,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+/// A `RecordIdentifier` together with the version of its RR set; see `VersionedDbEntry`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct VersionedRecordIdentifier {
+    #[serde(flatten)]
+    pub ident: RecordIdentifier,
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum RequestBody {
-    Get { records: Vec<RecordIdentifier> },
-    GetZoneRecords { names: Vec<Name> },
-    Set { records: Vec<DbEntry> },
-    Delete { records: Vec<RecordIdentifier> },
-    Search { globs: Vec<Glob> },
+    Get {
+        records: Vec<RecordIdentifier>,
+    },
+    GetZoneRecords {
+        names: Vec<Name>,
+    },
+    Set {
+        records: Vec<DbEntry>,
+    },
+    Delete {
+        records: Vec<RecordIdentifier>,
+    },
+    Search {
+        globs: Vec<Glob>,
+        queries: Vec<SearchQuery>,
+    },
     Health,
+    ImportZone {
+        zone: Name,
+        records: Vec<DbEntry>,
+    },
+    ExportZone {
+        names: Vec<Name>,
+    },
+    ImportZoneTransfer {
+        zone: Name,
+        primary: SocketAddr,
+    },
+    GetNotifyTargets {
+        zones: Vec<Name>,
+    },
+    SetNotifyTargets {
+        zone: Name,
+        targets: Vec<NotifyTarget>,
+    },
+    AcmePresent {
+        name: Name,
+        value: String,
+    },
+    AcmeCleanup {
+        name: Name,
+        value: String,
+    },
+    CreateZone {
+        zone: Name,
+        nameservers: Vec<Name>,
+    },
+    DeleteZone {
+        zones: Vec<Name>,
+    },
+    Update {
+        updates: Vec<RecordUpdate>,
+    },
+    ListZones {
+        zones: Vec<Name>,
+    },
+    DnsUpdate {
+        zone: Name,
+        set: Vec<DbEntry>,
+        delete: Vec<RecordIdentifier>,
+    },
+    Transaction {
+        operations: Vec<TransactionOperation>,
+    },
+    Diff {
+        zone: Name,
+        records: Vec<DbEntry>,
+    },
+    Sync {
+        zone: Name,
+        records: Vec<DbEntry>,
+    },
+    History {
+        query: HistoryQuery,
+    },
+    Rollback {
+        zone: Name,
+        rr_set: Option<RecordIdentifier>,
+        to: RollbackTarget,
+    },
+    Snapshot {
+        zones: Vec<Name>,
+    },
+    RestoreSnapshot {
+        zones: Vec<Name>,
+        resign: bool,
+    },
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -41,14 +151,43 @@ pub struct GetZoneRecordsRequestBody {
 pub struct SetRequestBody {
     pub client_username: String,
     pub confidant_password: String,
-    pub records: Vec<DbEntry>,
+    pub records: Vec<VersionedDbEntry>,
+    /// Only return the `ChangePlan` instead of setting the records.
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct DeleteRequestBody {
     pub client_username: String,
     pub confidant_password: String,
-    pub records: Vec<RecordIdentifier>,
+    pub records: Vec<VersionedRecordIdentifier>,
+    /// Only return the `ChangePlan` instead of deleting the records.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// What a request would change. This is returned instead of applying the change if the client
+/// requested a dry run.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ChangePlan {
+    /// RR sets that don't exist yet.
+    pub created: Vec<DbEntry>,
+    /// RR sets that exist and would be changed.
+    pub changed: Vec<RrSetChange>,
+    /// RR sets that exist and would be deleted.
+    pub deleted: Vec<DbEntry>,
+    /// Zones that would be created because their SOA record is set.
+    pub new_zones: Vec<Name>,
+    /// The RR sets that would be signed, including the DNSKEY, NSEC3, and SOA records managed by
+    /// pektin.
+    pub signatures: Vec<RecordIdentifier>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RrSetChange {
+    pub before: DbEntry,
+    pub after: DbEntry,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -60,7 +199,401 @@ pub struct Glob {
 pub struct SearchRequestBody {
     pub client_username: String,
     pub confidant_password: String,
+    #[serde(default)]
     pub globs: Vec<Glob>,
+    /// Structured queries that are searched after the globs. In the results, the index of a query
+    /// is the number of globs plus its position in this list.
+    #[serde(default)]
+    pub queries: Vec<SearchQuery>,
+    /// The maximum number of results to return. If set, the response is a `SearchPage` whose
+    /// cursor continues the search; a key may then appear in more than one page.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// The cursor of the previous `SearchPage`, to get the next page.
+    #[serde(default)]
+    pub cursor: Option<String>,
+    /// Whether to return the RR sets in addition to their identifiers and versions.
+    #[serde(default)]
+    pub include_records: bool,
+    /// Whether to stream the results as newline-delimited JSON, one `SearchStreamItem` per line.
+    /// The last line is a response containing the cursor to continue with (see `SearchPage`), or
+    /// an error response if the search failed.
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// A structured `search` query that is evaluated over the RR sets in the db. An RR set matches if
+/// it matches all conditions that are given.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct SearchQuery {
+    /// Only RR sets in this zone, not including its child zones.
+    #[serde(default)]
+    pub zone: Option<Name>,
+    /// Matches the name of the RR set, which is lowercase and absolute. Exact names are lowercased
+    /// before they are compared.
+    #[serde(default)]
+    pub name: Option<TextMatch>,
+    /// Only RR sets of these types; any type if empty.
+    #[serde(default)]
+    pub rr_types: Vec<RecordType>,
+    #[serde(default)]
+    pub ttl: Option<TtlRange>,
+    /// Conditions on the rdata. An RR set matches if one of its records matches all of them.
+    #[serde(default)]
+    pub rdata: Vec<RdataMatch>,
+}
+
+/// How a string is matched in a `SearchQuery`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextMatch {
+    Exact(String),
+    /// A glob with the same syntax as the globs in `Glob` (`*`, `?`, `[...]`, and `\` to escape).
+    Glob(String),
+    /// A regex that matches if it matches any part of the string, unless it is anchored.
+    Regex(String),
+}
+
+/// An inclusive range of TTLs in seconds.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct TtlRange {
+    #[serde(default)]
+    pub min: Option<u32>,
+    #[serde(default)]
+    pub max: Option<u32>,
+}
+
+/// A condition on a field of the rdata of a record, e.g. `{"field": "value", "exact":
+/// "203.0.113.7"}` for A records. The field is looked up in the JSON representation of the
+/// record; nested fields are separated by dots. Values that aren't strings are matched against
+/// their JSON representation.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RdataMatch {
+    pub field: String,
+    #[serde(flatten)]
+    pub value: TextMatch,
+}
+
+/// A key found by `search`.
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub ident: VersionedRecordIdentifier,
+    /// The RR set, if it was requested and still exists.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record: Option<DbEntry>,
+}
+
+/// A page of the results of a `search` with a limit.
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchPage {
+    /// The results of each glob and query in the order of the request.
+    pub results: Vec<Vec<SearchResult>>,
+    /// The cursor to get the next page with, or `None` if the search is complete.
+    pub cursor: Option<String>,
+}
+
+/// A line of a streamed `search` response.
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchStreamItem {
+    /// The index of the glob or query that matched the key.
+    pub glob: usize,
+    #[serde(flatten)]
+    pub result: SearchResult,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImportZoneRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    /// The zone to import the records into; also the initial `$ORIGIN` of the zone file.
+    pub zone: Name,
+    /// The zone in RFC 1035 master file format.
+    pub zone_file: String,
+    /// Only return the `ChangePlan` instead of importing the records.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImportZoneTransferRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zone: Name,
+    /// The address of the primary name server to request the zone from via AXFR.
+    pub primary: SocketAddr,
+    /// The TSIG key to authenticate the zone transfer with, in the format
+    /// `name:algorithm:base64-secret`.
+    #[serde(default)]
+    pub tsig_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExportZoneRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub names: Vec<Name>,
+    /// Also export the DNSKEY, RRSIG, and NSEC3 records of the zones.
+    #[serde(default)]
+    pub include_dnssec: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GetNotifyTargetsRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zones: Vec<Name>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SetNotifyTargetsRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zone: Name,
+    /// Replaces all previous notify targets of the zone.
+    pub targets: Vec<NotifyTarget>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AcmePresentRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    /// The domain to prove control over; the value is added at `_acme-challenge.<domain>` (see
+    /// `challenge_name()`).
+    pub domain: Name,
+    /// The key authorization digest to add as TXT value.
+    pub value: String,
+    #[serde(default = "default_acme_ttl")]
+    pub ttl: u32,
+    /// Wait until the signed value is visible in the db before answering.
+    #[serde(default)]
+    pub wait: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AcmeCleanupRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub domain: Name,
+    /// The TXT value to remove; other values at the challenge name are kept.
+    pub value: String,
+    /// Wait until the value is removed from the db before answering.
+    #[serde(default)]
+    pub wait: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CreateZoneRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zone: Name,
+    /// The names of the zone's name servers. Relative names are relative to the zone, e.g. `ns1`
+    /// becomes `ns1.<zone>`.
+    pub nameservers: Vec<Name>,
+    #[serde(default)]
+    pub soa: SoaTemplate,
+    #[serde(default = "default_zone_ttl")]
+    pub ns_ttl: u32,
+}
+
+/// The values of the SOA record of a newly created zone.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct SoaTemplate {
+    /// The primary name server; defaults to the first of the zone's name servers.
+    pub mname: Option<Name>,
+    /// The mailbox of the person responsible for the zone; defaults to `hostmaster.<zone>`.
+    pub rname: Option<Name>,
+    /// Defaults to the current date in the format `YYYYMMDD01`.
+    pub serial: Option<u32>,
+    pub refresh: i32,
+    pub retry: i32,
+    pub expire: i32,
+    pub minimum: u32,
+    pub ttl: u32,
+}
+
+impl Default for SoaTemplate {
+    fn default() -> Self {
+        Self {
+            mname: None,
+            rname: None,
+            serial: None,
+            refresh: 3600,
+            retry: 900,
+            expire: 604800,
+            minimum: 300,
+            ttl: default_zone_ttl(),
+        }
+    }
+}
+
+pub fn default_zone_ttl() -> u32 {
+    3600
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeleteZoneRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zones: Vec<Name>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ListZonesRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UpdateRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub updates: Vec<RecordUpdate>,
+}
+
+/// A change of individual records of an RR set.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RecordUpdate {
+    pub name: Name,
+    pub operation: RecordUpdateOperation,
+    /// The new TTL of the RR set. If omitted, the TTL of the existing RR set is kept.
+    #[serde(default)]
+    pub ttl: Option<u32>,
+    /// The records to add (`add-rdata`, `replace-rdata`) or remove (`remove-rdata`).
+    #[serde(flatten)]
+    pub rr_set: RrSet,
+    /// The records that are replaced by `rr_set` (only for `replace-rdata`). They must all exist.
+    #[serde(default)]
+    pub old: Option<RrSet>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordUpdateOperation {
+    AddRdata,
+    RemoveRdata,
+    ReplaceRdata,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TransactionRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub operations: Vec<TransactionOperation>,
+}
+
+/// An operation of a transaction. The operations are applied in order, so later operations see
+/// the effects of earlier ones, e.g. a `set` following a `delete` of the same RR set recreates it.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransactionOperation {
+    Set(VersionedDbEntry),
+    Delete(VersionedRecordIdentifier),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DiffRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zone: Name,
+    /// All RR sets the zone should contain, except for the DNSSEC records managed by pektin.
+    pub records: Vec<DbEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SyncRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zone: Name,
+    /// All RR sets the zone should contain, except for the DNSSEC records managed by pektin.
+    pub records: Vec<DbEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    #[serde(flatten)]
+    pub query: HistoryQuery,
+}
+
+/// Selects entries of the change history of a zone. Only entries matching all given filters are
+/// returned, newest first.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HistoryQuery {
+    pub zone: Name,
+    /// Only changes of RR sets with this name.
+    #[serde(default)]
+    pub name: Option<Name>,
+    /// Only changes made by this client.
+    #[serde(default)]
+    pub client: Option<String>,
+    /// Only changes made at or after this time, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// Only changes made at or before this time, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub until: Option<u64>,
+    /// The maximum number of entries to return.
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RollbackRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub zone: Name,
+    /// Only roll back this RR set instead of the whole zone.
+    #[serde(default)]
+    pub rr_set: Option<RecordIdentifier>,
+    pub to: RollbackTarget,
+}
+
+/// The point in the history of a zone that a rollback restores.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum RollbackTarget {
+    /// The state in which the zone had this SOA serial.
+    Serial(u32),
+    /// The state of the zone at this time, in milliseconds since the Unix epoch.
+    Timestamp(u64),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SnapshotRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    /// The zones to include in the snapshot. If empty, all zones are included.
+    #[serde(default)]
+    pub zones: Vec<Name>,
+    #[serde(default)]
+    pub format: SnapshotFormat,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RestoreSnapshotRequestBody {
+    pub client_username: String,
+    pub confidant_password: String,
+    pub snapshot: Snapshot,
+    /// The zones of the snapshot to restore. If empty, all zones of the snapshot are restored.
+    #[serde(default)]
+    pub zones: Vec<Name>,
+    /// Sign the restored records again instead of restoring their RRSIGs from the snapshot.
+    #[serde(default)]
+    pub resign: bool,
+}
+
+/// The differences between the RR sets of a zone in the db and the desired RR sets.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ZoneDiff {
+    /// Desired RR sets that don't exist in the db.
+    pub added: Vec<DbEntry>,
+    /// RR sets that exist in the db but differ from the desired ones.
+    pub modified: Vec<RrSetChange>,
+    /// RR sets in the db that are not desired.
+    pub removed: Vec<DbEntry>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -71,21 +604,112 @@ pub struct HealthRequestBody {
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db_pool: Pool,
-    pub db_pool_dnssec: Pool,
+    pub db_pool: StoragePool,
+    pub db_pool_dnssec: StoragePool,
     pub vault_uri: String,
     pub ribston_uri: String,
     pub vault_password: String,
     pub vault_user_name: String,
     pub skip_auth: String,
+    pub tsig_keys: Vec<TsigKey>,
+    /// Maps the (lowercase) names of TSIG keys to the pektin clients that DNS UPDATE requests
+    /// signed with these keys are made by.
+    pub tsig_key_clients: HashMap<Name, String>,
+    /// Maps the (lowercase) names of TSIG keys to the (lowercase) zones that zone transfers and
+    /// DNS UPDATEs signed with these keys are allowed for.
+    pub tsig_key_zones: HashMap<Name, Vec<Name>>,
 }
 
 impl_from_request_body!(GetRequestBody, Get, records);
 impl_from_request_body!(GetZoneRecordsRequestBody, GetZoneRecords, names);
-impl_from_request_body!(SetRequestBody, Set, records);
-impl_from_request_body!(DeleteRequestBody, Delete, records);
-impl_from_request_body!(SearchRequestBody, Search, globs);
 impl_from_request_body!(HealthRequestBody, Health);
+impl_from_request_body!(ExportZoneRequestBody, ExportZone, names);
+impl_from_request_body!(GetNotifyTargetsRequestBody, GetNotifyTargets, zones);
+impl_from_request_body!(DeleteZoneRequestBody, DeleteZone, zones);
+impl_from_request_body!(UpdateRequestBody, Update, updates);
+impl_from_request_body!(TransactionRequestBody, Transaction, operations);
+impl_from_request_body!(HistoryRequestBody, History, query);
+impl_from_request_body!(SnapshotRequestBody, Snapshot, zones);
+
+impl From<SearchRequestBody> for RequestBody {
+    fn from(value: SearchRequestBody) -> Self {
+        Self::Search {
+            globs: value.globs,
+            queries: value.queries,
+        }
+    }
+}
+
+impl From<SetRequestBody> for RequestBody {
+    fn from(value: SetRequestBody) -> Self {
+        Self::Set {
+            records: value.records.into_iter().map(|r| r.entry).collect(),
+        }
+    }
+}
+
+impl From<DeleteRequestBody> for RequestBody {
+    fn from(value: DeleteRequestBody) -> Self {
+        Self::Delete {
+            records: value.records.into_iter().map(|r| r.ident).collect(),
+        }
+    }
+}
+
+impl From<DiffRequestBody> for RequestBody {
+    fn from(value: DiffRequestBody) -> Self {
+        Self::Diff {
+            zone: value.zone,
+            records: value.records,
+        }
+    }
+}
+
+impl From<RollbackRequestBody> for RequestBody {
+    fn from(value: RollbackRequestBody) -> Self {
+        Self::Rollback {
+            zone: value.zone,
+            rr_set: value.rr_set,
+            to: value.to,
+        }
+    }
+}
+
+impl From<SyncRequestBody> for RequestBody {
+    fn from(value: SyncRequestBody) -> Self {
+        Self::Sync {
+            zone: value.zone,
+            records: value.records,
+        }
+    }
+}
+
+impl From<ImportZoneTransferRequestBody> for RequestBody {
+    fn from(value: ImportZoneTransferRequestBody) -> Self {
+        Self::ImportZoneTransfer {
+            zone: value.zone,
+            primary: value.primary,
+        }
+    }
+}
+
+impl From<CreateZoneRequestBody> for RequestBody {
+    fn from(value: CreateZoneRequestBody) -> Self {
+        Self::CreateZone {
+            zone: value.zone,
+            nameservers: value.nameservers,
+        }
+    }
+}
+
+impl From<SetNotifyTargetsRequestBody> for RequestBody {
+    fn from(value: SetNotifyTargetsRequestBody) -> Self {
+        Self::SetNotifyTargets {
+            zone: value.zone,
+            targets: value.targets,
+        }
+    }
+}
 
 pub struct RequestInfo {
     pub api_method: String,
@@ -18,6 +18,19 @@ pub fn deabsolute(name: &str) -> &str {
     }
 }
 
+/// Escapes the characters that have a special meaning in db globs, e.g. so that a name containing
+/// '*' (like a wildcard record) only matches itself.
+pub fn escape_glob(s: &str) -> String {
+    let mut glob = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            glob.push('\\');
+        }
+        glob.push(c);
+    }
+    glob
+}
+
 /// Takes a `Name` and a list of authoritative zones and returns the most specific zone (i.e. the
 /// one with the most labels) that contains the given `Name`.
 pub fn find_authoritative_zone(name: &Name, authoritative_zones: &[Name]) -> Option<Name> {
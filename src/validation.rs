@@ -1,13 +1,12 @@
 use std::collections::HashSet;
 
-use pektin_common::deadpool_redis::Connection;
-use pektin_common::get_authoritative_zones;
 use pektin_common::proto::rr::{Name, RecordType};
 use pektin_common::{DbEntry, RrSet};
 use thiserror::Error;
 use tracing::instrument;
 
 use crate::errors_and_responses::{PektinApiError, PektinApiResult};
+use crate::storage::{Connection, Storage};
 use crate::types::Glob;
 use crate::utils::find_authoritative_zone;
 
@@ -113,7 +112,7 @@ pub async fn check_soa(
     entries: &[DbEntry],
     con: &mut Connection,
 ) -> PektinApiResult<(Vec<PektinApiResult<()>>, Vec<Name>, Vec<Name>)> {
-    let authoritative_zones = get_authoritative_zones(con).await?;
+    let authoritative_zones = con.get_zones().await?;
     let authoritative_zones: Vec<_> = authoritative_zones
         .into_iter()
         .map(|zone| Name::from_utf8(zone).expect("Key in db is not a valid DNS name"))
@@ -128,14 +127,12 @@ pub async fn check_soa(
         }
     }
     for entry in entries {
-        let auth_zone =
-            if let Some(zone) = find_authoritative_zone(&entry.name, &authoritative_zones) {
-                zone
-            } else {
-                find_authoritative_zone(&entry.name, &new_authoritative_zones)
-                    .expect("No new or existing zone contains the db entry")
-            };
-        used_zones.insert(auth_zone);
+        // entries that are not contained in any zone are reported by the check below
+        if let Some(auth_zone) = find_authoritative_zone(&entry.name, &authoritative_zones)
+            .or_else(|| find_authoritative_zone(&entry.name, &new_authoritative_zones))
+        {
+            used_zones.insert(auth_zone);
+        }
     }
     let used_zones: Vec<_> = used_zones.into_iter().collect();
 
@@ -135,6 +135,81 @@ pub async fn get_health(uri: &str) -> u16 {
     health_code
 }
 
+/// the name of the key in vault's transit engine that the zone is signed with
+fn zone_dnssec_key_name(zone: &Name) -> String {
+    let crypto_key_type = "zsk";
+    let zone = zone.to_string();
+    let zone = deabsolute(&zone);
+    let zone = idna::domain_to_ascii(zone).expect("Failed to encode");
+    format!("{zone}-{crypto_key_type}")
+}
+
+/// creates the key the zone is signed with in vault's transit engine
+///
+/// if the key already exists, it is kept as is
+#[instrument(skip(vault_uri, vault_token))]
+pub async fn create_zone_dnssec_key(
+    zone: &Name,
+    vault_uri: &str,
+    vault_token: &str,
+) -> PektinApiResult<()> {
+    let key_name = zone_dnssec_key_name(zone);
+    let vault_res = reqwest::Client::new()
+        .post(format!("{vault_uri}/v1/pektin-transit/keys/{key_name}"))
+        .timeout(Duration::from_secs(2))
+        .header("X-Vault-Token", vault_token)
+        .json(&json!({
+            "type": "ecdsa-p256",
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+    debug!("Create DNSSEC key response status: {}", vault_res.status());
+
+    Ok(())
+}
+
+/// deletes the key the zone is signed with from vault's transit engine
+///
+/// deleting a key that doesn't exist is not an error
+#[instrument(skip(vault_uri, vault_token))]
+pub async fn delete_zone_dnssec_key(
+    zone: &Name,
+    vault_uri: &str,
+    vault_token: &str,
+) -> PektinApiResult<()> {
+    let key_name = zone_dnssec_key_name(zone);
+    let key_url = format!("{vault_uri}/v1/pektin-transit/keys/{key_name}");
+
+    // transit keys can only be deleted after explicitly allowing it
+    let vault_res = reqwest::Client::new()
+        .post(format!("{key_url}/config"))
+        .timeout(Duration::from_secs(2))
+        .header("X-Vault-Token", vault_token)
+        .json(&json!({
+            "deletion_allowed": true,
+        }))
+        .send()
+        .await?;
+    if vault_res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    vault_res.error_for_status()?;
+
+    let vault_res = reqwest::Client::new()
+        .delete(key_url)
+        .timeout(Duration::from_secs(2))
+        .header("X-Vault-Token", vault_token)
+        .send()
+        .await?;
+    debug!("Delete DNSSEC key response status: {}", vault_res.status());
+    if vault_res.status() != reqwest::StatusCode::NOT_FOUND {
+        vault_res.error_for_status()?;
+    }
+
+    Ok(())
+}
+
 /// returns all keys for the zone in PEM format, sorted in the order of their index in the vault response
 ///
 /// you probably want to use the last of the returned keys
@@ -157,12 +232,9 @@ pub async fn get_zone_dnssec_keys(
         /// in PEM format
         public_key: String,
     }
-    let crypto_key_type = "zsk";
-    let zone = zone.to_string();
-    let zone = deabsolute(&zone);
-    let zone = idna::domain_to_ascii(zone).expect("Failed to encode");
+    let key_name = zone_dnssec_key_name(zone);
 
-    let target_url = format!("{vault_uri}/v1/pektin-transit/keys/{zone}-{crypto_key_type}",);
+    let target_url = format!("{vault_uri}/v1/pektin-transit/keys/{key_name}");
     let vault_res = reqwest::Client::new()
         .get(target_url)
         .timeout(Duration::from_secs(2))
@@ -223,14 +295,9 @@ pub async fn sign_with_vault(
     struct VaultData {
         signature: String,
     }
-    let crypto_key_type = "zsk";
-
-    let zone = zone.to_string();
-    let zone = deabsolute(&zone);
-    let zone = idna::domain_to_ascii(zone).expect("Failed to encode");
+    let key_name = zone_dnssec_key_name(zone);
     let tbs_base64 = BASE64.encode(tbs.as_ref());
-    let post_target =
-        format!("{vault_uri}/v1/pektin-transit/sign/{zone}-{crypto_key_type}/sha2-256");
+    let post_target = format!("{vault_uri}/v1/pektin-transit/sign/{key_name}/sha2-256");
     debug!("Posting signing request to vault at {}", post_target);
 
     let vault_res: String = reqwest::Client::new()
@@ -0,0 +1,1193 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use data_encoding::{BASE64, HEXLOWER};
+use pektin_common::proto::rr::domain::Label;
+use pektin_common::proto::rr::rdata::{caa::Property, MX, SOA, SRV};
+use pektin_common::proto::rr::{Name, RData, Record, RecordType};
+use pektin_common::{
+    ARecord, AaaaRecord, CaaRecord, CnameRecord, DbEntry, MxRecord, NsRecord, OpenpgpkeyRecord,
+    RrSet, SoaRecord, SrvRecord, TlsaRecord, TxtRecord,
+};
+use tracing::instrument;
+
+/// An error that occurred while parsing a master file, together with the (1-based) line it
+/// occurred on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneFileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ZoneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// The result of successfully parsing a master file.
+#[derive(Debug, Clone)]
+pub struct ParsedZoneFile {
+    /// The RR sets in the order in which they first appeared in the file.
+    pub entries: Vec<DbEntry>,
+    /// The line each entry in `entries` first appeared on.
+    pub entry_lines: Vec<usize>,
+    /// How many DNSSEC records (e.g. RRSIG, DNSKEY, NSEC3) were skipped because pektin generates
+    /// them itself.
+    pub skipped_dnssec_records: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// An unquoted word as it appears in the file (`raw`) and with its escape sequences resolved
+    /// (`text`). Names are parsed from the raw word, because an escaped dot is part of a label.
+    Word { raw: String, text: String },
+    /// A quoted string with its escape sequences resolved.
+    Quoted(String),
+}
+
+impl Token {
+    fn as_str(&self) -> &str {
+        match self {
+            Token::Word { raw, .. } => raw,
+            Token::Quoted(s) => s,
+        }
+    }
+
+    /// The token if it is an unquoted word. Quoted strings are only allowed as character strings
+    /// in the RDATA, so they are never treated as names, numbers, directives, or `@`.
+    fn word(&self) -> Option<&str> {
+        match self {
+            Token::Word { raw, .. } => Some(raw),
+            Token::Quoted(_) => None,
+        }
+    }
+
+    /// The token as a character string, i.e. with its escape sequences resolved.
+    fn text(&self) -> &str {
+        match self {
+            Token::Word { text, .. } => text,
+            Token::Quoted(s) => s,
+        }
+    }
+}
+
+/// A logical line, i.e. a line in the master file where parentheses have been resolved.
+struct Entry {
+    line: usize,
+    /// true if the line started with whitespace, meaning that the owner name of the previous
+    /// entry should be used
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Splits the master file into logical lines, taking care of comments, quoted strings, escape
+/// sequences, and parentheses spanning multiple lines.
+fn tokenize(text: &str) -> Result<Vec<Entry>, ZoneFileError> {
+    let mut entries = vec![];
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut paren_depth = 0;
+    let mut current: Option<Entry> = None;
+    let mut at_line_start = true;
+
+    while let Some(c) = chars.next() {
+        if at_line_start && paren_depth == 0 {
+            at_line_start = false;
+            current = Some(Entry {
+                line,
+                blank_owner: c == ' ' || c == '\t',
+                tokens: vec![],
+            });
+        }
+        let entry = current.as_mut().expect("no current entry");
+        match c {
+            '\n' => {
+                line += 1;
+                if paren_depth == 0 {
+                    let entry = current.take().expect("no current entry");
+                    if !entry.tokens.is_empty() {
+                        entries.push(entry);
+                    }
+                    at_line_start = true;
+                }
+            }
+            ' ' | '\t' | '\r' => {}
+            ';' => {
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            '(' => paren_depth += 1,
+            ')' => {
+                if paren_depth == 0 {
+                    return Err(ZoneFileError {
+                        line,
+                        message: "unbalanced closing parenthesis".into(),
+                    });
+                }
+                paren_depth -= 1;
+            }
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match unescape(&mut chars) {
+                            Some(escaped) => s.push(escaped.into()),
+                            None => return Err(invalid_escape(line)),
+                        },
+                        Some('\n') => {
+                            line += 1;
+                            s.push('\n');
+                        }
+                        Some(c) => s.push(c),
+                        None => {
+                            return Err(ZoneFileError {
+                                line,
+                                message: "unterminated quoted string".into(),
+                            })
+                        }
+                    }
+                }
+                entry.tokens.push(Token::Quoted(s));
+            }
+            c => {
+                let mut raw = String::new();
+                let mut text = String::new();
+                let mut c = Some(c);
+                while let Some(ch) = c {
+                    if ch == '\\' {
+                        let escaped = unescape(&mut chars).ok_or_else(|| invalid_escape(line))?;
+                        raw.push_str(&escaped.to_string());
+                        text.push(escaped.into());
+                    } else {
+                        raw.push(ch);
+                        text.push(ch);
+                    }
+                    c = match chars.peek() {
+                        Some(' ' | '\t' | '\r' | '\n' | ';' | '(' | ')' | '"') | None => None,
+                        Some(_) => chars.next(),
+                    };
+                }
+                entry.tokens.push(Token::Word { raw, text });
+            }
+        }
+    }
+
+    if paren_depth != 0 {
+        return Err(ZoneFileError {
+            line,
+            message: "unbalanced opening parenthesis".into(),
+        });
+    }
+    if let Some(entry) = current {
+        if !entry.tokens.is_empty() {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// An escape sequence (RFC 1035, section 5.1): `\DDD` is the octet with the decimal value DDD,
+/// `\X` the character X itself, e.g. a quote, a dot in a label, or a space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    Octet(u8),
+    Char(char),
+}
+
+impl From<Escape> for char {
+    fn from(escape: Escape) -> Self {
+        match escape {
+            Escape::Octet(octet) => char::from(octet),
+            Escape::Char(c) => c,
+        }
+    }
+}
+
+impl fmt::Display for Escape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Escape::Octet(octet) => write!(f, "\\{octet:03}"),
+            Escape::Char(c) => write!(f, "\\{c}"),
+        }
+    }
+}
+
+/// Resolves the escape sequence after a backslash, or returns `None` if it is invalid.
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<Escape> {
+    match chars.next()? {
+        d if d.is_ascii_digit() => {
+            let mut digits = String::from(d);
+            for _ in 0..2 {
+                digits.push(chars.next().filter(char::is_ascii_digit)?);
+            }
+            digits.parse().map(Escape::Octet).ok()
+        }
+        c => Some(Escape::Char(c)),
+    }
+}
+
+fn invalid_escape(line: usize) -> ZoneFileError {
+    ZoneFileError {
+        line,
+        message: "invalid escape sequence".into(),
+    }
+}
+
+/// Parses a TTL value, either as a plain number of seconds or in BIND's unit notation (e.g. `1h30m`
+/// or `2D`).
+pub fn parse_ttl(s: &str) -> Option<u32> {
+    if !s.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if let Ok(ttl) = s.parse() {
+        return Some(ttl);
+    }
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        let value: u32 = number.parse().ok()?;
+        total = total.checked_add(value.checked_mul(multiplier)?)?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Some(total)
+}
+
+/// Parses a domain name as written in a master file. Relative names are made absolute with
+/// `origin`.
+///
+/// The labels are split at dots that are not escaped and their escape sequences are resolved, so
+/// `a\.b` is a single label containing a dot. Labels without escape sequences may contain
+/// non-ASCII characters, which are converted to punycode.
+fn parse_name(s: &str, origin: &Name) -> Result<Name, String> {
+    if s == "@" {
+        return Ok(origin.clone());
+    }
+    let invalid = |e: String| format!("invalid domain name '{s}': {e}");
+    if s == "." {
+        return Ok(Name::root());
+    }
+
+    let mut name = Name::new();
+    let mut label = String::new();
+    let mut octets: Vec<u8> = vec![];
+    let mut escaped = false;
+    let mut chars = s.chars().peekable();
+    loop {
+        let c = chars.next();
+        match c {
+            Some('\\') => {
+                escaped = true;
+                match unescape(&mut chars) {
+                    Some(Escape::Octet(octet)) => octets.push(octet),
+                    Some(Escape::Char(c)) => octets.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    None => return Err(invalid("invalid escape sequence".into())),
+                }
+            }
+            Some('.') | None => {
+                if c.is_none() && octets.is_empty() {
+                    // the name ends with a dot, i.e. it is absolute
+                    name.set_fqdn(true);
+                    break;
+                }
+                if octets.is_empty() {
+                    return Err(invalid("empty label".into()));
+                }
+                let next = if escaped {
+                    Label::from_raw_bytes(&octets)
+                } else {
+                    Label::from_utf8(&label)
+                };
+                name = next
+                    .and_then(|next| name.append_label(next))
+                    .map_err(|e| invalid(e.to_string()))?;
+                label.clear();
+                octets.clear();
+                escaped = false;
+                if c.is_none() {
+                    break;
+                }
+            }
+            Some(c) => {
+                label.push(c);
+                octets.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+        }
+    }
+
+    if name.is_fqdn() {
+        Ok(name)
+    } else {
+        name.append_domain(origin)
+            .map_err(|e| invalid(e.to_string()))
+    }
+}
+
+/// Takes the master file text of a zone and parses it into RR sets. `origin` is the initial value
+/// of `$ORIGIN` and is used to resolve relative names.
+///
+/// All errors are collected and returned together, each with the line it occurred on. DNSSEC
+/// records are skipped because they are generated when the records are signed.
+#[instrument(skip(text))]
+pub fn parse_zone_file(text: &str, origin: &Name) -> Result<ParsedZoneFile, Vec<ZoneFileError>> {
+    let entries = tokenize(text).map_err(|e| vec![e])?;
+
+    let mut origin = origin.clone();
+    let mut default_ttl: Option<u32> = None;
+    let mut last_owner: Option<Name> = None;
+    let mut last_ttl: Option<u32> = None;
+
+    let mut errors = vec![];
+    let mut rr_sets: Vec<DbEntry> = vec![];
+    let mut entry_lines = vec![];
+    let mut skipped_dnssec_records = 0;
+
+    'entries: for entry in entries {
+        let line = entry.line;
+        let mut error = |message: String| errors.push(ZoneFileError { line, message });
+        let mut tokens = entry.tokens.iter().peekable();
+
+        let first = *tokens.peek().expect("tokenize() returned an empty entry");
+        if let Some(first) = first
+            .word()
+            .filter(|w| w.starts_with('$') && !entry.blank_owner)
+        {
+            tokens.next();
+            let argument = tokens.next().map(|arg| arg.word().ok_or(arg.as_str()));
+            match (first.to_ascii_uppercase().as_str(), argument) {
+                ("$ORIGIN" | "$TTL", Some(Err(arg))) => error(format!(
+                    "invalid argument \"{arg}\" for {first}, must not be quoted"
+                )),
+                ("$ORIGIN", Some(Ok(arg))) => match parse_name(arg, &origin) {
+                    Ok(name) if name.is_fqdn() => origin = name,
+                    Ok(_) => error("$ORIGIN must be an absolute name".into()),
+                    Err(e) => error(e),
+                },
+                ("$TTL", Some(Ok(arg))) => match parse_ttl(arg) {
+                    Some(ttl) => default_ttl = Some(ttl),
+                    None => error(format!("invalid TTL '{arg}'")),
+                },
+                ("$ORIGIN" | "$TTL", None) => error(format!("missing argument for {first}")),
+                _ => error(format!("unsupported directive '{first}'")),
+            }
+            continue;
+        }
+
+        let owner = if entry.blank_owner {
+            match &last_owner {
+                Some(owner) => owner.clone(),
+                None => {
+                    error("no owner name given and there is no previous record".into());
+                    continue;
+                }
+            }
+        } else {
+            let owner = tokens.next().expect("tokenize() returned an empty entry");
+            match owner.word().map(|owner| parse_name(owner, &origin)) {
+                Some(Ok(name)) => name,
+                Some(Err(e)) => {
+                    error(e);
+                    continue;
+                }
+                None => {
+                    error(format!(
+                        "invalid owner name \"{}\", must not be quoted",
+                        owner.as_str()
+                    ));
+                    continue;
+                }
+            }
+        };
+        last_owner = Some(owner.clone());
+
+        // TTL and class may appear in any order, both are optional
+        let mut ttl = None;
+        let mut rr_type = None;
+        for token in tokens.by_ref() {
+            let Some(token) = token.word() else {
+                error(format!(
+                    "expected a record type, found \"{}\"",
+                    token.as_str()
+                ));
+                continue 'entries;
+            };
+            if token.eq_ignore_ascii_case("IN") {
+                continue;
+            } else if ["CH", "HS", "CS"].contains(&token.to_ascii_uppercase().as_str()) {
+                error(format!("unsupported class '{token}', only IN is supported"));
+                break;
+            } else if let (None, Some(t)) = (ttl, parse_ttl(token)) {
+                ttl = Some(t);
+            } else {
+                rr_type = Some(token);
+                break;
+            }
+        }
+        let rr_type = match rr_type.map(|t| RecordType::from_str(&t.to_ascii_uppercase())) {
+            Some(Ok(t)) => t,
+            Some(Err(_)) => {
+                error(format!("unknown record type '{}'", rr_type.unwrap()));
+                continue;
+            }
+            None => {
+                error("missing record type".into());
+                continue;
+            }
+        };
+
+        if rr_type.is_dnssec() {
+            skipped_dnssec_records += 1;
+            continue;
+        }
+
+        let ttl = match ttl.or(default_ttl).or(last_ttl) {
+            Some(ttl) => ttl,
+            None => {
+                error("no TTL given and no $TTL directive or previous TTL to use".into());
+                continue;
+            }
+        };
+        last_ttl = Some(ttl);
+
+        let rdata: Vec<_> = tokens.collect();
+        let rr_set = match parse_rdata(rr_type, &rdata, &origin) {
+            Ok(r) => r,
+            Err(e) => {
+                error(e);
+                continue;
+            }
+        };
+
+        let existing = rr_sets
+            .iter_mut()
+            .find(|e| e.name.to_lowercase() == owner.to_lowercase() && e.rr_type() == rr_type);
+        match existing {
+            Some(existing) if existing.ttl != ttl => error(format!(
+                "TTL {ttl} differs from the TTL {} of a previous {rr_type} record for {owner}",
+                existing.ttl
+            )),
+            Some(existing) => {
+                if let Err(e) = merge_rr_sets(&mut existing.rr_set, rr_set) {
+                    error(e);
+                }
+            }
+            None => {
+                rr_sets.push(DbEntry {
+                    name: owner,
+                    ttl,
+                    meta: "".to_string(),
+                    rr_set,
+                });
+                entry_lines.push(line);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(ParsedZoneFile {
+            entries: rr_sets,
+            entry_lines,
+            skipped_dnssec_records,
+        })
+    } else {
+        Err(errors)
+    }
+}
+
+/// Appends the records of `other` to `rr_set`, ignoring duplicates. Both must be of the same type.
+pub fn merge_rr_sets(rr_set: &mut RrSet, other: RrSet) -> Result<(), String> {
+    macro_rules! merge {
+        ($($variant:ident),*) => {
+            match (rr_set, other) {
+                $(
+                    (RrSet::$variant { rr_set }, RrSet::$variant { rr_set: other }) => {
+                        for record in other {
+                            if !rr_set.contains(&record) {
+                                rr_set.push(record);
+                            }
+                        }
+                        Ok(())
+                    }
+                )*
+                _ => Err("record type mismatch while merging RR sets".into()),
+            }
+        };
+    }
+    merge!(A, AAAA, CAA, CNAME, MX, NS, OPENPGPKEY, SOA, SRV, TLSA, TXT)
+}
+
+/// Removes the records of `other` from `rr_set` and returns how many records were removed. Both
+/// must be of the same type.
+pub fn remove_from_rr_set(rr_set: &mut RrSet, other: &RrSet) -> Result<usize, String> {
+    macro_rules! remove {
+        ($($variant:ident),*) => {
+            match (rr_set, other) {
+                $(
+                    (RrSet::$variant { rr_set }, RrSet::$variant { rr_set: other }) => {
+                        let len_before = rr_set.len();
+                        rr_set.retain(|record| !other.contains(record));
+                        Ok(len_before - rr_set.len())
+                    }
+                )*
+                _ => Err("record type mismatch while removing records from RR set".into()),
+            }
+        };
+    }
+    remove!(A, AAAA, CAA, CNAME, MX, NS, OPENPGPKEY, SOA, SRV, TLSA, TXT)
+}
+
+/// The RDATA field as an unquoted word, see `Token::word()`.
+fn rdata_word<'a>(token: Option<&&'a Token>, field: &str) -> Result<&'a str, String> {
+    let token = token.ok_or_else(|| format!("missing {field}"))?;
+    token
+        .word()
+        .ok_or_else(|| format!("invalid {field} \"{}\", must not be quoted", token.as_str()))
+}
+
+fn parse_u8(s: Option<&&Token>, field: &str) -> Result<u8, String> {
+    let s = rdata_word(s, field)?;
+    s.parse().map_err(|_| format!("invalid {field} '{s}'"))
+}
+
+fn parse_u16(s: Option<&&Token>, field: &str) -> Result<u16, String> {
+    let s = rdata_word(s, field)?;
+    s.parse().map_err(|_| format!("invalid {field} '{s}'"))
+}
+
+fn parse_u32(s: Option<&&Token>, field: &str) -> Result<u32, String> {
+    let s = rdata_word(s, field)?;
+    s.parse().map_err(|_| format!("invalid {field} '{s}'"))
+}
+
+fn parse_time(s: Option<&&Token>, field: &str) -> Result<u32, String> {
+    let s = rdata_word(s, field)?;
+    parse_ttl(s).ok_or_else(|| format!("invalid {field} '{s}'"))
+}
+
+fn parse_rdata_name(s: Option<&&Token>, field: &str, origin: &Name) -> Result<Name, String> {
+    parse_name(rdata_word(s, field)?, origin)
+}
+
+/// Concatenates the RDATA fields, which may be quoted or not, as character strings.
+fn concat(rdata: &[&Token]) -> String {
+    rdata.iter().map(|token| token.text()).collect()
+}
+
+/// Parses the RDATA part of a single record of the given type.
+fn parse_rdata(rr_type: RecordType, rdata: &[&Token], origin: &Name) -> Result<RrSet, String> {
+    let expect_len = |len: usize| {
+        if rdata.len() == len {
+            Ok(())
+        } else {
+            Err(format!(
+                "{rr_type} record must have {len} data fields, found {}",
+                rdata.len()
+            ))
+        }
+    };
+
+    let rr_set = match rr_type {
+        RecordType::A => {
+            expect_len(1)?;
+            let value = rdata_word(rdata.first(), "IPv4 address")?;
+            let value =
+                Ipv4Addr::from_str(value).map_err(|_| format!("invalid IPv4 address '{value}'"))?;
+            RrSet::A {
+                rr_set: vec![ARecord { value }],
+            }
+        }
+        RecordType::AAAA => {
+            expect_len(1)?;
+            let value = rdata_word(rdata.first(), "IPv6 address")?;
+            let value =
+                Ipv6Addr::from_str(value).map_err(|_| format!("invalid IPv6 address '{value}'"))?;
+            RrSet::AAAA {
+                rr_set: vec![AaaaRecord { value }],
+            }
+        }
+        RecordType::CAA => {
+            expect_len(3)?;
+            let flags = parse_u8(rdata.first(), "CAA flags")?;
+            RrSet::CAA {
+                rr_set: vec![CaaRecord {
+                    issuer_critical: flags & 0b1000_0000 != 0,
+                    tag: Property::from(rdata_word(rdata.get(1), "CAA tag")?.to_string()),
+                    value: rdata[2].text().to_string(),
+                }],
+            }
+        }
+        RecordType::CNAME => {
+            expect_len(1)?;
+            RrSet::CNAME {
+                rr_set: vec![CnameRecord {
+                    value: parse_rdata_name(rdata.first(), "CNAME target", origin)?,
+                }],
+            }
+        }
+        RecordType::MX => {
+            expect_len(2)?;
+            let preference = parse_u16(rdata.first(), "MX preference")?;
+            let exchange = parse_rdata_name(rdata.get(1), "MX exchange", origin)?;
+            RrSet::MX {
+                rr_set: vec![MxRecord {
+                    value: MX::new(preference, exchange),
+                }],
+            }
+        }
+        RecordType::NS => {
+            expect_len(1)?;
+            RrSet::NS {
+                rr_set: vec![NsRecord {
+                    value: parse_rdata_name(rdata.first(), "NS name", origin)?,
+                }],
+            }
+        }
+        RecordType::OPENPGPKEY => {
+            // the base64 encoded key is often split into multiple parts
+            if rdata.is_empty() {
+                return Err("missing OPENPGPKEY data".into());
+            }
+            RrSet::OPENPGPKEY {
+                rr_set: vec![OpenpgpkeyRecord {
+                    value: concat(rdata),
+                }],
+            }
+        }
+        RecordType::SOA => {
+            expect_len(7)?;
+            let mname = parse_rdata_name(rdata.first(), "SOA MNAME", origin)?;
+            let rname = parse_rdata_name(rdata.get(1), "SOA RNAME", origin)?;
+            let serial = parse_u32(rdata.get(2), "SOA serial")?;
+            let refresh = parse_time(rdata.get(3), "SOA refresh")?;
+            let retry = parse_time(rdata.get(4), "SOA retry")?;
+            let expire = parse_time(rdata.get(5), "SOA expire")?;
+            let minimum = parse_time(rdata.get(6), "SOA minimum")?;
+            RrSet::SOA {
+                rr_set: vec![SoaRecord {
+                    value: SOA::new(
+                        mname,
+                        rname,
+                        serial,
+                        refresh as i32,
+                        retry as i32,
+                        expire as i32,
+                        minimum,
+                    ),
+                }],
+            }
+        }
+        RecordType::SRV => {
+            expect_len(4)?;
+            let priority = parse_u16(rdata.first(), "SRV priority")?;
+            let weight = parse_u16(rdata.get(1), "SRV weight")?;
+            let port = parse_u16(rdata.get(2), "SRV port")?;
+            let target = parse_rdata_name(rdata.get(3), "SRV target", origin)?;
+            RrSet::SRV {
+                rr_set: vec![SrvRecord {
+                    value: SRV::new(priority, weight, port, target),
+                }],
+            }
+        }
+        RecordType::TLSA => {
+            // the hex encoded certificate data is often split into multiple parts
+            if rdata.len() < 4 {
+                return Err(format!(
+                    "TLSA record must have at least 4 data fields, found {}",
+                    rdata.len()
+                ));
+            }
+            let cert_usage = parse_u8(rdata.first(), "TLSA certificate usage")?;
+            let selector = parse_u8(rdata.get(1), "TLSA selector")?;
+            let matching = parse_u8(rdata.get(2), "TLSA matching type")?;
+            RrSet::TLSA {
+                rr_set: vec![TlsaRecord {
+                    cert_usage: cert_usage.into(),
+                    selector: selector.into(),
+                    matching: matching.into(),
+                    cert_data: concat(&rdata[3..]).to_lowercase(),
+                }],
+            }
+        }
+        RecordType::TXT => {
+            // multiple character strings are concatenated, see RFC 7208 section 3.3
+            if rdata.is_empty() {
+                return Err("missing TXT data".into());
+            }
+            RrSet::TXT {
+                rr_set: vec![TxtRecord {
+                    value: concat(rdata),
+                }],
+            }
+        }
+        _ => return Err(format!("unsupported record type '{rr_type}'")),
+    };
+
+    Ok(rr_set)
+}
+
+/// Converts the data of a single trust-dns record into an RR set containing just this record.
+pub fn rr_set_from_rdata(rdata: &RData) -> Result<RrSet, String> {
+    let rr_set = match rdata {
+        RData::A(value) => RrSet::A {
+            rr_set: vec![ARecord { value: *value }],
+        },
+        RData::AAAA(value) => RrSet::AAAA {
+            rr_set: vec![AaaaRecord { value: *value }],
+        },
+        RData::CAA(caa) => RrSet::CAA {
+            rr_set: vec![CaaRecord {
+                issuer_critical: caa.issuer_critical(),
+                tag: caa.tag().clone(),
+                // the Display impl of the value includes the surrounding quotes
+                value: caa.value().to_string().trim_matches('"').to_string(),
+            }],
+        },
+        RData::CNAME(value) => RrSet::CNAME {
+            rr_set: vec![CnameRecord {
+                value: value.clone(),
+            }],
+        },
+        RData::MX(value) => RrSet::MX {
+            rr_set: vec![MxRecord {
+                value: value.clone(),
+            }],
+        },
+        RData::NS(value) => RrSet::NS {
+            rr_set: vec![NsRecord {
+                value: value.clone(),
+            }],
+        },
+        RData::OPENPGPKEY(key) => RrSet::OPENPGPKEY {
+            rr_set: vec![OpenpgpkeyRecord {
+                value: BASE64.encode(key.public_key()),
+            }],
+        },
+        RData::SOA(value) => RrSet::SOA {
+            rr_set: vec![SoaRecord {
+                value: value.clone(),
+            }],
+        },
+        RData::SRV(value) => RrSet::SRV {
+            rr_set: vec![SrvRecord {
+                value: value.clone(),
+            }],
+        },
+        RData::TLSA(tlsa) => RrSet::TLSA {
+            rr_set: vec![TlsaRecord {
+                cert_usage: tlsa.cert_usage(),
+                selector: tlsa.selector(),
+                matching: tlsa.matching(),
+                cert_data: HEXLOWER.encode(tlsa.cert_data()),
+            }],
+        },
+        RData::TXT(txt) => RrSet::TXT {
+            rr_set: vec![TxtRecord {
+                // multiple character strings are concatenated, see RFC 7208 section 3.3
+                value: txt
+                    .txt_data()
+                    .iter()
+                    .map(|part| String::from_utf8_lossy(part))
+                    .collect(),
+            }],
+        },
+        other => {
+            return Err(format!(
+                "unsupported record type '{}'",
+                other.to_record_type()
+            ))
+        }
+    };
+    Ok(rr_set)
+}
+
+/// Converts trust-dns records (e.g. received in a zone transfer) into RR sets, merging records
+/// with the same name and type. DNSSEC records are skipped, their number is returned together
+/// with the RR sets.
+///
+/// If the records of an RR set have different TTLs, the smallest one is used.
+pub fn records_to_db_entries(records: &[Record]) -> Result<(Vec<DbEntry>, usize), Vec<String>> {
+    let mut entries: Vec<DbEntry> = Vec::new();
+    let mut skipped_dnssec_records = 0;
+    let mut errors = Vec::new();
+
+    for record in records {
+        if record.record_type().is_dnssec() {
+            skipped_dnssec_records += 1;
+            continue;
+        }
+        let rr_set = match record.data().map(rr_set_from_rdata) {
+            Some(Ok(rr_set)) => rr_set,
+            Some(Err(e)) => {
+                errors.push(format!("{} {}: {}", record.name(), record.record_type(), e));
+                continue;
+            }
+            None => continue,
+        };
+        let name = record.name().to_lowercase();
+        match entries
+            .iter_mut()
+            .find(|e| e.name == name && e.rr_type() == record.record_type())
+        {
+            Some(entry) => {
+                entry.ttl = entry.ttl.min(record.ttl());
+                if let Err(e) = merge_rr_sets(&mut entry.rr_set, rr_set) {
+                    errors.push(format!("{} {}: {}", record.name(), record.record_type(), e));
+                }
+            }
+            None => entries.push(DbEntry {
+                name,
+                ttl: record.ttl(),
+                meta: "".to_string(),
+                rr_set,
+            }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((entries, skipped_dnssec_records))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Quotes a string for use in a master file, escaping quotes, backslashes and non-printable
+/// characters.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\{:03}", c as u8)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits a TXT value into quoted character strings of at most 255 bytes each.
+fn quote_txt(s: &str) -> String {
+    let mut parts = vec![];
+    let mut part = String::new();
+    for c in s.chars() {
+        if part.len() + c.len_utf8() > 255 {
+            parts.push(quote(&part));
+            part.clear();
+        }
+        part.push(c);
+    }
+    parts.push(quote(&part));
+    parts.join(" ")
+}
+
+/// Returns the RDATA of all records in the db entry in master file presentation format.
+fn format_rdata(entry: &DbEntry) -> Vec<String> {
+    match &entry.rr_set {
+        RrSet::A { rr_set } => rr_set.iter().map(|r| r.value.to_string()).collect(),
+        RrSet::AAAA { rr_set } => rr_set.iter().map(|r| r.value.to_string()).collect(),
+        RrSet::CAA { rr_set } => rr_set
+            .iter()
+            .map(|r| {
+                let flags = if r.issuer_critical { 128 } else { 0 };
+                format!("{flags} {} {}", r.tag, quote(&r.value))
+            })
+            .collect(),
+        RrSet::CNAME { rr_set } => rr_set.iter().map(|r| r.value.to_string()).collect(),
+        RrSet::MX { rr_set } => rr_set.iter().map(|r| r.value.to_string()).collect(),
+        RrSet::NS { rr_set } => rr_set.iter().map(|r| r.value.to_string()).collect(),
+        RrSet::OPENPGPKEY { rr_set } => rr_set.iter().map(|r| r.value.clone()).collect(),
+        RrSet::SOA { rr_set } => rr_set.iter().map(|r| r.value.to_string()).collect(),
+        RrSet::SRV { rr_set } => rr_set.iter().map(|r| r.value.to_string()).collect(),
+        RrSet::TLSA { rr_set } => rr_set
+            .iter()
+            .map(|r| {
+                format!(
+                    "{} {} {} {}",
+                    u8::from(r.cert_usage),
+                    u8::from(r.selector),
+                    u8::from(r.matching),
+                    r.cert_data.to_lowercase()
+                )
+            })
+            .collect(),
+        RrSet::TXT { rr_set } => rr_set.iter().map(|r| quote_txt(&r.value)).collect(),
+        // DNSSEC records are only exported and never parsed by pektin, so we can use trust-dns's
+        // presentation format for them
+        _ => {
+            let records: Vec<Record> = match entry.clone().try_into() {
+                Ok(r) => r,
+                Err(_) => return vec![],
+            };
+            records
+                .iter()
+                .filter_map(|r| r.data().map(ToString::to_string))
+                .collect()
+        }
+    }
+}
+
+/// Renders the given db entries as master file text in a canonical form: all names are absolute,
+/// every record has an explicit TTL and class, the SOA record comes first and all other records
+/// are sorted by owner name (in DNSSEC canonical order), record type and RDATA.
+///
+/// The output can be parsed again with [`parse_zone_file`].
+#[instrument(skip(entries))]
+pub fn write_zone_file(zone: &Name, entries: &[DbEntry]) -> String {
+    let mut lines: Vec<(bool, Name, u16, String, String)> = entries
+        .iter()
+        .flat_map(|entry| {
+            let rr_type = entry.rr_type();
+            format_rdata(entry).into_iter().map(move |rdata| {
+                (
+                    !(rr_type == RecordType::SOA && &entry.name == zone),
+                    entry.name.to_lowercase(),
+                    u16::from(rr_type),
+                    format!(
+                        "{}\t{}\tIN\t{}",
+                        entry.name.to_lowercase(),
+                        entry.ttl,
+                        rr_type
+                    ),
+                    rdata,
+                )
+            })
+        })
+        .collect();
+    lines.sort();
+    lines.dedup();
+
+    let mut zone_file = format!("$ORIGIN {}\n", zone.to_lowercase());
+    for (_, _, _, prefix, rdata) in lines {
+        zone_file.push_str(&prefix);
+        zone_file.push('\t');
+        zone_file.push_str(&rdata);
+        zone_file.push('\n');
+    }
+    zone_file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin() -> Name {
+        Name::from_ascii("example.com.").unwrap()
+    }
+
+    #[test]
+    fn parse_ttl_requires_a_number() {
+        assert_eq!(parse_ttl("3600"), Some(3600));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("2D"), Some(2 * 24 * 60 * 60));
+        assert_eq!(parse_ttl(""), None);
+        assert_eq!(parse_ttl("h"), None);
+        assert_eq!(parse_ttl("1hm"), None);
+        assert_eq!(parse_ttl("1h30"), None);
+    }
+
+    #[test]
+    fn quoted_empty_ttl_is_an_error() {
+        let errors = parse_zone_file("$TTL \"\"\n", &origin()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 1);
+    }
+
+    #[test]
+    fn quoted_strings_are_not_syntax() {
+        let text = "$TTL 300\n@ IN TXT \"@\" \"(\" \"$ORIGIN\"\n";
+        let parsed = parse_zone_file(text, &origin()).unwrap();
+        assert_eq!(parsed.entries.len(), 1);
+        assert_eq!(parsed.entries[0].name, origin());
+        assert_eq!(
+            parsed.entries[0].rr_set,
+            RrSet::TXT {
+                rr_set: vec![TxtRecord {
+                    value: "@($ORIGIN".into()
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn quoted_names_are_rejected() {
+        let text = "$TTL 300\nwww IN CNAME \"@\"\n\"$TTL\" 300\n";
+        let errors = parse_zone_file(text, &origin()).unwrap_err();
+        assert_eq!(
+            errors.iter().map(|e| e.line).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+
+    fn labels(name: &Name) -> Vec<&[u8]> {
+        name.iter().collect()
+    }
+
+    #[test]
+    fn escape_sequences_are_decoded() {
+        let text = concat!(
+            "$TTL 300\n",
+            "@ IN TXT foo\\032bar \"a\\\"b\\\\c\\059\"\n",
+            "a\\.b IN A 192.0.2.1\n",
+            "\\065bc IN CNAME x\\.y.example.org.\n",
+            "*.wild IN A 192.0.2.2\n",
+        );
+        let parsed = parse_zone_file(text, &origin()).unwrap();
+        assert_eq!(parsed.entries.len(), 4);
+        assert_eq!(
+            parsed.entries[0].rr_set,
+            RrSet::TXT {
+                rr_set: vec![TxtRecord {
+                    value: "foo bara\"b\\c;".into()
+                }]
+            }
+        );
+        assert_eq!(
+            labels(&parsed.entries[1].name),
+            vec![&b"a.b"[..], b"example", b"com"]
+        );
+        assert_eq!(
+            labels(&parsed.entries[2].name),
+            vec![&b"Abc"[..], b"example", b"com"]
+        );
+        match &parsed.entries[2].rr_set {
+            RrSet::CNAME { rr_set } => assert_eq!(
+                labels(&rr_set[0].value),
+                vec![&b"x.y"[..], b"example", b"org"]
+            ),
+            rr_set => panic!("expected a CNAME RR set, got {rr_set:?}"),
+        }
+        assert!(parsed.entries[3].name.is_wildcard());
+    }
+
+    #[test]
+    fn invalid_escape_sequences_are_rejected() {
+        for text in ["@ 300 IN TXT foo\\25x\n", "@ 300 IN TXT \"\\256\"\n", "a\\"] {
+            let errors = parse_zone_file(text, &origin()).unwrap_err();
+            assert_eq!(errors.len(), 1, "{text}");
+            assert_eq!(errors[0].line, 1, "{text}");
+        }
+        let errors = parse_zone_file("a..b 300 IN A 192.0.2.1\n", &origin()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn escaped_names_and_strings_survive_a_round_trip() {
+        let text = concat!(
+            "$TTL 300\n",
+            "@ IN TXT \"a\\\"b\\\\c\\009d\"\n",
+            "a\\.b IN A 192.0.2.1\n",
+        );
+        let parsed = parse_zone_file(text, &origin()).unwrap();
+        let written = write_zone_file(&origin(), &parsed.entries);
+        let reparsed = parse_zone_file(&written, &origin()).unwrap();
+        assert_eq!(reparsed.entries, parsed.entries);
+    }
+
+    #[test]
+    fn include_is_unsupported() {
+        let errors = parse_zone_file("$INCLUDE other.zone\n", &origin()).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![ZoneFileError {
+                line: 1,
+                message: "unsupported directive '$INCLUDE'".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn directives_apply_to_the_following_records() {
+        let text = concat!(
+            "$TTL 300\n",
+            "a IN A 192.0.2.1\n",
+            "$TTL 600\n",
+            "$ORIGIN sub.example.com.\n",
+            "b IN A 192.0.2.2\n",
+            "c 60 IN A 192.0.2.3\n",
+            "@ IN A 192.0.2.4\n",
+        );
+        let parsed = parse_zone_file(text, &origin()).unwrap();
+        let entries: Vec<_> = parsed
+            .entries
+            .iter()
+            .map(|e| (e.name.to_string(), e.ttl))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("a.example.com.".into(), 300),
+                ("b.sub.example.com.".into(), 600),
+                ("c.sub.example.com.".into(), 60),
+                ("sub.example.com.".into(), 600),
+            ]
+        );
+        assert_eq!(parsed.entry_lines, vec![2, 5, 6, 7]);
+    }
+
+    #[test]
+    fn parentheses_span_multiple_lines() {
+        let text = concat!(
+            "$TTL 300\n",
+            "@ IN SOA ns1 hostmaster ( ; the primary and the contact\n",
+            "    2023010101 ; serial\n",
+            "    7200       ; refresh\n",
+            "    3600 1209600\n",
+            "    300 )\n",
+            "www IN TXT ( \"a\" ; first part\n",
+            "    \"(b)\" )\n",
+            "mail IN A 192.0.2.1\n",
+        );
+        let parsed = parse_zone_file(text, &origin()).unwrap();
+        assert_eq!(parsed.entry_lines, vec![2, 7, 9]);
+        match &parsed.entries[0].rr_set {
+            RrSet::SOA { rr_set } => {
+                let soa = &rr_set[0].value;
+                assert_eq!(soa.mname().to_string(), "ns1.example.com.");
+                assert_eq!(soa.rname().to_string(), "hostmaster.example.com.");
+                assert_eq!(soa.serial(), 2023010101);
+                assert_eq!(soa.refresh(), 7200);
+                assert_eq!(soa.retry(), 3600);
+                assert_eq!(soa.expire(), 1209600);
+                assert_eq!(soa.minimum(), 300);
+            }
+            rr_set => panic!("expected a SOA RR set, got {rr_set:?}"),
+        }
+        assert_eq!(
+            parsed.entries[1].rr_set,
+            RrSet::TXT {
+                rr_set: vec![TxtRecord {
+                    value: "a(b)".into()
+                }]
+            }
+        );
+
+        let errors = parse_zone_file("$TTL 300\n@ IN TXT ( \"a\"\n", &origin()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -0,0 +1,638 @@
+use std::net::SocketAddr;
+
+use pektin_common::proto::error::ProtoError;
+use pektin_common::proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use pektin_common::proto::rr::{Name, RData, Record, RecordType};
+use pektin_common::proto::serialize::binary::BinEncodable;
+use pektin_common::{DbEntry, RrSet};
+use rand::random;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::db::get_zone_rr_sets;
+use crate::errors_and_responses::{PektinApiError, PektinApiResult};
+use crate::journal::{get_journal, soa_serial, JournalEntry};
+use crate::storage::Storage;
+use crate::tsig::{
+    key_allowed_for_zone, sign_message, verify_message, verify_subsequent_message, TsigError,
+    TsigKey,
+};
+use crate::types::AppState;
+
+/// The maximum number of records we put into a single message of a zone transfer.
+const RECORDS_PER_MESSAGE: usize = 100;
+/// The maximum size of the records in a single message of a zone transfer. This leaves room for
+/// the header, the question and the TSIG record, as a DNS message over TCP can't exceed 65535 bytes.
+const MAX_RECORDS_SIZE: usize = 60_000;
+/// How long we wait for the other side of a zone transfer to send a message, both when requesting
+/// a zone transfer from a primary and when serving zone transfers. Idle connections of clients are
+/// closed after this time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The maximum number of consecutive messages without TSIG a primary may send (RFC 8945, section
+/// 5.3.1).
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+#[derive(Debug, Error)]
+pub enum ZoneTransferError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid DNS message: {0}")]
+    Proto(#[from] ProtoError),
+    #[error("TSIG error: {0}")]
+    Tsig(#[from] TsigError),
+    #[error("The primary did not answer in time")]
+    Timeout,
+    #[error("The primary answered with {0}")]
+    ErrorResponse(ResponseCode),
+    #[error("Invalid zone transfer response: {0}")]
+    InvalidResponse(&'static str),
+}
+
+/// Serves AXFR and IXFR requests for all zones on the given address. All requests must be signed
+/// with one of the TSIG keys in `state.tsig_keys` that is allowed for the zone (see
+/// `state.tsig_key_zones`).
+///
+/// IXFR responses are built from the zone journals. If the journal doesn't reach back to the
+/// serial of the client, the whole zone is sent instead (like an AXFR).
+pub async fn zone_transfer_server(state: AppState, bind_addr: String) -> PektinApiResult<()> {
+    let listener = TcpListener::bind(&bind_addr).await?;
+    info!("Serving zone transfers on {}", bind_addr);
+    if state.tsig_keys.is_empty() {
+        warn!("No TSIG keys are configured, all zone transfer requests will be rejected");
+    }
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Could not accept zone transfer connection: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&state, stream).await {
+                debug!("Zone transfer connection to {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(state: &AppState, mut stream: TcpStream) -> PektinApiResult<()> {
+    // DNS over TCP prefixes every message with its length as a two byte integer
+    loop {
+        let read_request = async {
+            let len = stream.read_u16().await?;
+            let mut request = vec![0; len as usize];
+            stream.read_exact(&mut request).await?;
+            Ok::<_, std::io::Error>(request)
+        };
+        let request = match timeout(REQUEST_TIMEOUT, read_request).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "the client did not send a request in time",
+                )
+                .into())
+            }
+        };
+
+        for response in handle_request(state, &request).await {
+            let len = u16::try_from(response.len()).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "zone transfer response exceeds 65535 bytes",
+                )
+            })?;
+            stream.write_u16(len).await?;
+            stream.write_all(&response).await?;
+        }
+        stream.flush().await?;
+    }
+}
+
+/// Handles a single request and returns the response messages in wire format.
+#[instrument(skip(state, request))]
+async fn handle_request(state: &AppState, request: &[u8]) -> Vec<Vec<u8>> {
+    let request_message = match Message::from_vec(request) {
+        Ok(m) => m,
+        Err(e) => {
+            debug!("Received invalid DNS message: {}", e);
+            return vec![];
+        }
+    };
+    let error_response = |response_code| {
+        Message::error_msg(
+            request_message.id(),
+            request_message.op_code(),
+            response_code,
+        )
+        .to_vec()
+        .map(|m| vec![m])
+        .unwrap_or_default()
+    };
+
+    let (key, request_mac) = match verify_message(request, &state.tsig_keys, None, true) {
+        Ok(k) => k,
+        Err(e) => {
+            debug!("Rejecting zone transfer request: {}", e);
+            return error_response(ResponseCode::NotAuth);
+        }
+    };
+    if request_message.message_type() != MessageType::Query
+        || request_message.op_code() != OpCode::Query
+    {
+        return error_response(ResponseCode::NotImp);
+    }
+    let query = match request_message.queries() {
+        [query] => query.clone(),
+        _ => return error_response(ResponseCode::FormErr),
+    };
+    if !key_allowed_for_zone(&state.tsig_key_zones, key, query.name()) {
+        debug!(
+            "Rejecting zone transfer request for {} because TSIG key {} is not allowed for it",
+            query.name(),
+            key.name
+        );
+        return error_response(ResponseCode::Refused);
+    }
+
+    let answers =
+        match answer_query(state, &request_message, query.name(), query.query_type()).await {
+            Ok(Ok(a)) => a,
+            Ok(Err(response_code)) => return error_response(response_code),
+            Err(e) => {
+                error!("Could not answer zone transfer request: {}", e);
+                return error_response(ResponseCode::ServFail);
+            }
+        };
+
+    let chunks = match split_into_messages(&answers) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Could not split zone transfer response: {}", e);
+            return error_response(ResponseCode::ServFail);
+        }
+    };
+    let mut responses = Vec::new();
+    let mut previous_mac = request_mac;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let mut response = Message::new();
+        response
+            .set_id(request_message.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true)
+            .add_query(query.clone())
+            .add_answers(chunk.iter().cloned());
+        let signed = sign_message(&mut response, key, Some(&previous_mac), i == 0)
+            .and_then(|mac| Ok((mac, response.to_vec()?)));
+        match signed {
+            Ok((mac, bytes)) => {
+                previous_mac = mac;
+                responses.push(bytes);
+            }
+            Err(e) => {
+                error!("Could not sign zone transfer response: {}", e);
+                return error_response(ResponseCode::ServFail);
+            }
+        }
+    }
+    responses
+}
+
+/// Splits the records into the chunks that are sent in separate messages, so that no message has
+/// more than `RECORDS_PER_MESSAGE` records or exceeds the maximum message size.
+///
+/// The sizes are measured without name compression, so they are an upper bound for the size of the
+/// records in the message.
+fn split_into_messages(records: &[Record]) -> Result<Vec<&[Record]>, ProtoError> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, record) in records.iter().enumerate() {
+        let record_size = record.to_bytes()?.len();
+        if record_size > MAX_RECORDS_SIZE {
+            return Err(format!(
+                "{} {} is too large for a DNS message",
+                record.name(),
+                record.record_type()
+            )
+            .into());
+        }
+        if i - start == RECORDS_PER_MESSAGE || size + record_size > MAX_RECORDS_SIZE {
+            chunks.push(&records[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += record_size;
+    }
+    if start < records.len() {
+        chunks.push(&records[start..]);
+    }
+    Ok(chunks)
+}
+
+/// Returns the records to answer the query with, or the response code to return if the query
+/// cannot be answered.
+async fn answer_query(
+    state: &AppState,
+    request: &Message,
+    zone: &Name,
+    query_type: RecordType,
+) -> PektinApiResult<Result<Vec<Record>, ResponseCode>> {
+    if !matches!(
+        query_type,
+        RecordType::AXFR | RecordType::IXFR | RecordType::SOA
+    ) {
+        return Ok(Err(ResponseCode::Refused));
+    }
+
+    let mut con = state.db_pool.get().await?;
+    let mut dnssec_con = state.db_pool_dnssec.get().await?;
+    let zone = zone.to_lowercase();
+    if !con.get_zones().await?.contains(&zone.to_string()) {
+        return Ok(Err(ResponseCode::NotAuth));
+    }
+
+    let entries = get_zone_rr_sets(&[&zone], true, &mut con, &mut dnssec_con)
+        .await?
+        .pop()
+        .flatten()
+        .unwrap_or_default();
+    let (soa, entries): (Vec<_>, Vec<_>) = entries
+        .into_iter()
+        .partition(|e| matches!(e.rr_set, RrSet::SOA { .. }));
+    let soa = match soa.into_iter().next() {
+        Some(soa) => soa,
+        None => return Ok(Err(ResponseCode::ServFail)),
+    };
+    let serial = soa_serial(&soa).expect("SOA entry contains no SOA record");
+    let soa_records = to_records(vec![soa])?;
+
+    match query_type {
+        RecordType::SOA => Ok(Ok(soa_records)),
+        RecordType::IXFR => {
+            // the client's current SOA record is in the authority section
+            let client_serial = request.name_servers().iter().find_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some(soa.serial()),
+                _ => None,
+            });
+            let client_serial = match client_serial {
+                Some(s) => s,
+                None => return Ok(Err(ResponseCode::FormErr)),
+            };
+            if client_serial == serial {
+                return Ok(Ok(soa_records));
+            }
+
+            let journal = get_journal(&zone, &mut con).await?;
+            match journal_since(&journal, client_serial, serial) {
+                Some(changes) => {
+                    let mut records = soa_records.clone();
+                    for change in changes {
+                        records.extend(to_records(vec![change.soa_before.clone()])?);
+                        records.extend(to_records(change.removed.clone())?);
+                        records.extend(to_records(vec![change.soa_after.clone()])?);
+                        records.extend(to_records(change.added.clone())?);
+                    }
+                    records.extend(soa_records);
+                    Ok(Ok(records))
+                }
+                None => {
+                    debug!(
+                        "journal of {} doesn't cover serial {}, sending the whole zone",
+                        zone, client_serial
+                    );
+                    Ok(Ok(whole_zone(soa_records, entries)?))
+                }
+            }
+        }
+        _ => Ok(Ok(whole_zone(soa_records, entries)?)),
+    }
+}
+
+/// Returns the uninterrupted sequence of journal entries leading from `from_serial` to
+/// `to_serial`, if the journal contains it.
+fn journal_since(
+    journal: &[JournalEntry],
+    from_serial: u32,
+    to_serial: u32,
+) -> Option<&[JournalEntry]> {
+    let start = journal
+        .iter()
+        .rposition(|entry| entry.serial_before() == from_serial)?;
+    let changes = &journal[start..];
+    let is_chain = changes
+        .windows(2)
+        .all(|w| w[0].serial_after() == w[1].serial_before());
+    if is_chain && changes.last()?.serial_after() == to_serial {
+        Some(changes)
+    } else {
+        None
+    }
+}
+
+/// The records of an AXFR response: the SOA record, all other records, and the SOA record again.
+fn whole_zone(soa_records: Vec<Record>, entries: Vec<DbEntry>) -> PektinApiResult<Vec<Record>> {
+    let mut records = soa_records.clone();
+    records.extend(to_records(entries)?);
+    records.extend(soa_records);
+    Ok(records)
+}
+
+fn to_records(entries: Vec<DbEntry>) -> PektinApiResult<Vec<Record>> {
+    let mut records = Vec::new();
+    for entry in entries {
+        let db_key = entry.db_key();
+        let entry_records: Vec<Record> = entry
+            .try_into()
+            .map_err(|_| PektinApiError::InvalidDbEntry(db_key))?;
+        records.extend(entry_records);
+    }
+    Ok(records)
+}
+
+/// Requests the zone from the primary via AXFR and returns its records. The SOA record is only
+/// contained once, as the first record.
+///
+/// If a TSIG key is given, the request is signed and all responses must be authenticated with the
+/// same key.
+#[instrument(skip(key))]
+pub async fn request_axfr(
+    zone: &Name,
+    primary: SocketAddr,
+    key: Option<&TsigKey>,
+) -> Result<Vec<Record>, ZoneTransferError> {
+    let mut request = Message::new();
+    request
+        .set_id(random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(zone.clone(), RecordType::AXFR));
+    let mut previous_mac = match key {
+        Some(key) => Some(sign_message(&mut request, key, None, true)?),
+        None => None,
+    };
+    let request_bytes = request.to_vec()?;
+
+    let mut stream = timeout(REQUEST_TIMEOUT, TcpStream::connect(primary))
+        .await
+        .map_err(|_| ZoneTransferError::Timeout)??;
+    stream.write_u16(request_bytes.len() as u16).await?;
+    stream.write_all(&request_bytes).await?;
+    stream.flush().await?;
+
+    let mut records: Vec<Record> = Vec::new();
+    let mut first_message = true;
+    let mut unsigned_messages = Vec::new();
+    let mut unsigned_count = 0;
+    loop {
+        let bytes = timeout(REQUEST_TIMEOUT, async {
+            let len = stream.read_u16().await?;
+            let mut bytes = vec![0; len as usize];
+            stream.read_exact(&mut bytes).await?;
+            Ok::<_, std::io::Error>(bytes)
+        })
+        .await
+        .map_err(|_| ZoneTransferError::Timeout)??;
+        let message = Message::from_vec(&bytes)?;
+        if message.id() != request.id() || message.message_type() != MessageType::Response {
+            return Err(ZoneTransferError::InvalidResponse(
+                "the response does not match the request",
+            ));
+        }
+
+        if let (Some(key), Some(mac)) = (key, &previous_mac) {
+            if !message.signature().is_empty() {
+                let new_mac = if first_message {
+                    verify_message(&bytes, std::slice::from_ref(key), Some(mac), true)?.1
+                } else {
+                    verify_subsequent_message(&bytes, key, mac, &unsigned_messages)?
+                };
+                previous_mac = Some(new_mac);
+                unsigned_messages.clear();
+                unsigned_count = 0;
+            } else if first_message || unsigned_count >= MAX_UNSIGNED_MESSAGES {
+                return Err(TsigError::Unsigned.into());
+            } else {
+                unsigned_messages.extend_from_slice(&bytes);
+                unsigned_count += 1;
+            }
+        }
+        first_message = false;
+
+        if message.response_code() != ResponseCode::NoError {
+            return Err(ZoneTransferError::ErrorResponse(message.response_code()));
+        }
+        if records.is_empty()
+            && message.answers().first().map(Record::record_type) != Some(RecordType::SOA)
+        {
+            return Err(ZoneTransferError::InvalidResponse(
+                "the first record is not a SOA record",
+            ));
+        }
+
+        for record in message.answers() {
+            // the transfer ends with the SOA record it started with
+            if !records.is_empty() && record.record_type() == RecordType::SOA {
+                if key.is_some() && unsigned_count > 0 {
+                    return Err(TsigError::Unsigned.into());
+                }
+                return Ok(records);
+            }
+            records.push(record.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pektin_common::proto::rr::dnssec::rdata::{DNSSECRData, NSEC, SIG};
+    use pektin_common::proto::rr::dnssec::Algorithm;
+    use pektin_common::proto::rr::rdata::{SOA, TXT};
+
+    use super::*;
+    use crate::test_utils::{a_entry, set_records, soa_entry, test_state};
+    use crate::zone_file::records_to_db_entries;
+
+    fn zone() -> Name {
+        Name::from_ascii("example.com.").unwrap()
+    }
+
+    fn soa() -> Record {
+        let soa = SOA::new(
+            Name::from_ascii("ns1.example.com.").unwrap(),
+            Name::from_ascii("hostmaster.example.com.").unwrap(),
+            2022010101,
+            3600,
+            600,
+            604800,
+            300,
+        );
+        Record::from_rdata(zone(), 3600, RData::SOA(soa))
+    }
+
+    fn txt(name: &str, len: usize) -> Record {
+        let name = Name::from_ascii(name).unwrap();
+        // a single character string can't be longer than 255 bytes
+        let strings = (0..len)
+            .step_by(250)
+            .map(|i| "a".repeat((len - i).min(250)));
+        Record::from_rdata(name, 300, RData::TXT(TXT::new(strings.collect())))
+    }
+
+    /// Serves a single AXFR request that is signed with the key. The records are sent in the given
+    /// messages, each of them signed.
+    async fn serve_axfr(listener: TcpListener, key: TsigKey, messages: Vec<Vec<Record>>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let len = stream.read_u16().await.unwrap();
+        let mut request = vec![0; len as usize];
+        stream.read_exact(&mut request).await.unwrap();
+        let (_, mut previous_mac) =
+            verify_message(&request, std::slice::from_ref(&key), None, true).unwrap();
+        let request = Message::from_vec(&request).unwrap();
+        assert_eq!(request.queries()[0].query_type(), RecordType::AXFR);
+
+        for (i, records) in messages.into_iter().enumerate() {
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(OpCode::Query)
+                .add_query(request.queries()[0].clone())
+                .add_answers(records);
+            previous_mac = sign_message(&mut response, &key, Some(&previous_mac), i == 0).unwrap();
+            let bytes = response.to_vec().unwrap();
+            stream.write_u16(bytes.len() as u16).await.unwrap();
+            stream.write_all(&bytes).await.unwrap();
+        }
+        stream.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn axfr_drops_dnssec_records_of_the_primary() {
+        let key = TsigKey::parse("transfer-key.:hmac-sha256:c2VjcmV0").unwrap();
+        let www = Name::from_ascii("www.example.com.").unwrap();
+        let mut rrsig = Record::from_rdata(
+            www.clone(),
+            300,
+            RData::DNSSEC(DNSSECRData::SIG(SIG::new(
+                RecordType::TXT,
+                Algorithm::ECDSAP256SHA256,
+                3,
+                300,
+                1_700_000_000,
+                1_600_000_000,
+                12345,
+                zone(),
+                vec![1, 2, 3, 4],
+            ))),
+        );
+        let nsec = Record::from_rdata(
+            www.clone(),
+            300,
+            RData::DNSSEC(DNSSECRData::NSEC(NSEC::new(
+                zone(),
+                vec![RecordType::TXT, RecordType::RRSIG, RecordType::NSEC],
+            ))),
+        );
+        // trust-dns uses SIG for the data of RRSIG records as well
+        rrsig.set_record_type(RecordType::RRSIG);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary = listener.local_addr().unwrap();
+        let messages = vec![
+            vec![soa(), txt("www.example.com.", 10), rrsig],
+            vec![nsec, txt("mail.example.com.", 10), soa()],
+        ];
+        let server = tokio::spawn(serve_axfr(listener, key.clone(), messages));
+
+        let records = request_axfr(&zone(), primary, Some(&key)).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].record_type(), RecordType::SOA);
+
+        let (entries, skipped_dnssec_records) = records_to_db_entries(&records).unwrap();
+        assert_eq!(skipped_dnssec_records, 2);
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|e| !e.rr_type().is_dnssec()));
+    }
+
+    #[test]
+    fn large_responses_are_split_by_size() {
+        let records: Vec<_> = (0..40)
+            .map(|i| txt(&format!("r{}.example.com.", i), 2000))
+            .collect();
+        let chunks = split_into_messages(&records).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), records.len());
+
+        let query = Query::query(zone(), RecordType::AXFR);
+        for chunk in chunks {
+            let mut message = Message::new();
+            message.add_query(query.clone()).add_answers(chunk.to_vec());
+            assert!(message.to_vec().unwrap().len() < MAX_RECORDS_SIZE + 1000);
+        }
+    }
+
+    #[test]
+    fn responses_are_split_by_record_count() {
+        let records: Vec<_> = (0..250)
+            .map(|i| txt(&format!("r{}.example.com.", i), 1))
+            .collect();
+        let chunks = split_into_messages(&records).unwrap();
+        let lens: Vec<_> = chunks.iter().map(|c| c.len()).collect();
+        assert_eq!(lens, vec![100, 100, 50]);
+    }
+
+    #[actix_web::test]
+    async fn ixfr_contains_the_nsec3_changes() {
+        let state = test_state();
+        set_records(&[soa_entry("example.com.", 1)], &state).await;
+        set_records(&[a_entry("www.example.com.", [192, 0, 2, 1])], &state).await;
+
+        // the secondary still has serial 1
+        let mut request = Message::new();
+        request
+            .add_query(Query::query(zone(), RecordType::IXFR))
+            .add_name_server(
+                to_records(vec![soa_entry("example.com.", 1)])
+                    .unwrap()
+                    .remove(0),
+            );
+        let records = answer_query(&state, &request, &zone(), RecordType::IXFR)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // SOA 2, SOA 1, removed records, SOA 2, added records, SOA 2
+        let soa_positions: Vec<_> = records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.record_type() == RecordType::SOA)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(soa_positions.len(), 4);
+        assert_eq!(&soa_positions[..2], &[0, 1]);
+        assert_eq!(soa_positions[3], records.len() - 1);
+        let count_nsec3 = |records: &[Record]| {
+            records
+                .iter()
+                .filter(|r| r.record_type() == RecordType::NSEC3)
+                .count()
+        };
+        let (removed, added) = records[2..records.len() - 1].split_at(soa_positions[2] - 2);
+        // the NSEC3 record of the apex changes and www gets one
+        assert_eq!(count_nsec3(removed), 1);
+        assert_eq!(count_nsec3(added), 2);
+        assert!(added.iter().any(|r| r.record_type() == RecordType::A
+            && r.name() == &Name::from_ascii("www.example.com.").unwrap()));
+    }
+}